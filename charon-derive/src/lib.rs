@@ -0,0 +1,250 @@
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, spanned::Spanned, Attribute, Data, DeriveInput, Error, Fields,
+    GenericArgument, PathArguments, Result, Type,
+};
+
+//
+// #[derive(Templated)] takes a struct whose leaves are TemplatedInput<T> and generates two things:
+//
+// - a Compiled<Name> struct with the same fields, where every TemplatedInput<T> is replaced with
+//   T, and any other named type Foo is replaced with CompiledFoo (which is expected to also be
+//   derived). Option, Vec and tuples are walked through.
+// - an implementation of charon::Templated for the struct, whose Output is the struct above.
+//
+// Fields marked #[templated(unwrap_or_default)] must be an Option; the compiled field drops the
+// Option and uses the default value of the compiled type when the field is unset.
+//
+// Fields marked #[templated(skip_none)] must stay an Option once compiled; the compiled field is
+// left out when serialized instead of being sent as null. Nothing else is skipped, so the compiled
+// structs serialize the way they did when they were written out by hand.
+//
+
+#[proc_macro_derive(Templated, attributes(templated))]
+pub fn derive_templated(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(input) {
+        Ok(ts) => ts.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let compiled_name = format_ident!("Compiled{}", name);
+
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "#[derive(Templated)] does not support generic structs",
+        ));
+    }
+
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new(
+                    name.span(),
+                    "#[derive(Templated)] requires a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                name.span(),
+                "#[derive(Templated)] can only be used on structs",
+            ))
+        }
+    };
+
+    let mut compiled_fields = Vec::new();
+    let mut assignments = Vec::new();
+
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let fvis = &field.vis;
+        let docs = field
+            .attrs
+            .iter()
+            .filter(|a| a.path().is_ident("doc"))
+            .collect::<Vec<&Attribute>>();
+
+        let options = field_options(&field.attrs)?;
+
+        let (ty, value) = if options.unwrap_or_default {
+            let inner = option_inner(&field.ty).ok_or_else(|| {
+                Error::new(
+                    field.ty.span(),
+                    "#[templated(unwrap_or_default)] can only be used on Option fields",
+                )
+            })?;
+
+            (
+                compiled_type(inner)?,
+                quote! {
+                    ::charon::Templated::compile(&self.#ident, globals, prompts, responses)?
+                        .unwrap_or_default()
+                },
+            )
+        } else {
+            (
+                compiled_type(&field.ty)?,
+                quote! {
+                    ::charon::Templated::compile(&self.#ident, globals, prompts, responses)?
+                },
+            )
+        };
+
+        let skip = if options.skip_none {
+            if option_inner(&ty).is_none() {
+                return Err(Error::new(
+                    field.ty.span(),
+                    "#[templated(skip_none)] can only be used on fields that are an Option once compiled",
+                ));
+            }
+
+            quote! { #[serde(skip_serializing_if = "Option::is_none")] }
+        } else {
+            quote! {}
+        };
+
+        compiled_fields.push(quote! {
+            #(#docs)*
+            #skip
+            #fvis #ident: #ty
+        });
+
+        assignments.push(quote! { #ident: #value });
+    }
+
+    let doc = format!(
+        "Compiled form of [`{}`], generated by `#[derive(Templated)]`.",
+        name
+    );
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(
+            Debug,
+            Clone,
+            Default,
+            Eq,
+            PartialEq,
+            ::serde::Serialize,
+            ::serde::Deserialize,
+        )]
+        #vis struct #compiled_name {
+            #(#compiled_fields,)*
+        }
+
+        impl ::charon::Templated for #name {
+            type Output = #compiled_name;
+
+            fn compile(
+                &self,
+                globals: &::charon::Global,
+                prompts: &::charon::PromptCollection,
+                responses: &::charon::PromptResponses,
+            ) -> ::std::result::Result<Self::Output, ::anyhow::Error> {
+                Ok(#compiled_name {
+                    #(#assignments,)*
+                })
+            }
+        }
+    })
+}
+
+#[derive(Default)]
+struct FieldOptions {
+    unwrap_or_default: bool,
+    skip_none: bool,
+}
+
+fn field_options(attrs: &[Attribute]) -> Result<FieldOptions> {
+    let mut options = FieldOptions::default();
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("templated")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("unwrap_or_default") {
+                options.unwrap_or_default = true;
+                Ok(())
+            } else if meta.path.is_ident("skip_none") {
+                options.skip_none = true;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported templated attribute"))
+            }
+        })?;
+    }
+
+    Ok(options)
+}
+
+fn option_inner(ty: &Type) -> Option<&Type> {
+    if let Type::Path(path) = ty {
+        let segment = path.path.segments.last()?;
+        if segment.ident == "Option" {
+            return generic_argument(&segment.arguments);
+        }
+    }
+
+    None
+}
+
+fn generic_argument(args: &PathArguments) -> Option<&Type> {
+    match args {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => match args.args.first() {
+            Some(GenericArgument::Type(ty)) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn compiled_type(ty: &Type) -> Result<Type> {
+    match ty {
+        Type::Tuple(tuple) => {
+            let mut tuple = tuple.clone();
+            for elem in tuple.elems.iter_mut() {
+                *elem = compiled_type(elem)?;
+            }
+            Ok(Type::Tuple(tuple))
+        }
+        Type::Path(path) if path.qself.is_none() => {
+            let mut path = path.clone();
+            let segment = path.path.segments.last_mut().unwrap();
+
+            match segment.ident.to_string().as_str() {
+                "TemplatedInput" => generic_argument(&segment.arguments)
+                    .cloned()
+                    .ok_or_else(|| Error::new(ty.span(), "TemplatedInput requires a type")),
+                "Option" | "Vec" => {
+                    let inner = generic_argument(&segment.arguments)
+                        .ok_or_else(|| Error::new(ty.span(), "expected a single type argument"))?;
+                    let inner = compiled_type(inner)?;
+                    segment.arguments = PathArguments::AngleBracketed(syn::parse_quote!(<#inner>));
+                    Ok(Type::Path(path))
+                }
+                _ => {
+                    if !segment.arguments.is_empty() {
+                        return Err(Error::new(
+                            ty.span(),
+                            "#[derive(Templated)] does not support generic field types",
+                        ));
+                    }
+
+                    segment.ident = format_ident!("Compiled{}", segment.ident);
+                    Ok(Type::Path(path))
+                }
+            }
+        }
+        _ => Err(Error::new(
+            ty.span(),
+            "#[derive(Templated)] does not support this field type",
+        )),
+    }
+}
@@ -1,10 +1,13 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use charon::{
-    generate_command, stop_package, Client, Global, GlobalRegistry, PackageTitle, Registry,
-    SourcePackage, SystemdUnit,
+    ask_prompts, create_secrets, generate_command, import, pack, prepare_vm_image,
+    responses_from_file, stop_package, Client, CompileErrors, Global, GlobalExplanation,
+    GlobalRegistry, PackageMetadata, PackageTitle, Registry, ResponseErrors, SearchQuery,
+    SearchResults, SourcePackage, SystemdUnit, DEFAULT_SEARCH_LIMIT,
 };
 use clap::{Parser, Subcommand};
 use fancy_duration::AsFancyDuration;
+use std::io::Write;
 use std::path::PathBuf;
 
 const DEFAULT_SOCKET_PATH: &str = "/tmp/charond.sock";
@@ -14,6 +17,11 @@ const DEFAULT_SOCKET_PATH: &str = "/tmp/charond.sock";
 struct MainArgs {
     #[arg(short = 'r', long = "registry", help = "Root path to package registry")]
     registry_path: Option<PathBuf>,
+    #[arg(
+        long = "state",
+        help = "Path to the local state of the registry, if not kept in the registry"
+    )]
+    state_path: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,9 +33,58 @@ enum Commands {
     Launch(LaunchArgs),
     Stop(StopArgs),
     CreateUnit(CreateUnitArgs),
+    Globals(GlobalsArgs),
+    Pack(PackArgs),
+    Import(ImportArgs),
     Remote(RemoteArgs),
 }
 
+#[derive(Parser, Debug, Clone)]
+#[command(about="Inspect the global variables packages are templated with", long_about=None)]
+struct GlobalsArgs {
+    #[command(subcommand)]
+    command: GlobalsCommands,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum GlobalsCommands {
+    Explain(ExplainArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Show the value of every global for a package and where it comes from", long_about=None)]
+struct ExplainArgs {
+    package_name: String,
+    package_version: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Bundle a package with its dependencies and images, to install it offline", long_about=None)]
+struct PackArgs {
+    package_name: String,
+    package_version: String,
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "Where to write the bundle, <name>-<version>.tar by default"
+    )]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Check a bundle made with pack and load it into the registry", long_about=None)]
+struct ImportArgs {
+    bundle: PathBuf,
+    #[arg(
+        short = 'i',
+        long = "install",
+        help = "Install the package through charond once it is imported"
+    )]
+    install: bool,
+    #[arg(short = 's', long = "socket", help = "Path to control socket")]
+    socket: Option<PathBuf>,
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(about="Remote Control charond through GRPC socket", long_about=None)]
 struct RemoteArgs {
@@ -41,6 +98,186 @@ struct RemoteArgs {
 enum RemoteCommands {
     Ping,
     WriteUnit(CreateUnitArgs),
+    Upgrade(UpgradeArgs),
+    Configure(ConfigureArgs),
+    Globals(RemoteGlobalsArgs),
+    Snapshot(SnapshotArgs),
+    Watch(WatchArgs),
+    Info(InfoArgs),
+    Resize(ResizeArgs),
+    Search(SearchArgs),
+    Show(ShowArgs),
+    Compile(CompileArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Show what is known about a package", long_about=None)]
+struct ShowArgs {
+    package_name: String,
+    #[arg(
+        short = 'v',
+        long = "version",
+        help = "Show this version instead of the latest"
+    )]
+    package_version: Option<String>,
+    #[arg(
+        short = 'd',
+        long = "definition",
+        help = "Show the definition of the package as it is in the registry instead"
+    )]
+    definition: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Show what a package compiles to with its current globals and responses", long_about=None)]
+struct CompileArgs {
+    package_name: String,
+    package_version: String,
+    #[arg(
+        default_value = "/tmp",
+        help = "Volume root to generate the launch command with"
+    )]
+    volume_root: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Find packages by name, description or tags", long_about=None)]
+struct SearchArgs {
+    #[arg(default_value = "", help = "Text to find in the name or description")]
+    text: String,
+    #[arg(
+        short = 't',
+        long = "tag",
+        help = "Only packages with this tag, may be repeated"
+    )]
+    tags: Vec<String>,
+    #[arg(long = "offset", default_value_t = 0, help = "Skip this many packages")]
+    offset: usize,
+    #[arg(
+        short = 'n',
+        long = "limit",
+        default_value_t = DEFAULT_SEARCH_LIMIT,
+        help = "Show at most this many packages"
+    )]
+    limit: usize,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Print what a running VM package reports, such as shutdowns and disk errors, until interrupted", long_about=None)]
+struct WatchArgs {
+    package_name: String,
+    package_version: String,
+    volume_root: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Show the state, vCPUs, memory and disk activity of a running VM package", long_about=None)]
+struct InfoArgs {
+    package_name: String,
+    package_version: String,
+    volume_root: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Give a running VM package the vCPUs, memory and volumes it has now, without restarting it", long_about=None)]
+struct ResizeArgs {
+    package_name: String,
+    package_version: String,
+    volume_root: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Manage snapshots of a running VM package", long_about=None)]
+struct SnapshotArgs {
+    #[command(subcommand)]
+    command: SnapshotCommands,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum SnapshotCommands {
+    #[command(about = "Take a snapshot of the VM")]
+    Create(SnapshotNameArgs),
+    #[command(about = "List the snapshots of the VM")]
+    List(CreateUnitArgs),
+    #[command(about = "Put the VM back as it was when the snapshot was taken")]
+    Restore(SnapshotNameArgs),
+    #[command(about = "Delete a snapshot of the VM")]
+    Delete(SnapshotNameArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+struct SnapshotNameArgs {
+    package_name: String,
+    package_version: String,
+    volume_root: PathBuf,
+    snapshot: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="View or change the global variables of a package", long_about=None)]
+struct RemoteGlobalsArgs {
+    #[command(subcommand)]
+    command: RemoteGlobalsCommands,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum RemoteGlobalsCommands {
+    Get(GlobalsGetArgs),
+    Set(GlobalsSetArgs),
+    Unset(GlobalsUnsetArgs),
+    Explain(ExplainArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Show the variables of a package", long_about=None)]
+struct GlobalsGetArgs {
+    package_name: String,
+    #[arg(
+        short = 'v',
+        long = "version",
+        help = "Show the overrides for this installed version instead"
+    )]
+    package_version: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Set variables of a package", long_about=None)]
+struct GlobalsSetArgs {
+    package_name: String,
+    #[arg(required = true, help = "Variables to set, as name=value")]
+    variables: Vec<String>,
+    #[arg(
+        short = 'v',
+        long = "version",
+        help = "Override the variables for this installed version only"
+    )]
+    package_version: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Remove a variable from a package", long_about=None)]
+struct GlobalsUnsetArgs {
+    package_name: String,
+    variable: String,
+    #[arg(
+        short = 'v',
+        long = "version",
+        help = "Remove the override for this installed version instead"
+    )]
+    package_version: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about="Answer the prompts for a package", long_about=None)]
+struct ConfigureArgs {
+    package_name: String,
+    package_version: String,
+    #[arg(
+        short = 'f',
+        long = "from-file",
+        help = "Read responses from a YAML or JSON file of prompt names to values instead of asking"
+    )]
+    from_file: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -58,6 +295,15 @@ struct CreateUnitArgs {
     systemd_root: Option<PathBuf>,
 }
 
+#[derive(Parser, Debug, Clone)]
+#[command(about="Upgrade an installed package, rolling back its volumes if the new version fails", long_about=None)]
+struct UpgradeArgs {
+    package_name: String,
+    from_version: String,
+    to_version: String,
+    volume_root: PathBuf,
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(about="Launch a package", long_about=None)]
 struct LaunchArgs {
@@ -79,6 +325,24 @@ struct StopArgs {
 struct NewPackageArgs {
     name: String,
     initial_version: String,
+    #[arg(short = 'd', long = "description", help = "What the package is")]
+    description: Option<String>,
+    #[arg(
+        short = 'm',
+        long = "maintainer",
+        help = "Who maintains the package, may be repeated"
+    )]
+    maintainers: Vec<String>,
+    #[arg(long = "homepage", help = "Homepage of the software the package runs")]
+    homepage: Option<String>,
+    #[arg(long = "license", help = "License of the software the package runs")]
+    license: Option<String>,
+    #[arg(
+        short = 't',
+        long = "tag",
+        help = "Tag to find the package by, may be repeated"
+    )]
+    tags: Vec<String>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -87,23 +351,119 @@ struct RemovePackageArgs {
     name: String,
 }
 
+fn print_explanation(explained: Vec<GlobalExplanation>) {
+    let name_width = explained.iter().map(|e| e.name.len()).max().unwrap_or(0);
+    let value_width = explained.iter().map(|e| e.value.len()).max().unwrap_or(0);
+
+    for explanation in explained {
+        let mut source = explanation.source.to_string();
+        if !explanation.shadowed.is_empty() {
+            source += &format!(
+                " (overrides {})",
+                explanation
+                    .shadowed
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+
+        println!(
+            "{:name_width$}  {:value_width$}  {}",
+            explanation.name, explanation.value, source
+        );
+    }
+}
+
+fn print_metadata(metadata: PackageMetadata) {
+    println!("{}", metadata.title);
+    if !metadata.description.is_empty() {
+        println!("{}", metadata.description);
+    }
+    println!();
+
+    let fields = [
+        ("Versions", metadata.versions.join(", ")),
+        ("Tags", metadata.tags.join(", ")),
+        ("Maintainers", metadata.maintainers.join(", ")),
+        ("Homepage", metadata.homepage.unwrap_or_default()),
+        ("Repository", metadata.repository.unwrap_or_default()),
+        ("License", metadata.license.unwrap_or_default()),
+        (
+            "Icon",
+            metadata
+                .icon
+                .map(|icon| format!("{} ({} bytes)", icon.name, icon.data.len()))
+                .unwrap_or_default(),
+        ),
+    ];
+
+    for (name, value) in fields {
+        if !value.is_empty() {
+            println!("{:12} {}", format!("{}:", name), value);
+        }
+    }
+
+    if let Some(readme) = metadata.readme {
+        println!("\n{}", readme.trim_end());
+    }
+}
+
+fn print_search_results(results: SearchResults, offset: usize) {
+    for entry in &results.packages {
+        let mut name = entry.title.name.clone();
+        if let Some(registry) = &entry.title.registry {
+            name = format!("{}/{}", registry, name);
+        }
+
+        let mut line = format!("{} {}", name, entry.title.version);
+        if !entry.tags.is_empty() {
+            line += &format!(" [{}]", entry.tags.join(", "));
+        }
+        if !entry.description.is_empty() {
+            line += &format!(": {}", entry.description);
+        }
+
+        println!("{}", line);
+    }
+
+    if results.packages.is_empty() {
+        eprintln!("No packages found ({} matched)", results.total);
+    } else {
+        eprintln!(
+            "Showing {}-{} of {} packages",
+            offset + 1,
+            offset + results.packages.len(),
+            results.total
+        );
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = MainArgs::parse();
     let cwd = std::env::current_dir()?;
     match args.command {
         Commands::NewPackage(new_args) => {
-            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()));
+            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()))
+                .with_state(args.state_path.clone());
             let sp = SourcePackage {
                 title: PackageTitle {
                     name: new_args.name.clone(),
                     version: new_args.initial_version,
+                    ..Default::default()
                 },
-                description: "Please modify this description".into(),
+                description: new_args.description.unwrap_or_default(),
+                maintainers: Some(new_args.maintainers).filter(|m| !m.is_empty()),
+                homepage: new_args.homepage,
+                license: new_args.license,
+                tags: Some(new_args.tags).filter(|t| !t.is_empty()),
                 ..Default::default()
             };
             r.write(&sp)?;
-            let gr = GlobalRegistry::new(args.registry_path.unwrap_or(cwd));
+            let gr =
+                GlobalRegistry::new(args.registry_path.unwrap_or(cwd)).with_state(args.state_path);
             let g = Global {
                 name: new_args.name,
                 ..Default::default()
@@ -111,18 +471,22 @@ async fn main() -> Result<()> {
             gr.set(&g)?;
         }
         Commands::RemovePackage(rp_args) => {
-            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()));
-            let gr = GlobalRegistry::new(args.registry_path.unwrap_or(cwd));
+            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()))
+                .with_state(args.state_path.clone());
+            let gr =
+                GlobalRegistry::new(args.registry_path.unwrap_or(cwd)).with_state(args.state_path);
             r.remove(&rp_args.name)?;
             gr.remove(&rp_args.name)?;
         }
         Commands::Launch(l_args) => {
-            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()));
-            let command = generate_command(
-                r.load(&l_args.package_name, &l_args.package_version)?
-                    .compile()?,
-                l_args.volume_root,
-            )?;
+            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()))
+                .with_state(args.state_path.clone());
+            let package = r
+                .load(&l_args.package_name, &l_args.package_version)?
+                .compile()?;
+            prepare_vm_image(&package, &l_args.volume_root)?;
+            create_secrets(&package, &l_args.volume_root)?;
+            let command = generate_command(package, l_args.volume_root)?;
 
             let status = std::process::Command::new(&command[0])
                 .args(command.iter().skip(1))
@@ -130,15 +494,18 @@ async fn main() -> Result<()> {
             std::process::exit(status.code().unwrap_or(1));
         }
         Commands::Stop(s_args) => {
-            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()));
+            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()))
+                .with_state(args.state_path.clone());
             stop_package(
                 r.load(&s_args.package_name, &s_args.package_version)?
                     .compile()?,
                 s_args.volume_root,
-            )?;
+            )
+            .await?;
         }
         Commands::CreateUnit(cu_args) => {
-            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()));
+            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()))
+                .with_state(args.state_path.clone());
             let systemd = SystemdUnit::new(
                 r.load(&cu_args.package_name, &cu_args.package_version)?
                     .compile()?,
@@ -158,6 +525,51 @@ async fn main() -> Result<()> {
                 systemd.filename().display()
             );
         }
+        Commands::Globals(g_args) => match g_args.command {
+            GlobalsCommands::Explain(e_args) => {
+                let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()))
+                    .with_state(args.state_path.clone());
+                print_explanation(
+                    r.load(&e_args.package_name, &e_args.package_version)?
+                        .global_layers()?
+                        .explain(),
+                );
+            }
+        },
+        Commands::Pack(p_args) => {
+            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()))
+                .with_state(args.state_path.clone());
+            let output = p_args.output.unwrap_or_else(|| {
+                format!("{}-{}.tar", p_args.package_name, p_args.package_version).into()
+            });
+            let manifest = pack(&r, &p_args.package_name, &p_args.package_version, &output)?;
+
+            eprintln!(
+                "Wrote {} with {} package(s) to '{}'",
+                manifest.package,
+                manifest.packages.len(),
+                output.display()
+            );
+        }
+        Commands::Import(i_args) => {
+            let r = Registry::new(args.registry_path.clone().unwrap_or(cwd.clone()))
+                .with_state(args.state_path.clone());
+            let manifest = import(&r, &i_args.bundle)?;
+
+            for title in &manifest.packages {
+                eprintln!("Imported {}", title);
+            }
+
+            if i_args.install {
+                let socket = i_args.socket.unwrap_or_else(|| DEFAULT_SOCKET_PATH.into());
+                Client::new(socket)?
+                    .control()
+                    .await?
+                    .install(&manifest.package.name, &manifest.package.version)
+                    .await?;
+                eprintln!("Installed {}", manifest.package);
+            }
+        }
         Commands::Remote(r_args) => {
             let socket = r_args.socket.unwrap_or_else(|| DEFAULT_SOCKET_PATH.into());
 
@@ -186,6 +598,341 @@ async fn main() -> Result<()> {
                         wu_args.package_name, wu_args.package_version,
                     );
                 }
+                RemoteCommands::Upgrade(u_args) => {
+                    client
+                        .control()
+                        .await?
+                        .upgrade(
+                            &u_args.package_name,
+                            &u_args.from_version,
+                            &u_args.to_version,
+                            u_args.volume_root,
+                        )
+                        .await?;
+                    eprintln!(
+                        "Upgraded {} from {} to {}",
+                        u_args.package_name, u_args.from_version, u_args.to_version,
+                    );
+                }
+                RemoteCommands::Configure(c_args) => {
+                    let mut query = client.query().await?;
+                    let prompts = query
+                        .get_prompts(&c_args.package_name, &c_args.package_version)
+                        .await?;
+                    let current = query
+                        .get_responses(&c_args.package_name, &c_args.package_version)
+                        .await?;
+
+                    let responses = match c_args.from_file {
+                        Some(path) => responses_from_file(&prompts, &current, &path)?,
+                        None => ask_prompts(
+                            &prompts,
+                            &current,
+                            &mut std::io::stdin().lock(),
+                            &mut std::io::stdout(),
+                            |output| {
+                                let secret = rpassword::read_password()?;
+                                writeln!(output)?;
+                                Ok(secret)
+                            },
+                        )?,
+                    };
+
+                    if let Err(e) = query
+                        .set_responses(&c_args.package_name, &c_args.package_version, responses)
+                        .await
+                    {
+                        if let Some(errors) = e.downcast_ref::<ResponseErrors>() {
+                            for error in &errors.0 {
+                                eprintln!("{}", error.message);
+                            }
+                            return Err(anyhow!("Responses were not saved"));
+                        }
+
+                        return Err(e);
+                    }
+
+                    eprintln!(
+                        "Saved responses for {}-{}",
+                        c_args.package_name, c_args.package_version,
+                    );
+                }
+                RemoteCommands::Resize(r_args) => {
+                    let resized = client
+                        .control()
+                        .await?
+                        .resize(
+                            &r_args.package_name,
+                            &r_args.package_version,
+                            r_args.volume_root,
+                        )
+                        .await?;
+
+                    eprintln!(
+                        "Resized {}-{}: {} to {} vCPUs, {}M to {}M memory",
+                        r_args.package_name,
+                        r_args.package_version,
+                        resized.cpus_before,
+                        resized.cpus,
+                        resized.memory_before,
+                        resized.memory,
+                    );
+                    for volume in resized.volumes_added {
+                        eprintln!("Added volume {}", volume);
+                    }
+                }
+                RemoteCommands::Info(i_args) => {
+                    let info = client
+                        .query()
+                        .await?
+                        .vm_info(
+                            &i_args.package_name,
+                            &i_args.package_version,
+                            i_args.volume_root,
+                        )
+                        .await?;
+
+                    println!("name: {}", info.name);
+                    println!("status: {}", info.status);
+                    println!("qemu: {}", info.qemu_version);
+                    println!(
+                        "memory: {} MiB base, {} MiB plugged",
+                        info.base_memory / 1024 / 1024,
+                        info.plugged_memory / 1024 / 1024
+                    );
+                    println!("cpus:");
+                    for cpu in info.cpus {
+                        println!("  {}  thread {}  {}", cpu.index, cpu.thread, cpu.qom_path);
+                    }
+                    println!("disks:");
+                    for disk in info.disks {
+                        println!(
+                            "  {}  read {} bytes in {} ops, wrote {} bytes in {} ops, {} flushes, {} failed",
+                            disk.name,
+                            disk.read_bytes,
+                            disk.reads,
+                            disk.written_bytes,
+                            disk.writes,
+                            disk.flushes,
+                            disk.failed_reads + disk.failed_writes,
+                        );
+                    }
+                    println!("pci:");
+                    for device in info.pci {
+                        println!(
+                            "  {:02x}:{:02x}.{}  {:04x}:{:04x}  {}  {}",
+                            device.bus,
+                            device.slot,
+                            device.function,
+                            device.vendor,
+                            device.device,
+                            device.description,
+                            device.qdev_id,
+                        );
+                    }
+                }
+                RemoteCommands::Watch(w_args) => {
+                    let mut events = client
+                        .query()
+                        .await?
+                        .watch_vm(
+                            &w_args.package_name,
+                            &w_args.package_version,
+                            w_args.volume_root,
+                        )
+                        .await?;
+
+                    while let Some(event) = events.next().await? {
+                        println!(
+                            "{}.{:06}  {}  {}",
+                            event.seconds, event.microseconds, event.event, event.description
+                        );
+                    }
+                }
+                RemoteCommands::Snapshot(s_args) => match s_args.command {
+                    SnapshotCommands::Create(c_args) => {
+                        client
+                            .control()
+                            .await?
+                            .create_snapshot(
+                                &c_args.package_name,
+                                &c_args.package_version,
+                                c_args.volume_root,
+                                &c_args.snapshot,
+                            )
+                            .await?;
+                        eprintln!("Took snapshot '{}'", c_args.snapshot);
+                    }
+                    SnapshotCommands::List(l_args) => {
+                        let snapshots = client
+                            .query()
+                            .await?
+                            .list_snapshots(
+                                &l_args.package_name,
+                                &l_args.package_version,
+                                l_args.volume_root,
+                            )
+                            .await?;
+
+                        let width = snapshots.iter().map(|s| s.name.len()).max().unwrap_or(0);
+                        for snapshot in snapshots {
+                            println!(
+                                "{:width$}  {}  {} bytes, after {}",
+                                snapshot.name,
+                                snapshot.date,
+                                snapshot.vm_state_size,
+                                std::time::Duration::from_nanos(snapshot.vm_clock).fancy_duration(),
+                            );
+                        }
+                    }
+                    SnapshotCommands::Restore(r_args) => {
+                        client
+                            .control()
+                            .await?
+                            .restore_snapshot(
+                                &r_args.package_name,
+                                &r_args.package_version,
+                                r_args.volume_root,
+                                &r_args.snapshot,
+                            )
+                            .await?;
+                        eprintln!("Restored snapshot '{}'", r_args.snapshot);
+                    }
+                    SnapshotCommands::Delete(d_args) => {
+                        client
+                            .control()
+                            .await?
+                            .delete_snapshot(
+                                &d_args.package_name,
+                                &d_args.package_version,
+                                d_args.volume_root,
+                                &d_args.snapshot,
+                            )
+                            .await?;
+                        eprintln!("Deleted snapshot '{}'", d_args.snapshot);
+                    }
+                },
+                RemoteCommands::Globals(g_args) => match g_args.command {
+                    RemoteGlobalsCommands::Get(get_args) => {
+                        let variables = client
+                            .query()
+                            .await?
+                            .get_globals(
+                                &get_args.package_name,
+                                get_args.package_version.as_deref(),
+                            )
+                            .await?;
+
+                        let mut variables = variables.into_iter().collect::<Vec<_>>();
+                        variables.sort();
+                        for (name, value) in variables {
+                            println!("{}={}", name, value);
+                        }
+                    }
+                    RemoteGlobalsCommands::Set(set_args) => {
+                        let version = set_args.package_version.as_deref();
+                        let mut variables = client
+                            .query()
+                            .await?
+                            .get_globals(&set_args.package_name, version)
+                            .await?;
+
+                        for variable in set_args.variables {
+                            let (name, value) = variable.split_once('=').ok_or_else(|| {
+                                anyhow!("'{}' is not in the form name=value", variable)
+                            })?;
+                            variables.insert(name.to_string(), value.to_string());
+                        }
+
+                        client
+                            .control()
+                            .await?
+                            .set_globals(&set_args.package_name, version, variables)
+                            .await?;
+                        eprintln!("Saved variables for {}", set_args.package_name);
+                    }
+                    RemoteGlobalsCommands::Unset(unset_args) => {
+                        client
+                            .control()
+                            .await?
+                            .delete_global(
+                                &unset_args.package_name,
+                                unset_args.package_version.as_deref(),
+                                &unset_args.variable,
+                            )
+                            .await?;
+                        eprintln!(
+                            "Removed '{}' from {}",
+                            unset_args.variable, unset_args.package_name
+                        );
+                    }
+                    RemoteGlobalsCommands::Explain(e_args) => print_explanation(
+                        client
+                            .query()
+                            .await?
+                            .explain_globals(&e_args.package_name, &e_args.package_version)
+                            .await?,
+                    ),
+                },
+                RemoteCommands::Show(s_args) => {
+                    let metadata = client
+                        .query()
+                        .await?
+                        .get_package(&s_args.package_name, s_args.package_version.as_deref())
+                        .await?;
+
+                    if s_args.definition {
+                        print!("{}", metadata.definition);
+                    } else {
+                        print_metadata(metadata);
+                    }
+                }
+                RemoteCommands::Compile(c_args) => {
+                    match client
+                        .query()
+                        .await?
+                        .compile(
+                            &c_args.package_name,
+                            &c_args.package_version,
+                            c_args.volume_root,
+                        )
+                        .await
+                    {
+                        Ok((package, command)) => {
+                            println!("{}", serde_json::to_string_pretty(&package)?);
+                            println!("\n{}", command.join(" "));
+                        }
+                        Err(e) => {
+                            if let Some(errors) = e.downcast_ref::<CompileErrors>() {
+                                for error in &errors.0 {
+                                    match error.template.as_str() {
+                                        "" => eprintln!("{}: {}", error.section, error.message),
+                                        template => eprintln!(
+                                            "{} ({}): {}",
+                                            error.section, template, error.message
+                                        ),
+                                    }
+                                }
+                                return Err(anyhow!("Package does not compile"));
+                            }
+
+                            return Err(e);
+                        }
+                    }
+                }
+                RemoteCommands::Search(s_args) => print_search_results(
+                    client
+                        .query()
+                        .await?
+                        .search(SearchQuery {
+                            text: s_args.text,
+                            tags: s_args.tags,
+                            offset: s_args.offset,
+                            limit: s_args.limit,
+                        })
+                        .await?,
+                    s_args.offset,
+                ),
             }
         }
     }
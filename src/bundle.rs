@@ -0,0 +1,792 @@
+use crate::{
+    download_vm_image, load_container_image, metadata::registry_file, save_container_image,
+    CompiledSource, PackageTitle, Registry, SourcePackage, GLOBAL_SUBPATH, PACKAGE_SUBPATH,
+    SIGNATURE_EXTENSION,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+//
+// a bundle is everything it takes to install a package without network access, in one tar
+// archive: the definitions of the package and of everything it depends on, their signatures,
+// variables and catalog files, under registry/ as they are laid out in the registry, and what
+// each of them runs under images/: the image of a VM, or a `podman save` OCI archive of a
+// container. the manifest comes first and has the checksum of every other file.
+//
+// importing a bundle puts the definitions into a registry, VM images into the image cache in the
+// registry's state, where launching a package looks before downloading, and container images into
+// podman's storage. registries synced from git only ever have what is in git, so bundles are
+// imported into local registries. images are not signed, so bundles with images are only imported
+// into registries that accept unsigned packages.
+//
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+const REGISTRY_PREFIX: &str = "registry";
+const IMAGES_PREFIX: &str = "images";
+const VM_IMAGE_FILENAME: &str = "image";
+const CONTAINER_IMAGE_FILENAME: &str = "oci.tar";
+const IMAGE_CACHE_SUBPATH: &str = "images";
+const IMPORT_PREFIX: &str = ".import";
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BundleManifest {
+    // the package the bundle was made for
+    pub package: PackageTitle,
+    // it and everything it depends on, dependencies first
+    pub packages: Vec<PackageTitle>,
+    // sha256 of every other file in the bundle, by path
+    pub files: BTreeMap<String, String>,
+}
+
+// where an imported VM image is kept until a package is launched
+pub fn cached_image_path(state: &Path, title: &PackageTitle) -> PathBuf {
+    state
+        .join(IMAGE_CACHE_SUBPATH)
+        .join(&title.name)
+        .join(&title.version)
+}
+
+enum BundleFile {
+    Data(Vec<u8>),
+    Path(PathBuf),
+}
+
+fn registry_path(path: &Path) -> String {
+    format!("{}/{}", REGISTRY_PREFIX, path.display())
+}
+
+fn definition_path(title: &PackageTitle) -> PathBuf {
+    Path::new(PACKAGE_SUBPATH)
+        .join(&title.name)
+        .join(format!("{}.json", title.version))
+}
+
+fn signature_path(title: &PackageTitle) -> PathBuf {
+    Path::new(PACKAGE_SUBPATH)
+        .join(&title.name)
+        .join(format!("{}.json.{}", title.version, SIGNATURE_EXTENSION))
+}
+
+fn variables_path(title: &PackageTitle) -> PathBuf {
+    Path::new(GLOBAL_SUBPATH).join(format!("{}.json", title.name))
+}
+
+fn variables_signature_path(title: &PackageTitle) -> PathBuf {
+    Path::new(GLOBAL_SUBPATH).join(format!("{}.json.{}", title.name, SIGNATURE_EXTENSION))
+}
+
+fn image_path(title: &PackageTitle, filename: &str) -> String {
+    format!(
+        "{}/{}/{}/{}",
+        IMAGES_PREFIX, title.name, title.version, filename
+    )
+}
+
+fn checksum<R: Read>(mut reader: R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 65536];
+
+    loop {
+        let size = reader.read(&mut buf)?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buf[..size]);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+// the package and everything it depends on, each once, dependencies first
+fn closure(
+    package: SourcePackage,
+    seen: &mut BTreeSet<(String, String)>,
+    packages: &mut Vec<SourcePackage>,
+) -> Result<()> {
+    if !seen.insert((package.title.name.clone(), package.title.version.clone())) {
+        return Ok(());
+    }
+
+    for dependency in package.dependencies()? {
+        closure(dependency, seen, packages)?;
+    }
+
+    packages.push(package);
+    Ok(())
+}
+
+pub fn pack(
+    registry: &Registry,
+    name: &str,
+    version: &str,
+    output: &Path,
+) -> Result<BundleManifest> {
+    let package = registry.load(name, version)?;
+
+    let mut packages = Vec::new();
+    closure(package, &mut BTreeSet::default(), &mut packages)?;
+
+    // images are fetched here before they go into the bundle
+    let work = PathBuf::from(format!("{}.work", output.display()));
+    std::fs::create_dir_all(&work)?;
+    let res = write_bundle(registry, name, version, &packages, &work, output);
+    std::fs::remove_dir_all(&work)?;
+    res
+}
+
+fn write_bundle(
+    registry: &Registry,
+    name: &str,
+    version: &str,
+    packages: &[SourcePackage],
+    work: &Path,
+    output: &Path,
+) -> Result<BundleManifest> {
+    let root = registry.path();
+    let mut files: Vec<(String, BundleFile)> = Vec::new();
+
+    for package in packages {
+        let title = &package.title;
+
+        files.push((
+            registry_path(&definition_path(title)),
+            BundleFile::Data(
+                registry
+                    .definition(&title.name, &title.version)?
+                    .into_bytes(),
+            ),
+        ));
+
+        if let Some(signature) = registry.signature(&title.name, &title.version)? {
+            files.push((
+                registry_path(&signature_path(title)),
+                BundleFile::Data(signature.into_bytes()),
+            ));
+        }
+
+        let mut registry_files = vec![variables_path(title), variables_signature_path(title)];
+        registry_files.extend(package.icon.clone());
+        registry_files.extend(package.readme.clone());
+
+        for path in registry_files {
+            let name = registry_path(&path);
+            let path = registry_file(&root, &path)?;
+            if std::fs::exists(&path)? && !files.iter().any(|(n, _)| *n == name) {
+                files.push((name, BundleFile::Path(path)));
+            }
+        }
+
+        let image = work.join(format!("{}-{}", title.name, title.version));
+        match package.compile_source()? {
+            CompiledSource::URL(url) => {
+                download_vm_image(&url, image.clone())
+                    .map_err(|e| anyhow!("cannot download image of {}: {}", title, e))?;
+                files.push((
+                    image_path(title, VM_IMAGE_FILENAME),
+                    BundleFile::Path(image),
+                ));
+            }
+            CompiledSource::Container(container) => {
+                save_container_image(&container, &image)?;
+                files.push((
+                    image_path(title, CONTAINER_IMAGE_FILENAME),
+                    BundleFile::Path(image),
+                ));
+            }
+        }
+    }
+
+    let mut manifest = BundleManifest {
+        package: PackageTitle {
+            name: name.to_string(),
+            version: version.to_string(),
+            ..Default::default()
+        },
+        packages: packages.iter().map(|p| p.title.clone()).collect(),
+        files: BTreeMap::default(),
+    };
+
+    for (name, file) in &files {
+        let sum = match file {
+            BundleFile::Data(data) => checksum(data.as_slice())?,
+            BundleFile::Path(path) => checksum(std::fs::File::open(path)?)?,
+        };
+        manifest.files.insert(name.clone(), sum);
+    }
+
+    let tmp = PathBuf::from(format!("{}.tmp", output.display()));
+    let mut builder = tar::Builder::new(std::fs::File::create(&tmp)?);
+
+    append_data(
+        &mut builder,
+        MANIFEST_FILENAME,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    for (name, file) in files {
+        match file {
+            BundleFile::Data(data) => append_data(&mut builder, &name, &data)?,
+            BundleFile::Path(path) => builder.append_path_with_name(path, name)?,
+        }
+    }
+
+    builder.into_inner()?;
+    std::fs::rename(tmp, output)?;
+
+    Ok(manifest)
+}
+
+fn append_data<W: std::io::Write>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    data: &[u8],
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    Ok(builder.append_data(&mut header, name, data)?)
+}
+
+// checks a bundle and puts what is in it in place. nothing is changed unless every file matches
+// its checksum and belongs to one of its packages, none would replace a different file already in
+// the registry, and every package is signed by a key the registry trusts, if it checks signatures.
+pub fn import(registry: &Registry, archive: &Path) -> Result<BundleManifest> {
+    if std::fs::exists(registry.path().join(".git"))? {
+        return Err(anyhow!(
+            "registry {} is synced from git, import the bundle into a local registry instead",
+            registry.path().display()
+        ));
+    }
+
+    let staging = registry
+        .state()
+        .join(format!("{}.{}", IMPORT_PREFIX, std::process::id()));
+
+    if std::fs::exists(&staging)? {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+
+    let res = import_staged(registry, archive, &staging);
+    std::fs::remove_dir_all(&staging)?;
+    res
+}
+
+fn import_staged(registry: &Registry, archive: &Path, staging: &Path) -> Result<BundleManifest> {
+    let mut unpacked = BTreeSet::default();
+
+    for entry in tar::Archive::new(std::fs::File::open(archive)?).entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+
+        if !entry.header().entry_type().is_file() {
+            return Err(anyhow!("bundle entry '{}' is not a file", name));
+        }
+
+        if !entry.unpack_in(staging)? {
+            return Err(anyhow!("bundle entry '{}' is outside of the bundle", name));
+        }
+
+        unpacked.insert(name);
+    }
+
+    if !unpacked.remove(MANIFEST_FILENAME) {
+        return Err(anyhow!("bundle has no manifest"));
+    }
+
+    let manifest: BundleManifest =
+        serde_json::from_reader(std::fs::File::open(staging.join(MANIFEST_FILENAME))?)
+            .map_err(|e| anyhow!("bundle manifest is invalid: {}", e))?;
+
+    let listed = manifest.files.keys().cloned().collect::<BTreeSet<String>>();
+    if let Some(name) = listed.symmetric_difference(&unpacked).next() {
+        return Err(anyhow!(
+            "bundle does not match its manifest: '{}' is missing or not listed",
+            name
+        ));
+    }
+
+    for (name, sum) in &manifest.files {
+        if checksum(std::fs::File::open(staging.join(name))?)? != *sum {
+            return Err(anyhow!(
+                "bundle file '{}' does not match its checksum",
+                name
+            ));
+        }
+    }
+
+    let trust = registry
+        .trust()
+        .map_err(|e| anyhow!("Refusing to import: {}", e))?;
+    // checked the same way as the registry the packages go to
+    let staged = Registry::new(staging.join(REGISTRY_PREFIX)).with_trust(trust.clone());
+    // the files pack would have put in the bundle for its packages, and nothing else
+    let mut allowed = BTreeSet::default();
+
+    for title in &manifest.packages {
+        let definition = std::fs::read(staging.join(registry_path(&definition_path(title))))
+            .map_err(|_| anyhow!("bundle has no definition for {}", title))?;
+
+        trust
+            .verify(
+                &definition,
+                staged.signature(&title.name, &title.version)?.as_deref(),
+            )
+            .map_err(|e| anyhow!("Refusing to import {}: {}", title, e))?;
+
+        let package = staged.load(&title.name, &title.version)?;
+        if package.title.name != title.name || package.title.version != title.version {
+            return Err(anyhow!(
+                "bundle definition for {} is for {}",
+                title,
+                package.title
+            ));
+        }
+
+        let mut registry_files = vec![
+            definition_path(title),
+            signature_path(title),
+            variables_path(title),
+            variables_signature_path(title),
+        ];
+        registry_files.extend(package.icon.clone());
+        registry_files.extend(package.readme.clone());
+        allowed.extend(registry_files.iter().map(|path| registry_path(path)));
+        allowed.insert(image_path(title, VM_IMAGE_FILENAME));
+        allowed.insert(image_path(title, CONTAINER_IMAGE_FILENAME));
+
+        if registry.exists(&title.name, Some(&title.version))?
+            && registry.definition(&title.name, &title.version)?.as_bytes() != definition
+        {
+            return Err(anyhow!(
+                "{} is already in the registry, and differs from the one in the bundle",
+                title
+            ));
+        }
+
+        // the variables are shared by every version of the package
+        let variables = staging.join(registry_path(&variables_path(title)));
+        let existing = registry.path().join(variables_path(title));
+        if std::fs::exists(&variables)?
+            && std::fs::exists(&existing)?
+            && std::fs::read(&existing)? != std::fs::read(&variables)?
+        {
+            return Err(anyhow!(
+                "the variables of {} are already in the registry, and differ from those in the bundle",
+                title.name
+            ));
+        }
+    }
+
+    if let Some(name) = manifest.files.keys().find(|name| !allowed.contains(*name)) {
+        return Err(anyhow!(
+            "bundle file '{}' does not belong to any of its packages",
+            name
+        ));
+    }
+
+    // images are only checked against the manifest, which nothing signs
+    if let Some(name) = manifest
+        .files
+        .keys()
+        .find(|name| !trust.unsigned && name.starts_with(&format!("{}/", IMAGES_PREFIX)))
+    {
+        return Err(anyhow!(
+            "Refusing to import: bundle image '{}' is not signed, and registry {} only accepts signed packages",
+            name,
+            registry.path().display()
+        ));
+    }
+
+    // nothing already in the registry is replaced with something else
+    let root = registry.path();
+    let mut copies = Vec::new();
+    for name in manifest
+        .files
+        .keys()
+        .filter_map(|name| name.strip_prefix(&format!("{}/", REGISTRY_PREFIX)))
+    {
+        let source = staging.join(REGISTRY_PREFIX).join(name);
+        let target = registry_file(&root, Path::new(name))?;
+        if std::fs::exists(&target)? && std::fs::read(&target)? != std::fs::read(&source)? {
+            return Err(anyhow!(
+                "{} is already in the registry, and differs from the one in the bundle",
+                name
+            ));
+        }
+        copies.push((source, target));
+    }
+
+    for (source, target) in copies {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(source, target)?;
+    }
+
+    for title in &manifest.packages {
+        let vm = staging.join(image_path(title, VM_IMAGE_FILENAME));
+        let container = staging.join(image_path(title, CONTAINER_IMAGE_FILENAME));
+
+        if std::fs::exists(&vm)? {
+            let cached = cached_image_path(&registry.state(), title);
+            std::fs::create_dir_all(cached.parent().unwrap())?;
+            std::fs::rename(vm, cached)?;
+        } else if std::fs::exists(&container)? {
+            load_container_image(&container)?;
+        }
+    }
+
+    registry.rebuild_index()?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        append_data, cached_image_path, checksum, import, pack, BundleManifest, MANIFEST_FILENAME,
+    };
+    use crate::{
+        trust::tests::{ssh_key, ssh_sign},
+        Global, GlobalRegistry, PackageTitle, Registry, Source, SourcePackage, Trust,
+    };
+    use ssh_key::PrivateKey;
+    use std::path::Path;
+
+    fn title(name: &str, version: &str) -> PackageTitle {
+        PackageTitle {
+            name: name.into(),
+            version: version.into(),
+            ..Default::default()
+        }
+    }
+
+    // copies a bundle, changing a file in it but not the manifest
+    fn tamper(archive: &Path, output: &Path, name: &str) {
+        let mut builder = tar::Builder::new(std::fs::File::create(output).unwrap());
+        for entry in tar::Archive::new(std::fs::File::open(archive).unwrap())
+            .entries()
+            .unwrap()
+        {
+            let entry = entry.unwrap();
+            let mut header = entry.header().clone();
+            let path = entry.path().unwrap().to_path_buf();
+
+            if path == Path::new(name) {
+                let data = b"tampered";
+                header.set_size(data.len() as u64);
+                header.set_cksum();
+                builder.append_data(&mut header, path, &data[..]).unwrap();
+            } else {
+                builder.append_data(&mut header, path, entry).unwrap();
+            }
+        }
+        builder.into_inner().unwrap();
+    }
+
+    // copies a bundle, adding a file to it and to its manifest
+    fn add(archive: &Path, output: &Path, name: &str, data: &[u8]) {
+        let mut builder = tar::Builder::new(std::fs::File::create(output).unwrap());
+        for entry in tar::Archive::new(std::fs::File::open(archive).unwrap())
+            .entries()
+            .unwrap()
+        {
+            let entry = entry.unwrap();
+            let mut header = entry.header().clone();
+            let path = entry.path().unwrap().to_path_buf();
+
+            if path == Path::new(MANIFEST_FILENAME) {
+                let mut manifest: BundleManifest = serde_json::from_reader(entry).unwrap();
+                manifest.files.insert(name.into(), checksum(data).unwrap());
+                let manifest = serde_json::to_vec(&manifest).unwrap();
+                header.set_size(manifest.len() as u64);
+                header.set_cksum();
+                builder
+                    .append_data(&mut header, path, manifest.as_slice())
+                    .unwrap();
+            } else {
+                builder.append_data(&mut header, path, entry).unwrap();
+            }
+        }
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, data).unwrap();
+        builder.into_inner().unwrap();
+    }
+
+    // a bundle of a package signed with key, and the files given
+    fn signed(output: &Path, key: &PrivateKey, files: &[(&str, &[u8])]) {
+        let definition = serde_json::to_vec(&SourcePackage {
+            title: title("signed", "1.0.0"),
+            ..Default::default()
+        })
+        .unwrap();
+        let signature = ssh_sign(key, &definition);
+
+        let mut files = files.to_vec();
+        files.push(("registry/packages/signed/1.0.0.json", &definition));
+        files.push((
+            "registry/packages/signed/1.0.0.json.sig",
+            signature.as_bytes(),
+        ));
+
+        let manifest = BundleManifest {
+            package: title("signed", "1.0.0"),
+            packages: vec![title("signed", "1.0.0")],
+            files: files
+                .iter()
+                .map(|(name, data)| (name.to_string(), checksum(*data).unwrap()))
+                .collect(),
+        };
+
+        let mut builder = tar::Builder::new(std::fs::File::create(output).unwrap());
+        append_data(
+            &mut builder,
+            MANIFEST_FILENAME,
+            &serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        for (name, data) in files {
+            append_data(&mut builder, name, data).unwrap();
+        }
+        builder.into_inner().unwrap();
+    }
+
+    #[test]
+    fn signed_images() {
+        let key = ssh_key(1);
+        let out = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let registry = Registry::new(target.path().to_path_buf()).with_trust(Trust {
+            keys: vec![key.public_key().to_openssh().unwrap()],
+            unsigned: false,
+        });
+
+        let archive = out.path().join("images.tar");
+        signed(
+            &archive,
+            &key,
+            &[("images/signed/1.0.0/oci.tar", b"not signed")],
+        );
+        let err = import(&registry, &archive).unwrap_err().to_string();
+        assert!(err.contains("image"), "{}", err);
+        assert!(!std::fs::exists(target.path().join("packages")).unwrap());
+
+        let archive = out.path().join("definitions.tar");
+        signed(&archive, &key, &[]);
+        import(&registry, &archive).unwrap();
+        assert_eq!(registry.list().unwrap(), vec![title("signed", "1.0.0")]);
+    }
+
+    #[test]
+    fn bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let images = tempfile::tempdir().unwrap();
+        let registry = Registry::new(dir.path().to_path_buf()).with_trust(Trust::unsigned());
+
+        std::fs::write(images.path().join("base.img"), b"base image").unwrap();
+        std::fs::write(images.path().join("app.img"), b"app image").unwrap();
+        std::fs::create_dir_all(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/app.md"), "# App\n").unwrap();
+
+        registry
+            .write(&SourcePackage {
+                title: title("base", "1.0.0"),
+                source: Source::URL(
+                    format!("file://{}/base.img", images.path().display())
+                        .parse()
+                        .unwrap(),
+                ),
+                ..Default::default()
+            })
+            .unwrap();
+        registry
+            .write(&SourcePackage {
+                title: title("app", "1.0.0"),
+                readme: Some("docs/app.md".into()),
+                dependencies: Some(vec![title("base", "1.0.0")]),
+                source: Source::URL("file://@image_dir@/app.img".parse().unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+        GlobalRegistry::new(dir.path().to_path_buf())
+            .set(&Global {
+                name: "app".into(),
+                variables: [("image_dir".to_string(), images.path().display().to_string())]
+                    .into_iter()
+                    .collect(),
+            })
+            .unwrap();
+
+        let out = tempfile::tempdir().unwrap();
+        let archive = out.path().join("app-1.0.0.tar");
+        let manifest = pack(&registry, "app", "1.0.0", &archive).unwrap();
+        assert_eq!(
+            manifest.packages,
+            vec![title("base", "1.0.0"), title("app", "1.0.0")]
+        );
+        assert_eq!(
+            manifest.files.keys().cloned().collect::<Vec<String>>(),
+            vec![
+                "images/app/1.0.0/image",
+                "images/base/1.0.0/image",
+                "registry/docs/app.md",
+                "registry/packages/app/1.0.0.json",
+                "registry/packages/base/1.0.0.json",
+                "registry/variables/app.json",
+            ]
+        );
+        // only the bundle is left behind
+        assert_eq!(std::fs::read_dir(out.path()).unwrap().count(), 1);
+
+        // the manifest comes first
+        let mut entries = tar::Archive::new(std::fs::File::open(&archive).unwrap());
+        let first = entries.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(first.path().unwrap().to_str(), Some(MANIFEST_FILENAME));
+        assert_eq!(
+            serde_json::from_reader::<_, BundleManifest>(first).unwrap(),
+            manifest
+        );
+
+        // import somewhere without network access, or the images
+        drop(images);
+        let target = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let imported = Registry::new(target.path().to_path_buf())
+            .with_state(Some(state.path().to_path_buf()))
+            .with_trust(Trust::unsigned());
+
+        assert_eq!(import(&imported, &archive).unwrap(), manifest);
+        assert_eq!(
+            imported.list().unwrap(),
+            vec![title("app", "1.0.0"), title("base", "1.0.0")]
+        );
+        assert_eq!(
+            imported.definition("app", "1.0.0").unwrap(),
+            registry.definition("app", "1.0.0").unwrap()
+        );
+        assert_eq!(
+            imported
+                .load("app", "1.0.0")
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .readme,
+            Some("# App\n".into())
+        );
+        assert!(imported.load("app", "1.0.0").unwrap().compile().is_ok());
+        assert_eq!(
+            std::fs::read(cached_image_path(state.path(), &title("app", "1.0.0"))).unwrap(),
+            b"app image"
+        );
+        assert_eq!(
+            std::fs::read(cached_image_path(state.path(), &title("base", "1.0.0"))).unwrap(),
+            b"base image"
+        );
+        // nothing is left over from importing
+        assert_eq!(std::fs::read_dir(state.path()).unwrap().count(), 2);
+
+        // importing again changes nothing
+        assert!(import(&imported, &archive).is_ok());
+
+        // a file that doesn't match its checksum
+        let tampered = out.path().join("tampered.tar");
+        tamper(&archive, &tampered, "registry/packages/base/1.0.0.json");
+        let target = tempfile::tempdir().unwrap();
+        let err = import(
+            &Registry::new(target.path().to_path_buf()).with_trust(Trust::unsigned()),
+            &tampered,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("checksum"), "{}", err);
+        assert!(!std::fs::exists(target.path().join("packages")).unwrap());
+
+        // files that are not part of any package in the bundle, even if they are in the manifest
+        for name in [
+            "registry/packages/other/1.0.0.json",
+            "registry/docs/other.md",
+            "images/other/1.0.0/image",
+        ] {
+            let added = out.path().join("added.tar");
+            add(&archive, &added, name, b"added");
+            let err = import(
+                &Registry::new(target.path().to_path_buf()).with_trust(Trust::unsigned()),
+                &added,
+            )
+            .unwrap_err()
+            .to_string();
+            assert!(err.contains("does not belong"), "{}", err);
+            assert!(!std::fs::exists(target.path().join("packages")).unwrap());
+            assert!(!std::fs::exists(target.path().join("docs")).unwrap());
+        }
+
+        // any file of the packages that differs from the one already there
+        std::fs::create_dir_all(target.path().join("docs")).unwrap();
+        std::fs::write(target.path().join("docs/app.md"), "# Not the app\n").unwrap();
+        let err = import(
+            &Registry::new(target.path().to_path_buf()).with_trust(Trust::unsigned()),
+            &archive,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("docs/app.md is already in the registry"),
+            "{}",
+            err
+        );
+        assert!(!std::fs::exists(target.path().join("packages")).unwrap());
+        std::fs::remove_dir_all(target.path().join("docs")).unwrap();
+
+        // a registry that only trusts signed packages
+        let registry = Registry::new(target.path().to_path_buf()).with_trust(Trust {
+            keys: Vec::new(),
+            unsigned: false,
+        });
+        let err = import(&registry, &archive).unwrap_err().to_string();
+        assert!(err.contains("Refusing to import"), "{}", err);
+        assert!(!std::fs::exists(target.path().join("packages")).unwrap());
+
+        // and one that trusts nothing at all
+        let registry = Registry::new(target.path().to_path_buf());
+        let err = import(&registry, &archive).unwrap_err().to_string();
+        assert!(err.contains("no keys are trusted"), "{}", err);
+        assert!(!std::fs::exists(target.path().join("packages")).unwrap());
+
+        // variables that differ from those already there
+        let registry = Registry::new(target.path().to_path_buf()).with_trust(Trust::unsigned());
+        GlobalRegistry::new(target.path().to_path_buf())
+            .set(&Global {
+                name: "app".into(),
+                variables: [("image_dir".to_string(), "/elsewhere".to_string())]
+                    .into_iter()
+                    .collect(),
+            })
+            .unwrap();
+        let err = import(&registry, &archive).unwrap_err().to_string();
+        assert!(err.contains("variables of app"), "{}", err);
+        assert!(!std::fs::exists(target.path().join("packages")).unwrap());
+
+        // a registry synced from git
+        std::fs::remove_dir_all(target.path().join("variables")).unwrap();
+        std::fs::create_dir_all(target.path().join(".git")).unwrap();
+        let err = import(&registry, &archive).unwrap_err().to_string();
+        assert!(err.contains("synced from git"), "{}", err);
+        assert!(!std::fs::exists(target.path().join("packages")).unwrap());
+    }
+}
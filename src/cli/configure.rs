@@ -0,0 +1,200 @@
+use crate::{Input, InputType, Prompt, PromptCollection, PromptResponse, PromptResponses, Secret};
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, Write};
+
+//
+// answering prompts from a terminal or a file, for `charon remote configure`. responses are
+// parsed according to the type of the prompt they answer, so nobody has to write the tagged
+// JSON the responses are stored as.
+//
+
+// the response in a line of text. strings and secrets are taken as they are, spaces and all.
+fn response_text<'a>(prompt: &Prompt, line: &'a str) -> &'a str {
+    match prompt.input_type {
+        InputType::String | InputType::Secret => line.trim_end_matches(['\r', '\n']),
+        _ => line.trim(),
+    }
+}
+
+// parses text into a response for the prompt. select prompts take the number of the option as
+// listed, its name or its value.
+pub fn parse_response(prompt: &Prompt, s: &str) -> Result<Input> {
+    let s = response_text(prompt, s);
+
+    let input = match &prompt.input_type {
+        InputType::Integer => Input::Integer(
+            s.parse()
+                .map_err(|_| anyhow!("'{}' is not a positive integer", s))?,
+        ),
+        InputType::SignedInteger => Input::SignedInteger(
+            s.parse()
+                .map_err(|_| anyhow!("'{}' is not an integer", s))?,
+        ),
+        InputType::Boolean => Input::Boolean(match s.to_lowercase().as_str() {
+            "y" | "yes" | "true" | "1" => true,
+            "n" | "no" | "false" | "0" => false,
+            _ => return Err(anyhow!("'{}' is not yes or no", s)),
+        }),
+        InputType::String => Input::String(s.to_string()),
+        InputType::Secret => Input::Secret(Secret::new(s)),
+        InputType::Select(options) => {
+            let by_number = s
+                .parse::<usize>()
+                .ok()
+                .and_then(|x| x.checked_sub(1))
+                .and_then(|x| options.get(x));
+
+            by_number
+                .or_else(|| {
+                    options
+                        .iter()
+                        .find(|option| option.name.eq_ignore_ascii_case(s))
+                })
+                .or_else(|| options.iter().find(|option| option.value.expose() == s))
+                .map(|option| option.value.clone())
+                .ok_or_else(|| anyhow!("'{}' is not one of the choices", s))?
+        }
+    };
+
+    prompt.validate(&input)?;
+    Ok(input)
+}
+
+fn set(responses: &mut PromptResponses, template: &str, input: Input) {
+    match responses.0.iter_mut().find(|r| r.template == template) {
+        Some(response) => response.input = input,
+        None => responses.0.push(PromptResponse {
+            template: template.to_string(),
+            input,
+        }),
+    }
+}
+
+// asks every prompt that applies, in order, offering the current response or the default. prompts
+// whose `when` depends on an earlier answer are decided as the answers come in. secrets are read
+// through read_secret, which is expected to not echo them.
+pub fn ask_prompts<R, W, S>(
+    prompts: &PromptCollection,
+    current: &PromptResponses,
+    input: &mut R,
+    output: &mut W,
+    mut read_secret: S,
+) -> Result<PromptResponses>
+where
+    R: BufRead,
+    W: Write,
+    S: FnMut(&mut W) -> Result<String>,
+{
+    let mut responses = current.clone();
+
+    for prompt in prompts.to_vec() {
+        if prompts.active(&responses)?.get(&prompt.template).is_none() {
+            continue;
+        }
+
+        let default = responses
+            .0
+            .iter()
+            .find(|r| r.template == prompt.template)
+            .map(|r| r.input.clone())
+            .or_else(|| prompt.default.clone());
+
+        writeln!(output)?;
+        writeln!(output, "{}", prompt.question)?;
+        if let Some(help) = &prompt.help {
+            writeln!(output, "  {}", help)?;
+        }
+
+        if let InputType::Select(options) = &prompt.input_type {
+            for (x, option) in options.iter().enumerate() {
+                writeln!(output, "  {}) {}", x + 1, option.name)?;
+            }
+        }
+
+        loop {
+            let hint = match (&default, &prompt.input_type) {
+                (Some(Input::Secret(_)), _) => " [keep current]".to_string(),
+                (Some(default), InputType::Select(options)) => options
+                    .iter()
+                    .find(|option| option.value == *default)
+                    .map(|option| format!(" [{}]", option.name))
+                    .unwrap_or_default(),
+                (Some(default), _) => format!(" [{}]", default),
+                (None, InputType::Boolean) => " (y/n)".to_string(),
+                (None, _) => String::new(),
+            };
+
+            write!(output, "{}{}: ", prompt.template, hint)?;
+            output.flush()?;
+
+            let line = if prompt.input_type == InputType::Secret {
+                read_secret(output)?
+            } else {
+                let mut line = String::new();
+                if input.read_line(&mut line)? == 0 {
+                    return Err(anyhow!("input ended before all prompts were answered"));
+                }
+                line
+            };
+
+            if response_text(&prompt, &line).is_empty() {
+                // keep the current response, or leave it to the default
+                match &default {
+                    Some(_) => break,
+                    None if !prompt.required => break,
+                    None => {
+                        writeln!(output, "A response is required.")?;
+                        continue;
+                    }
+                }
+            }
+
+            match parse_response(&prompt, &line) {
+                Ok(parsed) => {
+                    set(&mut responses, &prompt.template, parsed);
+                    break;
+                }
+                Err(e) => writeln!(output, "{}", e)?,
+            }
+        }
+    }
+
+    Ok(responses)
+}
+
+// reads responses from a YAML or JSON file mapping templates to plain values, over the current
+// responses
+pub fn responses_from_file(
+    prompts: &PromptCollection,
+    current: &PromptResponses,
+    path: &std::path::Path,
+) -> Result<PromptResponses> {
+    let values: std::collections::BTreeMap<String, serde_yaml_ng::Value> =
+        serde_yaml_ng::from_reader(std::fs::File::open(path)?)?;
+
+    let mut responses = current.clone();
+
+    for (template, value) in values {
+        let prompt = prompts
+            .get(&template)
+            .ok_or_else(|| anyhow!("there is no prompt '{}'", template))?;
+
+        let text = match value {
+            serde_yaml_ng::Value::String(s) => s,
+            serde_yaml_ng::Value::Number(n) => n.to_string(),
+            serde_yaml_ng::Value::Bool(b) => b.to_string(),
+            _ => {
+                return Err(anyhow!(
+                    "response to prompt '{}' must be a plain value",
+                    template
+                ))
+            }
+        };
+
+        let input = parse_response(prompt, &text)
+            .map_err(|e| anyhow!("response to prompt '{}': {}", template, e))?;
+        set(&mut responses, &template, input);
+    }
+
+    Ok(responses)
+}
@@ -0,0 +1,55 @@
+use crate::{qmp::messages::EventMessage, ProtoVmEvent};
+
+//
+// what a running VM reports about itself, such as being shut down or a disk failing, as charond
+// passes it on from qemu.
+//
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmEvent {
+    // as qemu names it, e.g. SHUTDOWN
+    pub event: String,
+    // when it happened, since the epoch
+    pub seconds: u64,
+    pub microseconds: u64,
+    pub description: String,
+    pub data: serde_json::Value,
+}
+
+impl From<EventMessage> for VmEvent {
+    fn from(value: EventMessage) -> Self {
+        let timestamp = value.timestamp.clone().unwrap_or_default();
+
+        Self {
+            description: value.parsed().to_string(),
+            event: value.event,
+            seconds: timestamp.seconds,
+            microseconds: timestamp.microseconds,
+            data: value.data.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<VmEvent> for ProtoVmEvent {
+    fn from(value: VmEvent) -> Self {
+        Self {
+            event: value.event,
+            seconds: value.seconds,
+            microseconds: value.microseconds,
+            description: value.description,
+            data: value.data.to_string(),
+        }
+    }
+}
+
+impl From<ProtoVmEvent> for VmEvent {
+    fn from(value: ProtoVmEvent) -> Self {
+        Self {
+            event: value.event,
+            seconds: value.seconds,
+            microseconds: value.microseconds,
+            description: value.description,
+            data: serde_json::from_str(&value.data).unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use super::{check_vm, vm_client};
+use crate::{CompiledPackage, ProtoVmCpu, ProtoVmDiskStats, ProtoVmInfo, ProtoVmPciDevice};
+use anyhow::Result;
+use std::path::Path;
+
+//
+// what qemu reports about a running VM: whether it runs, the host threads of its vCPUs, its
+// memory, and how much each disk has been used since it started.
+//
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VmInfo {
+    pub name: String,
+    // qemu's run state, e.g. running or paused
+    pub status: String,
+    pub running: bool,
+    pub qemu_version: String,
+    pub cpus: Vec<VmCpu>,
+    // in bytes
+    pub base_memory: u64,
+    pub plugged_memory: u64,
+    pub disks: Vec<VmDiskStats>,
+    pub pci: Vec<VmPciDevice>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VmCpu {
+    pub index: u64,
+    pub thread: u64,
+    pub qom_path: String,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VmDiskStats {
+    pub name: String,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub failed_reads: u64,
+    pub failed_writes: u64,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VmPciDevice {
+    pub bus: u64,
+    pub slot: u64,
+    pub function: u64,
+    pub vendor: u64,
+    pub device: u64,
+    pub description: String,
+    pub qdev_id: String,
+}
+
+impl From<VmInfo> for ProtoVmInfo {
+    fn from(value: VmInfo) -> Self {
+        Self {
+            name: value.name,
+            status: value.status,
+            running: value.running,
+            qemu_version: value.qemu_version,
+            cpus: value.cpus.into_iter().map(Into::into).collect(),
+            base_memory: value.base_memory,
+            plugged_memory: value.plugged_memory,
+            disks: value.disks.into_iter().map(Into::into).collect(),
+            pci: value.pci.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<ProtoVmInfo> for VmInfo {
+    fn from(value: ProtoVmInfo) -> Self {
+        Self {
+            name: value.name,
+            status: value.status,
+            running: value.running,
+            qemu_version: value.qemu_version,
+            cpus: value.cpus.into_iter().map(Into::into).collect(),
+            base_memory: value.base_memory,
+            plugged_memory: value.plugged_memory,
+            disks: value.disks.into_iter().map(Into::into).collect(),
+            pci: value.pci.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<VmCpu> for ProtoVmCpu {
+    fn from(value: VmCpu) -> Self {
+        Self {
+            index: value.index,
+            thread: value.thread,
+            qom_path: value.qom_path,
+        }
+    }
+}
+
+impl From<ProtoVmCpu> for VmCpu {
+    fn from(value: ProtoVmCpu) -> Self {
+        Self {
+            index: value.index,
+            thread: value.thread,
+            qom_path: value.qom_path,
+        }
+    }
+}
+
+impl From<VmDiskStats> for ProtoVmDiskStats {
+    fn from(value: VmDiskStats) -> Self {
+        Self {
+            name: value.name,
+            read_bytes: value.read_bytes,
+            written_bytes: value.written_bytes,
+            reads: value.reads,
+            writes: value.writes,
+            flushes: value.flushes,
+            failed_reads: value.failed_reads,
+            failed_writes: value.failed_writes,
+        }
+    }
+}
+
+impl From<ProtoVmDiskStats> for VmDiskStats {
+    fn from(value: ProtoVmDiskStats) -> Self {
+        Self {
+            name: value.name,
+            read_bytes: value.read_bytes,
+            written_bytes: value.written_bytes,
+            reads: value.reads,
+            writes: value.writes,
+            flushes: value.flushes,
+            failed_reads: value.failed_reads,
+            failed_writes: value.failed_writes,
+        }
+    }
+}
+
+impl From<VmPciDevice> for ProtoVmPciDevice {
+    fn from(value: VmPciDevice) -> Self {
+        Self {
+            bus: value.bus,
+            slot: value.slot,
+            function: value.function,
+            vendor: value.vendor,
+            device: value.device,
+            description: value.description,
+            qdev_id: value.qdev_id,
+        }
+    }
+}
+
+impl From<ProtoVmPciDevice> for VmPciDevice {
+    fn from(value: ProtoVmPciDevice) -> Self {
+        Self {
+            bus: value.bus,
+            slot: value.slot,
+            function: value.function,
+            vendor: value.vendor,
+            device: value.device,
+            description: value.description,
+            qdev_id: value.qdev_id,
+        }
+    }
+}
+
+pub async fn vm_info(package: &CompiledPackage, volume_root: &Path) -> Result<VmInfo> {
+    check_vm(package)?;
+
+    let client = vm_client(package, volume_root).await?;
+
+    let status = client.status().await?;
+    let memory = client.memory_size_summary().await?;
+
+    Ok(VmInfo {
+        name: client.name().await?.name.unwrap_or_default(),
+        status: status.status,
+        running: status.running,
+        qemu_version: client.version().await?.to_string(),
+        cpus: client
+            .cpus()
+            .await?
+            .into_iter()
+            .map(|cpu| VmCpu {
+                index: cpu.cpu_index,
+                thread: cpu.thread_id,
+                qom_path: cpu.qom_path,
+            })
+            .collect(),
+        base_memory: memory.base_memory,
+        plugged_memory: memory.plugged_memory.unwrap_or_default(),
+        disks: client
+            .block_stats()
+            .await?
+            .into_iter()
+            .map(|disk| VmDiskStats {
+                name: disk.name(),
+                read_bytes: disk.stats.rd_bytes,
+                written_bytes: disk.stats.wr_bytes,
+                reads: disk.stats.rd_operations,
+                writes: disk.stats.wr_operations,
+                flushes: disk.stats.flush_operations,
+                failed_reads: disk.stats.failed_rd_operations,
+                failed_writes: disk.stats.failed_wr_operations,
+            })
+            .collect(),
+        pci: client
+            .pci()
+            .await?
+            .iter()
+            .flat_map(|bus| bus.devices.iter().flat_map(|device| device.flatten()))
+            .map(|device| VmPciDevice {
+                bus: device.bus,
+                slot: device.slot,
+                function: device.function,
+                vendor: device.id.vendor,
+                device: device.id.device,
+                description: device.class_info.desc.unwrap_or_default(),
+                qdev_id: device.qdev_id,
+            })
+            .collect(),
+    })
+}
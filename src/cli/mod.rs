@@ -1,9 +1,11 @@
 use crate::{
+    cached_image_path,
     qmp::{client::Client, messages::GenericReturn},
     CompiledPackage, CompiledSource,
 };
 use anyhow::{anyhow, Result};
 use curl::easy::Easy;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::{io::Read, process::Stdio};
 use std::{
     io::Write,
@@ -11,13 +13,39 @@ use std::{
     sync::mpsc::channel,
 };
 
+mod configure;
+mod events;
+mod info;
+mod resize;
+mod snapshot;
+pub use configure::*;
+pub use events::*;
+pub use info::*;
+pub use resize::*;
+pub use snapshot::*;
+
 #[cfg(test)]
 mod tests;
 
 const PODMAN_COMMAND: &str = "podman";
 const QEMU_COMMAND: &str = "qemu-system-x86_64";
+const QEMU_IMG_COMMAND: &str = "qemu-img";
 const QEMU_IMAGE_FILENAME: &str = "image";
+// the VM runs from a qcow2 overlay of its image, so it can hold snapshots whatever the image is
+const QEMU_DISK_FILENAME: &str = "disk.qcow2";
+const QCOW2_FORMAT: &str = "qcow2";
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
 const QEMU_MONITOR_FILENAME: &str = "qemu-monitor";
+// charond keeps this one open to hear the events of the VM, so commands have the other to themselves
+const QEMU_EVENTS_MONITOR_FILENAME: &str = "qemu-events";
+const QEMU_SECRETS_DIRNAME: &str = "secrets";
+// where secrets show up for the guest: /sys/firmware/qemu_fw_cfg/by_name/opt/charon/secrets
+const QEMU_SECRETS_FW_CFG: &str = "opt/charon/secrets";
+// VMs start with room to grow by this much without a restart, in vCPUs and memory
+const QEMU_HOTPLUG_FACTOR: u64 = 2;
+// how many times memory can be added to a running VM
+const QEMU_MEMORY_SLOTS: u64 = 8;
+const QEMU_BALLOON_ID: &str = "balloon0";
 
 enum DownloadInfo {
     Data(Vec<u8>),
@@ -33,11 +61,94 @@ pub fn generate_command(package: CompiledPackage, volume_root: PathBuf) -> Resul
     }
 }
 
-pub fn stop_package(package: CompiledPackage, volume_root: PathBuf) -> Result<()> {
+pub async fn stop_package(package: CompiledPackage, volume_root: PathBuf) -> Result<()> {
     match package.source {
-        CompiledSource::URL(_) => vm_shutdown(&package, &volume_root),
+        CompiledSource::URL(_) => vm_shutdown(&package, &volume_root).await,
         CompiledSource::Container(_) => container_shutdown(&package, &volume_root),
+    }?;
+
+    remove_secrets(&package, &volume_root)
+}
+
+fn secret_name(package: &CompiledPackage, template: &str) -> String {
+    format!("{}-{}", package.title, template)
+}
+
+// secrets are handed over out of band so they never show up in the command line: podman gets them
+// on stdin, and VMs get files only root can read, which qemu passes in with -fw_cfg.
+pub fn create_secrets(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
+    if package.secrets.is_empty() {
+        return Ok(());
     }
+
+    match package.source {
+        CompiledSource::URL(_) => {
+            let dir = volume_root.join(QEMU_SECRETS_DIRNAME);
+            std::fs::create_dir_all(&dir)?;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+
+            for (template, secret) in &package.secrets {
+                let path = dir.join(template);
+                let _ = std::fs::remove_file(&path);
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .mode(0o600)
+                    .open(&path)?
+                    .write_all(secret.expose().unwrap_or_default().as_bytes())?;
+            }
+        }
+        CompiledSource::Container(_) => {
+            for (template, secret) in &package.secrets {
+                let mut child = std::process::Command::new(PODMAN_COMMAND)
+                    .args(vec![
+                        "secret",
+                        "create",
+                        "--replace",
+                        &secret_name(package, template),
+                        "-",
+                    ])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .spawn()?;
+
+                child
+                    .stdin
+                    .take()
+                    .unwrap()
+                    .write_all(secret.expose().unwrap_or_default().as_bytes())?;
+
+                if !child.wait()?.success() {
+                    return Err(anyhow!("could not create secret for prompt '{}'", template));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn remove_secrets(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
+    match package.source {
+        CompiledSource::URL(_) => {
+            let dir = volume_root.join(QEMU_SECRETS_DIRNAME);
+            if dir.exists() {
+                std::fs::remove_dir_all(dir)?;
+            }
+        }
+        CompiledSource::Container(_) => {
+            for (template, _) in &package.secrets {
+                std::process::Command::new(PODMAN_COMMAND)
+                    .args(vec!["secret", "rm", &secret_name(package, template)])
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()?;
+            }
+        }
+    }
+
+    Ok(())
 }
 
 pub fn container_shutdown(package: &CompiledPackage, _: &Path) -> Result<()> {
@@ -50,6 +161,129 @@ pub fn container_shutdown(package: &CompiledPackage, _: &Path) -> Result<()> {
     Ok(())
 }
 
+// writes the image of a container to an OCI archive, pulling it first if it isn't here yet
+pub fn save_container_image(image: &str, target: &Path) -> Result<()> {
+    let exists = std::process::Command::new(PODMAN_COMMAND)
+        .args(vec!["image", "exists", image])
+        .status()?;
+
+    if !exists.success() {
+        let status = std::process::Command::new(PODMAN_COMMAND)
+            .args(vec!["pull", image])
+            .stdout(Stdio::null())
+            .status()?;
+
+        if !status.success() {
+            return Err(anyhow!("could not pull container image '{}'", image));
+        }
+    }
+
+    let status = std::process::Command::new(PODMAN_COMMAND)
+        .args(vec!["save", "--format", "oci-archive", "-o"])
+        .arg(target)
+        .arg(image)
+        .status()?;
+
+    if !status.success() {
+        return Err(anyhow!("could not save container image '{}'", image));
+    }
+
+    Ok(())
+}
+
+pub fn load_container_image(archive: &Path) -> Result<()> {
+    let status = std::process::Command::new(PODMAN_COMMAND)
+        .args(vec!["load", "-i"])
+        .arg(archive)
+        .stdout(Stdio::null())
+        .status()?;
+
+    if !status.success() {
+        return Err(anyhow!(
+            "could not load container image from {}",
+            archive.display()
+        ));
+    }
+
+    Ok(())
+}
+
+// puts the image of a VM in the volume root, unless it is already there, and the overlay the VM
+// runs from on top of it. an image imported with a bundle is copied from the image cache, anything
+// else is downloaded.
+pub fn prepare_vm_image(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
+    let url = match &package.source {
+        CompiledSource::URL(url) => url,
+        CompiledSource::Container(_) => return Ok(()),
+    };
+
+    let image = volume_root.join(QEMU_IMAGE_FILENAME);
+    if !std::fs::exists(&image)? {
+        std::fs::create_dir_all(volume_root)?;
+
+        // so an image that was only partly written is not mistaken for one that is there
+        let tmp = volume_root.join(format!("{}.tmp", QEMU_IMAGE_FILENAME));
+        let cached = cached_image_path(&package.state(), &package.title);
+        if std::fs::exists(&cached)? {
+            std::fs::copy(cached, &tmp)?;
+        } else {
+            download_vm_image(url, tmp.clone())?;
+        }
+
+        std::fs::rename(tmp, &image)?;
+    }
+
+    let disk = volume_root.join(QEMU_DISK_FILENAME);
+    if std::fs::exists(&disk)? {
+        return Ok(());
+    }
+
+    let tmp = volume_root.join(format!("{}.tmp", QEMU_DISK_FILENAME));
+    let status = std::process::Command::new(QEMU_IMG_COMMAND)
+        .args(vm_overlay_args(&image, &tmp)?)
+        .stdout(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!(
+            "could not create the disk of {}: {} exited with {}",
+            package.title,
+            QEMU_IMG_COMMAND,
+            status
+        ));
+    }
+
+    Ok(std::fs::rename(tmp, disk)?)
+}
+
+// the arguments to qemu-img that make a qcow2 overlay of image at overlay. the backing file is
+// named relative to the overlay, so the volume root can be moved.
+pub fn vm_overlay_args(image: &Path, overlay: &Path) -> Result<Vec<String>> {
+    let mut magic = Vec::with_capacity(QCOW2_MAGIC.len());
+    std::fs::File::open(image)?
+        .take(QCOW2_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+
+    Ok(vec![
+        "create".into(),
+        "-f".into(),
+        QCOW2_FORMAT.into(),
+        "-F".into(),
+        if magic == QCOW2_MAGIC {
+            QCOW2_FORMAT
+        } else {
+            "raw"
+        }
+        .into(),
+        "-b".into(),
+        image
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a file", image.display()))?
+            .to_string_lossy()
+            .to_string(),
+        overlay.display().to_string(),
+    ])
+}
+
 pub fn download_vm_image(u: &str, target: PathBuf) -> Result<()> {
     let parsed: url::Url = u.parse()?;
 
@@ -96,7 +330,10 @@ pub fn download_vm_image(u: &str, target: PathBuf) -> Result<()> {
         // sigh.
         let mut f = std::fs::OpenOptions::new().read(true).open(&format!(
             "{}{}",
-            parsed.host().unwrap(),
+            parsed
+                .host()
+                .map(|host| host.to_string())
+                .unwrap_or_default(),
             parsed.path()
         ))?;
         let mut buf: [u8; 4096] = [0u8; 4096];
@@ -140,28 +377,70 @@ pub fn download_vm_image(u: &str, target: PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn vm_client(package: &CompiledPackage, volume_root: &Path) -> Result<Client> {
-    match Client::new(volume_root.join(QEMU_MONITOR_FILENAME)) {
-        Ok(mut us) => {
-            us.handshake()?;
-            us.send_command::<GenericReturn>("qmp_capabilities", None)?;
-            Ok(us)
-        }
-        Err(_) => Err(anyhow!("{} is not running or not monitored", package.title)),
+// where qemu listens for QMP, for a VM started with this volume root
+pub fn vm_monitor_path(volume_root: &Path) -> PathBuf {
+    volume_root.join(QEMU_MONITOR_FILENAME)
+}
+
+// where qemu reports the events of a VM started with this volume root
+pub fn vm_events_monitor_path(volume_root: &Path) -> PathBuf {
+    volume_root.join(QEMU_EVENTS_MONITOR_FILENAME)
+}
+
+// everything that talks to qemu is only for VMs
+pub fn check_vm(package: &CompiledPackage) -> Result<()> {
+    match package.source {
+        CompiledSource::URL(_) => Ok(()),
+        CompiledSource::Container(_) => Err(anyhow!("{} is a container, not a VM", package.title)),
     }
 }
 
-pub fn vm_ping(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
-    vm_client(package, volume_root)?;
+async fn vm_client(package: &CompiledPackage, volume_root: &Path) -> Result<Client> {
+    Client::connect(vm_monitor_path(volume_root))
+        .await
+        .map_err(|_| anyhow!("{} is not running or not monitored", package.title))
+}
+
+pub async fn vm_ping(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
+    vm_client(package, volume_root).await?;
     Ok(())
 }
 
-pub fn vm_shutdown(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
-    vm_client(package, volume_root)?.send_command("system_powerdown", None)
+pub async fn vm_shutdown(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
+    vm_client(package, volume_root)
+        .await?
+        .send_command::<GenericReturn>("system_powerdown", None)
+        .await?;
+    Ok(())
 }
 
-pub fn vm_quit(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
-    vm_client(package, volume_root)?.send_command("quit", None)
+pub async fn vm_quit(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
+    vm_client(package, volume_root)
+        .await?
+        .send_command::<GenericReturn>("quit", None)
+        .await?;
+    Ok(())
+}
+
+// volumes are files next to the VM's own, so they can't take their names
+fn check_vm_volume_name(name: &str) -> Result<()> {
+    let excluded_names = [
+        QEMU_IMAGE_FILENAME,
+        QEMU_DISK_FILENAME,
+        QEMU_MONITOR_FILENAME,
+        QEMU_EVENTS_MONITOR_FILENAME,
+        QEMU_SECRETS_DIRNAME,
+    ];
+
+    if excluded_names.contains(&name) {
+        return Err(anyhow!(
+            "VM volumes cannot be named '{}'",
+            // this outputs "'foo', or 'bar', or 'baz'"
+            excluded_names.join("', or '")
+        ));
+    }
+
+    Ok(())
 }
 
 pub fn generate_vm_command(package: &CompiledPackage, volume_root: &Path) -> Result<Vec<String>> {
@@ -185,41 +464,62 @@ pub fn generate_vm_command(package: &CompiledPackage, volume_root: &Path) -> Res
         ),
         "-mon".into(),
         "chardev=char0,mode=control,pretty=on".into(),
+        "-chardev".into(),
+        format!(
+            "socket,server=on,wait=off,id=char1,path={}",
+            vm_events_monitor_path(volume_root).display(),
+        ),
+        "-mon".into(),
+        "chardev=char1,mode=control,pretty=on".into(),
         "-machine".into(),
         "accel=kvm".into(),
         "-vga".into(),
         "none".into(), // FIXME: move to VNC
         "-m".into(),
-        format!("{}M", package.resources.memory),
+        format!(
+            "{}M,slots={},maxmem={}M",
+            package.resources.memory,
+            QEMU_MEMORY_SLOTS,
+            package.resources.memory * QEMU_HOTPLUG_FACTOR
+        ),
         "-cpu".into(),
         "max".into(),
         "-smp".into(),
         format!(
             "cpus={},cores={},maxcpus={}",
-            package.resources.cpus, package.resources.cpus, package.resources.cpus
+            package.resources.cpus,
+            package.resources.cpus * QEMU_HOTPLUG_FACTOR,
+            package.resources.cpus * QEMU_HOTPLUG_FACTOR
         ),
         "-nic".into(),
         format!("user{}", fwdrules),
+        "-device".into(),
+        format!("virtio-balloon-pci,id={}", QEMU_BALLOON_ID),
     ]);
 
     cmd.push("-drive".into());
     cmd.push(format!(
-        "driver=raw,if=virtio,file={},cache=none,media=disk,index={}",
-        volume_root.join(QEMU_IMAGE_FILENAME).display(),
+        "driver=qcow2,if=virtio,file={},cache=none,media=disk,index={}",
+        volume_root.join(QEMU_DISK_FILENAME).display(),
         // NOTE: this offsets the counter below for volumes
         0,
     ));
 
-    let excluded_names = [QEMU_IMAGE_FILENAME, QEMU_MONITOR_FILENAME];
+    for (template, _) in &package.secrets {
+        cmd.push("-fw_cfg".into());
+        cmd.push(format!(
+            "name={}/{},file={}",
+            QEMU_SECRETS_FW_CFG,
+            template,
+            volume_root
+                .join(QEMU_SECRETS_DIRNAME)
+                .join(template)
+                .display()
+        ));
+    }
 
     for (x, volume) in package.storage.volumes.iter().enumerate() {
-        if excluded_names.contains(&volume.name.as_str()) {
-            return Err(anyhow!(
-                "VM volumes cannot be named '{}'",
-                // this outputs "'foo', or 'bar', or 'baz'"
-                excluded_names.join("', or '")
-            ));
-        }
+        check_vm_volume_name(&volume.name)?;
 
         cmd.push("-drive".to_string());
         cmd.push(format!(
@@ -308,6 +608,14 @@ pub fn generate_container_command(
 
     // TODO: cgroups
 
+    // mounted at /run/secrets/<template>
+    for (template, _) in &package.secrets {
+        cmd.append(&mut vec![
+            "--secret".into(),
+            format!("{},target={}", secret_name(package, template), template),
+        ]);
+    }
+
     cmd.push(name.into());
 
     Ok(cmd)
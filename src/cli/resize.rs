@@ -0,0 +1,274 @@
+use super::{check_vm, check_vm_volume_name, vm_client};
+use crate::{
+    qmp::{
+        client::Client,
+        messages::{
+            Balloon, BlockdevAdd, BlockdevDel, DeviceAdd, DeviceDel, ObjectAdd, ObjectDel,
+            QueryBalloon, QueryHotpluggableCpus,
+        },
+    },
+    CompiledPackage, ProtoResized,
+};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+use std::path::Path;
+
+//
+// changing the vCPUs, memory and volumes of a running VM to what its package says now, without
+// restarting it. VMs are started with room for this: twice the vCPUs and memory they ask for, and
+// a balloon. memory that was added stays, so taking memory away is left to the balloon, and only
+// vCPUs that were added can be taken away again.
+//
+
+const MIB: u64 = 1024 * 1024;
+// anything added while the VM runs shows up here, under the id it was added with
+const QOM_PERIPHERAL: &str = "/machine/peripheral/";
+const HOTPLUG_CPU_PREFIX: &str = "vcpu";
+const HOTPLUG_DIMM_PREFIX: &str = "dimm";
+const HOTPLUG_VOLUME_PREFIX: &str = "volume-";
+
+// what a resize changed; memory is in MiB
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VmResize {
+    pub cpus_before: u64,
+    pub cpus: u64,
+    pub memory_before: u64,
+    pub memory: u64,
+    pub volumes_added: Vec<String>,
+}
+
+impl From<VmResize> for ProtoResized {
+    fn from(value: VmResize) -> Self {
+        Self {
+            cpus_before: value.cpus_before,
+            cpus: value.cpus,
+            memory_before: value.memory_before,
+            memory: value.memory,
+            volumes_added: value.volumes_added,
+        }
+    }
+}
+
+impl From<ProtoResized> for VmResize {
+    fn from(value: ProtoResized) -> Self {
+        Self {
+            cpus_before: value.cpus_before,
+            cpus: value.cpus,
+            memory_before: value.memory_before,
+            memory: value.memory,
+            volumes_added: value.volumes_added,
+        }
+    }
+}
+
+fn properties(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(properties) => properties,
+        _ => Map::new(),
+    }
+}
+
+pub async fn vm_resize(package: &CompiledPackage, volume_root: &Path) -> Result<VmResize> {
+    check_vm(package)?;
+
+    let client = vm_client(package, volume_root).await?;
+
+    // a VM started without a balloon has none of the room either, so find out before changing
+    // anything
+    let memory_before = client
+        .execute(&QueryBalloon)
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "{} cannot be resized until it is restarted: {}",
+                package.title,
+                e
+            )
+        })?
+        .actual
+        / MIB;
+
+    let cpus_before = resize_cpus(&client, package).await?;
+    resize_memory(&client, package).await?;
+    let volumes_added = add_volumes(&client, package, volume_root).await?;
+
+    Ok(VmResize {
+        cpus_before,
+        cpus: package.resources.cpus,
+        memory_before,
+        memory: package.resources.memory,
+        volumes_added,
+    })
+}
+
+async fn resize_cpus(client: &Client, package: &CompiledPackage) -> Result<u64> {
+    let cpus = client.cpus().await?;
+    let before = cpus.len() as u64;
+    let target = package.resources.cpus;
+
+    if target > before {
+        let needed = (target - before) as usize;
+
+        let mut free = client
+            .execute(&QueryHotpluggableCpus)
+            .await?
+            .into_iter()
+            .filter(|slot| slot.qom_path.is_none())
+            .collect::<Vec<_>>();
+
+        if free.len() < needed {
+            return Err(anyhow!(
+                "{} can have at most {} vCPUs until it is restarted",
+                package.title,
+                before + free.len() as u64
+            ));
+        }
+
+        free.sort_by_key(|slot| {
+            (
+                slot.props.socket_id,
+                slot.props.die_id,
+                slot.props.core_id,
+                slot.props.thread_id,
+            )
+        });
+
+        for slot in free.into_iter().take(needed) {
+            let id = format!(
+                "{}-{}-{}-{}",
+                HOTPLUG_CPU_PREFIX,
+                slot.props.socket_id.unwrap_or_default(),
+                slot.props.core_id.unwrap_or_default(),
+                slot.props.thread_id.unwrap_or_default()
+            );
+
+            client
+                .execute(&DeviceAdd {
+                    driver: slot.typ,
+                    id,
+                    properties: properties(serde_json::to_value(&slot.props)?),
+                })
+                .await?;
+        }
+    } else if target < before {
+        let needed = (before - target) as usize;
+
+        let mut added = cpus
+            .into_iter()
+            .filter(|cpu| cpu.qom_path.starts_with(QOM_PERIPHERAL))
+            .collect::<Vec<_>>();
+
+        if added.len() < needed {
+            return Err(anyhow!(
+                "{} cannot have fewer than {} vCPUs until it is restarted",
+                package.title,
+                before - added.len() as u64
+            ));
+        }
+
+        // the last ones added go first
+        added.sort_by_key(|cpu| std::cmp::Reverse(cpu.cpu_index));
+
+        for cpu in added.into_iter().take(needed) {
+            client
+                .execute(&DeviceDel {
+                    id: cpu.qom_path.trim_start_matches(QOM_PERIPHERAL).to_string(),
+                })
+                .await?;
+        }
+    }
+
+    Ok(before)
+}
+
+async fn resize_memory(client: &Client, package: &CompiledPackage) -> Result<()> {
+    let target = package.resources.memory * MIB;
+    let summary = client.memory_size_summary().await?;
+    let present = summary.base_memory + summary.plugged_memory.unwrap_or_default();
+
+    if target > present {
+        // named after where it starts, which is never the same twice
+        let id = format!("{}{}", HOTPLUG_DIMM_PREFIX, present / MIB);
+        let backend = format!("{}-memory", id);
+
+        client
+            .execute(&ObjectAdd {
+                qom_type: "memory-backend-ram".into(),
+                id: backend.clone(),
+                properties: properties(json!({ "size": target - present })),
+            })
+            .await?;
+
+        if let Err(e) = client
+            .execute(&DeviceAdd {
+                driver: "pc-dimm".into(),
+                id,
+                properties: properties(json!({ "memdev": backend })),
+            })
+            .await
+        {
+            let _ = client.execute(&ObjectDel { id: backend }).await;
+            return Err(e);
+        }
+    }
+
+    client.execute(&Balloon { value: target }).await?;
+    Ok(())
+}
+
+// volumes the package has now that the VM doesn't; volumes that are gone from the package are
+// left attached until the VM is restarted
+async fn add_volumes(
+    client: &Client,
+    package: &CompiledPackage,
+    volume_root: &Path,
+) -> Result<Vec<String>> {
+    let attached = client
+        .block_devices()
+        .await?
+        .result
+        .into_iter()
+        .filter_map(|block| block.inserted.and_then(|drive| drive.file))
+        .collect::<Vec<_>>();
+
+    let mut added = Vec::new();
+
+    for volume in &package.storage.volumes {
+        check_vm_volume_name(&volume.name)?;
+
+        let filename = volume_root.join(&volume.name).to_string_lossy().to_string();
+        if attached.contains(&filename) {
+            continue;
+        }
+
+        let node = format!("{}{}", HOTPLUG_VOLUME_PREFIX, volume.name);
+
+        client
+            .execute(&BlockdevAdd {
+                driver: "raw".into(),
+                node_name: node.clone(),
+                // the same as the volumes it starts with, which bypass the host's cache
+                properties: properties(json!({
+                    "file": { "driver": "file", "filename": filename },
+                    "cache": { "direct": true },
+                })),
+            })
+            .await?;
+
+        if let Err(e) = client
+            .execute(&DeviceAdd {
+                driver: "virtio-blk-pci".into(),
+                id: node.clone(),
+                properties: properties(json!({ "drive": node })),
+            })
+            .await
+        {
+            let _ = client.execute(&BlockdevDel { node_name: node }).await;
+            return Err(e);
+        }
+
+        added.push(volume.name.clone());
+    }
+
+    Ok(added)
+}
@@ -0,0 +1,170 @@
+use super::{check_vm, vm_client, QCOW2_FORMAT};
+use crate::{CompiledPackage, ProtoSnapshot, ProtoSnapshotList};
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+//
+// snapshots of a running VM, taken by qemu into its disks along with the state of the VM, so it
+// can be put back exactly as it was. qemu can only do this for disks in a format that holds
+// snapshots, such as qcow2. VMs run from a qcow2 overlay of their image, but their volumes are
+// raw, so check_vm_snapshots refuses VMs that have any before qemu is asked.
+//
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VmSnapshot {
+    pub name: String,
+    // when it was taken, in seconds since the epoch
+    pub date: u64,
+    // how long the VM had been running, in nanoseconds
+    pub vm_clock: u64,
+    pub vm_state_size: u64,
+}
+
+impl From<VmSnapshot> for ProtoSnapshot {
+    fn from(value: VmSnapshot) -> Self {
+        Self {
+            name: value.name,
+            date: value.date,
+            vm_clock: value.vm_clock,
+            vm_state_size: value.vm_state_size,
+        }
+    }
+}
+
+impl From<ProtoSnapshot> for VmSnapshot {
+    fn from(value: ProtoSnapshot) -> Self {
+        Self {
+            name: value.name,
+            date: value.date,
+            vm_clock: value.vm_clock,
+            vm_state_size: value.vm_state_size,
+        }
+    }
+}
+
+impl From<ProtoSnapshotList> for Vec<VmSnapshot> {
+    fn from(value: ProtoSnapshotList) -> Self {
+        value.snapshots.into_iter().map(Into::into).collect()
+    }
+}
+
+pub fn check_snapshot_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("snapshot name cannot be empty"));
+    }
+
+    Ok(())
+}
+
+// the snapshots of the VM, by name. a snapshot is taken of every disk, so the disks are read
+// together and each snapshot is listed once.
+pub async fn vm_snapshots(
+    package: &CompiledPackage,
+    volume_root: &Path,
+) -> Result<Vec<VmSnapshot>> {
+    check_vm(package)?;
+
+    let mut snapshots: Vec<VmSnapshot> = Vec::new();
+
+    for block in vm_client(package, volume_root)
+        .await?
+        .block_devices()
+        .await?
+        .result
+    {
+        let found = block
+            .inserted
+            .and_then(|drive| drive.image)
+            .and_then(|image| image.snapshots)
+            .map(|snapshots| snapshots.0)
+            .unwrap_or_default();
+
+        for snapshot in found {
+            let name = snapshot.name.unwrap_or_default();
+            if snapshots.iter().any(|s| s.name == name) {
+                continue;
+            }
+
+            snapshots.push(VmSnapshot {
+                name,
+                date: snapshot.date_sec.unwrap_or_default() as u64,
+                vm_clock: (snapshot.vm_clock_sec.unwrap_or_default() as u64) * 1_000_000_000
+                    + snapshot.vm_clock_nsec.unwrap_or_default() as u64,
+                vm_state_size: snapshot.vm_state_size.unwrap_or_default() as u64,
+            });
+        }
+    }
+
+    snapshots.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.name.cmp(&b.name)));
+    Ok(snapshots)
+}
+
+// every disk of the VM can hold snapshots
+pub async fn check_vm_snapshots(package: &CompiledPackage, volume_root: &Path) -> Result<()> {
+    check_vm(package)?;
+
+    for block in vm_client(package, volume_root)
+        .await?
+        .block_devices()
+        .await?
+        .result
+    {
+        if let Some(drv) = block
+            .inserted
+            .and_then(|inserted| inserted.drv)
+            .filter(|drv| drv != QCOW2_FORMAT)
+        {
+            return Err(anyhow!(
+                "{} cannot have snapshots: its disk {} is {}, not {}",
+                package.title,
+                block.device,
+                drv,
+                QCOW2_FORMAT
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn vm_snapshot_save(
+    package: &CompiledPackage,
+    volume_root: &Path,
+    name: &str,
+) -> Result<()> {
+    check_snapshot_name(name)?;
+    check_vm_snapshots(package, volume_root).await?;
+
+    vm_client(package, volume_root)
+        .await?
+        .snapshot_save(name)
+        .await
+}
+
+pub async fn vm_snapshot_load(
+    package: &CompiledPackage,
+    volume_root: &Path,
+    name: &str,
+) -> Result<()> {
+    check_snapshot_name(name)?;
+    check_vm_snapshots(package, volume_root).await?;
+
+    vm_client(package, volume_root)
+        .await?
+        .snapshot_load(name)
+        .await
+}
+
+pub async fn vm_snapshot_delete(
+    package: &CompiledPackage,
+    volume_root: &Path,
+    name: &str,
+) -> Result<()> {
+    check_vm(package)?;
+    check_snapshot_name(name)?;
+
+    vm_client(package, volume_root)
+        .await?
+        .snapshot_delete(name)
+        .await
+}
@@ -45,7 +45,7 @@ mod livetests {
 
     #[tokio::test]
     async fn launch_podman() {
-        let registry = Registry::new("testdata/registry".into());
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
         let td = TempDir::new().unwrap();
         let path = td.path();
 
@@ -68,7 +68,7 @@ mod livetests {
         let pkg = load(&registry, "podman-test", "0.0.3").unwrap();
         let args = generate_command(pkg.clone(), path.to_path_buf()).unwrap();
 
-        let _ = stop_package(pkg.clone(), path.to_path_buf());
+        let _ = stop_package(pkg.clone(), path.to_path_buf()).await;
 
         let mut child = std::process::Command::new(&args[0])
             .args(args.iter().skip(1))
@@ -98,7 +98,7 @@ mod livetests {
         let resp = reqwest::get("http://localhost:8000").await.unwrap();
         assert_eq!(resp.status(), 200);
 
-        stop_package(pkg, path.to_path_buf()).unwrap();
+        stop_package(pkg, path.to_path_buf()).await.unwrap();
         let status = child.wait().unwrap();
         assert!(status.success())
     }
@@ -106,7 +106,7 @@ mod livetests {
     //
     // #[test]
     // fn launch_qemu() {
-    //     let registry = Registry::new("testdata/registry".into());
+    //     let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
     //     let args = generate_command(
     //         load(&registry, "plex-qemu", "0.0.2").unwrap(),
     //         "testdata/volume-root".into(),
@@ -121,9 +121,38 @@ mod livetests {
 mod cli_generation {
     use super::*;
 
+    #[test]
+    fn vm_overlay() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image");
+        let overlay = dir.path().join("disk.qcow2");
+
+        std::fs::write(&image, b"raw bytes").unwrap();
+        assert_eq!(
+            vm_overlay_args(&image, &overlay).unwrap(),
+            string_vec(vec![
+                "create",
+                "-f",
+                "qcow2",
+                "-F",
+                "raw",
+                "-b",
+                "image",
+                overlay.to_str().unwrap()
+            ])
+        );
+
+        std::fs::write(&image, b"QFI\xfb\0\0\0\x03").unwrap();
+        assert_eq!(vm_overlay_args(&image, &overlay).unwrap()[4], "qcow2");
+
+        // no image, nothing to put an overlay on
+        std::fs::remove_file(&image).unwrap();
+        assert!(vm_overlay_args(&image, &overlay).is_err());
+    }
+
     #[test]
     fn qemu_cli() {
-        let registry = Registry::new("testdata/registry".into());
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
         assert_eq!(
             generate_command(
                 load(&registry, "plex-qemu", "0.0.2").unwrap(),
@@ -137,20 +166,26 @@ mod cli_generation {
                 "socket,server=on,wait=off,id=char0,path=/volume-root/qemu-monitor",
                 "-mon",
                 "chardev=char0,mode=control,pretty=on",
+                "-chardev",
+                "socket,server=on,wait=off,id=char1,path=/volume-root/qemu-events",
+                "-mon",
+                "chardev=char1,mode=control,pretty=on",
                 "-machine",
                 "accel=kvm",
                 "-vga",
                 "none",
                 "-m",
-                "8192M",
+                "8192M,slots=8,maxmem=16384M",
                 "-cpu",
                 "max",
                 "-smp",
-                "cpus=4,cores=4,maxcpus=4",
+                "cpus=4,cores=8,maxcpus=8",
                 "-nic",
                 "user",
+                "-device",
+                "virtio-balloon-pci,id=balloon0",
                 "-drive",
-                "driver=raw,if=virtio,file=/volume-root/image,cache=none,media=disk,index=0",
+                "driver=qcow2,if=virtio,file=/volume-root/disk.qcow2,cache=none,media=disk,index=0",
                 "-drive",
                 "driver=raw,if=virtio,file=/volume-root/test,cache=none,media=disk,index=1"
             ]),
@@ -169,27 +204,79 @@ mod cli_generation {
                 "socket,server=on,wait=off,id=char0,path=/volume-root/qemu-monitor",
                 "-mon",
                 "chardev=char0,mode=control,pretty=on",
+                "-chardev",
+                "socket,server=on,wait=off,id=char1,path=/volume-root/qemu-events",
+                "-mon",
+                "chardev=char1,mode=control,pretty=on",
                 "-machine",
                 "accel=kvm",
                 "-vga",
                 "none",
                 "-m",
-                "4096M",
+                "4096M,slots=8,maxmem=8192M",
                 "-cpu",
                 "max",
                 "-smp",
-                "cpus=8,cores=8,maxcpus=8",
+                "cpus=8,cores=16,maxcpus=16",
                 "-nic",
                 "user,hostfwd=tcp:0.0.0.0:1234-:5678,hostfwd=tcp:0.0.0.0:2345-:6789",
+                "-device",
+                "virtio-balloon-pci,id=balloon0",
                 "-drive",
-                "driver=raw,if=virtio,file=/volume-root/image,cache=none,media=disk,index=0"
+                "driver=qcow2,if=virtio,file=/volume-root/disk.qcow2,cache=none,media=disk,index=0"
             ]),
         );
     }
 
+    #[test]
+    fn secrets_cli() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
+        let secrets = vec![("api_token".to_string(), Secret::new("hunter2"))];
+
+        let mut pkg = load(&registry, "plex", "0.0.2").unwrap();
+        pkg.secrets = secrets.clone();
+        assert_eq!(
+            generate_command(pkg, "/volume-root".into()).unwrap(),
+            string_vec(vec![
+                PODMAN_COMMAND,
+                "run",
+                "--rm",
+                "--name",
+                "plex-0.0.2",
+                "--secret",
+                "plex-0.0.2-api_token,target=api_token",
+                "scratch"
+            ])
+        );
+
+        let mut pkg = load(&registry, "plex-qemu", "0.0.1").unwrap();
+        pkg.secrets = secrets;
+        let args = generate_command(pkg.clone(), "/volume-root".into()).unwrap();
+        assert!(args.windows(2).any(|w| w
+            == string_vec(vec![
+                "-fw_cfg",
+                "name=opt/charon/secrets/api_token,file=/volume-root/secrets/api_token"
+            ])));
+        assert!(!args.iter().any(|arg| arg.contains("hunter2")));
+
+        let td = tempfile::tempdir().unwrap();
+        create_secrets(&pkg, td.path()).unwrap();
+        let path = td.path().join("secrets/api_token");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hunter2");
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        remove_secrets(&pkg, td.path()).unwrap();
+        assert!(!path.exists());
+    }
+
     #[test]
     fn podman_cli() {
-        let registry = Registry::new("testdata/registry".into());
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
         assert_eq!(
             generate_command(
                 load(&registry, "plex", "0.0.2").unwrap(),
@@ -248,3 +335,582 @@ mod cli_generation {
         );
     }
 }
+
+mod configure {
+    use super::*;
+
+    fn prompts() -> PromptCollection {
+        PromptCollection(vec![
+            Prompt {
+                template: "cpus".into(),
+                question: "How many CPUs?".into(),
+                input_type: InputType::Select(vec![
+                    SelectOption {
+                        name: "Small".into(),
+                        value: Input::Integer(1),
+                    },
+                    SelectOption {
+                        name: "Large".into(),
+                        value: Input::Integer(4),
+                    },
+                ]),
+                ..Default::default()
+            },
+            Prompt {
+                template: "https".into(),
+                question: "Enable HTTPS?".into(),
+                input_type: InputType::Boolean,
+                default: Some(Input::Boolean(false)),
+                ..Default::default()
+            },
+            Prompt {
+                template: "port".into(),
+                question: "Which port for HTTPS?".into(),
+                input_type: InputType::Integer,
+                min: Some(1024),
+                required: true,
+                when: Some("https".into()),
+                ..Default::default()
+            },
+            Prompt {
+                template: "token".into(),
+                question: "API token?".into(),
+                input_type: InputType::Secret,
+                ..Default::default()
+            },
+        ])
+    }
+
+    #[test]
+    fn parse() {
+        let prompts = prompts();
+        let cpus = prompts.get("cpus").unwrap();
+        assert_eq!(parse_response(cpus, "2").unwrap(), Input::Integer(4));
+        assert_eq!(parse_response(cpus, "small").unwrap(), Input::Integer(1));
+        assert_eq!(parse_response(cpus, "4").unwrap(), Input::Integer(4));
+        assert!(parse_response(cpus, "3").is_err());
+
+        let https = prompts.get("https").unwrap();
+        assert_eq!(parse_response(https, "Y").unwrap(), Input::Boolean(true));
+        assert!(parse_response(https, "maybe").is_err());
+
+        let port = prompts.get("port").unwrap();
+        assert!(parse_response(port, "abc").is_err());
+        assert!(parse_response(port, "80").is_err());
+        assert_eq!(parse_response(port, "8443").unwrap(), Input::Integer(8443));
+        assert_eq!(
+            parse_response(port, " 8443 \n").unwrap(),
+            Input::Integer(8443)
+        );
+
+        // strings and secrets keep their spaces, only the line ends are dropped
+        let token = prompts.get("token").unwrap();
+        assert_eq!(
+            parse_response(token, " pass word \r\n").unwrap(),
+            Input::Secret(Secret::new(" pass word "))
+        );
+        let name = Prompt {
+            template: "name".into(),
+            question: "Name?".into(),
+            input_type: InputType::String,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_response(&name, "  padded  \n").unwrap(),
+            Input::String("  padded  ".into())
+        );
+    }
+
+    #[test]
+    fn interactive() {
+        let prompts = prompts();
+
+        // a bad port is asked again, the token comes through the secret reader
+        let mut input = std::io::Cursor::new("large\ny\n80\n8443\n");
+        let mut output = Vec::new();
+        let responses = ask_prompts(
+            &prompts,
+            &Default::default(),
+            &mut input,
+            &mut output,
+            |_| Ok("hunter22".to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            responses,
+            PromptResponses(vec![
+                PromptResponse {
+                    template: "cpus".into(),
+                    input: Input::Integer(4),
+                },
+                PromptResponse {
+                    template: "https".into(),
+                    input: Input::Boolean(true),
+                },
+                PromptResponse {
+                    template: "port".into(),
+                    input: Input::Integer(8443),
+                },
+                PromptResponse {
+                    template: "token".into(),
+                    input: Input::Secret(Secret::new("hunter22")),
+                },
+            ])
+        );
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("  2) Large"));
+        assert!(output.contains("https [false]: "));
+        assert!(!output.contains("hunter22"));
+
+        // empty answers keep what's there, and port no longer applies
+        let mut input = std::io::Cursor::new("\nn\n");
+        let kept = ask_prompts(
+            &prompts,
+            &responses.redacted(),
+            &mut input,
+            &mut Vec::new(),
+            |_| Ok(String::new()),
+        )
+        .unwrap();
+
+        assert_eq!(kept.0[0].input, Input::Integer(4));
+        assert_eq!(kept.0[1].input, Input::Boolean(false));
+        assert_eq!(kept.0[3].input, Input::Secret(Secret::redacted()));
+    }
+
+    #[test]
+    fn from_file() {
+        let prompts = prompts();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"cpus: Small\nhttps: true\nport: 8443\n")
+            .unwrap();
+
+        let responses = responses_from_file(&prompts, &Default::default(), file.path()).unwrap();
+        assert_eq!(responses.0.len(), 3);
+        assert_eq!(responses.0[2].input, Input::Integer(8443));
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"{\"color\": \"red\"}").unwrap();
+        assert!(responses_from_file(&prompts, &Default::default(), file.path()).is_err());
+    }
+}
+
+mod snapshots {
+    use super::*;
+    use crate::qmp::testing::{error, event, reply, FakeQmp};
+    use serde_json::json;
+
+    fn disk(snapshots: serde_json::Value) -> serde_json::Value {
+        json!({
+            "device": "",
+            "inserted": {
+                "node-name": "#block123",
+                "drv": "qcow2",
+                "image": { "snapshots": snapshots },
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn vm_snapshots() {
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
+        let package = load(&registry, "plex-qemu", "0.0.2").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        // the VM isn't running
+        assert!(super::super::vm_snapshots(&package, dir.path())
+            .await
+            .is_err());
+
+        let qmp = FakeQmp::start(
+            &dir.path().join(QEMU_MONITOR_FILENAME),
+            |command| match command["execute"].as_str().unwrap() {
+                "query-block" => vec![reply(json!([
+                    disk(json!([
+                        { "name": "later", "date-sec": 200, "vm-clock-sec": 1, "vm-clock-nsec": 5, "vm-state-size": 10 },
+                        { "name": "first", "date-sec": 100, "vm-clock-sec": 0, "vm-clock-nsec": 0, "vm-state-size": 0 },
+                    ])),
+                    disk(json!([{ "name": "first", "date-sec": 100 }])),
+                    json!({ "device": "cdrom" }),
+                ]))],
+                "snapshot-save" | "snapshot-delete" => vec![
+                    reply(json!({})),
+                    event(
+                        "JOB_STATUS_CHANGE",
+                        json!({ "id": "snapshot", "status": "concluded" }),
+                    ),
+                ],
+                "snapshot-load" => vec![error("Snapshot 'broken' is corrupt")],
+                "query-jobs" => vec![reply(json!([{
+                    "id": "snapshot",
+                    "type": "snapshot-save",
+                    "status": "concluded",
+                    "current-progress": 1,
+                    "total-progress": 1,
+                }]))],
+                _ => vec![reply(json!({}))],
+            },
+        );
+
+        assert_eq!(
+            super::super::vm_snapshots(&package, dir.path())
+                .await
+                .unwrap(),
+            vec![
+                VmSnapshot {
+                    name: "first".into(),
+                    date: 100,
+                    vm_clock: 0,
+                    vm_state_size: 0,
+                },
+                VmSnapshot {
+                    name: "later".into(),
+                    date: 200,
+                    vm_clock: 1_000_000_005,
+                    vm_state_size: 10,
+                },
+            ]
+        );
+
+        vm_snapshot_save(&package, dir.path(), "new").await.unwrap();
+        let saved = qmp
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|command| command["execute"] == "snapshot-save")
+            .cloned()
+            .unwrap();
+        assert_eq!(saved["arguments"]["tag"], "new");
+        assert_eq!(
+            saved["arguments"]["devices"],
+            json!(["#block123", "#block123"])
+        );
+
+        vm_snapshot_delete(&package, dir.path(), "new")
+            .await
+            .unwrap();
+        assert!(vm_snapshot_load(&package, dir.path(), "broken")
+            .await
+            .is_err());
+        assert!(vm_snapshot_save(&package, dir.path(), "").await.is_err());
+
+        // containers have no snapshots
+        let container = load(&registry, "podman-test", "0.0.2").unwrap();
+        assert!(vm_snapshot_save(&container, dir.path(), "new")
+            .await
+            .is_err());
+        assert!(super::super::vm_snapshots(&container, dir.path())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn raw_disks() {
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
+        let package = load(&registry, "plex-qemu", "0.0.2").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let qmp = FakeQmp::start(
+            &dir.path().join(QEMU_MONITOR_FILENAME),
+            |command| match command["execute"].as_str().unwrap() {
+                "query-block" => vec![reply(json!([
+                    { "device": "virtio0", "inserted": { "node-name": "#block1", "drv": "qcow2" } },
+                    { "device": "virtio1", "inserted": { "node-name": "#block2", "drv": "raw" } },
+                ]))],
+                _ => vec![reply(json!({}))],
+            },
+        );
+
+        let e = check_vm_snapshots(&package, dir.path()).await.unwrap_err();
+        assert!(e.to_string().contains("virtio1 is raw"), "{}", e);
+        assert!(vm_snapshot_save(&package, dir.path(), "new").await.is_err());
+        assert!(vm_snapshot_load(&package, dir.path(), "old").await.is_err());
+        // qemu is never asked
+        assert!(qmp
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|command| command["execute"] == "query-block"));
+    }
+}
+
+mod vm_info {
+    use super::*;
+    use crate::qmp::testing::{introspection, FakeQmp};
+
+    #[tokio::test]
+    async fn vm_info_of_running_vm() {
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
+        let package = load(&registry, "plex-qemu", "0.0.2").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        // the VM isn't running
+        assert!(super::super::vm_info(&package, dir.path()).await.is_err());
+
+        let _qmp = FakeQmp::start(&dir.path().join(QEMU_MONITOR_FILENAME), |command| {
+            vec![introspection(command).unwrap()]
+        });
+
+        let info = super::super::vm_info(&package, dir.path()).await.unwrap();
+        assert_eq!(info.name, "plex");
+        assert_eq!(info.status, "running");
+        assert!(info.running);
+        assert_eq!(info.qemu_version, "9.2.0 (Debian 1:9.2.0)");
+        assert_eq!(
+            info.cpus[1],
+            VmCpu {
+                index: 1,
+                thread: 1235,
+                qom_path: "/machine/unattached/device[1]".into(),
+            }
+        );
+        assert_eq!(info.base_memory, 4294967296);
+        assert_eq!(info.plugged_memory, 0);
+        assert_eq!(
+            info.disks,
+            vec![VmDiskStats {
+                name: "#block123".into(),
+                read_bytes: 1024,
+                written_bytes: 2048,
+                reads: 2,
+                writes: 4,
+                flushes: 1,
+                failed_reads: 0,
+                failed_writes: 1,
+            }]
+        );
+        // devices behind the bridge are listed too
+        assert_eq!(
+            info.pci
+                .iter()
+                .map(|device| device.qdev_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["", "bridge", "disk0"]
+        );
+        assert_eq!(info.pci[2].description, "SCSI controller");
+
+        let container = load(&registry, "podman-test", "0.0.2").unwrap();
+        assert!(super::super::vm_info(&container, dir.path()).await.is_err());
+    }
+}
+
+mod resize {
+    use super::*;
+    use crate::qmp::testing::{error, reply, FakeQmp};
+    use serde_json::{json, Value};
+
+    const MIB: u64 = 1024 * 1024;
+
+    // a running VM with these vCPUs, by qom-path, out of 8 places for them, and these files as
+    // disks
+    fn vm(
+        cpus: Vec<&'static str>,
+        memory: u64,
+        disks: Vec<String>,
+        balloon: bool,
+    ) -> impl Fn(&Value) -> Vec<Value> + Send + Sync + 'static {
+        move |command| {
+            let ret = match command["execute"].as_str().unwrap() {
+                "query-balloon" if !balloon => {
+                    return vec![error("No balloon device has been activated")]
+                }
+                "query-balloon" => json!({ "actual": memory * MIB }),
+                "query-cpus-fast" => cpus
+                    .iter()
+                    .enumerate()
+                    .map(|(x, path)| {
+                        json!({ "cpu-index": x, "qom-path": path, "thread-id": 100 + x })
+                    })
+                    .collect(),
+                "query-hotpluggable-cpus" => (0..8)
+                    .rev()
+                    .map(|x| {
+                        let mut slot = json!({
+                            "type": "max-x86_64-cpu",
+                            "vcpus-count": 1,
+                            "props": { "socket-id": 0, "core-id": x, "thread-id": 0 },
+                        });
+                        if x < cpus.len() {
+                            slot["qom-path"] = json!(cpus[x]);
+                        }
+                        slot
+                    })
+                    .collect(),
+                "query-memory-size-summary" => json!({ "base-memory": memory * MIB }),
+                "query-block" => disks
+                    .iter()
+                    .map(|file| json!({ "device": "", "inserted": { "file": file } }))
+                    .collect(),
+                _ => json!({}),
+            };
+
+            vec![reply(ret)]
+        }
+    }
+
+    fn sent(qmp: &FakeQmp, execute: &str) -> Vec<Value> {
+        qmp.commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|command| command["execute"] == execute)
+            .map(|command| command["arguments"].clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn grow() {
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
+        // 4 vCPUs, 8192M and a volume named test
+        let package = load(&registry, "plex-qemu", "0.0.2").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        // the VM isn't running
+        assert!(vm_resize(&package, dir.path()).await.is_err());
+
+        let image = dir.path().join("image").to_string_lossy().to_string();
+        let qmp = FakeQmp::start(
+            &dir.path().join(QEMU_MONITOR_FILENAME),
+            vm(
+                vec![
+                    "/machine/unattached/device[0]",
+                    "/machine/unattached/device[1]",
+                ],
+                4096,
+                vec![image],
+                true,
+            ),
+        );
+
+        assert_eq!(
+            vm_resize(&package, dir.path()).await.unwrap(),
+            VmResize {
+                cpus_before: 2,
+                cpus: 4,
+                memory_before: 4096,
+                memory: 8192,
+                volumes_added: vec!["test".into()],
+            }
+        );
+
+        // the first free places get the new vCPUs
+        let devices = sent(&qmp, "device_add");
+        assert_eq!(
+            devices[..2],
+            [
+                json!({ "driver": "max-x86_64-cpu", "id": "vcpu-0-2-0", "socket-id": 0, "core-id": 2, "thread-id": 0 }),
+                json!({ "driver": "max-x86_64-cpu", "id": "vcpu-0-3-0", "socket-id": 0, "core-id": 3, "thread-id": 0 }),
+            ]
+        );
+
+        // the difference in memory is added as a DIMM, then given to the guest
+        assert_eq!(
+            sent(&qmp, "object-add"),
+            vec![
+                json!({ "qom-type": "memory-backend-ram", "id": "dimm4096-memory", "size": 4096 * MIB })
+            ]
+        );
+        assert_eq!(
+            devices[2],
+            json!({ "driver": "pc-dimm", "id": "dimm4096", "memdev": "dimm4096-memory" })
+        );
+        assert_eq!(sent(&qmp, "balloon"), vec![json!({ "value": 8192 * MIB })]);
+
+        // and the volume it didn't have
+        let blockdevs = sent(&qmp, "blockdev-add");
+        assert_eq!(blockdevs.len(), 1);
+        assert_eq!(blockdevs[0]["node-name"], "volume-test");
+        assert_eq!(
+            blockdevs[0]["file"]["filename"],
+            dir.path().join("test").to_string_lossy().to_string()
+        );
+        assert_eq!(
+            devices[3],
+            json!({ "driver": "virtio-blk-pci", "id": "volume-test", "drive": "volume-test" })
+        );
+        assert_eq!(devices.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn shrink() {
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
+        let package = load(&registry, "plex-qemu", "0.0.2").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let disks = vec![
+            dir.path().join("image").to_string_lossy().to_string(),
+            dir.path().join("test").to_string_lossy().to_string(),
+        ];
+
+        let qmp = FakeQmp::start(
+            &dir.path().join(QEMU_MONITOR_FILENAME),
+            vm(
+                vec![
+                    "/machine/unattached/device[0]",
+                    "/machine/unattached/device[1]",
+                    "/machine/unattached/device[2]",
+                    "/machine/peripheral/vcpu-0-3-0",
+                    "/machine/peripheral/vcpu-0-4-0",
+                    "/machine/peripheral/vcpu-0-5-0",
+                ],
+                16384,
+                disks.clone(),
+                true,
+            ),
+        );
+
+        let resized = vm_resize(&package, dir.path()).await.unwrap();
+        assert_eq!(resized.cpus_before, 6);
+        assert_eq!(resized.memory_before, 16384);
+        assert!(resized.volumes_added.is_empty());
+
+        // the last vCPUs added go, and the balloon takes back the memory
+        assert_eq!(
+            sent(&qmp, "device_del"),
+            vec![json!({ "id": "vcpu-0-5-0" }), json!({ "id": "vcpu-0-4-0" })]
+        );
+        assert!(sent(&qmp, "device_add").is_empty());
+        assert!(sent(&qmp, "object-add").is_empty());
+        assert_eq!(sent(&qmp, "balloon"), vec![json!({ "value": 8192 * MIB })]);
+
+        // vCPUs it started with stay
+        let dir = tempfile::tempdir().unwrap();
+        let _qmp = FakeQmp::start(
+            &dir.path().join(QEMU_MONITOR_FILENAME),
+            vm(
+                vec![
+                    "/machine/unattached/device[0]",
+                    "/machine/unattached/device[1]",
+                    "/machine/unattached/device[2]",
+                    "/machine/unattached/device[3]",
+                    "/machine/unattached/device[4]",
+                ],
+                8192,
+                disks.clone(),
+                true,
+            ),
+        );
+        assert_eq!(
+            vm_resize(&package, dir.path())
+                .await
+                .unwrap_err()
+                .to_string(),
+            "plex-qemu-0.0.2 cannot have fewer than 5 vCPUs until it is restarted"
+        );
+
+        // a VM started without a balloon has to be restarted
+        let dir = tempfile::tempdir().unwrap();
+        let qmp = FakeQmp::start(
+            &dir.path().join(QEMU_MONITOR_FILENAME),
+            vm(vec!["/machine/unattached/device[0]"], 8192, disks, false),
+        );
+        assert!(vm_resize(&package, dir.path()).await.is_err());
+        assert_eq!(qmp.executed(), vec!["query-balloon"]);
+
+        let container = load(&registry, "podman-test", "0.0.2").unwrap();
+        assert!(vm_resize(&container, dir.path()).await.is_err());
+    }
+}
@@ -2,8 +2,11 @@ use crate::grpc::query_client::QueryClient as GRPCQueryClient;
 use crate::grpc::status_client::StatusClient as GRPCStatusClient;
 use crate::{grpc::control_client::ControlClient as GRPCControlClient, ProtoPackageTitle};
 use crate::{
-    InputType, InstallStatus, PackageTitle, Prompt, PromptCollection, PromptResponses,
-    ProtoPackageTitleWithRoot, ProtoPromptResponses, ProtoType,
+    CompileErrors, CompiledPackage, Facts, GlobalExplanation, InstallStatus, PackageMetadata,
+    PackageTitle, PromptCollection, PromptResponses, ProtoGlobalName, ProtoGlobals,
+    ProtoPackageTitleWithRoot, ProtoPromptQuery, ProtoPromptResponses, ProtoSnapshotName,
+    ProtoUpgradeTitle, ProtoVmEvent, ResponseErrors, SearchQuery, SearchResults, Variables,
+    VmEvent, VmInfo, VmResize, VmSnapshot,
 };
 use anyhow::Result;
 use std::path::PathBuf;
@@ -26,6 +29,18 @@ pub struct QueryClient {
     client: GRPCQueryClient<Channel>,
 }
 
+// the events of a VM as they come, from QueryClient::watch_vm
+pub struct VmEvents {
+    stream: tonic::Streaming<ProtoVmEvent>,
+}
+
+impl VmEvents {
+    // the next event, or None once charond hangs up
+    pub async fn next(&mut self) -> Result<Option<VmEvent>> {
+        Ok(self.stream.message().await?.map(Into::into))
+    }
+}
+
 impl Client {
     pub fn new(socket: PathBuf) -> anyhow::Result<Self> {
         Ok(Self { socket })
@@ -54,6 +69,32 @@ impl StatusClient {
     pub async fn ping(&mut self) -> Result<()> {
         Ok(self.client.ping(Request::new(())).await?.into_inner())
     }
+
+    pub async fn facts(&mut self) -> Result<Facts> {
+        Ok(Facts(
+            self.client
+                .facts(Request::new(()))
+                .await?
+                .into_inner()
+                .facts
+                .into_iter()
+                .collect(),
+        ))
+    }
+}
+
+fn snapshot_name(
+    name: &str,
+    version: &str,
+    volume_root: PathBuf,
+    snapshot: &str,
+) -> ProtoSnapshotName {
+    ProtoSnapshotName {
+        name: name.into(),
+        version: version.into(),
+        volume_root: volume_root.to_string_lossy().to_string(),
+        snapshot: snapshot.into(),
+    }
 }
 
 impl ControlClient {
@@ -63,6 +104,7 @@ impl ControlClient {
             .install(Request::new(ProtoPackageTitle {
                 name: name.to_string(),
                 version: version.to_string(),
+                ..Default::default()
             }))
             .await?
             .into_inner())
@@ -74,6 +116,7 @@ impl ControlClient {
             .uninstall(Request::new(ProtoPackageTitle {
                 name: name.to_string(),
                 version: version.to_string(),
+                ..Default::default()
             }))
             .await?
             .into_inner())
@@ -85,6 +128,7 @@ impl ControlClient {
             .installed(Request::new(ProtoPackageTitle {
                 name: name.to_string(),
                 version: version.to_string(),
+                ..Default::default()
             }))
             .await?
             .into_inner();
@@ -111,10 +155,101 @@ impl ControlClient {
             .into_inner())
     }
 
+    pub async fn upgrade(
+        &mut self,
+        name: &str,
+        from_version: &str,
+        to_version: &str,
+        volume_root: PathBuf,
+    ) -> Result<()> {
+        let out = ProtoUpgradeTitle {
+            name: name.into(),
+            from_version: from_version.into(),
+            to_version: to_version.into(),
+            volume_root: volume_root.to_str().unwrap().to_string(),
+        };
+
+        self.client.upgrade(Request::new(out)).await?;
+        Ok(())
+    }
+
+    pub async fn create_snapshot(
+        &mut self,
+        name: &str,
+        version: &str,
+        volume_root: PathBuf,
+        snapshot: &str,
+    ) -> Result<()> {
+        self.client
+            .create_snapshot(Request::new(snapshot_name(
+                name,
+                version,
+                volume_root,
+                snapshot,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn restore_snapshot(
+        &mut self,
+        name: &str,
+        version: &str,
+        volume_root: PathBuf,
+        snapshot: &str,
+    ) -> Result<()> {
+        self.client
+            .restore_snapshot(Request::new(snapshot_name(
+                name,
+                version,
+                volume_root,
+                snapshot,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_snapshot(
+        &mut self,
+        name: &str,
+        version: &str,
+        volume_root: PathBuf,
+        snapshot: &str,
+    ) -> Result<()> {
+        self.client
+            .delete_snapshot(Request::new(snapshot_name(
+                name,
+                version,
+                volume_root,
+                snapshot,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn resize(
+        &mut self,
+        name: &str,
+        version: &str,
+        volume_root: PathBuf,
+    ) -> Result<VmResize> {
+        Ok(self
+            .client
+            .resize(Request::new(ProtoPackageTitleWithRoot {
+                name: name.into(),
+                version: version.into(),
+                volume_root: volume_root.to_string_lossy().to_string(),
+            }))
+            .await?
+            .into_inner()
+            .into())
+    }
+
     pub async fn remove_unit(&mut self, name: &str, version: &str) -> Result<()> {
         let out = ProtoPackageTitle {
             name: name.into(),
             version: version.into(),
+            ..Default::default()
         };
 
         Ok(self
@@ -123,6 +258,41 @@ impl ControlClient {
             .await?
             .into_inner())
     }
+
+    // with a version, sets the overrides for that installed version instead of the package's own
+    // variables. the variables replace whatever was set before.
+    pub async fn set_globals(
+        &mut self,
+        name: &str,
+        version: Option<&str>,
+        variables: Variables,
+    ) -> Result<()> {
+        let out = ProtoGlobals {
+            name: name.into(),
+            version: version.unwrap_or_default().into(),
+            variables: variables.into_iter().collect(),
+            effective: Default::default(),
+        };
+
+        self.client.set_globals(Request::new(out)).await?;
+        Ok(())
+    }
+
+    pub async fn delete_global(
+        &mut self,
+        name: &str,
+        version: Option<&str>,
+        variable: &str,
+    ) -> Result<()> {
+        let out = ProtoGlobalName {
+            name: name.into(),
+            version: version.unwrap_or_default().into(),
+            variable: variable.into(),
+        };
+
+        self.client.delete_global(Request::new(out)).await?;
+        Ok(())
+    }
 }
 
 impl QueryClient {
@@ -139,12 +309,122 @@ impl QueryClient {
             v.push(PackageTitle {
                 name: item.name,
                 version: item.version,
+                registry: Some(item.registry).filter(|r| !r.is_empty()),
             })
         }
 
         Ok(v)
     }
 
+    // the latest version without one
+    pub async fn get_package(
+        &mut self,
+        name: &str,
+        version: Option<&str>,
+    ) -> Result<PackageMetadata> {
+        self.client
+            .get_package(Request::new(ProtoPackageTitle {
+                name: name.into(),
+                version: version.unwrap_or_default().into(),
+                ..Default::default()
+            }))
+            .await?
+            .into_inner()
+            .try_into()
+    }
+
+    // what the package compiles to and the command that launches it. compile errors come back as
+    // CompileErrors, so callers can tell where each one is.
+    pub async fn compile(
+        &mut self,
+        name: &str,
+        version: &str,
+        volume_root: PathBuf,
+    ) -> Result<(CompiledPackage, Vec<String>)> {
+        let compiled = match self
+            .client
+            .compile(Request::new(ProtoPackageTitleWithRoot {
+                name: name.into(),
+                version: version.into(),
+                volume_root: volume_root.to_string_lossy().to_string(),
+            }))
+            .await
+        {
+            Ok(compiled) => compiled.into_inner(),
+            Err(status) => {
+                return match CompileErrors::from_status(&status) {
+                    Some(errors) => Err(errors.into()),
+                    None => Err(status.into()),
+                }
+            }
+        };
+
+        Ok((serde_json::from_str(&compiled.package)?, compiled.command))
+    }
+
+    pub async fn list_snapshots(
+        &mut self,
+        name: &str,
+        version: &str,
+        volume_root: PathBuf,
+    ) -> Result<Vec<VmSnapshot>> {
+        Ok(self
+            .client
+            .list_snapshots(Request::new(ProtoPackageTitleWithRoot {
+                name: name.into(),
+                version: version.into(),
+                volume_root: volume_root.to_string_lossy().to_string(),
+            }))
+            .await?
+            .into_inner()
+            .into())
+    }
+
+    pub async fn vm_info(
+        &mut self,
+        name: &str,
+        version: &str,
+        volume_root: PathBuf,
+    ) -> Result<VmInfo> {
+        Ok(self
+            .client
+            .vm_info(Request::new(ProtoPackageTitleWithRoot {
+                name: name.into(),
+                version: version.into(),
+                volume_root: volume_root.to_string_lossy().to_string(),
+            }))
+            .await?
+            .into_inner()
+            .into())
+    }
+
+    pub async fn watch_vm(
+        &mut self,
+        name: &str,
+        version: &str,
+        volume_root: PathBuf,
+    ) -> Result<VmEvents> {
+        Ok(VmEvents {
+            stream: self
+                .client
+                .watch_vm(Request::new(ProtoPackageTitleWithRoot {
+                    name: name.into(),
+                    version: version.into(),
+                    volume_root: volume_root.to_string_lossy().to_string(),
+                }))
+                .await?
+                .into_inner(),
+        })
+    }
+
+    pub async fn search(&mut self, query: SearchQuery) -> Result<SearchResults> {
+        self.client
+            .search(Request::new(query.into()))
+            .await?
+            .into_inner()
+            .try_into()
+    }
+
     pub async fn list(&mut self) -> Result<Vec<PackageTitle>> {
         let list = self.client.list(Request::new(())).await?.into_inner();
 
@@ -154,16 +434,18 @@ impl QueryClient {
             v.push(PackageTitle {
                 name: item.name,
                 version: item.version,
+                registry: Some(item.registry).filter(|r| !r.is_empty()),
             })
         }
 
         Ok(v)
     }
 
-    pub async fn get_responses(&mut self, name: &str) -> Result<PromptResponses> {
+    pub async fn get_responses(&mut self, name: &str, version: &str) -> Result<PromptResponses> {
         let title = ProtoPackageTitle {
             name: name.into(),
-            version: String::new(),
+            version: version.into(),
+            ..Default::default()
         };
 
         let responses = self
@@ -174,45 +456,127 @@ impl QueryClient {
         let mut out = Vec::new();
 
         for response in responses.responses {
-            out.push(response.into())
+            out.push(response.try_into()?)
         }
 
         Ok(PromptResponses(out))
     }
 
+    // prompts that still need answers to upgrade from one version to another
+    pub async fn pending_prompts(
+        &mut self,
+        name: &str,
+        from_version: &str,
+        to_version: &str,
+    ) -> Result<PromptCollection> {
+        let prompts = self
+            .client
+            .pending_prompts(Request::new(ProtoUpgradeTitle {
+                name: name.into(),
+                from_version: from_version.into(),
+                to_version: to_version.into(),
+                ..Default::default()
+            }))
+            .await?
+            .into_inner();
+
+        let mut out = Vec::new();
+
+        for prompt in prompts.prompts {
+            out.push(prompt.try_into()?);
+        }
+
+        Ok(PromptCollection(out))
+    }
+
     pub async fn get_prompts(&mut self, name: &str, version: &str) -> Result<PromptCollection> {
-        let title = ProtoPackageTitle {
+        self.query_prompts(ProtoPromptQuery {
             name: name.into(),
             version: version.into(),
-        };
+            ..Default::default()
+        })
+        .await
+    }
 
+    // only the prompts that apply, given the responses so far
+    pub async fn get_applicable_prompts(
+        &mut self,
+        name: &str,
+        version: &str,
+        responses: PromptResponses,
+    ) -> Result<PromptCollection> {
+        self.query_prompts(ProtoPromptQuery {
+            name: name.into(),
+            version: version.into(),
+            responses: responses.0.into_iter().map(Into::into).collect(),
+            applicable: true,
+        })
+        .await
+    }
+
+    async fn query_prompts(&mut self, query: ProtoPromptQuery) -> Result<PromptCollection> {
         let prompts = self
             .client
-            .get_prompts(Request::new(title))
+            .get_prompts(Request::new(query))
             .await?
             .into_inner();
 
         let mut out = Vec::new();
 
-        for prompt in &prompts.prompts {
-            out.push(Prompt {
-                template: prompt.template.clone(),
-                question: prompt.question.clone(),
-                input_type: match prompt.input_type() {
-                    ProtoType::String => InputType::String,
-                    ProtoType::Integer => InputType::Integer,
-                    ProtoType::SignedInteger => InputType::SignedInteger,
-                    ProtoType::Boolean => InputType::Boolean,
-                },
-            });
+        for prompt in prompts.prompts {
+            out.push(prompt.try_into()?);
         }
 
         Ok(PromptCollection(out))
     }
 
-    pub async fn set_responses(&mut self, name: &str, responses: PromptResponses) -> Result<()> {
+    // with a version, the overrides for that installed version instead of the package's own
+    // variables
+    pub async fn get_globals(&mut self, name: &str, version: Option<&str>) -> Result<Variables> {
+        Ok(self
+            .query_globals(name, version.unwrap_or_default())
+            .await?
+            .variables
+            .into_iter()
+            .collect())
+    }
+
+    // every global the version of the package is templated with, and where it comes from
+    pub async fn explain_globals(
+        &mut self,
+        name: &str,
+        version: &str,
+    ) -> Result<Vec<GlobalExplanation>> {
+        self.query_globals(name, version)
+            .await?
+            .effective
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect()
+    }
+
+    async fn query_globals(&mut self, name: &str, version: &str) -> Result<ProtoGlobals> {
+        Ok(self
+            .client
+            .get_globals(Request::new(ProtoPackageTitle {
+                name: name.into(),
+                version: version.into(),
+                ..Default::default()
+            }))
+            .await?
+            .into_inner())
+    }
+
+    // the responses are checked against the prompts of this version of the package
+    pub async fn set_responses(
+        &mut self,
+        name: &str,
+        version: &str,
+        responses: PromptResponses,
+    ) -> Result<()> {
         let mut out = ProtoPromptResponses {
             name: name.to_string(),
+            version: version.to_string(),
             responses: Default::default(),
         };
 
@@ -220,7 +584,13 @@ impl QueryClient {
             out.responses.push(response.into());
         }
 
-        self.client.set_responses(Request::new(out)).await?;
-        Ok(())
+        // rejected responses come back as ResponseErrors, so callers can tell which prompt is wrong
+        match self.client.set_responses(Request::new(out)).await {
+            Ok(_) => Ok(()),
+            Err(status) => match ResponseErrors::from_status(&status) {
+                Some(errors) => Err(errors.into()),
+                None => Err(status.into()),
+            },
+        }
     }
 }
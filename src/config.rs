@@ -1,13 +1,17 @@
-use crate::{Registry, SYSTEMD_SERVICE_ROOT};
+use crate::{
+    move_legacy_state, sync_registry, FactOverrides, Facts, FactsRegistry, GlobalRegistry,
+    Registries, Registry, RegistryPin, Trust, Variables, REGISTRY_SEPARATOR, SYSTEMD_SERVICE_ROOT,
+};
 use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::path::PathBuf;
 use tracing::info;
 use tracing_subscriber::FmtSubscriber;
 
-const GIT_PATH: &str = "git"; // FIXME: for now. This should be absolute or configurable, at least.
 const GIT_DEFAULT_REPOSITORY: &str = "https://github.com/trunk-os/charon-packages";
 const REGISTRY_DEFAULT_PATH: &str = "/trunk/charon/registry";
+const REGISTRY_DEFAULT_NAME: &str = "default";
+pub const STATE_SUFFIX: &str = "-state";
 
 pub const DEFAULT_CHARON_BIN_PATH: &str = "/usr/bin/charon";
 
@@ -50,21 +54,86 @@ impl From<tracing::Level> for LogLevel {
     }
 }
 
+fn default_registry_name() -> String {
+    REGISTRY_DEFAULT_NAME.into()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct RegistryConfig {
+    #[serde(default = "default_registry_name")]
+    pub name: String,
     pub path: PathBuf,
     pub url: Option<String>,
+    // registries with a higher priority are searched first
+    #[serde(default)]
+    pub priority: i64,
+    // the branch, tag or commit to follow, for registries synced from a url
+    pub pin: Option<RegistryPin>,
+    // where local state such as what is installed and the responses to prompts is kept. registries
+    // synced from a url keep it beside the registry, so the worktree only has what is in git.
+    pub state: Option<PathBuf>,
+    // keys trusted to sign the packages of this registry, see trust.rs
+    #[serde(default)]
+    pub keys: Vec<String>,
+    // load packages from this registry without checking their signatures
+    #[serde(default)]
+    pub unsigned: bool,
+}
+
+impl RegistryConfig {
+    pub fn state_path(&self) -> Option<PathBuf> {
+        match (&self.state, &self.url) {
+            (Some(state), _) => Some(state.clone()),
+            (None, Some(_)) => {
+                let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+                name.push(STATE_SUFFIX);
+                Some(self.path.with_file_name(name))
+            }
+            (None, None) => None,
+        }
+    }
+
+    pub fn trust(&self) -> Trust {
+        Trust {
+            keys: self.keys.clone(),
+            unsigned: self.unsigned,
+        }
+    }
 }
 
 impl Default for RegistryConfig {
     fn default() -> Self {
         Self {
+            name: default_registry_name(),
             path: REGISTRY_DEFAULT_PATH.into(),
             url: Some(GIT_DEFAULT_REPOSITORY.into()),
+            priority: 0,
+            pin: None,
+            state: None,
+            keys: Vec::new(),
+            unsigned: false,
         }
     }
 }
 
+// `registry` used to be a single registry, which is still accepted
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<RegistryConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(RegistryConfig),
+        Many(Vec<RegistryConfig>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(registry) => vec![registry],
+        OneOrMany::Many(registries) => registries,
+    })
+}
+
 fn default_systemd_root() -> Option<PathBuf> {
     Some(SYSTEMD_SERVICE_ROOT.into())
 }
@@ -75,7 +144,8 @@ fn default_charon_path() -> Option<PathBuf> {
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct Config {
-    pub registry: RegistryConfig,
+    #[serde(alias = "registry", deserialize_with = "one_or_many")]
+    pub registries: Vec<RegistryConfig>,
     pub socket: PathBuf,
     #[serde(default = "default_systemd_root")]
     pub systemd_root: Option<PathBuf>,
@@ -83,6 +153,9 @@ pub struct Config {
     pub debug: Option<bool>,
     #[serde(default = "default_charon_path")]
     pub charon_path: Option<PathBuf>,
+    #[serde(default)]
+    pub facts: FactOverrides,
+    pub globals: Option<PathBuf>,
 }
 
 impl Config {
@@ -95,62 +168,243 @@ impl Config {
             ))
             .finish();
         tracing::subscriber::set_global_default(subscriber)?;
+        this.check_registries()?;
         this.sync_registry()?;
         info!("Configuration parsed successfully.");
         Ok(this)
     }
 
-    pub fn registry(&self) -> Registry {
-        Registry::new(self.registry.path.clone())
+    // the configured registries in the order they are searched, or the default registry if none
+    // are configured
+    pub fn registry_configs(&self) -> Vec<RegistryConfig> {
+        if self.registries.is_empty() {
+            return vec![RegistryConfig::default()];
+        }
+
+        let mut configs = self.registries.clone();
+        // stable, so registries of the same priority keep the order they were configured in
+        configs.sort_by_key(|config| std::cmp::Reverse(config.priority));
+        configs
     }
 
-    pub fn debug(&self) -> bool {
-        self.debug.unwrap_or_default()
+    pub fn registries(&self) -> Registries {
+        Registries(
+            self.registry_configs()
+                .into_iter()
+                .map(|config| {
+                    Registry::named(&config.name, config.path.clone())
+                        .with_state(config.state_path())
+                        .with_trust(config.trust())
+                })
+                .collect(),
+        )
     }
 
-    pub fn sync_registry(&self) -> Result<()> {
-        if let Some(url) = &self.registry.url {
-            // exists. here, we want to store any files we have laying around so the rebase doesn't
-            // fail. this is admittedly pretty dodgy but I don't have a better solution right now.
-            if std::fs::exists(&self.registry.path)? {
-                self.run_command(vec![GIT_PATH.into(), "add".into(), ".".into()])?;
-                self.run_command(vec![GIT_PATH.into(), "stash".into()])?;
-                self.run_command(vec![GIT_PATH.into(), "pull".into(), "--rebase".into()])?;
-                // FIXME this sucks
-                let _ = self.run_command(vec![GIT_PATH.into(), "stash".into(), "apply".into()]);
-            } else {
-                std::fs::create_dir_all(&self.registry.path)?;
-                // first time, clone it
-                self.run_command(vec![
-                    GIT_PATH.into(),
-                    "clone".into(),
-                    url.clone(),
-                    self.registry.path.to_string_lossy().to_string(),
-                ])?;
+    pub fn check_registries(&self) -> Result<()> {
+        let mut seen = std::collections::BTreeSet::default();
+
+        for config in &self.registries {
+            if config.name.is_empty() || config.name.contains(REGISTRY_SEPARATOR) {
+                return Err(anyhow!(
+                    "registry name '{}' is invalid: it must not be empty or contain '{}'",
+                    config.name,
+                    REGISTRY_SEPARATOR
+                ));
+            }
+
+            if !seen.insert(&config.name) {
+                return Err(anyhow!(
+                    "registry '{}' is configured more than once",
+                    config.name
+                ));
             }
+
+            config
+                .trust()
+                .check()
+                .map_err(|e| anyhow!("registry '{}': {}", config.name, e))?;
         }
 
         Ok(())
     }
 
-    fn run_command(&self, command: Vec<String>) -> Result<()> {
-        let mut iter = command.iter();
-        if let Some(cmd) = iter.nth(0) {
-            let status = std::process::Command::new(cmd)
-                .args(iter.collect::<Vec<&String>>())
-                .current_dir(&self.registry.path)
-                .status()?;
-            if !status.success() {
-                return Err(anyhow!(
-                    "command {:?} failed: exit status {}",
-                    command.clone(),
-                    status.code().unwrap_or(1)
-                ));
+    pub fn facts(&self) -> Facts {
+        Facts::detect().with_overrides(&self.facts)
+    }
+
+    // records the keys each registry trusts, where `charon launch` checks them when it loads the
+    // package it runs
+    pub fn store_trust(&self) -> Result<()> {
+        for config in self.registry_configs() {
+            Registry::named(&config.name, config.path.clone())
+                .with_state(config.state_path())
+                .trust_registry()
+                .set(&config.trust())?;
+        }
+
+        Ok(())
+    }
+
+    // stores the overridden facts in each registry, where packages find them when compiled
+    pub fn store_facts(&self) -> Result<()> {
+        for config in self.registry_configs() {
+            FactsRegistry::new(config.state_path().unwrap_or(config.path))
+                .set_overrides(&self.facts)?;
+        }
+
+        Ok(())
+    }
+
+    // stores the site-wide globals in each registry, under every package's own variables
+    pub fn store_site_globals(&self) -> Result<()> {
+        let variables: Variables = match &self.globals {
+            Some(path) => serde_yaml_ng::from_reader(
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .open(path)
+                    .map_err(|e| anyhow!("cannot open globals file {}: {}", path.display(), e))?,
+            )?,
+            None => Default::default(),
+        };
+
+        for config in self.registry_configs() {
+            GlobalRegistry::new(config.path.clone())
+                .with_state(config.state_path())
+                .set_site(&variables)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn debug(&self) -> bool {
+        self.debug.unwrap_or_default()
+    }
+
+    pub fn sync_registry(&self) -> Result<()> {
+        let registries = self.registries();
+
+        for config in self.registry_configs() {
+            if let Some(url) = &config.url {
+                if let Some(state) = config.state_path() {
+                    move_legacy_state(&config.path, &state)?;
+                }
+
+                sync_registry(&config.path, url, config.pin.as_ref())?;
+
+                if let Some(registry) = registries.get(&config.name) {
+                    registry.rebuild_index()?;
+                }
             }
-        } else {
-            return Err(anyhow!("please specify a command"));
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::RegistryPin;
+    use std::path::PathBuf;
+
+    #[test]
+    fn registries() {
+        // a single registry, as configured before there could be more than one
+        let config: Config = serde_yaml_ng::from_str(
+            "registry:\n  path: testdata/registry\n  url: null\nsocket: /tmp/charond.sock\n",
+        )
+        .unwrap();
+        let configs = config.registry_configs();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].name, "default");
+        assert!(config.check_registries().is_ok());
+
+        let config: Config = serde_yaml_ng::from_str(
+            r#"
+registries:
+  - name: upstream
+    path: /trunk/charon/registry
+    url: https://github.com/trunk-os/charon-packages
+  - name: ours
+    path: /trunk/charon/ours
+    url: null
+    priority: 10
+  - name: theirs
+    path: /trunk/charon/theirs
+    url: null
+socket: /tmp/charond.sock
+"#,
+        )
+        .unwrap();
+        assert!(config.check_registries().is_ok());
+        assert_eq!(
+            config
+                .registries()
+                .0
+                .iter()
+                .map(|r| r.name().unwrap().to_string())
+                .collect::<Vec<String>>(),
+            vec!["ours", "upstream", "theirs"]
+        );
+
+        let config: Config = serde_yaml_ng::from_str(
+            "registries:\n  - name: a\n    path: /a\n    url: null\n  - name: a\n    path: /b\n    url: null\nsocket: /tmp/charond.sock\n",
+        )
+        .unwrap();
+        assert!(config.check_registries().is_err());
+
+        let config: Config = serde_yaml_ng::from_str(
+            "registries:\n  - name: a/b\n    path: /a\n    url: null\nsocket: /tmp/charond.sock\n",
+        )
+        .unwrap();
+        assert!(config.check_registries().is_err());
+
+        // nothing configured is the default registry
+        assert_eq!(Config::default().registry_configs().len(), 1);
+    }
+
+    #[test]
+    fn registry_state() {
+        let config: Config = serde_yaml_ng::from_str(
+            r#"
+registries:
+  - name: upstream
+    path: /trunk/charon/registry
+    url: https://github.com/trunk-os/charon-packages
+    pin:
+      tag: v1.0.0
+  - name: pinned
+    path: /trunk/charon/pinned
+    url: https://github.com/trunk-os/charon-packages
+    pin:
+      branch: stable
+    state: /trunk/charon/state
+  - name: local
+    path: /trunk/charon/local
+    url: null
+socket: /tmp/charond.sock
+"#,
+        )
+        .unwrap();
+
+        let configs = config.registry_configs();
+        assert_eq!(configs[0].pin, Some(RegistryPin::Tag("v1.0.0".into())));
+        assert_eq!(configs[1].pin, Some(RegistryPin::Branch("stable".into())));
+        assert_eq!(configs[2].pin, None);
+
+        // git registries keep their state beside the worktree, unless told otherwise
+        assert_eq!(
+            configs[0].state_path(),
+            Some(PathBuf::from("/trunk/charon/registry-state"))
+        );
+        assert_eq!(
+            configs[1].state_path(),
+            Some(PathBuf::from("/trunk/charon/state"))
+        );
+        assert_eq!(configs[2].state_path(), None);
+        assert_eq!(
+            config.registries().0[2].state(),
+            PathBuf::from("/trunk/charon/local")
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+
+//
+// facts are values about the host the packages run on, available to templates as globals under a
+// reserved namespace, e.g. `@host.cpus@`. they are detected when a package is compiled and any of
+// them may be overridden in the daemon configuration, which is stored in the registry so `charon
+// launch` sees the same values as the daemon.
+//
+
+pub const FACTS_NAMESPACE: &str = "host";
+pub const FACTS_OVERRIDES_FILENAME: &str = "facts.json";
+const DEFAULT_DATA_POOL: &str = "trunk";
+const DEFAULT_TIMEZONE: &str = "UTC";
+// nothing is sent to this address, it is only used to find the interface with the default route
+const ROUTE_PROBE_ADDRESS: &str = "192.0.2.1:9";
+
+pub type FactOverrides = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Facts(pub BTreeMap<String, String>);
+
+impl Facts {
+    // detects what it can about this host. facts that cannot be detected are left out, so
+    // templates referring to them fail rather than silently receive an empty value.
+    pub fn detect() -> Self {
+        let mut facts = BTreeMap::default();
+
+        if let Some(hostname) = read_trimmed("/proc/sys/kernel/hostname") {
+            facts.insert("hostname".into(), hostname);
+        }
+
+        if let Some(ip) = primary_ip() {
+            facts.insert("ip".into(), ip);
+        }
+
+        if let Ok(cpus) = std::thread::available_parallelism() {
+            facts.insert("cpus".into(), cpus.to_string());
+        }
+
+        if let Some(memory) = total_memory() {
+            facts.insert("memory".into(), memory.to_string());
+        }
+
+        facts.insert("timezone".into(), timezone());
+        facts.insert("pool".into(), DEFAULT_DATA_POOL.into());
+
+        Self(facts)
+    }
+
+    pub fn with_overrides(mut self, overrides: &FactOverrides) -> Self {
+        for (name, value) in overrides {
+            self.0.insert(name.clone(), value.clone());
+        }
+
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.0.get(name).cloned()
+    }
+
+    // the facts as global variables, e.g. `host.cpus`
+    pub fn variables(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        self.0
+            .iter()
+            .map(|(name, value)| (format!("{}.{}", FACTS_NAMESPACE, name), value.clone()))
+    }
+}
+
+pub fn is_reserved(name: &str) -> bool {
+    name.split_once('.')
+        .is_some_and(|(namespace, _)| namespace == FACTS_NAMESPACE)
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn primary_ip() -> Option<String> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(ROUTE_PROBE_ADDRESS).ok()?;
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
+
+// in megabytes, the unit package resources are expressed in
+fn total_memory() -> Option<u64> {
+    std::fs::read_to_string("/proc/meminfo")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|rest| {
+            rest.trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .map(|kb| kb / 1024)
+}
+
+fn timezone() -> String {
+    read_trimmed("/etc/timezone")
+        .or_else(|| {
+            std::fs::read_link("/etc/localtime").ok().and_then(|link| {
+                link.to_string_lossy()
+                    .split_once("zoneinfo/")
+                    .map(|(_, zone)| zone.to_string())
+            })
+        })
+        .unwrap_or_else(|| DEFAULT_TIMEZONE.into())
+}
+
+pub struct FactsRegistry {
+    pub root: PathBuf,
+}
+
+impl FactsRegistry {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn overrides(&self) -> Result<FactOverrides> {
+        let path = self.root.join(FACTS_OVERRIDES_FILENAME);
+
+        if !std::fs::exists(&path)? {
+            return Ok(Default::default());
+        }
+
+        Ok(serde_json::from_reader(
+            std::fs::OpenOptions::new().read(true).open(path)?,
+        )?)
+    }
+
+    pub fn set_overrides(&self, overrides: &FactOverrides) -> Result<()> {
+        if let Some(name) = overrides.keys().find(|name| name.contains('.')) {
+            return Err(anyhow!(
+                "fact '{}' is invalid: facts are named without the '{}.' prefix",
+                name,
+                FACTS_NAMESPACE
+            ));
+        }
+
+        let path = self.root.join(FACTS_OVERRIDES_FILENAME);
+
+        if overrides.is_empty() {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        std::fs::create_dir_all(&self.root)?;
+        let tmp = self.root.join(format!("{}.tmp", FACTS_OVERRIDES_FILENAME));
+        serde_json::to_writer_pretty(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&tmp)?,
+            overrides,
+        )?;
+
+        Ok(std::fs::rename(tmp, path)?)
+    }
+
+    pub fn get(&self) -> Result<Facts> {
+        Ok(Facts::detect().with_overrides(&self.overrides()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_reserved, FactOverrides, Facts, FactsRegistry};
+
+    #[test]
+    fn detect() {
+        let facts = Facts::detect();
+        assert_eq!(
+            facts.get("cpus").unwrap(),
+            std::thread::available_parallelism().unwrap().to_string()
+        );
+        assert!(facts.get("timezone").is_some());
+        assert!(facts.get("pool").is_some());
+        assert!(facts.get("nonexistent").is_none());
+    }
+
+    #[test]
+    fn overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let fr = FactsRegistry::new(dir.path().to_path_buf());
+        assert!(fr.overrides().unwrap().is_empty());
+
+        let mut overrides = FactOverrides::default();
+        overrides.insert("ip".into(), "10.0.0.2".into());
+        overrides.insert("pool".into(), "tank".into());
+        fr.set_overrides(&overrides).unwrap();
+        assert_eq!(fr.overrides().unwrap(), overrides);
+
+        let facts = fr.get().unwrap();
+        assert_eq!(facts.get("ip").unwrap(), "10.0.0.2");
+        assert_eq!(facts.get("pool").unwrap(), "tank");
+        assert!(facts.get("cpus").is_some());
+
+        fr.set_overrides(&Default::default()).unwrap();
+        assert!(fr.overrides().unwrap().is_empty());
+        // removing twice is fine
+        fr.set_overrides(&Default::default()).unwrap();
+
+        let mut overrides = FactOverrides::default();
+        overrides.insert("host.ip".into(), "10.0.0.2".into());
+        assert!(fr.set_overrides(&overrides).is_err());
+    }
+
+    #[test]
+    fn reserved() {
+        assert!(is_reserved("host.cpus"));
+        assert!(!is_reserved("host"));
+        assert!(!is_reserved("hostname"));
+    }
+}
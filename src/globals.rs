@@ -1,8 +1,13 @@
+use crate::{is_reserved, Facts, ProtoGlobalExplanation, Template, FACTS_NAMESPACE};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
-const GLOBAL_SUBPATH: &str = "variables";
+pub const GLOBAL_SUBPATH: &str = "variables";
+pub const SITE_GLOBALS_FILENAME: &str = "site.json";
 const DELIMITER: char = '@';
 
 pub type Variables = HashMap<String, String>;
@@ -19,48 +24,7 @@ impl Global {
     }
 
     pub fn template(&self, s: &str) -> Result<String> {
-        let mut tmp = String::new();
-        let mut inside = false;
-        let mut out = String::new();
-
-        for ch in s.chars() {
-            if inside && ch == DELIMITER {
-                inside = false;
-                if tmp.is_empty() {
-                    // @@, not a template
-                    out.push(DELIMITER);
-                    continue;
-                }
-
-                let mut matched = false;
-                for (key, value) in &self.variables {
-                    if key == &tmp {
-                        out += value.as_str();
-                        matched = true;
-                        break;
-                    }
-                }
-
-                if !matched {
-                    return Err(anyhow!("No response matches prompt '{}'", tmp));
-                }
-
-                tmp = String::new();
-            } else if ch == DELIMITER {
-                inside = true
-            } else if inside {
-                tmp.push(ch)
-            } else {
-                out.push(ch)
-            }
-        }
-
-        // if we were inside at the end of the string, don't swallow the ?
-        if inside {
-            out += &(DELIMITER.to_string() + &tmp);
-        }
-
-        Ok(out)
+        Template::parse(s, DELIMITER)?.render(|name| Ok(self.var(name)))
     }
 }
 
@@ -76,21 +40,216 @@ impl PartialOrd for Global {
     }
 }
 
+// where a global variable comes from. layers are listed lowest first, a variable in a later layer
+// replaces the same variable in an earlier one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum GlobalSource {
+    #[serde(rename = "fact")]
+    Fact,
+    #[serde(rename = "site")]
+    Site,
+    #[serde(rename = "package")]
+    Package,
+    #[serde(rename = "install")]
+    Install,
+}
+
+impl std::fmt::Display for GlobalSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Fact => "fact",
+            Self::Site => "site",
+            Self::Package => "package",
+            Self::Install => "install",
+        })
+    }
+}
+
+impl std::str::FromStr for GlobalSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "fact" => Self::Fact,
+            "site" => Self::Site,
+            "package" => Self::Package,
+            "install" => Self::Install,
+            _ => return Err(anyhow!("invalid global source '{}'", s)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GlobalExplanation {
+    pub name: String,
+    pub value: String,
+    pub source: GlobalSource,
+    // the layers that also set this variable, but were replaced
+    pub shadowed: Vec<GlobalSource>,
+}
+
+impl From<GlobalExplanation> for ProtoGlobalExplanation {
+    fn from(value: GlobalExplanation) -> Self {
+        Self {
+            name: value.name,
+            value: value.value,
+            source: value.source.to_string(),
+            shadowed: value.shadowed.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+impl TryFrom<ProtoGlobalExplanation> for GlobalExplanation {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ProtoGlobalExplanation) -> Result<Self> {
+        Ok(Self {
+            name: value.name,
+            value: value.value,
+            source: value.source.parse()?,
+            shadowed: value
+                .shadowed
+                .iter()
+                .map(|s| s.parse())
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct GlobalLayers {
+    pub name: String,
+    pub layers: Vec<(GlobalSource, Variables)>,
+}
+
+impl GlobalLayers {
+    // the host facts namespace belongs to the facts, anything else trying to set it is ignored
+    fn effective(&self) -> impl Iterator<Item = (GlobalSource, &String, &String)> {
+        self.layers.iter().flat_map(|(source, variables)| {
+            variables
+                .iter()
+                .filter(move |(name, _)| (*source == GlobalSource::Fact) == is_reserved(name))
+                .map(move |(name, value)| (*source, name, value))
+        })
+    }
+
+    pub fn global(&self) -> Global {
+        Global {
+            name: self.name.clone(),
+            variables: self
+                .effective()
+                .map(|(_, name, value)| (name.clone(), value.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn explain(&self) -> Vec<GlobalExplanation> {
+        let mut explained: BTreeMap<&String, GlobalExplanation> = BTreeMap::default();
+
+        for (source, name, value) in self.effective() {
+            let explanation = explained.entry(name).or_insert_with(|| GlobalExplanation {
+                name: name.clone(),
+                value: value.clone(),
+                source,
+                shadowed: Vec::new(),
+            });
+
+            if explanation.source != source {
+                explanation.shadowed.push(explanation.source);
+                explanation.source = source;
+                explanation.value = value.clone();
+            }
+        }
+
+        explained.into_values().collect()
+    }
+}
+
+fn check_reserved(variables: &Variables) -> Result<()> {
+    match variables.keys().find(|name| is_reserved(name)) {
+        Some(name) => Err(anyhow!(
+            "variable '{}' is invalid: the '{}' namespace is reserved for host facts",
+            name,
+            FACTS_NAMESPACE
+        )),
+        None => Ok(()),
+    }
+}
+
+fn write_variables<T: Serialize>(dir: &std::path::Path, filename: &str, value: &T) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!("{}.tmp", filename));
+    serde_json::to_writer_pretty(
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&tmp)?,
+        value,
+    )?;
+
+    Ok(std::fs::rename(tmp, dir.join(filename))?)
+}
+
+fn read_variables(path: PathBuf) -> Result<Variables> {
+    if !std::fs::exists(&path)? {
+        return Ok(Default::default());
+    }
+
+    Ok(serde_json::from_reader(
+        std::fs::OpenOptions::new().read(true).open(path)?,
+    )?)
+}
+
+fn remove_variables(path: PathBuf) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// the variables of packages live in the registry itself. the site-wide variables, the overrides for
+// installed versions and any local edits of a package's own variables are local state, kept in the
+// state directory when there is one, so a registry synced from git is never written to.
 pub struct GlobalRegistry {
     pub root: PathBuf,
+    pub state: Option<PathBuf>,
 }
 
 impl GlobalRegistry {
     pub fn new(root: PathBuf) -> Self {
-        Self { root }
+        Self { root, state: None }
+    }
+
+    pub fn with_state(mut self, state: Option<PathBuf>) -> Self {
+        self.state = state;
+        self
+    }
+
+    fn state(&self) -> &PathBuf {
+        self.state.as_ref().unwrap_or(&self.root)
+    }
+
+    fn local_path(&self, name: &str) -> PathBuf {
+        self.state()
+            .join(GLOBAL_SUBPATH)
+            .join(format!("{}.json", name))
     }
 
     pub fn remove(&self, name: &str) -> Result<()> {
-        Ok(std::fs::remove_file(
+        let installs = self.state().join(GLOBAL_SUBPATH).join(name);
+        if std::fs::exists(&installs)? {
+            std::fs::remove_dir_all(installs)?;
+        }
+
+        std::fs::remove_file(
             self.root
                 .join(GLOBAL_SUBPATH)
                 .join(format!("{}.json", name)),
-        )?)
+        )?;
+
+        // without a state directory this was the file above
+        remove_variables(self.local_path(name))
     }
 
     pub fn get(&self, name: &str) -> Result<Global> {
@@ -104,29 +263,116 @@ impl GlobalRegistry {
     }
 
     pub fn set(&self, global: &Global) -> Result<()> {
-        let pb = self.root.join(GLOBAL_SUBPATH);
-
-        std::fs::create_dir_all(&pb)?;
-        let name = pb.join(format!("{}.json.tmp", global.name));
-        serde_json::to_writer_pretty(
-            std::fs::OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(&name)?,
+        check_reserved(&global.variables)?;
+        write_variables(
+            &self.root.join(GLOBAL_SUBPATH),
+            &format!("{}.json", global.name),
             global,
-        )?;
+        )
+    }
 
-        Ok(std::fs::rename(
-            name,
-            pb.join(format!("{}.json", &global.name)),
-        )?)
+    // site-wide variables, shared by every package. the daemon stores them from its configuration.
+    pub fn site(&self) -> Result<Variables> {
+        read_variables(self.state().join(SITE_GLOBALS_FILENAME))
+    }
+
+    pub fn set_site(&self, variables: &Variables) -> Result<()> {
+        check_reserved(variables)?;
+
+        if variables.is_empty() {
+            return remove_variables(self.state().join(SITE_GLOBALS_FILENAME));
+        }
+
+        write_variables(self.state(), SITE_GLOBALS_FILENAME, variables)
+    }
+
+    // overrides for one installed version of a package
+    pub fn install(&self, name: &str, version: &str) -> Result<Variables> {
+        read_variables(
+            self.state()
+                .join(GLOBAL_SUBPATH)
+                .join(name)
+                .join(format!("{}.json", version)),
+        )
+    }
+
+    pub fn set_install(&self, name: &str, version: &str, variables: &Variables) -> Result<()> {
+        check_reserved(variables)?;
+
+        let pb = self.state().join(GLOBAL_SUBPATH).join(name);
+        let filename = format!("{}.json", version);
+
+        if variables.is_empty() {
+            return remove_variables(pb.join(filename));
+        }
+
+        write_variables(&pb, &filename, variables)
+    }
+
+    // the variables one layer sets: the package's own without a version, or the overrides for
+    // that installed version. the package's own are the local edits of them, once there are any.
+    pub fn variables(&self, name: &str, version: Option<&str>) -> Result<Variables> {
+        match version {
+            Some(version) => self.install(name, version),
+            None if std::fs::exists(self.local_path(name))? => {
+                let local: Global = serde_json::from_reader(
+                    std::fs::OpenOptions::new()
+                        .read(true)
+                        .open(self.local_path(name))?,
+                )?;
+                Ok(local.variables)
+            }
+            None if std::fs::exists(
+                self.root
+                    .join(GLOBAL_SUBPATH)
+                    .join(format!("{}.json", name)),
+            )? =>
+            {
+                Ok(self.get(name)?.variables)
+            }
+            None => Ok(Default::default()),
+        }
+    }
+
+    pub fn set_variables(
+        &self,
+        name: &str,
+        version: Option<&str>,
+        variables: &Variables,
+    ) -> Result<()> {
+        match version {
+            Some(version) => self.set_install(name, version, variables),
+            None => {
+                check_reserved(variables)?;
+                write_variables(
+                    &self.state().join(GLOBAL_SUBPATH),
+                    &format!("{}.json", name),
+                    &Global {
+                        name: name.to_string(),
+                        variables: variables.clone(),
+                    },
+                )
+            }
+        }
+    }
+
+    pub fn layers(&self, name: &str, version: &str, facts: &Facts) -> Result<GlobalLayers> {
+        Ok(GlobalLayers {
+            name: name.to_string(),
+            layers: vec![
+                (GlobalSource::Fact, facts.variables().collect()),
+                (GlobalSource::Site, self.site()?),
+                (GlobalSource::Package, self.variables(name, None)?),
+                (GlobalSource::Install, self.install(name, version)?),
+            ],
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Global, GlobalRegistry, Variables};
+    use super::{Global, GlobalRegistry, GlobalSource, Variables};
+    use crate::{FactOverrides, Facts};
 
     #[test]
     fn sort() {
@@ -160,9 +406,7 @@ mod tests {
             variables: variables.clone(),
         }];
 
-        let registry = GlobalRegistry {
-            root: dir.path().into(),
-        };
+        let registry = GlobalRegistry::new(dir.path().into());
 
         for item in table {
             for (key, value) in &variables {
@@ -186,6 +430,8 @@ mod tests {
         };
 
         assert!(global.template("@nonexistent@".into()).is_err());
+        assert_eq!(global.template("@nonexistent|default:x@").unwrap(), "x");
+        assert_eq!(global.template("@foo|upper@").unwrap(), "BAR");
         assert_eq!(global.template("@foo@".into(),).unwrap(), "bar");
         assert_eq!(global.template("@foo@ @baz@".into(),).unwrap(), "bar quux");
 
@@ -204,4 +450,130 @@ mod tests {
             "bgates@microsoft.com"
         );
     }
+
+    #[test]
+    fn layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = GlobalRegistry::new(dir.path().into());
+
+        let vars = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Variables>()
+        };
+
+        registry
+            .set_site(&vars(&[
+                ("domain", "example.org"),
+                ("smtp", "mail.example.org"),
+            ]))
+            .unwrap();
+        registry
+            .set(&Global {
+                name: "plex".into(),
+                variables: vars(&[("domain", "plex.example.org"), ("port", "32400")]),
+            })
+            .unwrap();
+        registry
+            .set_install("plex", "1.2.3", &vars(&[("port", "32401")]))
+            .unwrap();
+
+        // the facts namespace is reserved outside of facts
+        assert!(registry.set_site(&vars(&[("host.ip", "x")])).is_err());
+        assert!(registry
+            .set_install("plex", "1.2.3", &vars(&[("host.ip", "x")]))
+            .is_err());
+
+        let mut overrides = FactOverrides::default();
+        overrides.insert("pool".into(), "tank".into());
+        let facts = Facts::detect().with_overrides(&overrides);
+
+        let layers = registry.layers("plex", "1.2.3", &facts).unwrap();
+        let global = layers.global();
+        assert_eq!(
+            global
+                .template("@domain@ @smtp@ @port@ @host.pool@")
+                .unwrap(),
+            "plex.example.org mail.example.org 32401 tank"
+        );
+
+        let explained = layers.explain();
+        let explain = |name: &str| explained.iter().find(|e| e.name == name).unwrap();
+        assert_eq!(explain("domain").source, GlobalSource::Package);
+        assert_eq!(explain("domain").shadowed, vec![GlobalSource::Site]);
+        assert_eq!(explain("smtp").source, GlobalSource::Site);
+        assert!(explain("smtp").shadowed.is_empty());
+        assert_eq!(explain("port").value, "32401");
+        assert_eq!(explain("port").source, GlobalSource::Install);
+        assert_eq!(explain("port").shadowed, vec![GlobalSource::Package]);
+        assert_eq!(explain("host.pool").source, GlobalSource::Fact);
+
+        // other versions don't see the overrides
+        let global = registry.layers("plex", "1.2.4", &facts).unwrap().global();
+        assert_eq!(global.var("port").unwrap(), "32400");
+
+        // removing the site-wide and install variables leaves the package's own
+        registry.set_site(&Variables::default()).unwrap();
+        registry
+            .set_install("plex", "1.2.3", &Variables::default())
+            .unwrap();
+        let global = registry.layers("plex", "1.2.3", &facts).unwrap().global();
+        assert_eq!(global.var("port").unwrap(), "32400");
+        assert!(global.var("smtp").is_none());
+
+        registry
+            .set_install("plex", "1.2.3", &vars(&[("port", "32401")]))
+            .unwrap();
+        registry.remove("plex").unwrap();
+        assert!(registry.install("plex", "1.2.3").unwrap().is_empty());
+    }
+
+    #[test]
+    fn local_edits() {
+        let root = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let registry =
+            GlobalRegistry::new(root.path().into()).with_state(Some(state.path().into()));
+
+        let vars = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Variables>()
+        };
+
+        let package = Global {
+            name: "plex".into(),
+            variables: vars(&[("port", "32400")]),
+        };
+        registry.set(&package).unwrap();
+
+        // edits of the package's own variables are kept with the state, the registry is left as
+        // it came
+        registry
+            .set_variables("plex", None, &vars(&[("port", "32401")]))
+            .unwrap();
+        assert_eq!(registry.get("plex").unwrap(), package);
+        assert_eq!(
+            registry.variables("plex", None).unwrap(),
+            vars(&[("port", "32401")])
+        );
+        assert!(state
+            .path()
+            .join(super::GLOBAL_SUBPATH)
+            .join("plex.json")
+            .exists());
+
+        let facts = Facts::detect();
+        let global = registry.layers("plex", "1.0.0", &facts).unwrap().global();
+        assert_eq!(global.var("port").unwrap(), "32401");
+
+        assert!(registry
+            .set_variables("plex", None, &vars(&[("host.ip", "x")]))
+            .is_err());
+
+        registry.remove("plex").unwrap();
+        assert!(registry.variables("plex", None).unwrap().is_empty());
+    }
 }
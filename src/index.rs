@@ -0,0 +1,495 @@
+use crate::{PackageTitle, ProtoIndexEntry, ProtoSearchQuery, ProtoSearchResults, Trust};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+//
+// the index of a registry is a summary of its packages, so they can be listed and searched
+// without reading every definition. it is kept with the registry's state and rebuilt when the
+// registry is synced or written to, or when the modification times or sizes of its package
+// directories and definitions no longer match those it was built from, e.g. after a definition
+// was edited by hand.
+//
+
+const INDEX_FILENAME: &str = "index.json";
+pub const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+static SAVES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    // the title of the latest version
+    pub title: PackageTitle,
+    pub description: String,
+    pub tags: Vec<String>,
+    // every version, latest first
+    pub versions: Vec<String>,
+}
+
+impl From<IndexEntry> for ProtoIndexEntry {
+    fn from(value: IndexEntry) -> Self {
+        Self {
+            title: Some(value.title.into()),
+            description: value.description,
+            tags: value.tags,
+            versions: value.versions,
+        }
+    }
+}
+
+impl TryFrom<ProtoIndexEntry> for IndexEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ProtoIndexEntry) -> Result<Self> {
+        Ok(Self {
+            title: value
+                .title
+                .ok_or_else(|| anyhow!("index entry has no title"))?
+                .into(),
+            description: value.description,
+            tags: value.tags,
+            versions: value.versions,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RegistryIndex {
+    // modification times and sizes of the packages directory, under "", of each package
+    // directory, under its name, and of each file in it, under `<name>/<file>`
+    pub fingerprint: BTreeMap<String, (u64, u64)>,
+    // what the packages listed were checked against, the index is rebuilt when it changes
+    #[serde(default)]
+    pub trust: Option<Trust>,
+    // by name
+    pub packages: Vec<IndexEntry>,
+}
+
+impl RegistryIndex {
+    pub fn fingerprint(packages: &Path) -> Result<BTreeMap<String, (u64, u64)>> {
+        let mut fingerprint = BTreeMap::default();
+        fingerprint.insert(String::new(), modified(&std::fs::metadata(packages)?)?);
+
+        for item in std::fs::read_dir(packages)? {
+            let item = item?;
+            let metadata = item.metadata()?;
+            if !metadata.is_dir() {
+                continue;
+            }
+
+            let name = item.file_name().to_string_lossy().to_string();
+
+            // editing a definition in place doesn't change the directory it is in
+            for file in std::fs::read_dir(item.path())? {
+                let file = file?;
+                fingerprint.insert(
+                    format!("{}/{}", name, file.file_name().to_string_lossy()),
+                    modified(&file.metadata()?)?,
+                );
+            }
+
+            fingerprint.insert(name, modified(&metadata)?);
+        }
+
+        Ok(fingerprint)
+    }
+
+    // None if there is no index, or it cannot be read and has to be rebuilt anyway
+    pub fn load(state: &Path) -> Result<Option<Self>> {
+        let path = state.join(INDEX_FILENAME);
+
+        if !std::fs::exists(&path)? {
+            return Ok(None);
+        }
+
+        Ok(serde_json::from_reader(std::fs::OpenOptions::new().read(true).open(path)?).ok())
+    }
+
+    pub fn save(&self, state: &Path) -> Result<()> {
+        std::fs::create_dir_all(state)?;
+        // requests handled at the same time may each rebuild the index, so each writes its own
+        let tmp = state.join(format!(
+            "{}.{}.{}.tmp",
+            INDEX_FILENAME,
+            std::process::id(),
+            SAVES.fetch_add(1, Ordering::Relaxed)
+        ));
+        serde_json::to_writer(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&tmp)?,
+            self,
+        )?;
+
+        Ok(std::fs::rename(tmp, state.join(INDEX_FILENAME))?)
+    }
+
+    // every version of every package, in the order of Registry::list
+    pub fn titles(&self) -> Vec<PackageTitle> {
+        self.packages
+            .iter()
+            .flat_map(|entry| {
+                entry.versions.iter().map(|version| PackageTitle {
+                    version: version.clone(),
+                    ..entry.title.clone()
+                })
+            })
+            .collect()
+    }
+}
+
+fn modified(metadata: &std::fs::Metadata) -> Result<(u64, u64)> {
+    Ok((
+        metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos() as u64,
+        metadata.len(),
+    ))
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SearchQuery {
+    // found in the name or description, ignoring case. everything matches an empty one.
+    pub text: String,
+    // the package has all of these
+    pub tags: Vec<String>,
+    pub offset: usize,
+    // DEFAULT_SEARCH_LIMIT if zero
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    pub fn matches(&self, entry: &IndexEntry) -> bool {
+        let text = self.text.to_lowercase();
+
+        (entry.title.name.to_lowercase().contains(&text)
+            || entry.description.to_lowercase().contains(&text))
+            && self.tags.iter().all(|tag| entry.tags.contains(tag))
+    }
+
+    pub fn limit(&self) -> usize {
+        if self.limit == 0 {
+            DEFAULT_SEARCH_LIMIT
+        } else {
+            self.limit
+        }
+    }
+}
+
+impl From<SearchQuery> for ProtoSearchQuery {
+    fn from(value: SearchQuery) -> Self {
+        Self {
+            text: value.text,
+            tags: value.tags,
+            offset: value.offset as u64,
+            limit: value.limit as u64,
+        }
+    }
+}
+
+impl From<ProtoSearchQuery> for SearchQuery {
+    fn from(value: ProtoSearchQuery) -> Self {
+        Self {
+            text: value.text,
+            tags: value.tags,
+            offset: value.offset as usize,
+            limit: value.limit as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SearchResults {
+    // the page asked for
+    pub packages: Vec<IndexEntry>,
+    // how many packages matched in all
+    pub total: usize,
+}
+
+impl From<SearchResults> for ProtoSearchResults {
+    fn from(value: SearchResults) -> Self {
+        Self {
+            packages: value.packages.into_iter().map(Into::into).collect(),
+            total: value.total as u64,
+        }
+    }
+}
+
+impl TryFrom<ProtoSearchResults> for SearchResults {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ProtoSearchResults) -> Result<Self> {
+        Ok(Self {
+            packages: value
+                .packages
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_>>()?,
+            total: value.total as usize,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SearchQuery, DEFAULT_SEARCH_LIMIT, INDEX_FILENAME};
+    use crate::{
+        trust::tests::{MINISIGN_KEY, MINISIGN_MESSAGE, MINISIGN_SIGNATURE},
+        PackageTitle, Registries, Registry, SourcePackage, Trust,
+    };
+
+    fn package(name: &str, version: &str, description: &str, tags: &[&str]) -> SourcePackage {
+        SourcePackage {
+            title: PackageTitle {
+                name: name.into(),
+                version: version.into(),
+                ..Default::default()
+            },
+            description: description.into(),
+            tags: Some(tags.iter().map(ToString::to_string).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn index() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let registry = Registry::new(dir.path().to_path_buf())
+            .with_state(Some(state.path().to_path_buf()))
+            .with_trust(Trust::unsigned());
+
+        registry
+            .write(&package("plex", "1.0.0", "old plex", &["media"]))
+            .unwrap();
+        registry
+            .write(&package("plex", "1.1.0", "Plex media server", &["media"]))
+            .unwrap();
+        registry
+            .write(&package("nginx", "1.0.0", "web server", &["web"]))
+            .unwrap();
+
+        let index = registry.index().unwrap();
+        assert!(std::fs::exists(state.path().join(INDEX_FILENAME)).unwrap());
+        assert_eq!(index.packages.len(), 2);
+        assert_eq!(index.packages[0].title.name, "nginx");
+        assert_eq!(index.packages[1].title.version, "1.1.0");
+        assert_eq!(index.packages[1].description, "Plex media server");
+        assert_eq!(index.packages[1].versions, vec!["1.1.0", "1.0.0"]);
+        assert_eq!(index.titles(), registry.list().unwrap());
+
+        // changes made behind the registry's back are noticed
+        std::fs::create_dir_all(dir.path().join("packages/added")).unwrap();
+        std::fs::write(
+            dir.path().join("packages/added/0.1.0.json"),
+            r#"{"title":{"name":"added","version":"0.1.0"},"description":"by hand","source":{"container":"scratch"}}"#,
+        )
+        .unwrap();
+        let index = registry.index().unwrap();
+        assert_eq!(index.packages.len(), 3);
+        assert_eq!(index.packages[0].description, "by hand");
+
+        // and so are definitions edited in place, even when they are the same size
+        std::fs::write(
+            dir.path().join("packages/added/0.1.0.json"),
+            r#"{"title":{"name":"added","version":"0.1.0"},"description":"BY HAND","source":{"container":"scratch"}}"#,
+        )
+        .unwrap();
+        let index = registry.index().unwrap();
+        assert_eq!(index.packages[0].description, "BY HAND");
+        std::fs::write(
+            dir.path().join("packages/added/0.1.0.json"),
+            r#"{"title":{"name":"added","version":"0.1.0"},"description":"edited by hand","source":{"container":"scratch"}}"#,
+        )
+        .unwrap();
+        let index = registry.index().unwrap();
+        assert_eq!(index.packages[0].description, "edited by hand");
+
+        // an index that cannot be read is rebuilt
+        std::fs::write(state.path().join(INDEX_FILENAME), "garbage").unwrap();
+        assert_eq!(registry.index().unwrap(), index);
+
+        registry.remove("added").unwrap();
+        assert_eq!(registry.index().unwrap().packages.len(), 2);
+
+        // the latest version by semver, not by name
+        registry
+            .write(&package("plex", "1.10.0", "Plex 1.10", &["media"]))
+            .unwrap();
+        registry
+            .write(&package("plex", "1.9.0", "Plex 1.9", &["media"]))
+            .unwrap();
+        let index = registry.index().unwrap();
+        assert_eq!(index.packages[1].title.version, "1.10.0");
+        assert_eq!(index.packages[1].description, "Plex 1.10");
+        assert_eq!(
+            index.packages[1].versions,
+            vec!["1.10.0", "1.9.0", "1.1.0", "1.0.0"]
+        );
+    }
+
+    #[test]
+    fn trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let registry = Registry::new(dir.path().to_path_buf())
+            .with_state(Some(state.path().to_path_buf()))
+            .with_trust(Trust {
+                keys: vec![MINISIGN_KEY.into()],
+                unsigned: false,
+            });
+
+        std::fs::create_dir_all(dir.path().join("packages/signed")).unwrap();
+        std::fs::write(
+            dir.path().join("packages/signed/1.0.0.json"),
+            MINISIGN_MESSAGE,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("packages/signed/1.0.0.json.sig"),
+            MINISIGN_SIGNATURE,
+        )
+        .unwrap();
+        // a later version nobody signed doesn't hide the signed one
+        registry
+            .write(&package("signed", "2.0.0", "unsigned", &[]))
+            .unwrap();
+        registry
+            .write(&package("unsigned", "1.0.0", "unsigned", &[]))
+            .unwrap();
+
+        let index = registry.index().unwrap();
+        assert_eq!(index.packages.len(), 1);
+        assert_eq!(index.packages[0].title.name, "signed");
+        assert_eq!(index.packages[0].versions, vec!["1.0.0"]);
+        assert!(registry.load("signed", "2.0.0").is_err());
+        assert!(registry.load("unsigned", "1.0.0").is_err());
+
+        // an index built with other trust is not reused
+        let unsigned = Registry::new(dir.path().to_path_buf())
+            .with_state(Some(state.path().to_path_buf()))
+            .with_trust(Trust::unsigned());
+        assert_eq!(unsigned.index().unwrap().packages.len(), 2);
+        assert_eq!(registry.index().unwrap(), index);
+
+        // nor is anything listed without trust
+        let untrusted =
+            Registry::new(dir.path().to_path_buf()).with_state(Some(state.path().to_path_buf()));
+        assert!(untrusted.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn search() {
+        let ours = tempfile::tempdir().unwrap();
+        let upstream = tempfile::tempdir().unwrap();
+        let registries = Registries(vec![
+            Registry::named("ours", ours.path().to_path_buf()).with_trust(Trust::unsigned()),
+            Registry::named("upstream", upstream.path().to_path_buf())
+                .with_trust(Trust::unsigned()),
+        ]);
+
+        let ours = registries.get("ours").unwrap();
+        let upstream = registries.get("upstream").unwrap();
+
+        ours.write(&package("plex", "1.0.0", "Our Plex", &["media"]))
+            .unwrap();
+        upstream
+            .write(&package("plex", "1.1.0", "Plex media server", &["media"]))
+            .unwrap();
+        upstream
+            .write(&package(
+                "jellyfin",
+                "1.0.0",
+                "media server",
+                &["media", "free"],
+            ))
+            .unwrap();
+        upstream
+            .write(&package("nginx", "1.0.0", "web server", &["web"]))
+            .unwrap();
+
+        let names = |query: SearchQuery| {
+            let results = registries.search(&query).unwrap();
+            (
+                results
+                    .packages
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            "{}/{}",
+                            entry.title.registry.clone().unwrap(),
+                            entry.title.name
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+                results.total,
+            )
+        };
+
+        assert_eq!(
+            names(SearchQuery::default()),
+            (
+                vec![
+                    "ours/plex".into(),
+                    "upstream/jellyfin".into(),
+                    "upstream/nginx".into(),
+                    "upstream/plex".into()
+                ],
+                4
+            )
+        );
+        assert_eq!(
+            names(SearchQuery {
+                text: "SERVER".into(),
+                ..Default::default()
+            }),
+            (
+                vec![
+                    "upstream/jellyfin".into(),
+                    "upstream/nginx".into(),
+                    "upstream/plex".into()
+                ],
+                3
+            )
+        );
+        assert_eq!(
+            names(SearchQuery {
+                text: "plex".into(),
+                ..Default::default()
+            }),
+            (vec!["ours/plex".into(), "upstream/plex".into()], 2)
+        );
+        assert_eq!(
+            names(SearchQuery {
+                tags: vec!["media".into(), "free".into()],
+                ..Default::default()
+            }),
+            (vec!["upstream/jellyfin".into()], 1)
+        );
+        assert_eq!(
+            names(SearchQuery {
+                tags: vec!["media".into()],
+                offset: 1,
+                limit: 1,
+                ..Default::default()
+            }),
+            (vec!["upstream/jellyfin".into()], 3)
+        );
+        assert_eq!(
+            names(SearchQuery {
+                offset: 10,
+                ..Default::default()
+            }),
+            (vec![], 4)
+        );
+
+        assert_eq!(SearchQuery::default().limit(), DEFAULT_SEARCH_LIMIT);
+    }
+}
@@ -1,11 +1,112 @@
-use crate::{Global, PromptCollection, PromptParser, PromptResponses};
+use crate::{
+    Global, PromptCollection, PromptParser, PromptResponses, ProtoInput, ProtoSelectOption,
+    ProtoType,
+};
+use anyhow::anyhow;
 use serde::{de::Visitor, Deserialize, Serialize};
 use std::str::FromStr;
 
+pub use charon_derive::Templated;
+
 //
 // see package.rs for some important understanding about this package that I won't repeat here
 //
 
+// Templated is implemented for anything that can be turned into its compiled form with the globals,
+// prompts and responses for a package. Structs get this through #[derive(Templated)], which also
+// generates the Compiled* twin of the struct. The implementations below cover the leaves and
+// containers the derive walks through.
+pub trait Templated {
+    type Output;
+
+    fn compile(
+        &self,
+        globals: &Global,
+        prompts: &PromptCollection,
+        responses: &PromptResponses,
+    ) -> Result<Self::Output, anyhow::Error>;
+}
+
+impl<T> Templated for TemplatedInput<T>
+where
+    T: FromStr + Serialize,
+    T::Err: Send + Sync + std::error::Error + 'static,
+{
+    type Output = T;
+
+    fn compile(
+        &self,
+        globals: &Global,
+        prompts: &PromptCollection,
+        responses: &PromptResponses,
+    ) -> Result<Self::Output, anyhow::Error> {
+        self.output(globals, prompts, responses)
+    }
+}
+
+impl<T> Templated for Option<T>
+where
+    T: Templated,
+{
+    type Output = Option<T::Output>;
+
+    fn compile(
+        &self,
+        globals: &Global,
+        prompts: &PromptCollection,
+        responses: &PromptResponses,
+    ) -> Result<Self::Output, anyhow::Error> {
+        self.as_ref()
+            .map(|x| x.compile(globals, prompts, responses))
+            .transpose()
+    }
+}
+
+impl<T> Templated for Vec<T>
+where
+    T: Templated,
+{
+    type Output = Vec<T::Output>;
+
+    fn compile(
+        &self,
+        globals: &Global,
+        prompts: &PromptCollection,
+        responses: &PromptResponses,
+    ) -> Result<Self::Output, anyhow::Error> {
+        let mut v = Vec::with_capacity(self.len());
+        for item in self {
+            v.push(item.compile(globals, prompts, responses)?);
+        }
+
+        Ok(v)
+    }
+}
+
+macro_rules! templated_tuple {
+    ($($name:ident: $index:tt),+) => {
+        impl<$($name),+> Templated for ($($name,)+)
+        where
+            $($name: Templated,)+
+        {
+            type Output = ($($name::Output,)+);
+
+            fn compile(
+                &self,
+                globals: &Global,
+                prompts: &PromptCollection,
+                responses: &PromptResponses,
+            ) -> Result<Self::Output, anyhow::Error> {
+                Ok(($(self.$index.compile(globals, prompts, responses)?,)+))
+            }
+        }
+    };
+}
+
+templated_tuple!(A: 0, B: 1);
+templated_tuple!(A: 0, B: 1, C: 2);
+templated_tuple!(A: 0, B: 1, C: 2, D: 3);
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct TemplatedInput<T> {
     input: String,
@@ -153,16 +254,99 @@ where
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub enum InputType {
     #[serde(rename = "integer")]
     Integer,
     #[serde(rename = "signed_integer")]
     SignedInteger,
+    #[default]
     #[serde(rename = "string")]
     String,
     #[serde(rename = "boolean")]
     Boolean,
+    #[serde(rename = "select")]
+    Select(Vec<SelectOption>),
+    #[serde(rename = "secret")]
+    Secret,
+}
+
+impl InputType {
+    pub fn accepts(&self, input: &Input) -> bool {
+        match self {
+            Self::Integer => matches!(input, Input::Integer(_)),
+            Self::SignedInteger => matches!(input, Input::SignedInteger(_)),
+            Self::String => matches!(input, Input::String(_)),
+            Self::Boolean => matches!(input, Input::Boolean(_)),
+            Self::Select(options) => options.iter().any(|option| option.value == *input),
+            Self::Secret => matches!(input, Input::Secret(_)),
+        }
+    }
+
+    // converts an input of another type to this one through its text, e.g. for responses
+    // carried over from an earlier version of a package
+    pub fn convert(&self, input: &Input) -> Result<Input, anyhow::Error> {
+        if self.accepts(input) {
+            return Ok(input.clone());
+        }
+
+        let value = input.expose();
+
+        let converted = match self {
+            Self::Integer => Input::from_proto(ProtoType::Integer, &value).ok(),
+            Self::SignedInteger => Input::from_proto(ProtoType::SignedInteger, &value).ok(),
+            Self::Boolean => Input::from_proto(ProtoType::Boolean, &value).ok(),
+            Self::String => Some(Input::String(value)),
+            Self::Secret => Some(Input::Secret(Secret::new(value))),
+            Self::Select(options) => options
+                .iter()
+                .find(|option| option.value.expose() == value)
+                .map(|option| option.value.clone()),
+        };
+
+        converted.ok_or_else(|| anyhow!("'{}' cannot be converted to {}", input, self))
+    }
+
+    // what a prompt of this type resolves to when it doesn't apply and has no default
+    pub fn zero(&self) -> Input {
+        match self {
+            Self::Integer => Input::Integer(0),
+            Self::SignedInteger => Input::SignedInteger(0),
+            Self::String => Input::String(String::new()),
+            Self::Boolean => Input::Boolean(false),
+            Self::Select(options) => options
+                .first()
+                .map(|option| option.value.clone())
+                .unwrap_or_else(|| Input::String(String::new())),
+            Self::Secret => Input::Secret(Secret::new("")),
+        }
+    }
+}
+
+impl std::fmt::Display for InputType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Integer => "integer",
+            Self::SignedInteger => "signed integer",
+            Self::String => "string",
+            Self::Boolean => "boolean",
+            Self::Select(_) => "select",
+            Self::Secret => "secret",
+        })
+    }
+}
+
+impl From<&InputType> for ProtoType {
+    fn from(value: &InputType) -> Self {
+        match value {
+            InputType::Integer => ProtoType::Integer,
+            InputType::SignedInteger => ProtoType::SignedInteger,
+            InputType::String => ProtoType::String,
+            InputType::Boolean => ProtoType::Boolean,
+            InputType::Select(_) => ProtoType::Select,
+            InputType::Secret => ProtoType::Secret,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -171,6 +355,44 @@ pub struct SelectOption {
     pub value: Input,
 }
 
+impl From<SelectOption> for ProtoSelectOption {
+    fn from(value: SelectOption) -> Self {
+        Self {
+            name: value.name,
+            value: value.value.expose(),
+            input_type: value.value.proto_type().into(),
+        }
+    }
+}
+
+impl From<Input> for ProtoInput {
+    fn from(value: Input) -> Self {
+        Self {
+            value: value.expose(),
+            input_type: value.proto_type().into(),
+        }
+    }
+}
+
+impl TryFrom<ProtoInput> for Input {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ProtoInput) -> Result<Self, Self::Error> {
+        Input::from_proto(value.input_type(), &value.value)
+    }
+}
+
+impl TryFrom<ProtoSelectOption> for SelectOption {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ProtoSelectOption) -> Result<Self, Self::Error> {
+        Ok(Self {
+            value: Input::from_proto(value.input_type(), &value.value)?,
+            name: value.name,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Input {
     #[serde(rename = "integer")]
@@ -181,6 +403,45 @@ pub enum Input {
     String(String),
     #[serde(rename = "boolean")]
     Boolean(bool),
+    #[serde(rename = "secret")]
+    Secret(Secret),
+}
+
+impl Input {
+    // the type tag sent alongside the stringified input over GRPC
+    pub fn proto_type(&self) -> ProtoType {
+        match self {
+            Input::Integer(_) => ProtoType::Integer,
+            Input::SignedInteger(_) => ProtoType::SignedInteger,
+            Input::Boolean(_) => ProtoType::Boolean,
+            Input::String(_) => ProtoType::String,
+            Input::Secret(_) => ProtoType::Secret,
+        }
+    }
+
+    // the value as it is sent over GRPC and compared in conditions. unlike Display, this reveals
+    // secrets; a redacted secret is empty.
+    pub fn expose(&self) -> String {
+        match self {
+            Input::Secret(secret) => secret.expose().unwrap_or_default().to_string(),
+            input => input.to_string(),
+        }
+    }
+
+    pub fn from_proto(input_type: ProtoType, value: &str) -> Result<Self, anyhow::Error> {
+        Ok(match input_type {
+            ProtoType::Integer => Input::Integer(value.parse()?),
+            ProtoType::SignedInteger => Input::SignedInteger(value.parse()?),
+            ProtoType::Boolean => Input::Boolean(value.parse()?),
+            ProtoType::String => Input::String(value.to_string()),
+            ProtoType::Secret => Input::Secret(Secret::new(value)),
+            ProtoType::Select => {
+                return Err(anyhow!(
+                    "select is a prompt type, values must be sent with the type of the option"
+                ))
+            }
+        })
+    }
 }
 
 impl std::fmt::Display for Input {
@@ -190,6 +451,66 @@ impl std::fmt::Display for Input {
             Input::SignedInteger(x) => x.to_string(),
             Input::String(x) => x.to_string(),
             Input::Boolean(x) => x.to_string(),
+            Input::Secret(x) => x.to_string(),
         })
     }
 }
+
+// the value of a secret prompt. it never shows up in Debug or Display, so it can't leak into logs
+// or error messages by accident. secrets sent back over GRPC are redacted, which leaves them without
+// a value; a redacted secret stands in for the one already stored.
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct Secret(Option<String>);
+
+impl Secret {
+    pub fn new(s: impl Into<String>) -> Self {
+        Self(Some(s.into()))
+    }
+
+    pub fn redacted() -> Self {
+        Self(None)
+    }
+
+    pub fn is_redacted(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn expose(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        match &self.0 {
+            Some(s) => serializer.serialize_str(s),
+            None => Err(serde::ser::Error::custom(
+                "a redacted secret cannot be stored",
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(Self::new(String::deserialize(deserializer)?))
+    }
+}
@@ -1,24 +1,47 @@
+// lets #[derive(Templated)] refer to ::charon from inside this crate
+extern crate self as charon;
+
+mod bundle;
 mod cli;
 mod client;
 mod config;
+mod facts;
 mod globals;
 mod grpc;
+mod index;
 mod input;
+mod metadata;
 mod package;
 mod prompt;
+mod registries;
 mod server;
+mod sync;
 mod systemd;
+mod template;
+mod trust;
+mod upgrade;
+mod zfs;
 
 #[allow(dead_code)]
 pub(crate) mod qmp;
 
+pub use bundle::*;
 pub use cli::*;
 pub use client::*;
 pub use config::*;
+pub use facts::*;
 pub use globals::*;
 pub use grpc::*;
+pub use index::*;
 pub use input::*;
+pub use metadata::*;
 pub use package::*;
 pub use prompt::*;
+pub use registries::*;
 pub use server::*;
+pub use sync::*;
 pub use systemd::*;
+pub use template::*;
+pub use trust::*;
+pub use upgrade::*;
+pub use zfs::*;
@@ -0,0 +1,285 @@
+use crate::{PackageTitle, ProtoPackage, Registry, SourcePackage};
+use anyhow::{anyhow, Result};
+use std::path::{Component, Path, PathBuf};
+
+//
+// what is shown about a package in a catalog, as opposed to what it takes to run it. the icon and
+// readme are files in the registry, named relative to its root in the package definition, and are
+// read here so a client doesn't need access to the registry.
+//
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PackageMetadata {
+    pub title: PackageTitle,
+    pub description: String,
+    pub maintainers: Vec<String>,
+    pub homepage: Option<String>,
+    pub repository: Option<String>,
+    pub license: Option<String>,
+    pub tags: Vec<String>,
+    pub icon: Option<Icon>,
+    // markdown
+    pub readme: Option<String>,
+    // every version of the package, latest first
+    pub versions: Vec<String>,
+    // the definition of this version, as it is in the registry
+    pub definition: String,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Icon {
+    // the file name, from which the format can be told
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl SourcePackage {
+    pub fn metadata(&self) -> Result<PackageMetadata> {
+        let root = self.root.clone().ok_or_else(|| {
+            anyhow!("source package does not contain registry information, cannot find metadata")
+        })?;
+
+        let icon = match &self.icon {
+            Some(icon) => {
+                let path = registry_file(&root, icon)?;
+                Some(Icon {
+                    name: path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                    data: std::fs::read(&path)
+                        .map_err(|e| anyhow!("cannot read icon {}: {}", icon.display(), e))?,
+                })
+            }
+            None => None,
+        };
+
+        let readme = match &self.readme {
+            Some(readme) => Some(
+                std::fs::read_to_string(registry_file(&root, readme)?)
+                    .map_err(|e| anyhow!("cannot read readme {}: {}", readme.display(), e))?,
+            ),
+            None => None,
+        };
+
+        Ok(PackageMetadata {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            maintainers: self.maintainers.clone().unwrap_or_default(),
+            homepage: self.homepage.clone(),
+            repository: self.repository.clone(),
+            license: self.license.clone(),
+            tags: self.tags.clone().unwrap_or_default(),
+            icon,
+            readme,
+            versions: vec![self.title.version.clone()],
+            definition: Registry::new(root).definition(&self.title.name, &self.title.version)?,
+        })
+    }
+}
+
+// files named by a package have to be inside its registry, also once symlinks are followed. the
+// file doesn't have to exist yet, what does of the path is checked.
+pub(crate) fn registry_file(root: &Path, path: &Path) -> Result<PathBuf> {
+    let outside = || {
+        anyhow!(
+            "{} must be relative to the registry, and inside it",
+            path.display()
+        )
+    };
+
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(outside());
+    }
+
+    let file = root.join(path);
+    if !std::fs::exists(root)? {
+        return Ok(file);
+    }
+
+    // symlink_metadata, so a symlink that leads nowhere is resolved too, and refused
+    let mut existing = file.as_path();
+    while std::fs::symlink_metadata(existing).is_err() {
+        existing = existing.parent().ok_or_else(outside)?;
+    }
+
+    if !existing
+        .canonicalize()
+        .map_err(|_| outside())?
+        .starts_with(root.canonicalize()?)
+    {
+        return Err(outside());
+    }
+
+    Ok(file)
+}
+
+impl From<PackageMetadata> for ProtoPackage {
+    fn from(value: PackageMetadata) -> Self {
+        let (icon_name, icon) = value
+            .icon
+            .map(|icon| (icon.name, icon.data))
+            .unwrap_or_default();
+
+        Self {
+            title: Some(value.title.into()),
+            description: value.description,
+            maintainers: value.maintainers,
+            homepage: value.homepage.unwrap_or_default(),
+            repository: value.repository.unwrap_or_default(),
+            license: value.license.unwrap_or_default(),
+            tags: value.tags,
+            icon_name,
+            icon,
+            readme: value.readme.unwrap_or_default(),
+            versions: value.versions,
+            definition: value.definition,
+        }
+    }
+}
+
+impl TryFrom<ProtoPackage> for PackageMetadata {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ProtoPackage) -> Result<Self> {
+        let optional = |s: String| Some(s).filter(|s| !s.is_empty());
+
+        Ok(Self {
+            title: value
+                .title
+                .ok_or_else(|| anyhow!("package has no title"))?
+                .into(),
+            description: value.description,
+            maintainers: value.maintainers,
+            homepage: optional(value.homepage),
+            repository: optional(value.repository),
+            license: optional(value.license),
+            tags: value.tags,
+            icon: Some(Icon {
+                name: value.icon_name,
+                data: value.icon,
+            })
+            .filter(|icon| !icon.name.is_empty()),
+            readme: optional(value.readme),
+            versions: value.versions,
+            definition: value.definition,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Icon, PackageMetadata};
+    use crate::{PackageTitle, ProtoPackage, Registries, Registry, SourcePackage, Trust};
+
+    #[test]
+    fn metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let registries = Registries(vec![
+            Registry::named("test", dir.path().to_path_buf()).with_trust(Trust::unsigned())
+        ]);
+        let registry = registries.get("test").unwrap();
+
+        std::fs::create_dir_all(dir.path().join("assets")).unwrap();
+        std::fs::write(dir.path().join("assets/plex.png"), b"\x89PNG").unwrap();
+        std::fs::write(dir.path().join("assets/plex.md"), "# Plex\n").unwrap();
+
+        let mut package = SourcePackage {
+            title: PackageTitle {
+                name: "plex".into(),
+                version: "1.0.0".into(),
+                ..Default::default()
+            },
+            description: "Plex media server".into(),
+            maintainers: Some(vec!["Jane Doe <jane@example.org>".into()]),
+            homepage: Some("https://plex.tv".into()),
+            license: Some("proprietary".into()),
+            tags: Some(vec!["media".into()]),
+            icon: Some("assets/plex.png".into()),
+            readme: Some("./assets/plex.md".into()),
+            ..Default::default()
+        };
+        registry.write(&package).unwrap();
+
+        package.title.version = "0.9.0".into();
+        package.icon = None;
+        registry.write(&package).unwrap();
+
+        let metadata = registries.metadata("plex", None).unwrap();
+        assert_eq!(metadata.title.version, "1.0.0");
+        assert_eq!(metadata.title.registry, Some("test".into()));
+        assert_eq!(metadata.versions, vec!["1.0.0", "0.9.0"]);
+        assert_eq!(metadata.maintainers, vec!["Jane Doe <jane@example.org>"]);
+        assert_eq!(metadata.repository, None);
+        assert_eq!(
+            metadata.icon,
+            Some(Icon {
+                name: "plex.png".into(),
+                data: b"\x89PNG".to_vec(),
+            })
+        );
+        assert_eq!(metadata.readme, Some("# Plex\n".into()));
+        assert_eq!(
+            serde_json::from_str::<SourcePackage>(&metadata.definition).unwrap(),
+            SourcePackage {
+                root: None,
+                title: PackageTitle {
+                    version: "1.0.0".into(),
+                    ..package.title.clone()
+                },
+                icon: Some("assets/plex.png".into()),
+                ..package.clone()
+            }
+        );
+
+        // the proto form round-trips
+        assert_eq!(
+            PackageMetadata::try_from(ProtoPackage::from(metadata.clone())).unwrap(),
+            metadata
+        );
+
+        let metadata = registries.metadata("test/plex", Some("0.9.0")).unwrap();
+        assert_eq!(metadata.title.version, "0.9.0");
+        assert_eq!(metadata.icon, None);
+        assert_eq!(metadata.versions, vec!["1.0.0", "0.9.0"]);
+
+        assert!(registries.metadata("plex", Some("2.0.0")).is_err());
+        assert!(registries.metadata("nginx", None).is_err());
+
+        // files outside the registry, also through symlinks, or missing, are refused
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.png"), b"secret").unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("secret.png"),
+            dir.path().join("assets/linked.png"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("linked")).unwrap();
+        std::os::unix::fs::symlink(
+            dir.path().join("assets/plex.png"),
+            dir.path().join("assets/inside.png"),
+        )
+        .unwrap();
+
+        package.icon = Some("assets/inside.png".into());
+        registry.write(&package).unwrap();
+        assert!(registries.metadata("plex", Some("0.9.0")).is_ok());
+
+        for icon in [
+            "../plex.png",
+            "/etc/passwd",
+            "assets/missing.png",
+            "assets/linked.png",
+            "linked/secret.png",
+            "linked/missing.png",
+        ] {
+            package.icon = Some(icon.into());
+            registry.write(&package).unwrap();
+            assert!(registries.metadata("plex", Some("0.9.0")).is_err());
+            assert!(registry.validate("plex", "0.9.0").is_err());
+        }
+    }
+}
@@ -1,27 +1,69 @@
 use crate::{
-    proto_package_installed::ProtoInstallState, Global, GlobalRegistry, PromptCollection,
-    PromptResponses, ProtoLastRunState, ProtoLoadState, ProtoRuntimeState, ProtoStatus,
-    ResponseRegistry, SystemdUnit, TemplatedInput,
+    proto_package_installed::ProtoInstallState, Facts, FactsRegistry, Global, GlobalLayers,
+    GlobalRegistry, IndexEntry, InputType, Migration, PromptCollection, PromptResponses,
+    ProtoCompileError, ProtoCompileErrors, ProtoLastRunState, ProtoLoadState, ProtoPackageTitle,
+    ProtoRuntimeState, ProtoStatus, RegistryIndex, ResponseRegistry, Secret, SystemdUnit,
+    Templated, TemplatedInput, Trust, TrustRegistry, GLOBAL_SUBPATH, SIGNATURE_EXTENSION,
+    STATE_SUFFIX,
 };
 use anyhow::{anyhow, Result};
 use buckle::systemd::{LastRunState, LoadState, RuntimeState};
+use prost::Message;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
 //
 // something really important to understand about this code is that the TemplatedInput type is only
 // really constrained by the concrete types it leverages. Everything else in input.rs is basically
-// just enough bullshit to keep rust happy. A proc macro would make this much simpler, and I will
-// get to that eventually.
+// just enough bullshit to keep rust happy.
+//
+// The sections below use #[derive(Templated)] from charon-derive, which generates the Compiled*
+// struct for each one and its compile() method. If you add a field to a section, the compiled side
+// picks it up automatically; nested sections need to derive Templated too.
 //
 
-const PACKAGE_SUBPATH: &str = "packages";
-const INSTALLED_SUBPATH: &str = "installed";
+pub const PACKAGE_SUBPATH: &str = "packages";
+pub const INSTALLED_SUBPATH: &str = "installed";
+
+fn read_definition(root: &Path, name: &str, version: &str) -> Result<Vec<u8>> {
+    let pb = root
+        .join(PACKAGE_SUBPATH)
+        .join(name)
+        .join(format!("{}.json", version));
+
+    std::fs::read(pb).map_err(|e| {
+        anyhow!(
+            "Error loading {}/{} package definition: {}",
+            name,
+            version,
+            e
+        )
+    })
+}
 
 #[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SourcePackage {
     pub title: PackageTitle,
     pub description: String,
+    // for finding the package, see SearchQuery
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    // e.g. `Jane Doe <jane@example.org>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintainers: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    // where the software the package runs is developed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    // preferably an SPDX identifier, e.g. `MIT`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    // files relative to the root of the registry, see PackageMetadata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub readme: Option<PathBuf>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub dependencies: Option<Vec<PackageTitle>>,
     pub source: Source,
@@ -35,8 +77,16 @@ pub struct SourcePackage {
     pub resources: Option<Resources>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub prompts: Option<PromptCollection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub migrations: Option<Vec<Migration>>,
     #[serde(skip)]
     pub root: Option<std::path::PathBuf>,
+    // where local state such as responses is kept, if not in the registry itself
+    #[serde(skip)]
+    pub state: Option<std::path::PathBuf>,
+    // what the registry trusts, so dependencies are checked the same way
+    #[serde(skip)]
+    pub trust: Option<Trust>,
 }
 
 impl PartialOrd for SourcePackage {
@@ -55,29 +105,19 @@ impl Ord for SourcePackage {
 
 impl SourcePackage {
     pub fn from_file(root: &Path, name: &str, version: &str) -> Result<Self> {
-        let pb = root
-            .join(PACKAGE_SUBPATH)
-            .join(name)
-            .join(format!("{}.json", version));
-        let mut res: Self =
-            serde_json::from_reader(std::fs::OpenOptions::new().read(true).open(pb).map_err(
-                |e| {
-                    anyhow!(
-                        "Error loading {}/{} package definition: {}",
-                        name,
-                        version,
-                        e
-                    )
-                },
-            )?)
-            .map_err(|e| {
-                anyhow!(
-                    "Error parsing JSON in {}/{} package definition: {}",
-                    name,
-                    version,
-                    e
-                )
-            })?;
+        Self::from_definition(root, name, version, &read_definition(root, name, version)?)
+    }
+
+    // parses a definition already read from the registry at root
+    fn from_definition(root: &Path, name: &str, version: &str, definition: &[u8]) -> Result<Self> {
+        let mut res: Self = serde_json::from_slice(definition).map_err(|e| {
+            anyhow!(
+                "Error parsing JSON in {}/{} package definition: {}",
+                name,
+                version,
+                e
+            )
+        })?;
         res.root = Some(root.to_path_buf());
         Ok(res)
     }
@@ -90,41 +130,109 @@ impl SourcePackage {
             ));
         }
 
-        let registry = GlobalRegistry {
-            root: self.root.clone().unwrap().clone(),
-        };
+        let registry = GlobalRegistry::new(self.root.clone().unwrap().clone());
 
         registry.get(&self.title.name)
     }
 
+    // every layer of globals this package sees, from the host facts up to the overrides for this
+    // version
+    pub fn global_layers(&self) -> Result<GlobalLayers> {
+        let root = self.root.clone().ok_or_else(|| {
+            anyhow!("source package does not contain registry information, cannot find globals")
+        })?;
+
+        GlobalRegistry::new(root)
+            .with_state(self.state.clone())
+            .layers(&self.title.name, &self.title.version, &self.facts()?)
+    }
+
+    // the state directory, which is the registry itself unless configured otherwise
+    fn state_path(&self) -> Option<PathBuf> {
+        self.state.clone().or_else(|| self.root.clone())
+    }
+
+    #[inline]
+    pub fn facts(&self) -> Result<Facts> {
+        match self.state_path() {
+            Some(state) => FactsRegistry::new(state).get(),
+            None => Err(anyhow!(
+                "source package does not contain registry information, cannot find facts"
+            )),
+        }
+    }
+
     #[inline]
     pub fn response_registry(&self) -> Result<ResponseRegistry> {
-        if self.root.is_none() {
-            return Err(anyhow!(
+        match self.state_path() {
+            Some(state) => Ok(ResponseRegistry::new(state)),
+            None => Err(anyhow!(
                 "source package does not contain registry information, cannot find responses"
-            ));
+            )),
         }
-
-        Ok(ResponseRegistry::new(self.root.clone().unwrap().clone()))
     }
 
     #[inline]
     pub fn set_responses(&self, responses: &PromptResponses) -> Result<()> {
-        self.response_registry()?.set(&self.title.name, responses)
+        self.response_registry()?
+            .set(&self.title.name, &self.title.version, responses)
     }
 
-    #[inline]
+    // the responses for this version. a version without any of its own starts with those of an
+    // earlier version, carried over by migrate(): the version a migration is declared for, or
+    // else the most recent earlier one.
     pub fn responses(&self) -> Result<PromptResponses> {
-        self.response_registry()?.get(&self.title.name)
+        let registry = self.response_registry()?;
+
+        if registry.exists(&self.title.name, &self.title.version) {
+            return registry.get(&self.title.name, &self.title.version);
+        }
+
+        let versions = registry.versions(&self.title.name)?;
+        let from = self
+            .migrations
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|migration| migration.from)
+            .find(|from| versions.contains(from))
+            .or_else(|| {
+                versions
+                    .into_iter()
+                    .rfind(|version| compare_versions(version, &self.title.version).is_lt())
+            });
+
+        match from {
+            Some(from) => self.migrate(&from, &registry.get(&self.title.name, &from)?),
+            None => Ok(PromptResponses::default()),
+        }
+    }
+
+    // carries responses for an earlier version over to this one. without a migration for that
+    // version, responses are only converted to the types of the prompts they answer.
+    pub fn migrate(&self, from: &str, responses: &PromptResponses) -> Result<PromptResponses> {
+        let migration = self
+            .migrations
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|migration| migration.from == from)
+            .unwrap_or_else(|| Migration {
+                from: from.to_string(),
+                ..Default::default()
+            });
+
+        migration.apply(responses, &self.prompts.clone().unwrap_or_default())
     }
 
     pub fn compile(&self) -> Result<CompiledPackage> {
-        let globals = self.globals()?;
+        let globals = self.global_layers()?.global();
         let prompts = self.prompts.clone().unwrap_or_default();
-        let responses = self.responses().unwrap_or_default();
+        let responses = self.responses()?;
 
         Ok(CompiledPackage {
             root: self.root.clone().unwrap_or_default(),
+            state: self.state_path().unwrap_or_default(),
             title: self.title.clone(),
             description: self.description.clone(),
             dependencies: self.dependencies.clone().unwrap_or_default(),
@@ -149,9 +257,92 @@ impl SourcePackage {
                 .clone()
                 .unwrap_or_default()
                 .compile(&globals, &prompts, &responses)?,
+            secrets: prompts.secrets(&responses)?,
         })
     }
 
+    // where the package comes from, without the rest of the package. unlike compile(), this does
+    // not need every prompt answered, only those the source refers to.
+    pub fn compile_source(&self) -> Result<CompiledSource> {
+        self.source.compile(
+            &self.global_layers()?.global(),
+            &self.prompts.clone().unwrap_or_default(),
+            &self.responses()?,
+        )
+    }
+
+    // every problem found compiling this package, for a client to show before it is installed.
+    // unlike compile(), this carries on past the first, and also checks the responses against the
+    // prompts, so it may find problems with a package that compiles.
+    pub fn compile_errors(&self) -> CompileErrors {
+        let mut errors = CompileErrors::default();
+
+        let globals = match self.global_layers() {
+            Ok(layers) => layers.global(),
+            Err(e) => {
+                errors.push("globals", "", e.to_string());
+                return errors;
+            }
+        };
+
+        let prompts = self.prompts.clone().unwrap_or_default();
+        let responses = match self.responses() {
+            Ok(responses) => responses,
+            Err(e) => {
+                errors.push("responses", "", e.to_string());
+                return errors;
+            }
+        };
+
+        if let Err(e) = prompts.validate(&responses) {
+            for e in e.0 {
+                errors.push("prompts", &e.template, e.message);
+            }
+        }
+
+        let sections = [
+            (
+                "source",
+                self.source
+                    .compile(&globals, &prompts, &responses)
+                    .map(|_| ()),
+            ),
+            (
+                "networking",
+                self.networking
+                    .compile(&globals, &prompts, &responses)
+                    .map(|_| ()),
+            ),
+            (
+                "storage",
+                self.storage
+                    .compile(&globals, &prompts, &responses)
+                    .map(|_| ()),
+            ),
+            (
+                "system",
+                self.system
+                    .compile(&globals, &prompts, &responses)
+                    .map(|_| ()),
+            ),
+            (
+                "resources",
+                self.resources
+                    .compile(&globals, &prompts, &responses)
+                    .map(|_| ()),
+            ),
+            ("secrets", prompts.secrets(&responses).map(|_| ())),
+        ];
+
+        for (section, result) in sections {
+            if let Err(e) = result {
+                errors.push(section, "", e.to_string());
+            }
+        }
+
+        errors
+    }
+
     pub fn dependencies(&self) -> Result<Vec<SourcePackage>> {
         // FIXME: this check probably shouldn't exist
         if self.root.is_none() {
@@ -160,14 +351,15 @@ impl SourcePackage {
             ));
         }
 
+        let mut registry = Registry::new(self.root.clone().unwrap()).with_state(self.state.clone());
+        if let Some(trust) = &self.trust {
+            registry = registry.with_trust(trust.clone());
+        }
+
         let mut v = Vec::new();
 
         for item in self.dependencies.clone().unwrap_or_default() {
-            v.push(Self::from_file(
-                self.root.clone().unwrap().as_path(),
-                &item.name,
-                &item.version,
-            )?)
+            v.push(registry.load(&item.name, &item.version)?)
         }
 
         Ok(v)
@@ -184,8 +376,13 @@ pub struct CompiledPackage {
     pub storage: CompiledStorage,
     pub system: CompiledSystem,
     pub resources: CompiledResources,
+    // values of the secret prompts, by template. these are handed to the package as files and are
+    // never written out with the rest of the package.
+    #[serde(skip)]
+    pub secrets: Vec<(String, Secret)>,
 
     root: PathBuf,
+    state: PathBuf,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -262,20 +459,124 @@ impl From<ProtoInstallState> for InstallStatus {
     }
 }
 
+// a problem compiling a package: the section of the definition it was found in, and for problems
+// with the response to a prompt, the prompt
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompileError {
+    pub section: String,
+    pub template: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CompileErrors(pub Vec<CompileError>);
+
+impl CompileErrors {
+    pub fn push(&mut self, section: &str, template: &str, message: String) {
+        self.0.push(CompileError {
+            section: section.to_string(),
+            template: template.to_string(),
+            message,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // the errors sent along with a FailedPrecondition status by Compile
+    pub fn from_status(status: &tonic::Status) -> Option<Self> {
+        if status.code() != tonic::Code::FailedPrecondition || status.details().is_empty() {
+            return None;
+        }
+
+        ProtoCompileErrors::decode(status.details())
+            .ok()
+            .map(Into::into)
+    }
+}
+
+impl std::fmt::Display for CompileErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(
+            &self
+                .0
+                .iter()
+                .map(|e| format!("{}: {}", e.section, e.message))
+                .collect::<Vec<String>>()
+                .join("; "),
+        )
+    }
+}
+
+impl std::error::Error for CompileErrors {}
+
+impl From<CompileErrors> for ProtoCompileErrors {
+    fn from(value: CompileErrors) -> Self {
+        Self {
+            errors: value
+                .0
+                .into_iter()
+                .map(|e| ProtoCompileError {
+                    section: e.section,
+                    template: e.template,
+                    message: e.message,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<ProtoCompileErrors> for CompileErrors {
+    fn from(value: ProtoCompileErrors) -> Self {
+        Self(
+            value
+                .errors
+                .into_iter()
+                .map(|e| CompileError {
+                    section: e.section,
+                    template: e.template,
+                    message: e.message,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl From<CompileErrors> for tonic::Status {
+    fn from(value: CompileErrors) -> Self {
+        tonic::Status::with_details(
+            tonic::Code::FailedPrecondition,
+            value.to_string(),
+            ProtoCompileErrors::from(value).encode_to_vec().into(),
+        )
+    }
+}
+
 impl CompiledPackage {
     pub fn systemd_unit(&self, systemd_root: PathBuf, charon_path: PathBuf) -> SystemdUnit {
         SystemdUnit::new(self.clone(), systemd_root, charon_path)
     }
 
+    // the registry the package was compiled from
+    pub fn root(&self) -> PathBuf {
+        self.root.clone()
+    }
+
+    // where the local state of the registry is kept
+    pub fn state(&self) -> PathBuf {
+        self.state.clone()
+    }
+
     fn installed_path(&self) -> PathBuf {
-        self.root
+        self.state
             .join(INSTALLED_SUBPATH)
             .join(&self.title.name)
             .join(&self.title.version)
     }
 
     pub async fn install(&self) -> Result<()> {
-        let pb = self.root.join(INSTALLED_SUBPATH).join(&self.title.name);
+        let pb = self.state.join(INSTALLED_SUBPATH).join(&self.title.name);
         std::fs::create_dir_all(&pb)?;
 
         std::fs::OpenOptions::new()
@@ -292,8 +593,12 @@ impl CompiledPackage {
         Ok(())
     }
 
+    pub fn is_installed(&self) -> Result<bool> {
+        Ok(std::fs::exists(self.installed_path())?)
+    }
+
     pub async fn installed(&self) -> Result<InstallStatus> {
-        if std::fs::exists(self.installed_path())? {
+        if self.is_installed()? {
             let client = buckle::systemd::Systemd::new_system().await?;
             let path = client
                 .get_unit(format!("{}.service", self.title.to_string()))
@@ -310,6 +615,60 @@ impl CompiledPackage {
 pub struct PackageTitle {
     pub name: String,
     pub version: String,
+    // the registry the package was found in, when there is more than one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+}
+
+// orders versions the way semver does, so 0.10.0 comes after 0.9.0 and 1.0.0-rc1 before 1.0.0.
+// versions that aren't semver are compared part by part between the dots, numerically where both
+// parts are numbers. versions that are equal this way are ordered as strings, so only the same
+// version compares equal.
+pub fn compare_versions(left: &str, right: &str) -> std::cmp::Ordering {
+    match (semver::Version::parse(left), semver::Version::parse(right)) {
+        (Ok(l), Ok(r)) => l.cmp(&r),
+        _ => {
+            let mut l = left.split('.');
+            let mut r = right.split('.');
+
+            loop {
+                let ordering = match (l.next(), r.next()) {
+                    (None, None) => break std::cmp::Ordering::Equal,
+                    (None, Some(_)) => break std::cmp::Ordering::Less,
+                    (Some(_), None) => break std::cmp::Ordering::Greater,
+                    (Some(l), Some(r)) => match (l.parse::<u64>(), r.parse::<u64>()) {
+                        (Ok(l), Ok(r)) => l.cmp(&r),
+                        _ => l.cmp(r),
+                    },
+                };
+
+                if ordering.is_ne() {
+                    break ordering;
+                }
+            }
+        }
+    }
+    .then_with(|| left.cmp(right))
+}
+
+impl From<PackageTitle> for ProtoPackageTitle {
+    fn from(value: PackageTitle) -> Self {
+        Self {
+            name: value.name,
+            version: value.version,
+            registry: value.registry.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ProtoPackageTitle> for PackageTitle {
+    fn from(value: ProtoPackageTitle) -> Self {
+        Self {
+            name: value.name,
+            version: value.version,
+            registry: Some(value.registry).filter(|r| !r.is_empty()),
+        }
+    }
 }
 
 impl std::fmt::Display for PackageTitle {
@@ -329,10 +688,10 @@ impl PartialOrd for PackageTitle {
 impl Ord for PackageTitle {
     #[inline]
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match self.name.cmp(&other.name) {
-            std::cmp::Ordering::Equal => self.version.cmp(&other.version),
-            x => x,
-        }
+        self.name
+            .cmp(&other.name)
+            .then_with(|| self.version.cmp(&other.version))
+            .then_with(|| self.registry.cmp(&other.registry))
     }
 }
 
@@ -351,8 +710,10 @@ impl Default for Source {
     }
 }
 
-impl Source {
-    pub fn compile(
+impl Templated for Source {
+    type Output = CompiledSource;
+
+    fn compile(
         &self,
         globals: &Global,
         prompts: &PromptCollection,
@@ -380,117 +741,28 @@ impl Default for CompiledSource {
     }
 }
 
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, Templated)]
 pub struct Networking {
+    #[templated(unwrap_or_default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub forward_ports: Option<Vec<(TemplatedInput<u16>, TemplatedInput<u16>)>>,
+    #[templated(unwrap_or_default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub expose_ports: Option<Vec<(TemplatedInput<u16>, TemplatedInput<u16>)>>,
+    #[templated(skip_none)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub internal_network: Option<TemplatedInput<String>>,
+    #[templated(skip_none)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hostname: Option<TemplatedInput<String>>,
 }
 
-impl Networking {
-    pub fn compile(
-        &self,
-        globals: &Global,
-        prompts: &PromptCollection,
-        responses: &PromptResponses,
-    ) -> Result<CompiledNetworking> {
-        let mut forward_ports = Vec::new();
-        if let Some(fp) = &self.forward_ports {
-            for port in fp {
-                forward_ports.push((
-                    port.0.output(globals, prompts, responses)?,
-                    port.1.output(globals, prompts, responses)?,
-                ));
-            }
-        }
-
-        let mut expose_ports = Vec::new();
-        if let Some(ep) = &self.expose_ports {
-            for port in ep {
-                expose_ports.push((
-                    port.0.output(globals, prompts, responses)?,
-                    port.1.output(globals, prompts, responses)?,
-                ));
-            }
-        }
-
-        let internal_network = if let Some(internal_network) = self
-            .internal_network
-            .as_ref()
-            .map(|x| x.output(globals, prompts, responses))
-        {
-            match internal_network {
-                Ok(x) => Some(x),
-                Err(e) => return Err(e),
-            }
-        } else {
-            None
-        };
-
-        let hostname = if let Some(hostname) = self
-            .hostname
-            .as_ref()
-            .map(|x| x.output(globals, prompts, responses))
-        {
-            match hostname {
-                Ok(x) => Some(x),
-                Err(e) => return Err(e),
-            }
-        } else {
-            None
-        };
-
-        Ok(CompiledNetworking {
-            forward_ports,
-            expose_ports,
-            internal_network,
-            hostname,
-        })
-    }
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
-pub struct CompiledNetworking {
-    pub forward_ports: Vec<(u16, u16)>,
-    pub expose_ports: Vec<(u16, u16)>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub internal_network: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub hostname: Option<String>,
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, Templated)]
 pub struct Storage {
     pub volumes: Vec<Volume>,
 }
 
-impl Storage {
-    pub fn compile(
-        &self,
-        globals: &Global,
-        prompts: &PromptCollection,
-        responses: &PromptResponses,
-    ) -> Result<CompiledStorage> {
-        let mut v = Vec::new();
-        for volume in &self.volumes {
-            v.push(volume.compile(globals, prompts, responses)?);
-        }
-
-        Ok(CompiledStorage { volumes: v })
-    }
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
-pub struct CompiledStorage {
-    pub volumes: Vec<CompiledVolume>,
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, Templated)]
 pub struct Volume {
     pub name: TemplatedInput<String>,
     pub size: TemplatedInput<u64>,
@@ -499,129 +771,170 @@ pub struct Volume {
     pub private: TemplatedInput<bool>,
 }
 
-impl Volume {
-    pub fn compile(
-        &self,
-        globals: &Global,
-        prompts: &PromptCollection,
-        responses: &PromptResponses,
-    ) -> Result<CompiledVolume> {
-        let mountpoint = if let Some(mountpoint) = self
-            .mountpoint
-            .as_ref()
-            .map(|x| x.output(globals, prompts, responses))
-        {
-            match mountpoint {
-                Ok(x) => Some(x),
-                Err(e) => return Err(e),
-            }
-        } else {
-            None
-        };
-
-        Ok(CompiledVolume {
-            name: self.name.output(globals, prompts, responses)?,
-            size: self.size.output(globals, prompts, responses)?,
-            mountpoint,
-            recreate: self.recreate.output(globals, prompts, responses)?,
-            private: self.private.output(globals, prompts, responses)?,
-        })
-    }
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
-pub struct CompiledVolume {
-    pub name: String,
-    pub size: u64,
-    pub mountpoint: Option<String>,
-    pub recreate: bool,
-    pub private: bool,
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, Templated)]
 pub struct System {
-    // --pid host
+    /// --pid host
     pub host_pid: TemplatedInput<bool>,
-    // --net host
+    /// --net host
     pub host_net: TemplatedInput<bool>,
     pub capabilities: Vec<TemplatedInput<String>>,
     pub privileged: TemplatedInput<bool>,
 }
 
-impl System {
-    pub fn compile(
-        &self,
-        globals: &Global,
-        prompts: &PromptCollection,
-        responses: &PromptResponses,
-    ) -> Result<CompiledSystem> {
-        let mut capabilities = Vec::new();
-
-        for cap in &self.capabilities {
-            capabilities.push(cap.output(globals, prompts, responses)?);
-        }
-
-        Ok(CompiledSystem {
-            host_pid: self.host_pid.output(globals, prompts, responses)?,
-            host_net: self.host_net.output(globals, prompts, responses)?,
-            capabilities,
-            privileged: self.privileged.output(globals, prompts, responses)?,
-        })
-    }
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
-pub struct CompiledSystem {
-    // --pid host
-    pub host_pid: bool,
-    // --net host
-    pub host_net: bool,
-    pub capabilities: Vec<String>,
-    pub privileged: bool,
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, Templated)]
 pub struct Resources {
     pub cpus: TemplatedInput<u64>,
     pub memory: TemplatedInput<u64>,
     // probably something to bring in PCI devices to appease the crypto folks
 }
 
-impl Resources {
-    pub fn compile(
-        &self,
-        globals: &Global,
-        prompts: &PromptCollection,
-        responses: &PromptResponses,
-    ) -> Result<CompiledResources> {
-        Ok(CompiledResources {
-            cpus: self.cpus.output(globals, prompts, responses)?,
-            memory: self.memory.output(globals, prompts, responses)?,
-        })
-    }
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
-pub struct CompiledResources {
-    pub cpus: u64,
-    pub memory: u64,
-    // probably something to bring in PCI devices to appease the crypto folks
-}
-
+#[derive(Debug, Clone)]
 pub struct Registry {
     root: PathBuf,
+    name: Option<String>,
+    state: Option<PathBuf>,
+    trust: Option<Trust>,
 }
 
 impl Registry {
     pub fn new(root: PathBuf) -> Self {
-        Self { root }
+        Self {
+            root,
+            name: None,
+            state: None,
+            trust: None,
+        }
+    }
+
+    // a registry that is one of several, see Registries
+    pub fn named(name: &str, root: PathBuf) -> Self {
+        Self {
+            root,
+            name: Some(name.to_string()),
+            state: None,
+            trust: None,
+        }
+    }
+
+    // keeps local state, such as what is installed and the responses to prompts, outside of the
+    // registry. without one, it is kept in the registry.
+    pub fn with_state(mut self, state: Option<PathBuf>) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn state(&self) -> PathBuf {
+        self.state.clone().unwrap_or_else(|| self.root.clone())
+    }
+
+    // what packages must be signed by, see trust.rs. without it, the record kept for the registry
+    // is used.
+    pub fn with_trust(mut self, trust: Trust) -> Self {
+        self.trust = Some(trust);
+        self
+    }
+
+    pub fn trust(&self) -> Result<Trust> {
+        if let Some(trust) = &self.trust {
+            return Ok(trust.clone());
+        }
+
+        self.trust_registry().get()?.ok_or_else(|| {
+            anyhow!(
+                "no keys are trusted to sign packages of registry {}",
+                self.root.display()
+            )
+        })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn exists(&self, name: &str, version: Option<&str>) -> Result<bool> {
+        let pb = self.root.join(PACKAGE_SUBPATH).join(name);
+
+        Ok(match version {
+            Some(version) => std::fs::exists(pb.join(format!("{}.json", version)))?,
+            None => std::fs::exists(pb)?,
+        })
     }
 
     pub fn path(&self) -> PathBuf {
         self.root.clone()
     }
 
+    // every version of every package, from the index
     pub fn list(&self) -> Result<Vec<PackageTitle>> {
+        Ok(self.index()?.titles())
+    }
+
+    // the index of this registry, rebuilt if the registry changed since it was built
+    pub fn index(&self) -> Result<RegistryIndex> {
+        let fingerprint = RegistryIndex::fingerprint(&self.root.join(PACKAGE_SUBPATH))?;
+
+        match RegistryIndex::load(&self.state())? {
+            Some(index) if index.fingerprint == fingerprint && index.trust == self.trust().ok() => {
+                Ok(index)
+            }
+            _ => self.rebuild_index(),
+        }
+    }
+
+    pub fn rebuild_index(&self) -> Result<RegistryIndex> {
+        // taken first, so anything changed while scanning makes the index stale
+        let fingerprint = RegistryIndex::fingerprint(&self.root.join(PACKAGE_SUBPATH))?;
+        let trust = self.trust().ok();
+        let mut packages: Vec<IndexEntry> = Vec::new();
+
+        for title in self.scan()? {
+            // only what load() accepts is listed, so without trust nothing is
+            let Some(definition) = trust.as_ref().and_then(|trust| {
+                let definition = read_definition(&self.root, &title.name, &title.version).ok()?;
+                self.verify(trust, &title.name, &title.version, &definition)
+                    .ok()
+                    .map(|_| definition)
+            }) else {
+                continue;
+            };
+
+            match packages.last_mut() {
+                Some(entry) if entry.title.name == title.name => entry.versions.push(title.version),
+                _ => {
+                    // versions are scanned latest first. a definition that cannot be parsed is
+                    // still listed, it fails when it is loaded.
+                    let package = SourcePackage::from_definition(
+                        &self.root,
+                        &title.name,
+                        &title.version,
+                        &definition,
+                    )
+                    .ok();
+
+                    packages.push(IndexEntry {
+                        description: package
+                            .as_ref()
+                            .map(|p| p.description.clone())
+                            .unwrap_or_default(),
+                        tags: package.and_then(|p| p.tags).unwrap_or_default(),
+                        versions: vec![title.version.clone()],
+                        title,
+                    })
+                }
+            }
+        }
+
+        let index = RegistryIndex {
+            fingerprint,
+            trust,
+            packages,
+        };
+        index.save(&self.state())?;
+        Ok(index)
+    }
+
+    // walks the registry for every version of every package
+    fn scan(&self) -> Result<Vec<PackageTitle>> {
         let mut v = Vec::new();
 
         let mut items = std::fs::read_dir(self.root.join(PACKAGE_SUBPATH))?
@@ -643,7 +956,9 @@ impl Registry {
             let mut inner = std::fs::read_dir(item)?
                 .filter_map(|x| {
                     if let Ok(x) = x {
-                        if x.metadata().ok()?.is_file() {
+                        if x.metadata().ok()?.is_file()
+                            && x.path().extension().is_some_and(|ext| ext == "json")
+                        {
                             return Some(x.path());
                         }
                     }
@@ -652,14 +967,15 @@ impl Registry {
                 })
                 .collect::<Vec<PathBuf>>();
 
-            inner.sort();
-
-            for item in inner.iter().rev().collect::<Vec<&PathBuf>>() {
-                let version = item.file_stem().unwrap().to_str().unwrap();
+            let version = |path: &PathBuf| path.file_stem().unwrap().to_str().unwrap().to_string();
+            // latest first
+            inner.sort_by(|l, r| compare_versions(&version(r), &version(l)));
 
+            for item in &inner {
                 v.push(PackageTitle {
                     name: name.to_string(),
-                    version: version.to_string(),
+                    version: version(item),
+                    registry: self.name.clone(),
                 });
             }
         }
@@ -670,7 +986,13 @@ impl Registry {
     pub fn installed(&self) -> Result<Vec<PackageTitle>> {
         let mut v = Vec::new();
 
-        let items = std::fs::read_dir(self.root.join(INSTALLED_SUBPATH))?;
+        // nothing was ever installed from this registry
+        let dir = self.state().join(INSTALLED_SUBPATH);
+        if !dir.exists() {
+            return Ok(v);
+        }
+
+        let items = std::fs::read_dir(dir)?;
 
         for item in items {
             let item = item?;
@@ -688,6 +1010,7 @@ impl Registry {
                         v.push(PackageTitle {
                             name: name.to_string(),
                             version: version.to_string(),
+                            registry: self.name.clone(),
                         });
                     }
                 }
@@ -698,7 +1021,7 @@ impl Registry {
     }
 
     pub fn response_registry(&self) -> ResponseRegistry {
-        ResponseRegistry::new(self.root.clone())
+        ResponseRegistry::new(self.state())
     }
 
     pub fn validate(&self, name: &str, version: &str) -> Result<()> {
@@ -710,6 +1033,25 @@ impl Registry {
 
         // validate we can load globals, but we don't need them
         let _ = package.globals()?;
+        // and that the icon and readme can be found
+        let _ = package.metadata()?;
+
+        let prompts = package.prompts.clone().unwrap_or_default();
+        prompts.check_conditions()?;
+
+        // defaults have to satisfy the prompts they belong to, and would be anything but secret
+        for prompt in prompts.to_vec() {
+            if let Some(default) = &prompt.default {
+                if prompt.input_type == InputType::Secret {
+                    return Err(anyhow!(
+                        "secret prompt '{}' cannot have a default",
+                        prompt.template
+                    ));
+                }
+
+                prompt.validate(default)?;
+            }
+        }
 
         let dependencies = package.dependencies.clone().unwrap_or_default();
 
@@ -722,13 +1064,101 @@ impl Registry {
     }
 
     pub fn remove(&self, name: &str) -> Result<()> {
-        Ok(std::fs::remove_dir_all(
-            self.root.join(PACKAGE_SUBPATH).join(name),
-        )?)
+        std::fs::remove_dir_all(self.root.join(PACKAGE_SUBPATH).join(name))?;
+        self.rebuild_index()?;
+        Ok(())
     }
 
+    // refuses packages that are not signed by a key the registry trusts, see trust.rs
     pub fn load(&self, name: &str, version: &str) -> Result<SourcePackage> {
-        SourcePackage::from_file(&self.root, name, version)
+        let definition = read_definition(&self.root, name, version)?;
+
+        let trust = self
+            .trust()
+            .map_err(|e| anyhow!("Refusing to load {}/{} package: {}", name, version, e))?;
+        self.verify(&trust, name, version, &definition)
+            .map_err(|e| anyhow!("Refusing to load {}/{} package: {}", name, version, e))?;
+
+        let mut package = SourcePackage::from_definition(&self.root, name, version, &definition)?;
+        package
+            .prompts
+            .clone()
+            .unwrap_or_default()
+            .check_templates()
+            .map_err(|e| anyhow!("Refusing to load {}/{} package: {}", name, version, e))?;
+        package.state = self.state.clone();
+        package.trust = Some(trust);
+        Ok(package)
+    }
+
+    // checks a definition of the package, and its variables, against what the registry trusts
+    fn verify(&self, trust: &Trust, name: &str, version: &str, definition: &[u8]) -> Result<()> {
+        trust.verify(definition, self.signature(name, version)?.as_deref())?;
+
+        // the variables of the package end up in it as much as the definition does
+        let variables = self
+            .root
+            .join(GLOBAL_SUBPATH)
+            .join(format!("{}.json", name));
+        if std::fs::exists(&variables)? {
+            trust
+                .verify(
+                    &std::fs::read(&variables)?,
+                    self.variables_signature(name)?.as_deref(),
+                )
+                .map_err(|e| anyhow!("its variables: {}", e))?;
+        }
+
+        Ok(())
+    }
+
+    // the detached signature of a definition, if it has one
+    pub fn signature(&self, name: &str, version: &str) -> Result<Option<String>> {
+        let pb = self
+            .root
+            .join(PACKAGE_SUBPATH)
+            .join(name)
+            .join(format!("{}.json.{}", version, SIGNATURE_EXTENSION));
+
+        if !std::fs::exists(&pb)? {
+            return Ok(None);
+        }
+
+        Ok(Some(std::fs::read_to_string(pb)?))
+    }
+
+    // the detached signature of the variables of a package, signed like its definitions
+    pub fn variables_signature(&self, name: &str) -> Result<Option<String>> {
+        let pb = self
+            .root
+            .join(GLOBAL_SUBPATH)
+            .join(format!("{}.json.{}", name, SIGNATURE_EXTENSION));
+
+        if !std::fs::exists(&pb)? {
+            return Ok(None);
+        }
+
+        Ok(Some(std::fs::read_to_string(pb)?))
+    }
+
+    // the definition as it is in the registry
+    pub fn definition(&self, name: &str, version: &str) -> Result<String> {
+        Ok(String::from_utf8(read_definition(
+            &self.root, name, version,
+        )?)?)
+    }
+
+    // the record of what the registry trusts is never kept in the registry itself: it is kept
+    // with the state when that is elsewhere, and beside the registry otherwise
+    pub fn trust_registry(&self) -> TrustRegistry {
+        match &self.state {
+            Some(state) if !state.starts_with(&self.root) => TrustRegistry::new(state.clone()),
+            _ => {
+                let mut name = self.root.file_name().unwrap_or_default().to_os_string();
+                name.push(STATE_SUFFIX);
+                TrustRegistry::new(self.root.with_file_name(name))
+            }
+        }
     }
 
     pub fn write(&self, package: &SourcePackage) -> Result<()> {
@@ -744,10 +1174,9 @@ impl Registry {
 
         serde_json::to_writer_pretty(&f, &package)?;
 
-        Ok(std::fs::rename(
-            &name,
-            pb.join(format!("{}.json", package.title.version)),
-        )?)
+        std::fs::rename(&name, pb.join(format!("{}.json", package.title.version)))?;
+        self.rebuild_index()?;
+        Ok(())
     }
 
     #[inline]
@@ -759,12 +1188,15 @@ impl Registry {
 #[cfg(test)]
 mod tests {
     use crate::{
-        CompiledPackage, Global, GlobalRegistry, PackageTitle, Registry, SourcePackage, Variables,
+        compare_versions, CompiledNetworking, CompiledPackage, CompiledStorage, CompiledVolume,
+        Global, GlobalRegistry, Input, InputType, Networking, PackageTitle, Prompt,
+        PromptCollection, PromptResponse, PromptResponses, Registry, SourcePackage, Storage,
+        Templated, Trust, Variables, Volume,
     };
 
     #[test]
     fn dependencies() {
-        let registry = Registry::new("testdata/registry".into());
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
         let pkg = registry.load("with-dependencies", "0.0.1").unwrap();
         let plex = registry.load("plex", "0.0.2").unwrap();
         let deps = pkg.dependencies().unwrap();
@@ -774,7 +1206,7 @@ mod tests {
 
     #[test]
     fn validate() {
-        let registry = Registry::new("testdata/registry".into());
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
         assert!(registry.validate("plex", "0.0.1").is_ok());
         assert!(registry.validate("plex", "0.0.2").is_ok());
 
@@ -797,6 +1229,47 @@ mod tests {
         assert!(registry.validate("bad-name-version", "0.0.2").is_err());
     }
 
+    #[test]
+    fn prompt_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Registry::new(dir.path().to_path_buf()).with_trust(Trust::unsigned());
+
+        for (template, ok) in [
+            ("password", true),
+            ("../x", false),
+            ("a,file=/etc/shadow", false),
+            ("/abs", false),
+        ] {
+            registry
+                .write(&SourcePackage {
+                    title: PackageTitle {
+                        name: "secretive".into(),
+                        version: "0.0.1".into(),
+                        ..Default::default()
+                    },
+                    prompts: Some(PromptCollection(vec![Prompt {
+                        template: template.into(),
+                        question: "Password?".into(),
+                        input_type: InputType::Secret,
+                        ..Default::default()
+                    }])),
+                    root: Some(dir.path().to_path_buf()),
+                    ..Default::default()
+                })
+                .unwrap();
+
+            assert_eq!(
+                registry.load("secretive", "0.0.1").is_ok(),
+                ok,
+                "{}",
+                template
+            );
+            if !ok {
+                assert!(registry.validate("secretive", "0.0.1").is_err());
+            }
+        }
+    }
+
     #[test]
     fn io() {
         let dir = tempfile::tempdir().unwrap();
@@ -804,19 +1277,24 @@ mod tests {
             title: PackageTitle {
                 name: "plex".into(),
                 version: "1.2.3".into(),
+                ..Default::default()
             },
             root: Some(dir.path().to_path_buf()),
             ..Default::default()
         }];
 
-        let pr = Registry {
-            root: dir.path().to_path_buf(),
-        };
+        let pr = Registry::new(dir.path().to_path_buf()).with_trust(Trust::unsigned());
 
         for item in table {
             assert!(pr.write(item).is_ok());
             let cmp = pr.load(&item.title.name, &item.title.version).unwrap();
-            assert_eq!(item.clone(), cmp);
+            assert_eq!(
+                SourcePackage {
+                    trust: Some(Trust::unsigned()),
+                    ..item.clone()
+                },
+                cmp
+            );
         }
     }
 
@@ -827,14 +1305,13 @@ mod tests {
             title: PackageTitle {
                 name: "plex".into(),
                 version: "1.2.3".into(),
+                ..Default::default()
             },
             root: Some(dir.path().to_path_buf()),
             ..Default::default()
         }];
 
-        let pr = Registry {
-            root: dir.path().to_path_buf(),
-        };
+        let pr = Registry::new(dir.path().to_path_buf()).with_trust(Trust::unsigned());
 
         for item in packages {
             pr.write(item).unwrap();
@@ -849,9 +1326,7 @@ mod tests {
             variables: variables.clone(),
         }];
 
-        let gr = GlobalRegistry {
-            root: dir.path().to_path_buf(),
-        };
+        let gr = GlobalRegistry::new(dir.path().to_path_buf());
 
         for item in globals {
             assert!(gr.set(item).is_ok());
@@ -867,14 +1342,13 @@ mod tests {
             title: PackageTitle {
                 name: "plex".into(),
                 version: "1.2.3".into(),
+                ..Default::default()
             },
             root: Some(dir.path().to_path_buf()),
             ..Default::default()
         }];
 
-        let pr = Registry {
-            root: dir.path().to_path_buf(),
-        };
+        let pr = Registry::new(dir.path().to_path_buf()).with_trust(Trust::unsigned());
 
         for item in packages {
             pr.write(item).unwrap();
@@ -889,9 +1363,7 @@ mod tests {
             variables: variables.clone(),
         }];
 
-        let gr = GlobalRegistry {
-            root: dir.path().to_path_buf(),
-        };
+        let gr = GlobalRegistry::new(dir.path().to_path_buf());
 
         for item in globals {
             assert!(gr.set(item).is_ok());
@@ -904,12 +1376,107 @@ mod tests {
             out,
             CompiledPackage {
                 root: dir.path().to_path_buf(),
+                state: dir.path().to_path_buf(),
                 title: PackageTitle {
                     name: "plex".into(),
                     version: "1.2.3".into(),
+                    ..Default::default()
                 },
                 ..Default::default()
             }
         )
     }
+
+    #[test]
+    fn versions() {
+        use std::cmp::Ordering;
+
+        assert_eq!(compare_versions("0.10.0", "0.9.0"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0.0-rc1", "1.0.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0.0", "1.0.0"), Ordering::Equal);
+        // not semver
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.9", "1.9.1"), Ordering::Less);
+        assert_eq!(
+            compare_versions("2024.beta", "2024.alpha"),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn derived_compile() {
+        let mut variables = Variables::default();
+        variables.insert("host".into(), "plex.local".into());
+        let globals = Global {
+            name: "plex".into(),
+            variables,
+        };
+        let prompts = PromptCollection(vec![Prompt {
+            template: "port".into(),
+            question: "Which port?".into(),
+            input_type: InputType::Integer,
+            ..Default::default()
+        }]);
+        let responses = PromptResponses(vec![PromptResponse {
+            template: "port".into(),
+            input: Input::Integer(32400),
+        }]);
+
+        let networking = Networking {
+            forward_ports: Some(vec![("?port?".parse().unwrap(), "32400".parse().unwrap())]),
+            hostname: Some("@host@".parse().unwrap()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            networking.compile(&globals, &prompts, &responses).unwrap(),
+            CompiledNetworking {
+                forward_ports: vec![(32400, 32400)],
+                expose_ports: vec![],
+                internal_network: None,
+                hostname: Some("plex.local".into()),
+            }
+        );
+
+        let storage = Storage {
+            volumes: vec![Volume {
+                name: "data".parse().unwrap(),
+                size: "?port?".parse().unwrap(),
+                mountpoint: None,
+                recreate: "true".parse().unwrap(),
+                private: "false".parse().unwrap(),
+            }],
+        };
+
+        assert_eq!(
+            storage.compile(&globals, &prompts, &responses).unwrap(),
+            CompiledStorage {
+                volumes: vec![CompiledVolume {
+                    name: "data".into(),
+                    size: 32400,
+                    mountpoint: None,
+                    recreate: true,
+                    private: false,
+                }]
+            }
+        );
+
+        // compiled packages serialize the way they always have: unset networking names are left
+        // out, an unset mountpoint is sent as null
+        let json =
+            serde_json::to_value(networking.compile(&globals, &prompts, &responses).unwrap())
+                .unwrap();
+        assert!(json.get("internal_network").is_none());
+        let json =
+            serde_json::to_value(storage.compile(&globals, &prompts, &responses).unwrap()).unwrap();
+        assert_eq!(json["volumes"][0]["mountpoint"], serde_json::Value::Null);
+        assert!(json["volumes"][0].get("mountpoint").is_some());
+
+        // unparseable output for the field type is an error
+        let networking = Networking {
+            forward_ports: Some(vec![("@host@".parse().unwrap(), "1".parse().unwrap())]),
+            ..Default::default()
+        };
+        assert!(networking.compile(&globals, &prompts, &responses).is_err());
+    }
 }
@@ -1,63 +1,198 @@
-use std::path::PathBuf;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
-use crate::{Input, InputType, ProtoPromptResponse, ProtoType};
+use crate::{
+    compare_versions, is_identifier, Input, InputType, ProtoPrompt, ProtoPromptResponse,
+    ProtoResponseError, ProtoResponseErrors, ProtoType, Secret, Template,
+};
 use anyhow::{anyhow, Result};
+use prost::Message;
 use serde::{Deserialize, Serialize};
 
 pub const RESPONSES_SUBPATH: &str = "responses";
+pub const SECRETS_SUBPATH: &str = "secrets";
 const DELIMITER: char = '?';
 
 pub struct ResponseRegistry {
     pub root: PathBuf,
 }
 
+// responses are kept per package version, as responses/<name>/<version>.json. responses to secret
+// prompts are kept apart from the rest, in files only root can read. responses written before they
+// were kept per version live in responses/<name>.json and are used for any version that has none
+// of its own.
 impl ResponseRegistry {
     pub fn new(root: PathBuf) -> Self {
         Self { root }
     }
 
-    pub fn remove(&self, name: &str) -> Result<()> {
-        Ok(std::fs::remove_file(
-            self.root
-                .join(RESPONSES_SUBPATH)
-                .join(format!("{}.json", name)),
-        )?)
+    // the public and secret files for a version, or the unversioned ones for None
+    fn files(&self, name: &str, version: Option<&str>) -> (PathBuf, PathBuf) {
+        let path = |subpath: &str| match version {
+            Some(version) => self
+                .root
+                .join(subpath)
+                .join(name)
+                .join(format!("{}.json", version)),
+            None => self.root.join(subpath).join(format!("{}.json", name)),
+        };
+
+        (path(RESPONSES_SUBPATH), path(SECRETS_SUBPATH))
+    }
+
+    // versions with responses of their own, oldest first
+    pub fn versions(&self, name: &str) -> Result<Vec<String>> {
+        let dir = self.root.join(RESPONSES_SUBPATH).join(name);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut v = Vec::new();
+        for item in std::fs::read_dir(dir)? {
+            let path = item?.path();
+            match path.file_stem().and_then(|x| x.to_str()) {
+                Some(version) if path.extension().and_then(|x| x.to_str()) == Some("json") => {
+                    v.push(version.to_string())
+                }
+                _ => {}
+            }
+        }
+
+        v.sort_by(|l, r| compare_versions(l, r));
+        Ok(v)
     }
 
-    pub fn get(&self, name: &str) -> Result<PromptResponses> {
-        Ok(serde_json::from_reader(
-            std::fs::OpenOptions::new().read(true).open(
-                self.root
-                    .join(RESPONSES_SUBPATH)
-                    .join(format!("{}.json", name)),
-            )?,
-        )?)
+    pub fn exists(&self, name: &str, version: &str) -> bool {
+        self.files(name, Some(version)).0.exists() || self.files(name, None).0.exists()
     }
 
-    pub fn set(&self, name: &str, responses: &PromptResponses) -> Result<()> {
-        let pb = self.root.join(RESPONSES_SUBPATH);
+    pub fn remove(&self, name: &str, version: &str) -> Result<()> {
+        let (public, secrets) = self.files(name, Some(version));
+
+        match std::fs::remove_file(secrets) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        Ok(std::fs::remove_file(public)?)
+    }
+
+    pub fn get(&self, name: &str, version: &str) -> Result<PromptResponses> {
+        let (mut public, mut secrets) = self.files(name, Some(version));
+        if !public.exists() {
+            (public, secrets) = self.files(name, None);
+        }
+
+        let mut responses: PromptResponses =
+            serde_json::from_reader(std::fs::OpenOptions::new().read(true).open(public)?)?;
+
+        if secrets.exists() {
+            let secrets: PromptResponses =
+                serde_json::from_reader(std::fs::OpenOptions::new().read(true).open(secrets)?)?;
+            responses.0.extend(secrets.0);
+        }
+
+        Ok(responses)
+    }
+
+    pub fn set(&self, name: &str, version: &str, responses: &PromptResponses) -> Result<()> {
+        let (secrets, public): (Vec<PromptResponse>, Vec<PromptResponse>) = responses
+            .0
+            .iter()
+            .cloned()
+            .partition(|response| matches!(response.input, Input::Secret(_)));
+
+        let (public_path, secrets_path) = self.files(name, Some(version));
+
+        if secrets.is_empty() {
+            match std::fs::remove_file(&secrets_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        } else {
+            let pb = self.root.join(SECRETS_SUBPATH);
+            std::fs::create_dir_all(&pb)?;
+            std::fs::set_permissions(&pb, std::fs::Permissions::from_mode(0o700))?;
+            Self::write(&secrets_path, &PromptResponses(secrets), 0o600)?;
+        }
+
+        Self::write(&public_path, &PromptResponses(public), 0o644)
+    }
+
+    fn write(path: &Path, responses: &PromptResponses, mode: u32) -> Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        let tmpname = path.with_extension("json.tmp");
+        // the mode only applies when the file is created, so don't reuse a leftover one
+        match std::fs::remove_file(&tmpname) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
 
-        std::fs::create_dir_all(&pb)?;
-        let tmpname = pb.join(format!("{}.json.tmp", name));
         serde_json::to_writer_pretty(
             std::fs::OpenOptions::new()
                 .create(true)
                 .truncate(true)
                 .write(true)
+                .mode(mode)
                 .open(&tmpname)?,
             responses,
         )?;
 
-        Ok(std::fs::rename(
-            &tmpname,
-            pb.join(format!("{}.json", name)),
-        )?)
+        Ok(std::fs::rename(&tmpname, path)?)
     }
 }
 
 #[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub struct PromptResponses(pub Vec<PromptResponse>);
 
+impl PromptResponses {
+    // the responses with every secret redacted, for handing to clients
+    pub fn redacted(&self) -> Self {
+        Self(
+            self.0
+                .iter()
+                .map(|response| match response.input {
+                    Input::Secret(_) => PromptResponse {
+                        template: response.template.clone(),
+                        input: Input::Secret(Secret::redacted()),
+                    },
+                    _ => response.clone(),
+                })
+                .collect(),
+        )
+    }
+
+    // fills in redacted secrets with the values they stand in for
+    pub fn restore_secrets(&mut self, stored: &PromptResponses) -> Result<(), ResponseErrors> {
+        let mut errors = ResponseErrors::default();
+
+        for response in self.0.iter_mut() {
+            match &response.input {
+                Input::Secret(secret) if secret.is_redacted() => {
+                    match stored.0.iter().find(|s| {
+                        s.template == response.template
+                            && matches!(&s.input, Input::Secret(s) if !s.is_redacted())
+                    }) {
+                        Some(s) => response.input = s.input.clone(),
+                        None => errors.push(
+                            &response.template,
+                            format!("no stored value for secret '{}'", response.template),
+                        ),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
 impl From<Vec<PromptResponse>> for PromptResponses {
     fn from(value: Vec<PromptResponse>) -> Self {
         Self(value)
@@ -74,27 +209,12 @@ impl PromptParser {
 
     pub fn prompts(&self, s: String) -> Result<Vec<Prompt>> {
         let mut v = Vec::new();
-        let mut inside = false;
-        let mut tmp = String::new();
-
-        for ch in s.chars() {
-            if inside && ch == DELIMITER {
-                inside = false;
-                if tmp.is_empty() {
-                    // ??, not a template
-                    continue;
-                }
 
-                for prompt in &self.collection().to_vec() {
-                    if prompt.template == tmp {
-                        v.push(prompt.clone())
-                    }
+        for name in Template::parse(&s, DELIMITER)?.variables() {
+            for prompt in &self.collection().to_vec() {
+                if prompt.template == name {
+                    v.push(prompt.clone())
                 }
-                tmp = String::new();
-            } else if ch == DELIMITER {
-                inside = true
-            } else if inside {
-                tmp.push(ch)
             }
         }
 
@@ -102,64 +222,486 @@ impl PromptParser {
     }
 
     pub fn template(&self, s: String, responses: &PromptResponses) -> Result<String> {
-        let mut tmp = String::new();
-        let mut inside = false;
-        let mut out = String::new();
-
-        for ch in s.chars() {
-            if inside && ch == DELIMITER {
-                inside = false;
-                if tmp.is_empty() {
-                    // ??, not a template
-                    out.push(DELIMITER);
-                    continue;
+        let values = self.0.resolve(responses)?;
+
+        Template::parse(&s, DELIMITER)?.render(|name| {
+            let value = match values.iter().find(|(prompt, _, _)| prompt.template == name) {
+                Some((_, _, value)) => value.clone(),
+                None => responses
+                    .0
+                    .iter()
+                    .find(|response| response.template == name)
+                    .map(|response| response.input.clone()),
+            };
+
+            match value {
+                Some(Input::Secret(_)) => Err(anyhow!(
+                    "prompt '{}' is a secret, it is passed to the package as a file and cannot be templated",
+                    name
+                )),
+                value => Ok(value.map(|value| value.to_string())),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Prompt {
+    pub template: String,
+    pub question: String,
+    pub input_type: InputType,
+    // used when there is no response for this prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Input>,
+    // bounds on the value for integers, and on the length for strings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<i64>,
+    // regular expression a string response must match in full
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    // a response must be given, even if there is a default
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+    // expression over earlier prompts deciding whether this prompt is asked at all. when it
+    // doesn't hold, the prompt resolves to its default, or the zero value of its type.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+}
+
+impl Prompt {
+    fn check_bounds(&self, value: i128, unit: &str) -> Result<()> {
+        if let Some(min) = self.min.filter(|min| value < *min as i128) {
+            return Err(anyhow!(
+                "prompt '{}' must be at least {}{}",
+                self.template,
+                min,
+                unit
+            ));
+        }
+
+        if let Some(max) = self.max.filter(|max| value > *max as i128) {
+            return Err(anyhow!(
+                "prompt '{}' must be at most {}{}",
+                self.template,
+                max,
+                unit
+            ));
+        }
+
+        Ok(())
+    }
+
+    // shown is the value as it may appear in errors, which is nothing for secrets
+    fn check_string(&self, value: &str, shown: Option<&str>) -> Result<()> {
+        self.check_bounds(value.chars().count() as i128, " characters long")?;
+
+        if let Some(pattern) = &self.pattern {
+            let re = regex::Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| anyhow!("prompt '{}' has an invalid pattern: {}", self.template, e))?;
+
+            if !re.is_match(value) {
+                return Err(match shown {
+                    Some(shown) => anyhow!(
+                        "'{}' does not match the pattern for prompt '{}': {}",
+                        shown,
+                        self.template,
+                        pattern
+                    ),
+                    None => anyhow!(
+                        "response does not match the pattern for prompt '{}': {}",
+                        self.template,
+                        pattern
+                    ),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self, input: &Input) -> Result<()> {
+        if !self.input_type.accepts(input) {
+            return Err(match &self.input_type {
+                InputType::Select(options) => anyhow!(
+                    "'{}' is not a valid choice for prompt '{}', expected one of: {}",
+                    input,
+                    self.template,
+                    options
+                        .iter()
+                        .map(|option| option.value.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+                input_type => anyhow!(
+                    "prompt '{}' expects a response of type {}",
+                    self.template,
+                    input_type
+                ),
+            });
+        }
+
+        match input {
+            Input::Integer(x) => self.check_bounds(*x as i128, "")?,
+            Input::SignedInteger(x) => self.check_bounds(*x as i128, "")?,
+            Input::String(x) => self.check_string(x, Some(x))?,
+            Input::Secret(x) => {
+                if let Some(x) = x.expose() {
+                    self.check_string(x, None)?
                 }
+            }
+            Input::Boolean(_) => {}
+        }
 
-                let mut matched = false;
-                for response in &responses.0 {
-                    if response.template == tmp {
-                        out += &response.to_string();
-                        matched = true;
-                        break;
+        Ok(())
+    }
+}
+
+impl From<Prompt> for ProtoPrompt {
+    fn from(value: Prompt) -> Self {
+        Self {
+            template: value.template,
+            question: value.question,
+            input_type: ProtoType::from(&value.input_type).into(),
+            options: match value.input_type {
+                InputType::Select(options) => options.into_iter().map(Into::into).collect(),
+                _ => Vec::new(),
+            },
+            default_value: value.default.map(Into::into),
+            min: value.min,
+            max: value.max,
+            pattern: value.pattern,
+            required: value.required,
+            help: value.help,
+            when: value.when,
+        }
+    }
+}
+
+impl TryFrom<ProtoPrompt> for Prompt {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ProtoPrompt) -> Result<Self> {
+        Ok(Self {
+            input_type: match value.input_type() {
+                ProtoType::String => InputType::String,
+                ProtoType::Integer => InputType::Integer,
+                ProtoType::SignedInteger => InputType::SignedInteger,
+                ProtoType::Boolean => InputType::Boolean,
+                ProtoType::Secret => InputType::Secret,
+                ProtoType::Select => {
+                    let mut options = Vec::with_capacity(value.options.len());
+                    for option in value.options {
+                        options.push(option.try_into()?);
                     }
+                    InputType::Select(options)
                 }
+            },
+            template: value.template,
+            question: value.question,
+            default: value.default_value.map(TryInto::try_into).transpose()?,
+            min: value.min,
+            max: value.max,
+            pattern: value.pattern,
+            required: value.required,
+            help: value.help,
+            when: value.when,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, Default, PartialEq, Serialize, Deserialize)]
+pub struct PromptCollection(pub Vec<Prompt>);
+
+impl PromptCollection {
+    pub fn to_vec(&self) -> Vec<Prompt> {
+        self.0.clone()
+    }
+
+    pub fn get(&self, template: &str) -> Option<&Prompt> {
+        self.0.iter().find(|prompt| prompt.template == template)
+    }
 
-                if !matched {
-                    return Err(anyhow!("No response matches prompt '{}'", tmp));
+    // templates name the responses in other templates, and secrets by them in files and in
+    // options to qemu and podman, so they have to be names a template could refer to
+    pub fn check_templates(&self) -> Result<()> {
+        for prompt in &self.0 {
+            if !is_identifier(&prompt.template) {
+                return Err(anyhow!("invalid prompt name '{}'", prompt.template));
+            }
+        }
+
+        Ok(())
+    }
+
+    // conditions have to parse, and may only refer to prompts that come before them
+    pub fn check_conditions(&self) -> Result<()> {
+        for (i, prompt) in self.0.iter().enumerate() {
+            let when = match &prompt.when {
+                Some(when) => Template::parse_expression(when).map_err(|e| {
+                    anyhow!("invalid condition for prompt '{}': {}", prompt.template, e)
+                })?,
+                None => continue,
+            };
+
+            for name in when.variables() {
+                if self.0[i..].iter().any(|p| p.template == name) {
+                    return Err(anyhow!(
+                        "condition for prompt '{}' refers to '{}', which is not an earlier prompt",
+                        prompt.template,
+                        name
+                    ));
                 }
+            }
+        }
+
+        Ok(())
+    }
+
+    // the prompts that apply given these responses, in order
+    pub fn active(&self, responses: &PromptResponses) -> Result<PromptCollection> {
+        Ok(PromptCollection(
+            self.resolve(responses)?
+                .into_iter()
+                .filter(|(_, active, _)| *active)
+                .map(|(prompt, _, _)| prompt.clone())
+                .collect(),
+        ))
+    }
+
+    // the prompts that apply and still need an answer: required ones without a response, ones
+    // with neither a response nor a default, and ones whose response is no longer valid
+    pub fn pending(&self, responses: &PromptResponses) -> Result<PromptCollection> {
+        let mut v = Vec::new();
+
+        for prompt in self.active(responses)?.0 {
+            let pending = match responses
+                .0
+                .iter()
+                .find(|response| response.template == prompt.template)
+            {
+                Some(response) => prompt.validate(&response.input).is_err(),
+                None => prompt.required || prompt.default.is_none(),
+            };
+
+            if pending {
+                v.push(prompt);
+            }
+        }
+
+        Ok(PromptCollection(v))
+    }
+
+    // the values of the secret prompts that apply, by template
+    pub fn secrets(&self, responses: &PromptResponses) -> Result<Vec<(String, Secret)>> {
+        let mut v = Vec::new();
+
+        for (prompt, active, value) in self.resolve(responses)? {
+            match value {
+                Some(Input::Secret(secret)) if active && !secret.is_redacted() => {
+                    v.push((prompt.template.clone(), secret))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(v)
+    }
+
+    // walks the prompts in order, deciding whether each is active and what it resolves to. an
+    // active prompt resolves to its response or default, an inactive one to its default or the
+    // zero value of its type. `when` only sees the prompts before it, and any response that
+    // doesn't belong to a prompt.
+    fn resolve(&self, responses: &PromptResponses) -> Result<Vec<(&Prompt, bool, Option<Input>)>> {
+        let mut values: Vec<(&Prompt, bool, Option<Input>)> = Vec::with_capacity(self.0.len());
+
+        for prompt in &self.0 {
+            let active = match &prompt.when {
+                Some(when) => Template::parse_expression(when)
+                    .map_err(|e| {
+                        anyhow!("invalid condition for prompt '{}': {}", prompt.template, e)
+                    })?
+                    .truthy(|name| {
+                        if let Some((_, _, value)) =
+                            values.iter().find(|(p, _, _)| p.template == name)
+                        {
+                            return Ok(value.as_ref().map(Input::expose));
+                        }
 
-                tmp = String::new();
-            } else if ch == DELIMITER {
-                inside = true
-            } else if inside {
-                tmp.push(ch)
+                        if self.get(name).is_some() {
+                            return Ok(None);
+                        }
+
+                        Ok(responses
+                            .0
+                            .iter()
+                            .find(|response| response.template == name)
+                            .map(|response| response.input.expose()))
+                    })?,
+                None => true,
+            };
+
+            let value = if active {
+                responses
+                    .0
+                    .iter()
+                    .find(|response| response.template == prompt.template)
+                    .map(|response| response.input.clone())
+                    .or_else(|| prompt.default.clone())
             } else {
-                out.push(ch)
+                Some(
+                    prompt
+                        .default
+                        .clone()
+                        .unwrap_or_else(|| prompt.input_type.zero()),
+                )
+            };
+
+            values.push((prompt, active, value));
+        }
+
+        Ok(values)
+    }
+
+    // checks every response against the prompt it answers, and that every required prompt that
+    // applies was answered. all of the problems are returned, by template.
+    pub fn validate(&self, responses: &PromptResponses) -> Result<(), ResponseErrors> {
+        let mut errors = ResponseErrors::default();
+
+        let active = match self.active(responses) {
+            Ok(active) => active,
+            Err(e) => {
+                errors.push("", e.to_string());
+                return Err(errors);
+            }
+        };
+
+        for (i, response) in responses.0.iter().enumerate() {
+            let template = &response.template;
+
+            if responses.0[..i].iter().any(|r| r.template == *template) {
+                errors.push(
+                    template,
+                    format!("more than one response for prompt '{}'", template),
+                );
+                continue;
+            }
+
+            let result = match (self.get(template), active.get(template)) {
+                (None, _) => Err(anyhow!("there is no prompt '{}'", template)),
+                (Some(prompt), Some(_)) => prompt.validate(&response.input),
+                // prompts that don't apply only need the right type, their responses aren't used
+                (Some(prompt), None) if !prompt.input_type.accepts(&response.input) => {
+                    Err(anyhow!(
+                        "prompt '{}' expects a response of type {}",
+                        template,
+                        prompt.input_type
+                    ))
+                }
+                (Some(_), None) => Ok(()),
+            };
+
+            if let Err(e) = result {
+                errors.push(template, e.to_string());
             }
         }
 
-        // if we were inside at the end of the string, don't swallow the ?
-        if inside {
-            out += &(DELIMITER.to_string() + &tmp);
+        for prompt in &active.0 {
+            if prompt.required && !responses.0.iter().any(|r| r.template == prompt.template) {
+                errors.push(
+                    &prompt.template,
+                    format!("prompt '{}' requires a response", prompt.template),
+                );
+            }
         }
 
-        Ok(out)
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-pub struct Prompt {
+// carries the responses for an earlier version of a package over to the version that declares it.
+// responses can be dropped, renamed to answer a different prompt, or have their values mapped to
+// new ones. anything not mentioned is kept as it is. either way a response is converted to the
+// type of the prompt that now answers it, if that changed; a migration has to map any value that
+// can't be converted.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Migration {
+    // the version whose responses are migrated
+    pub from: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub responses: Vec<ResponseMigration>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ResponseMigration {
+    // the template of the response in the earlier version
     pub template: String,
-    pub question: String,
-    pub input_type: InputType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rename: Option<String>,
+    // old values, as text, to new ones
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub map: std::collections::BTreeMap<String, Input>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub drop: bool,
 }
 
-#[derive(Debug, Clone, Eq, Default, PartialEq, Serialize, Deserialize)]
-pub struct PromptCollection(pub Vec<Prompt>);
+impl Migration {
+    pub fn apply(
+        &self,
+        responses: &PromptResponses,
+        prompts: &PromptCollection,
+    ) -> Result<PromptResponses> {
+        let mut v: Vec<PromptResponse> = Vec::with_capacity(responses.0.len());
 
-impl PromptCollection {
-    pub fn to_vec(&self) -> Vec<Prompt> {
-        self.0.clone()
+        for response in &responses.0 {
+            let step = self
+                .responses
+                .iter()
+                .find(|step| step.template == response.template);
+
+            if step.is_some_and(|step| step.drop) {
+                continue;
+            }
+
+            let template = step
+                .and_then(|step| step.rename.clone())
+                .unwrap_or_else(|| response.template.clone());
+
+            let mut input = step
+                .and_then(|step| step.map.get(&response.input.expose()).cloned())
+                .unwrap_or_else(|| response.input.clone());
+
+            if let Some(prompt) = prompts.get(&template) {
+                input = prompt.input_type.convert(&input).map_err(|e| {
+                    anyhow!(
+                        "response '{}' from version {} does not fit prompt '{}': {}",
+                        response.template,
+                        self.from,
+                        template,
+                        e
+                    )
+                })?;
+            }
+
+            if v.iter().any(|r| r.template == template) {
+                return Err(anyhow!(
+                    "more than one response from version {} migrates to prompt '{}'",
+                    self.from,
+                    template
+                ));
+            }
+
+            v.push(PromptResponse { template, input });
+        }
+
+        Ok(PromptResponses(v))
     }
 }
 
@@ -179,38 +721,153 @@ impl From<PromptResponse> for ProtoPromptResponse {
     fn from(value: PromptResponse) -> Self {
         Self {
             template: value.template.clone(),
-            response: value.input.to_string(),
+            response: value.input.expose(),
             // tag the type separate of the input, probably the only way this is going to work
-            input_type: match value.input {
-                Input::Integer(_) => ProtoType::Integer,
-                Input::SignedInteger(_) => ProtoType::SignedInteger,
-                Input::Boolean(_) => ProtoType::Boolean,
-                Input::String(_) => ProtoType::String,
-            }
-            .into(),
+            input_type: value.input.proto_type().into(),
+            redacted: matches!(&value.input, Input::Secret(secret) if secret.is_redacted()),
         }
     }
 }
 
-impl From<ProtoPromptResponse> for PromptResponse {
-    fn from(value: ProtoPromptResponse) -> Self {
+impl TryFrom<ProtoPromptResponse> for PromptResponse {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ProtoPromptResponse) -> Result<Self> {
+        let input_type = ProtoType::try_from(value.input_type).map_err(|_| {
+            anyhow!(
+                "response to prompt '{}' has an unknown type",
+                value.template
+            )
+        })?;
+
+        let input = if value.redacted {
+            Input::Secret(Secret::redacted())
+        } else {
+            Input::from_proto(input_type, &value.response).map_err(|e| {
+                anyhow!(
+                    "response to prompt '{}' is not a valid {}: {}",
+                    value.template,
+                    input_type.as_str_name().to_lowercase(),
+                    e
+                )
+            })?
+        };
+
+        Ok(Self {
+            template: value.template,
+            input,
+        })
+    }
+}
+
+// a problem with the response to one prompt. template is empty for problems that aren't about any
+// one response.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ResponseError {
+    pub template: String,
+    pub message: String,
+}
+
+// every problem found with a set of responses, at most one per prompt
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ResponseErrors(pub Vec<ResponseError>);
+
+impl ResponseErrors {
+    pub fn push(&mut self, template: &str, message: String) {
+        if !self.0.iter().any(|e| e.template == template) {
+            self.0.push(ResponseError {
+                template: template.to_string(),
+                message,
+            })
+        }
+    }
+
+    pub fn extend(&mut self, other: ResponseErrors) {
+        for e in other.0 {
+            self.push(&e.template, e.message)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, template: &str) -> Option<&ResponseError> {
+        self.0.iter().find(|e| e.template == template)
+    }
+
+    // the errors sent along with an InvalidArgument status by SetResponses
+    pub fn from_status(status: &tonic::Status) -> Option<Self> {
+        if status.code() != tonic::Code::InvalidArgument || status.details().is_empty() {
+            return None;
+        }
+
+        ProtoResponseErrors::decode(status.details())
+            .ok()
+            .map(Into::into)
+    }
+}
+
+impl std::fmt::Display for ResponseErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(
+            &self
+                .0
+                .iter()
+                .map(|e| e.message.clone())
+                .collect::<Vec<String>>()
+                .join("; "),
+        )
+    }
+}
+
+impl std::error::Error for ResponseErrors {}
+
+impl From<ResponseErrors> for ProtoResponseErrors {
+    fn from(value: ResponseErrors) -> Self {
         Self {
-            template: value.template.clone(),
-            input: match value.input_type() {
-                ProtoType::Integer => Input::Integer(value.response.parse().unwrap()),
-                ProtoType::SignedInteger => Input::SignedInteger(value.response.parse().unwrap()),
-                ProtoType::Boolean => Input::Boolean(value.response.parse().unwrap()),
-                ProtoType::String => Input::String(value.response),
-            },
+            errors: value
+                .0
+                .into_iter()
+                .map(|e| ProtoResponseError {
+                    template: e.template,
+                    message: e.message,
+                })
+                .collect(),
         }
     }
 }
 
+impl From<ProtoResponseErrors> for ResponseErrors {
+    fn from(value: ProtoResponseErrors) -> Self {
+        Self(
+            value
+                .errors
+                .into_iter()
+                .map(|e| ResponseError {
+                    template: e.template,
+                    message: e.message,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl From<ResponseErrors> for tonic::Status {
+    fn from(value: ResponseErrors) -> Self {
+        tonic::Status::with_details(
+            tonic::Code::InvalidArgument,
+            value.to_string(),
+            ProtoResponseErrors::from(value).encode_to_vec().into(),
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::PromptResponse;
+    use crate::{PromptResponse, PromptResponses, ProtoPrompt, SelectOption};
 
-    use super::{Input, InputType, Prompt, PromptCollection, PromptParser};
+    use super::{Input, InputType, Prompt, PromptCollection, PromptParser, ResponseErrors};
     use lazy_static::lazy_static;
 
     lazy_static! {
@@ -219,16 +876,19 @@ mod tests {
                 template: "greeting".into(),
                 question: "how do we greet each other in computers?".into(),
                 input_type: InputType::String,
+                ..Default::default()
             },
             Prompt {
                 template: "shoesize".into(),
                 question: "what is your shoe size?".into(),
                 input_type: InputType::Integer,
+                ..Default::default()
             },
             Prompt {
                 template: "file".into(),
                 question: "Give me the name of your favorite file".into(),
                 input_type: InputType::String,
+                ..Default::default()
             },
         ]
         .to_vec();
@@ -312,6 +972,13 @@ mod tests {
             "hello, world! 20"
         );
 
+        assert_eq!(
+            parser
+                .template("?shoesize|default:11?".into(), &Default::default())
+                .unwrap(),
+            "11"
+        );
+
         assert!(parser
             .template("?greeting".into(), &Default::default())
             .is_ok());
@@ -384,6 +1051,459 @@ mod tests {
         assert_eq!(*parser.prompts("why so serious?".into()).unwrap(), vec![]);
     }
 
+    #[test]
+    fn validate() {
+        assert!(PROMPTS[1].validate(&Input::Integer(10)).is_ok());
+        assert!(PROMPTS[1].validate(&Input::String("10".into())).is_err());
+
+        let select = Prompt {
+            template: "flavor".into(),
+            question: "Which flavor?".into(),
+            input_type: InputType::Select(vec![
+                SelectOption {
+                    name: "Vanilla".into(),
+                    value: Input::String("vanilla".into()),
+                },
+                SelectOption {
+                    name: "Chocolate".into(),
+                    value: Input::String("chocolate".into()),
+                },
+            ]),
+            ..Default::default()
+        };
+
+        assert!(select.validate(&Input::String("vanilla".into())).is_ok());
+        assert!(select.validate(&Input::String("Vanilla".into())).is_err());
+        assert!(select.validate(&Input::Integer(0)).is_err());
+
+        let proto: ProtoPrompt = select.clone().into();
+        assert_eq!(proto.options.len(), 2);
+        assert_eq!(Prompt::try_from(proto).unwrap(), select);
+    }
+
+    #[test]
+    fn constraints() {
+        let port = Prompt {
+            template: "port".into(),
+            question: "Which port?".into(),
+            input_type: InputType::Integer,
+            default: Some(Input::Integer(8080)),
+            min: Some(1024),
+            max: Some(65535),
+            ..Default::default()
+        };
+
+        assert!(port.validate(&Input::Integer(1024)).is_ok());
+        assert!(port.validate(&Input::Integer(65535)).is_ok());
+        assert!(port.validate(&Input::Integer(80)).is_err());
+        assert!(port.validate(&Input::Integer(65536)).is_err());
+
+        let path = Prompt {
+            template: "path".into(),
+            question: "Where?".into(),
+            input_type: InputType::String,
+            pattern: Some("/[a-z/]+".into()),
+            max: Some(10),
+            required: true,
+            ..Default::default()
+        };
+
+        assert!(path.validate(&Input::String("/srv/data".into())).is_ok());
+        assert!(path.validate(&Input::String("srv/data".into())).is_err());
+        assert!(path
+            .validate(&Input::String("/srv/data/media".into()))
+            .is_err());
+
+        let collection = PromptCollection(vec![port.clone(), path.clone()]);
+        let parser = PromptParser(collection.clone());
+
+        // the default is used when there is no response
+        assert_eq!(
+            parser
+                .template("?port?".into(), &Default::default())
+                .unwrap(),
+            "8080"
+        );
+
+        let responses: PromptResponses = vec![PromptResponse {
+            template: "path".into(),
+            input: Input::String("/srv".into()),
+        }]
+        .into();
+
+        assert_eq!(
+            parser.template("?port? ?path?".into(), &responses).unwrap(),
+            "8080 /srv"
+        );
+
+        assert!(collection.validate(&responses).is_ok());
+        // path is required
+        assert!(collection.validate(&Default::default()).is_err());
+
+        let proto: ProtoPrompt = port.clone().into();
+        assert_eq!(Prompt::try_from(proto).unwrap(), port);
+    }
+
+    #[test]
+    fn conditions() {
+        let https = Prompt {
+            template: "https".into(),
+            question: "Enable HTTPS?".into(),
+            input_type: InputType::Boolean,
+            ..Default::default()
+        };
+
+        let https_port = Prompt {
+            template: "https_port".into(),
+            question: "Which port for HTTPS?".into(),
+            input_type: InputType::Integer,
+            default: Some(Input::Integer(443)),
+            required: true,
+            when: Some("https".into()),
+            ..Default::default()
+        };
+
+        let cert = Prompt {
+            template: "cert".into(),
+            question: "Path to the certificate?".into(),
+            input_type: InputType::String,
+            when: Some("https && https_port != 443".into()),
+            ..Default::default()
+        };
+
+        let collection = PromptCollection(vec![https.clone(), https_port.clone(), cert.clone()]);
+        assert!(collection.check_conditions().is_ok());
+
+        let parser = PromptParser(collection.clone());
+
+        let off: PromptResponses = vec![
+            PromptResponse {
+                template: "https".into(),
+                input: Input::Boolean(false),
+            },
+            PromptResponse {
+                template: "https_port".into(),
+                input: Input::Integer(8443),
+            },
+        ]
+        .into();
+
+        // only https applies, and the stale port is ignored in favor of the default
+        assert_eq!(
+            collection.active(&off).unwrap().to_vec(),
+            vec![https.clone()]
+        );
+        assert_eq!(
+            parser
+                .template("?https_port? '?cert?'".into(), &off)
+                .unwrap(),
+            "443 ''"
+        );
+        // the required port isn't required when it doesn't apply
+        assert!(collection.validate(&Default::default()).is_ok());
+
+        let on: PromptResponses = vec![
+            PromptResponse {
+                template: "https".into(),
+                input: Input::Boolean(true),
+            },
+            PromptResponse {
+                template: "https_port".into(),
+                input: Input::Integer(8443),
+            },
+        ]
+        .into();
+
+        assert_eq!(
+            collection.active(&on).unwrap().to_vec(),
+            vec![https.clone(), https_port.clone(), cert.clone()]
+        );
+        assert_eq!(parser.template("?https_port?".into(), &on).unwrap(), "8443");
+        // cert applies but has no response or default
+        assert!(parser.template("?cert?".into(), &on).is_err());
+
+        // conditions may only look backwards
+        let backwards = PromptCollection(vec![cert, https, https_port]);
+        assert!(backwards.check_conditions().is_err());
+    }
+
+    #[test]
+    fn templates() {
+        let prompts = |template: &str| {
+            PromptCollection(vec![Prompt {
+                template: template.into(),
+                question: "Password?".into(),
+                input_type: InputType::Secret,
+                ..Default::default()
+            }])
+        };
+
+        for template in ["password", "db_password", "admin.password", "api-key"] {
+            assert!(prompts(template).check_templates().is_ok(), "{}", template);
+        }
+
+        for template in ["../x", "a,file=/etc/shadow", "/abs", "", ".hidden", "a/b"] {
+            assert!(prompts(template).check_templates().is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn secrets() {
+        use crate::{ResponseRegistry, Secret, RESPONSES_SUBPATH, SECRETS_SUBPATH};
+        use std::os::unix::fs::PermissionsExt;
+
+        let token = Prompt {
+            template: "token".into(),
+            question: "What is your API token?".into(),
+            input_type: InputType::Secret,
+            min: Some(8),
+            ..Default::default()
+        };
+
+        assert!(token
+            .validate(&Input::Secret(Secret::new("hunter2")))
+            .is_err());
+        assert!(token
+            .validate(&Input::Secret(Secret::new("hunter22")))
+            .is_ok());
+        assert!(token.validate(&Input::String("hunter22".into())).is_err());
+
+        let responses: PromptResponses = vec![
+            PromptResponse {
+                template: "name".into(),
+                input: Input::String("erik".into()),
+            },
+            PromptResponse {
+                template: "token".into(),
+                input: Input::Secret(Secret::new("hunter22")),
+            },
+        ]
+        .into();
+
+        assert!(!format!("{:?}", responses).contains("hunter22"));
+
+        let collection = PromptCollection(vec![token]);
+        assert_eq!(
+            collection.secrets(&responses).unwrap(),
+            vec![("token".to_string(), Secret::new("hunter22"))]
+        );
+
+        // secrets can't end up on a command line
+        let parser = PromptParser(collection);
+        assert_eq!(
+            parser.template("?name?".into(), &responses).unwrap(),
+            "erik"
+        );
+        assert!(parser.template("?token?".into(), &responses).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let registry = ResponseRegistry::new(dir.path().to_path_buf());
+        registry.set("test", "0.0.1", &responses).unwrap();
+
+        let public = std::fs::read_to_string(
+            dir.path()
+                .join(RESPONSES_SUBPATH)
+                .join("test")
+                .join("0.0.1.json"),
+        )
+        .unwrap();
+        assert!(public.contains("erik"));
+        assert!(!public.contains("hunter22"));
+
+        let secrets = dir
+            .path()
+            .join(SECRETS_SUBPATH)
+            .join("test")
+            .join("0.0.1.json");
+        assert!(std::fs::read_to_string(&secrets)
+            .unwrap()
+            .contains("hunter22"));
+        assert_eq!(
+            std::fs::metadata(&secrets).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let stored = registry.get("test", "0.0.1").unwrap();
+        assert_eq!(stored, responses);
+
+        // oldest first, by version and not by name
+        registry.set("test", "0.0.10", &responses).unwrap();
+        registry.set("test", "0.0.9", &responses).unwrap();
+        assert_eq!(
+            registry.versions("test").unwrap(),
+            vec!["0.0.1", "0.0.9", "0.0.10"]
+        );
+
+        // a redacted secret sent back in keeps the stored value
+        let mut redacted = stored.redacted();
+        assert_ne!(redacted, responses);
+        redacted.restore_secrets(&stored).unwrap();
+        assert_eq!(redacted, responses);
+        assert!(stored
+            .redacted()
+            .restore_secrets(&Default::default())
+            .is_err());
+
+        registry.remove("test", "0.0.1").unwrap();
+        assert!(!secrets.exists());
+    }
+
+    #[test]
+    fn migrations() {
+        use crate::{Migration, ResponseMigration};
+
+        let prompts = PromptCollection(vec![
+            Prompt {
+                template: "cpus".into(),
+                question: "How many CPUs?".into(),
+                input_type: InputType::Integer,
+                ..Default::default()
+            },
+            Prompt {
+                template: "flavor".into(),
+                question: "Which flavor?".into(),
+                input_type: InputType::Select(vec![SelectOption {
+                    name: "Vanilla".into(),
+                    value: Input::String("vanilla".into()),
+                }]),
+                ..Default::default()
+            },
+            Prompt {
+                template: "hostname".into(),
+                question: "What is the hostname?".into(),
+                input_type: InputType::String,
+                required: true,
+                ..Default::default()
+            },
+        ]);
+
+        let old: PromptResponses = vec![
+            PromptResponse {
+                template: "processors".into(),
+                input: Input::String("4".into()),
+            },
+            PromptResponse {
+                template: "flavor".into(),
+                input: Input::String("plain".into()),
+            },
+            PromptResponse {
+                template: "color".into(),
+                input: Input::String("red".into()),
+            },
+        ]
+        .into();
+
+        let migration = Migration {
+            from: "0.0.1".into(),
+            responses: vec![
+                ResponseMigration {
+                    template: "processors".into(),
+                    rename: Some("cpus".into()),
+                    ..Default::default()
+                },
+                ResponseMigration {
+                    template: "flavor".into(),
+                    map: [("plain".to_string(), Input::String("vanilla".into()))].into(),
+                    ..Default::default()
+                },
+                ResponseMigration {
+                    template: "color".into(),
+                    drop: true,
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let migrated = migration.apply(&old, &prompts).unwrap();
+        assert_eq!(
+            migrated,
+            vec![
+                PromptResponse {
+                    template: "cpus".into(),
+                    input: Input::Integer(4),
+                },
+                PromptResponse {
+                    template: "flavor".into(),
+                    input: Input::String("vanilla".into()),
+                },
+            ]
+            .into()
+        );
+
+        assert_eq!(
+            prompts.pending(&migrated).unwrap().to_vec(),
+            vec![prompts.get("hostname").unwrap().clone()]
+        );
+
+        // without the map, plain isn't one of the options
+        let unmapped = Migration {
+            from: "0.0.1".into(),
+            ..Default::default()
+        };
+        assert!(unmapped.apply(&old, &prompts).is_err());
+    }
+
+    #[test]
+    fn response_errors() {
+        use crate::{ProtoPromptResponse, ProtoType};
+
+        let bad = ProtoPromptResponse {
+            template: "port".into(),
+            response: "abc".into(),
+            input_type: ProtoType::Integer.into(),
+            redacted: false,
+        };
+        assert!(PromptResponse::try_from(bad).is_err());
+
+        let unknown = ProtoPromptResponse {
+            template: "port".into(),
+            response: "80".into(),
+            input_type: 100,
+            redacted: false,
+        };
+        assert!(PromptResponse::try_from(unknown).is_err());
+
+        let collection = PromptCollection(vec![
+            Prompt {
+                template: "port".into(),
+                question: "Which port?".into(),
+                input_type: InputType::Integer,
+                min: Some(1024),
+                ..Default::default()
+            },
+            Prompt {
+                template: "hostname".into(),
+                question: "What is the hostname?".into(),
+                input_type: InputType::String,
+                required: true,
+                ..Default::default()
+            },
+        ]);
+
+        let responses: PromptResponses = vec![
+            PromptResponse {
+                template: "port".into(),
+                input: Input::Integer(80),
+            },
+            PromptResponse {
+                template: "color".into(),
+                input: Input::String("red".into()),
+            },
+        ]
+        .into();
+
+        // everything wrong is reported, not just the first problem
+        let errors = collection.validate(&responses).unwrap_err();
+        assert_eq!(errors.0.len(), 3);
+        assert!(errors.get("port").is_some());
+        assert!(errors.get("color").is_some());
+        assert!(errors.get("hostname").is_some());
+
+        let status: tonic::Status = errors.clone().into();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(ResponseErrors::from_status(&status), Some(errors));
+    }
+
     #[test]
     fn input_conversion() {
         assert_eq!("20", Input::Integer(20).to_string());
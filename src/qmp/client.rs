@@ -1,103 +1,294 @@
-use super::messages::{ErrorReturn, Event, GenericReturn, JobInfo, QueryBlock, QueryJobs};
+use super::messages::{
+    BlockStats, Command, CpuInfoFast, ErrorDetail, Event, EventMessage, GenericReturn, MemoryInfo,
+    NameInfo, PciInfo, QueryBlock, QueryBlockstats, QueryCpusFast, QueryJobs,
+    QueryMemorySizeSummary, QueryName, QueryPci, QueryStatus, QueryVersion, Return, StatusInfo,
+    VersionInfo,
+};
 use anyhow::{anyhow, Result};
 use serde_json::{json, Value};
 use std::{
-    io::{prelude::*, BufReader},
-    os::unix::net::UnixStream,
+    collections::HashMap,
     path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
 };
 
+// how long a command may take to be answered before it is given up on
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a job, such as a snapshot, may take to finish; these write the whole VM out to disk
+pub const DEFAULT_JOB_TIMEOUT: Duration = Duration::from_secs(600);
+
+const EVENT_BUFFER: usize = 64;
+const SNAPSHOT_JOB_ID: &str = "snapshot";
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Value>>>>;
+
+//
+// a connection to the QMP monitor of a VM. commands are tagged with an id, which qemu repeats in
+// its reply, so any number of them can be in flight at once; a single task reads everything that
+// comes back and hands each reply to the command that is waiting for it. events are sent to
+// whoever is subscribed with events().
+//
+
 pub struct Client {
-    output: UnixStream,
-    input: BufReader<UnixStream>,
+    output: tokio::sync::Mutex<OwnedWriteHalf>,
+    pending: Pending,
+    // only the reader holds on to the sender, so subscribers see the channel close with it
+    events: broadcast::WeakSender<EventMessage>,
+    next_id: AtomicU64,
+    reader: JoinHandle<()>,
+    timeout: Duration,
+    job_timeout: Duration,
 }
 
-impl Client {
-    pub fn new(us: PathBuf) -> std::io::Result<Self> {
-        let stream = UnixStream::connect(us)?;
-        return Ok(Self {
-            output: stream.try_clone()?,
-            input: BufReader::new(stream),
-        });
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.reader.abort();
     }
+}
 
-    fn read_input<T>(&mut self) -> Result<T>
-    where
-        T: for<'de> serde::Deserialize<'de> + Default + std::fmt::Debug,
-    {
-        let mut buf = String::new();
-        while let Ok(_) = self.input.read_line(&mut buf) {
-            if buf.ends_with("\r\n}\r\n") {
-                match serde_json::from_str::<T>(&buf) {
-                    Ok(obj) => {
-                        return Ok(obj);
-                    }
-                    Err(e) => {
-                        // incoming event, ignore it and retry
-                        if let Ok(_) = serde_json::from_str::<Event>(&buf) {
-                            buf = String::new();
-                        } else if let Ok(e) = serde_json::from_str::<ErrorReturn>(&buf) {
-                            // got an error, return it
-                            return Err(e.into());
-                        } else if let Ok(ret) = serde_json::from_str::<GenericReturn>(&buf) {
-                            return ret.into();
-                        } else {
-                            // return the original error
-                            return Err(e.into());
-                        }
-                    }
+// pulls every complete message off the front of the buffer, leaving any partial one behind
+fn take_messages(buf: &mut Vec<u8>) -> Result<Vec<Value>> {
+    let mut messages = Vec::new();
+    let mut stream = serde_json::Deserializer::from_slice(buf).into_iter::<Value>();
+
+    loop {
+        match stream.next() {
+            Some(Ok(message)) => messages.push(message),
+            Some(Err(e)) if e.is_eof() => break,
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
+        }
+    }
+
+    let consumed = stream.byte_offset();
+    buf.drain(..consumed);
+    Ok(messages)
+}
+
+async fn read_greeting(input: &mut OwnedReadHalf, buf: &mut Vec<u8>) -> Result<()> {
+    loop {
+        let mut chunk = [0u8; 4096];
+        let n = input.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("QMP monitor closed before greeting"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        // the greeting is always the first thing sent, and only ever once
+        let mut stream = serde_json::Deserializer::from_slice(buf).into_iter::<Value>();
+        match stream.next() {
+            Some(Ok(greeting)) => {
+                if greeting.get("QMP").is_none() {
+                    return Err(anyhow!("QMP monitor sent {} instead of greeting", greeting));
                 }
+                let consumed = stream.byte_offset();
+                buf.drain(..consumed);
+                return Ok(());
             }
+            Some(Err(e)) if e.is_eof() => {}
+            Some(Err(e)) => return Err(e.into()),
+            None => {}
         }
-
-        return Err(anyhow!("Read past end of input"));
     }
+}
 
-    fn send_output(&mut self, val: Value) -> Result<()> {
-        match self.output.write_all(&val.to_string().as_bytes()) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(e)),
+async fn read_messages(
+    mut input: OwnedReadHalf,
+    mut buf: Vec<u8>,
+    pending: Pending,
+    events: broadcast::Sender<EventMessage>,
+) {
+    loop {
+        let Ok(messages) = take_messages(&mut buf) else {
+            break;
+        };
+
+        for message in messages {
+            if message.get("event").is_some() {
+                if let Ok(event) = serde_json::from_value::<EventMessage>(message) {
+                    // nobody listening is fine
+                    let _ = events.send(event);
+                }
+            } else if let Some(id) = message.get("id").and_then(Value::as_u64) {
+                let waiting = pending.lock().unwrap().remove(&id);
+                if let Some(waiting) = waiting {
+                    let _ = waiting.send(message);
+                }
+            }
+        }
+
+        let mut chunk = [0u8; 4096];
+        match input.read(&mut chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
         }
     }
 
-    pub fn handshake(&mut self) -> Result<()> {
-        // read_input hangs if the type isn't specified
-        self.read_input::<Event>()?;
-        Ok(())
+    // dropping whatever is still waiting tells it the monitor went away
+    pending.lock().unwrap().clear();
+}
+
+impl Client {
+    pub async fn connect(us: PathBuf) -> Result<Self> {
+        Self::connect_with_timeout(us, DEFAULT_COMMAND_TIMEOUT).await
+    }
+
+    pub async fn connect_with_timeout(us: PathBuf, timeout: Duration) -> Result<Self> {
+        let stream = UnixStream::connect(us).await?;
+        let (mut input, output) = stream.into_split();
+
+        let mut buf = Vec::new();
+        tokio::time::timeout(timeout, read_greeting(&mut input, &mut buf))
+            .await
+            .map_err(|_| anyhow!("QMP monitor did not greet within {:?}", timeout))??;
+
+        let pending = Pending::default();
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let weak = events.downgrade();
+        let reader = tokio::spawn(read_messages(input, buf, pending.clone(), events));
+
+        let client = Self {
+            output: tokio::sync::Mutex::new(output),
+            pending,
+            events: weak,
+            next_id: AtomicU64::new(0),
+            reader,
+            timeout,
+            job_timeout: DEFAULT_JOB_TIMEOUT,
+        };
+
+        client
+            .send_command::<GenericReturn>("qmp_capabilities", None)
+            .await?;
+        Ok(client)
+    }
+
+    pub fn with_job_timeout(mut self, timeout: Duration) -> Self {
+        self.job_timeout = timeout;
+        self
     }
 
-    pub fn parsed_reply(&mut self) -> Result<GenericReturn> {
-        self.read_input()
+    // events sent by qemu from now on. the receiver is closed when the monitor goes away.
+    pub fn events(&self) -> broadcast::Receiver<EventMessage> {
+        match self.events.upgrade() {
+            Some(events) => events.subscribe(),
+            None => broadcast::channel(1).1,
+        }
     }
 
-    pub fn send_command<T>(&mut self, execute: &str, args: Option<Value>) -> Result<T>
+    pub async fn send_command<T>(&self, execute: &str, args: Option<Value>) -> Result<T>
     where
         T: for<'de> serde::Deserialize<'de> + Default + std::fmt::Debug,
     {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        let mut command = json!({
+            "execute": execute,
+            "id": id,
+        });
         if let Some(args) = args {
-            self.send_output(json!({
-                "execute": execute,
-                "arguments": args,
-            }))?;
-        } else {
-            self.send_output(json!({
-                "execute": execute,
-            }))?;
+            command["arguments"] = args;
+        }
+
+        let (s, r) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, s);
+
+        let res = tokio::time::timeout(self.timeout, async {
+            self.output
+                .lock()
+                .await
+                .write_all(command.to_string().as_bytes())
+                .await?;
+            r.await
+                .map_err(|_| anyhow!("QMP monitor closed before answering {}", execute))
+        })
+        .await;
+
+        let reply = match res {
+            Ok(reply) => reply?,
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                return Err(anyhow!(
+                    "{} was not answered within {:?}",
+                    execute,
+                    self.timeout
+                ));
+            }
+        };
+
+        if let Some(error) = reply.get("error") {
+            return Err(anyhow!(
+                "{}",
+                serde_json::from_value::<ErrorDetail>(error.clone())?
+            ));
         }
 
-        self.read_input()
+        Ok(serde_json::from_value(reply)?)
     }
 
-    pub fn block_devices(&mut self) -> Result<QueryBlock> {
-        self.send_command("query-block", None)
+    pub async fn execute<C: Command>(&self, command: &C) -> Result<C::Response> {
+        let args = match serde_json::to_value(command)? {
+            Value::Object(args) if !args.is_empty() => Some(Value::Object(args)),
+            _ => None,
+        };
+
+        Ok(self
+            .send_command::<Return<C::Response>>(C::NAME, args)
+            .await?
+            .result)
+    }
+
+    pub async fn status(&self) -> Result<StatusInfo> {
+        self.execute(&QueryStatus).await
+    }
+
+    pub async fn cpus(&self) -> Result<Vec<CpuInfoFast>> {
+        self.execute(&QueryCpusFast).await
+    }
+
+    pub async fn memory_size_summary(&self) -> Result<MemoryInfo> {
+        self.execute(&QueryMemorySizeSummary).await
     }
 
-    pub fn jobs(&mut self) -> Result<QueryJobs> {
-        self.send_command("query-jobs", None)
+    pub async fn block_stats(&self) -> Result<Vec<BlockStats>> {
+        self.execute(&QueryBlockstats::default()).await
     }
 
-    pub fn disk_nodes(&mut self) -> Result<Vec<String>> {
-        let blocks = self.block_devices()?.result;
+    pub async fn name(&self) -> Result<NameInfo> {
+        self.execute(&QueryName).await
+    }
+
+    pub async fn version(&self) -> Result<VersionInfo> {
+        self.execute(&QueryVersion).await
+    }
+
+    pub async fn pci(&self) -> Result<Vec<PciInfo>> {
+        self.execute(&QueryPci).await
+    }
+
+    pub async fn block_devices(&self) -> Result<QueryBlock> {
+        self.send_command("query-block", None).await
+    }
+
+    pub async fn jobs(&self) -> Result<QueryJobs> {
+        self.send_command("query-jobs", None).await
+    }
+
+    pub async fn disk_nodes(&self) -> Result<Vec<String>> {
+        let blocks = self.block_devices().await?.result;
 
         let mut disks = Vec::new();
 
@@ -112,116 +303,328 @@ impl Client {
         Ok(disks)
     }
 
-    pub fn wait_for_job(&mut self, id: &str) -> Result<JobInfo> {
-        loop {
-            let res = self.jobs();
-
-            if let Ok(jobs) = res {
-                for job in jobs.result {
-                    if job.id == id {
-                        match job.status.as_str() {
-                            "concluded" | "null" => {
-                                if let Some(error) = job.error {
-                                    self.delete_job(id)?;
-                                    return Err(anyhow!(error));
-                                } else {
-                                    self.delete_job(id)?;
-                                    return Ok(job);
-                                }
-                            }
-                            _ => {}
-                        }
-                        break;
-                    }
-                }
-            } else if let Err(e) = res {
-                self.delete_job(id)?;
-                return Err(e);
-            }
-
-            std::thread::sleep(std::time::Duration::new(0, 200))
-        }
+    pub async fn dismiss_job(&self, id: &str) -> Result<()> {
+        self.send_command::<GenericReturn>("job-dismiss", Some(json!({"id": id})))
+            .await?;
+        Ok(())
     }
 
-    pub fn delete_job(&mut self, id: &str) -> Result<()> {
-        loop {
-            let mut found = false;
-            let res = self.send_command::<QueryJobs>("job-dismiss", Some(json!({"id": id})));
-            if let Ok(jobs) = res {
-                for job in &jobs.result {
-                    if job.id == id {
-                        found = true;
+    // waits for qemu to report the job as concluded, then collects its result and dismisses it.
+    // events can be missed if the job finishes before the caller subscribed, so subscribe before
+    // starting the job.
+    async fn wait_for_job(
+        &self,
+        events: &mut broadcast::Receiver<EventMessage>,
+        id: &str,
+    ) -> Result<()> {
+        let concluded = async {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(anyhow!("QMP monitor closed while job {} was running", id))
                     }
-                }
-            } else {
-                break;
-            }
+                };
 
-            if !found {
-                break;
+                match event.parsed() {
+                    Event::JobStatusChange(data) if data.id == id && data.status == "concluded" => {
+                        return Ok(())
+                    }
+                    _ => {}
+                }
             }
-        }
+        };
 
-        Ok(())
+        tokio::time::timeout(self.job_timeout, concluded)
+            .await
+            .map_err(|_| anyhow!("job {} did not finish within {:?}", id, self.job_timeout))??;
+        self.job_result(id).await
     }
 
-    fn cleanup_job(&mut self, res: Result<GenericReturn, anyhow::Error>, id: &str) -> Result<()> {
-        if let Err(e) = self.wait_for_job(id) {
-            self.delete_job(id)?;
-            return Err(e);
+    // a concluded job stays around, holding its error if it failed, until it is dismissed
+    async fn job_result(&self, id: &str) -> Result<()> {
+        let error = self
+            .jobs()
+            .await?
+            .result
+            .into_iter()
+            .find(|job| job.id == id)
+            .and_then(|job| job.error);
+
+        self.dismiss_job(id).await?;
+
+        match error {
+            Some(error) => Err(anyhow!(error)),
+            None => Ok(()),
         }
+    }
 
-        if let Err(e) = res {
-            return Err(e);
+    async fn run_job(&self, execute: &str, args: Value) -> Result<()> {
+        let mut events = self.events();
+        self.send_command::<GenericReturn>(execute, Some(args))
+            .await?;
+
+        let res = self.wait_for_job(&mut events, SNAPSHOT_JOB_ID).await;
+        if res.is_err() {
+            // a job that timed out has to be cancelled before it can be dismissed
+            let _ = self
+                .send_command::<GenericReturn>("job-cancel", Some(json!({"id": SNAPSHOT_JOB_ID})))
+                .await;
         }
-
-        Ok(())
+        res
     }
 
-    pub fn snapshot_save(&mut self, name: &str) -> Result<()> {
-        let disks = self.disk_nodes()?;
+    pub async fn snapshot_save(&self, name: &str) -> Result<()> {
+        let disks = self.disk_nodes().await?;
+        // the state of the VM is kept on its first disk
+        let vmstate = disks
+            .first()
+            .ok_or_else(|| anyhow!("VM has no disk to save snapshot {} to", name))?;
 
-        let res = self.send_command::<GenericReturn>(
+        self.run_job(
             "snapshot-save",
-            Some(json!({
-                "job-id": "snapshot",
+            json!({
+                "job-id": SNAPSHOT_JOB_ID,
                 "tag": name,
-                "vmstate": disks[0],
+                "vmstate": vmstate,
                 "devices": disks,
-            })),
-        );
-
-        self.cleanup_job(res, "snapshot")
+            }),
+        )
+        .await
     }
 
-    pub fn snapshot_load(&mut self, name: &str) -> Result<()> {
-        let disks = self.disk_nodes()?;
+    pub async fn snapshot_load(&self, name: &str) -> Result<()> {
+        let disks = self.disk_nodes().await?;
+        // the state of the VM is kept on its first disk
+        let vmstate = disks
+            .first()
+            .ok_or_else(|| anyhow!("VM has no disk to load snapshot {} from", name))?;
 
-        let res = self.send_command::<GenericReturn>(
+        self.run_job(
             "snapshot-load",
-            Some(json!({
-                "job-id": "snapshot",
+            json!({
+                "job-id": SNAPSHOT_JOB_ID,
                 "tag": name,
-                "vmstate": disks[0],
+                "vmstate": vmstate,
                 "devices": disks,
-            })),
-        );
-
-        self.cleanup_job(res, "snapshot")
+            }),
+        )
+        .await
     }
 
-    pub fn snapshot_delete(&mut self, name: &str) -> Result<()> {
-        let disks = self.disk_nodes()?;
+    pub async fn snapshot_delete(&self, name: &str) -> Result<()> {
+        let disks = self.disk_nodes().await?;
 
-        let res = self.send_command::<GenericReturn>(
+        self.run_job(
             "snapshot-delete",
-            Some(json!({
-                "job-id": "snapshot",
+            json!({
+                "job-id": SNAPSHOT_JOB_ID,
                 "tag": name,
                 "devices": disks,
-            })),
+            }),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmp::testing::{error, event, reply, FakeQmp};
+
+    fn job_event(status: &str) -> Value {
+        event(
+            "JOB_STATUS_CHANGE",
+            json!({ "id": SNAPSHOT_JOB_ID, "status": status }),
+        )
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qmp.sock");
+        let qmp = FakeQmp::start(&path, |command| {
+            match command["execute"].as_str().unwrap() {
+                // events in front of a reply are not mistaken for it
+                "query-block" => vec![
+                    event("RESUME", json!({})),
+                    reply(json!([{ "device": "hd0", "inserted": { "node-name": "#block1" } }])),
+                ],
+                "query-jobs" => vec![reply(json!([]))],
+                "hang" => vec![],
+                "broken" => vec![error("it broke")],
+                _ => vec![reply(json!({}))],
+            }
+        });
+
+        let client = Client::connect_with_timeout(path, Duration::from_millis(500))
+            .await
+            .unwrap();
+
+        let (disks, jobs) = tokio::join!(client.disk_nodes(), client.jobs());
+        assert_eq!(disks.unwrap(), vec!["#block1".to_string()]);
+        assert!(jobs.unwrap().result.is_empty());
+
+        let e = client
+            .send_command::<GenericReturn>("broken", None)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "GenericError: it broke");
+
+        let e = client
+            .send_command::<GenericReturn>("hang", None)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("not answered"));
+
+        // a command that timed out doesn't get in the way of the next one
+        client
+            .send_command::<GenericReturn>("system_powerdown", None)
+            .await
+            .unwrap();
+
+        // every command was sent with an id of its own
+        let commands = qmp.commands.lock().unwrap().clone();
+        let mut ids = commands
+            .iter()
+            .map(|command| command["id"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), commands.len());
+    }
+
+    #[tokio::test]
+    async fn typed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qmp.sock");
+        let qmp = FakeQmp::start(&path, |command| {
+            vec![crate::qmp::testing::introspection(command).unwrap_or_else(|| reply(json!({})))]
+        });
+
+        let client = Client::connect(path).await.unwrap();
+
+        let status = client.status().await.unwrap();
+        assert!(status.running);
+        assert_eq!(status.status, "running");
+        assert_eq!(client.name().await.unwrap().name.unwrap(), "plex");
+        assert_eq!(
+            client.version().await.unwrap().to_string(),
+            "9.2.0 (Debian 1:9.2.0)"
+        );
+        assert_eq!(
+            client
+                .cpus()
+                .await
+                .unwrap()
+                .iter()
+                .map(|cpu| cpu.thread_id)
+                .collect::<Vec<_>>(),
+            vec![1234, 1235]
+        );
+
+        let memory = client.memory_size_summary().await.unwrap();
+        assert_eq!(memory.base_memory, 4294967296);
+        assert_eq!(memory.plugged_memory, None);
+
+        let disks = client.block_stats().await.unwrap();
+        assert_eq!(disks[0].name(), "#block123");
+        assert_eq!(disks[0].stats.wr_bytes, 2048);
+
+        let pci = client.pci().await.unwrap();
+        assert_eq!(pci[0].devices[1].flatten().len(), 2);
+
+        client
+            .execute(&QueryBlockstats {
+                query_nodes: Some(true),
+            })
+            .await
+            .unwrap();
+
+        // arguments are only sent for commands that have any
+        let commands = qmp.commands.lock().unwrap().clone();
+        assert!(commands[0].get("arguments").is_none());
+        assert_eq!(
+            commands.last().unwrap()["arguments"],
+            json!({ "query-nodes": true })
         );
+    }
+
+    #[tokio::test]
+    async fn jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qmp.sock");
+        let tag = Arc::new(Mutex::new(Value::Null));
+        let qmp = FakeQmp::start(&path, move |command| {
+            let execute = command["execute"].as_str().unwrap();
+            if execute.starts_with("snapshot-") {
+                *tag.lock().unwrap() = command["arguments"]["tag"].clone();
+            }
+            let tag = tag.lock().unwrap().clone();
+
+            match execute {
+                "query-block" => vec![reply(
+                    json!([{ "device": "hd0", "inserted": { "node-name": "#block1" } }]),
+                )],
+                "snapshot-save" if tag == "slow" => {
+                    vec![reply(json!({})), job_event("created"), job_event("running")]
+                }
+                "snapshot-save" | "snapshot-delete" | "snapshot-load" => vec![
+                    reply(json!({})),
+                    job_event("created"),
+                    job_event("running"),
+                    job_event("concluded"),
+                ],
+                "query-jobs" => vec![reply(json!([{
+                    "id": SNAPSHOT_JOB_ID,
+                    "type": execute,
+                    "status": "concluded",
+                    "current-progress": 1,
+                    "total-progress": 1,
+                    "error": (tag == "old").then_some("Snapshot 'old' is corrupt"),
+                }]))],
+                _ => vec![reply(json!({}))],
+            }
+        });
+
+        let client = Client::connect(path)
+            .await
+            .unwrap()
+            .with_job_timeout(Duration::from_millis(500));
+
+        client.snapshot_save("new").await.unwrap();
+        assert_eq!(
+            qmp.executed(),
+            vec!["query-block", "snapshot-save", "query-jobs", "job-dismiss"]
+        );
+
+        client.snapshot_delete("new").await.unwrap();
+
+        // the job reports its error when it concludes
+        let e = client.snapshot_load("old").await.unwrap_err();
+        assert_eq!(e.to_string(), "Snapshot 'old' is corrupt");
+
+        // a job that never concludes is given up on and cancelled
+        let e = client.snapshot_save("slow").await.unwrap_err();
+        assert!(e.to_string().contains("did not finish"));
+        assert_eq!(qmp.executed().last().unwrap(), "job-cancel");
+    }
+
+    #[tokio::test]
+    async fn no_disks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qmp.sock");
+        let qmp = FakeQmp::start(&path, |command| {
+            match command["execute"].as_str().unwrap() {
+                "query-block" => vec![reply(json!([{ "device": "cd0" }]))],
+                _ => vec![reply(json!({}))],
+            }
+        });
 
-        self.cleanup_job(res, "snapshot")
+        let client = Client::connect(path).await.unwrap();
+        assert!(client.snapshot_save("new").await.is_err());
+        assert!(client.snapshot_load("old").await.is_err());
+        assert_eq!(qmp.executed(), vec!["query-block", "query-block"]);
     }
 }
@@ -0,0 +1,129 @@
+use super::{Command, CpuInstanceProperties};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+//
+// commands that change what a running VM has: vCPUs, memory and disks, added and removed while
+// it runs, and the balloon, which gives memory back to the host or lets the guest use more.
+//
+
+// the reply of a command that only does something
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Empty {}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryHotpluggableCpus;
+
+impl Command for QueryHotpluggableCpus {
+    const NAME: &'static str = "query-hotpluggable-cpus";
+    type Response = Vec<HotpluggableCpu>;
+}
+
+// a place for a vCPU; qom-path is only set once there is one in it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HotpluggableCpu {
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub vcpus_count: u64,
+    pub props: CpuInstanceProperties,
+    pub qom_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryBalloon;
+
+impl Command for QueryBalloon {
+    const NAME: &'static str = "query-balloon";
+    type Response = BalloonInfo;
+}
+
+// the memory the guest has, in bytes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BalloonInfo {
+    pub actual: u64,
+}
+
+// asks the guest to use this much memory, in bytes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Balloon {
+    pub value: u64,
+}
+
+impl Command for Balloon {
+    const NAME: &'static str = "balloon";
+    type Response = Empty;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceAdd {
+    pub driver: String,
+    pub id: String,
+    #[serde(flatten)]
+    pub properties: Map<String, Value>,
+}
+
+impl Command for DeviceAdd {
+    const NAME: &'static str = "device_add";
+    type Response = Empty;
+}
+
+// asks the guest to let go of the device; it is gone once the guest agrees
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceDel {
+    pub id: String,
+}
+
+impl Command for DeviceDel {
+    const NAME: &'static str = "device_del";
+    type Response = Empty;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ObjectAdd {
+    pub qom_type: String,
+    pub id: String,
+    #[serde(flatten)]
+    pub properties: Map<String, Value>,
+}
+
+impl Command for ObjectAdd {
+    const NAME: &'static str = "object-add";
+    type Response = Empty;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ObjectDel {
+    pub id: String,
+}
+
+impl Command for ObjectDel {
+    const NAME: &'static str = "object-del";
+    type Response = Empty;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlockdevAdd {
+    pub driver: String,
+    pub node_name: String,
+    #[serde(flatten)]
+    pub properties: Map<String, Value>,
+}
+
+impl Command for BlockdevAdd {
+    const NAME: &'static str = "blockdev-add";
+    type Response = Empty;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlockdevDel {
+    pub node_name: String,
+}
+
+impl Command for BlockdevDel {
+    const NAME: &'static str = "blockdev-del";
+    type Response = Empty;
+}
@@ -1,4 +1,9 @@
 mod block;
+mod hotplug;
+mod query;
+
+pub use hotplug::*;
+pub use query::*;
 
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
@@ -51,12 +56,13 @@ pub struct JobInfo {
     pub error: Option<String>,
 }
 
+// an event as qemu sends it; parsed() says what it means
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
-pub struct Event {
+pub struct EventMessage {
     pub timestamp: Option<Timestamp>,
     pub event: String,
-    pub data: Option<EventData>,
+    pub data: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -66,13 +72,103 @@ pub struct Timestamp {
     pub microseconds: u64,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Shutdown(ShutdownData),
+    Reset(ShutdownData),
+    Stop,
+    Resume,
+    BlockIoError(BlockIoErrorData),
+    GuestPanicked(GuestPanickedData),
+    JobStatusChange(JobStatusData),
+    // anything else qemu has to say, or an event whose data could not be read
+    Other(String),
+}
+
+// the data of SHUTDOWN and RESET: whether the guest asked for it, and why
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ShutdownData {
+    pub guest: bool,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlockIoErrorData {
+    pub device: String,
+    pub node_name: Option<String>,
+    pub operation: String,
+    pub action: String,
+    pub nospace: Option<bool>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GuestPanickedData {
+    pub action: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
-pub struct EventData {
+pub struct JobStatusData {
     pub status: String,
     pub id: String,
 }
 
+impl EventMessage {
+    fn data<T>(&self) -> Option<T>
+    where
+        T: for<'de> serde::Deserialize<'de>,
+    {
+        serde_json::from_value(self.data.clone()?).ok()
+    }
+
+    pub fn parsed(&self) -> Event {
+        let event = match self.event.as_str() {
+            "SHUTDOWN" => self.data().map(Event::Shutdown),
+            "RESET" => self.data().map(Event::Reset),
+            "STOP" => Some(Event::Stop),
+            "RESUME" => Some(Event::Resume),
+            "BLOCK_IO_ERROR" => self.data().map(Event::BlockIoError),
+            "GUEST_PANICKED" => self.data().map(Event::GuestPanicked),
+            "JOB_STATUS_CHANGE" => self.data().map(Event::JobStatusChange),
+            _ => None,
+        };
+
+        event.unwrap_or_else(|| Event::Other(self.event.clone()))
+    }
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let by = |guest: bool| if guest { "guest" } else { "host" };
+
+        match self {
+            Event::Shutdown(data) => write!(f, "shut down by {}: {}", by(data.guest), data.reason),
+            Event::Reset(data) => write!(f, "reset by {}: {}", by(data.guest), data.reason),
+            Event::Stop => f.write_str("stopped"),
+            Event::Resume => f.write_str("resumed"),
+            Event::BlockIoError(data) => write!(
+                f,
+                "{} error on {}: {} ({})",
+                data.operation,
+                // disks added with -blockdev have no device name
+                match (&data.node_name, data.device.as_str()) {
+                    (Some(node), "") => node,
+                    _ => &data.device,
+                },
+                data.reason,
+                data.action
+            ),
+            Event::GuestPanicked(data) => write!(f, "guest panicked ({})", data.action),
+            Event::JobStatusChange(data) => write!(f, "job {} is {}", data.id, data.status),
+            Event::Other(name) => f.write_str(name),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct ErrorReturn {
@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+
+//
+// commands that ask qemu about the VM, each with the reply it gets. the command is what is sent
+// as the arguments, so those without arguments are empty.
+//
+
+pub trait Command: Serialize {
+    const NAME: &'static str;
+    type Response: for<'de> Deserialize<'de> + Default + std::fmt::Debug;
+}
+
+// the reply to a command, as qemu wraps it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Return<T> {
+    #[serde(rename = "return")]
+    pub result: T,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryStatus;
+
+impl Command for QueryStatus {
+    const NAME: &'static str = "query-status";
+    type Response = StatusInfo;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StatusInfo {
+    pub running: bool,
+    // qemu's RunState, e.g. running, paused or shutdown
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryCpusFast;
+
+impl Command for QueryCpusFast {
+    const NAME: &'static str = "query-cpus-fast";
+    type Response = Vec<CpuInfoFast>;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CpuInfoFast {
+    pub cpu_index: u64,
+    pub qom_path: String,
+    // the host thread running this vCPU
+    pub thread_id: u64,
+    pub props: Option<CpuInstanceProperties>,
+    pub target: Option<String>,
+}
+
+// where a vCPU sits; also what device_add is given to put one there
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CpuInstanceProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub die_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub core_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryMemorySizeSummary;
+
+impl Command for QueryMemorySizeSummary {
+    const NAME: &'static str = "query-memory-size-summary";
+    type Response = MemoryInfo;
+}
+
+// in bytes; plugged memory is what was added since boot, and is missing without any
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MemoryInfo {
+    pub base_memory: u64,
+    pub plugged_memory: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QueryBlockstats {
+    // report every block node instead of only the disks the guest sees
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_nodes: Option<bool>,
+}
+
+impl Command for QueryBlockstats {
+    const NAME: &'static str = "query-blockstats";
+    type Response = Vec<BlockStats>;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlockStats {
+    pub device: Option<String>,
+    pub qdev: Option<String>,
+    pub node_name: Option<String>,
+    pub stats: BlockDeviceStats,
+    pub parent: Option<Box<BlockStats>>,
+    pub backing: Option<Box<BlockStats>>,
+}
+
+impl BlockStats {
+    // what the disk is called: its device, or its node for disks added with -blockdev
+    pub fn name(&self) -> String {
+        match (&self.device, &self.node_name) {
+            (Some(device), _) if !device.is_empty() => device.clone(),
+            (_, Some(node)) => node.clone(),
+            _ => self.qdev.clone().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", default)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+    pub rd_total_time_ns: u64,
+    pub wr_total_time_ns: u64,
+    pub flush_total_time_ns: u64,
+    pub failed_rd_operations: u64,
+    pub failed_wr_operations: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryName;
+
+impl Command for QueryName {
+    const NAME: &'static str = "query-name";
+    type Response = NameInfo;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NameInfo {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryVersion;
+
+impl Command for QueryVersion {
+    const NAME: &'static str = "query-version";
+    type Response = VersionInfo;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VersionInfo {
+    pub qemu: VersionTriple,
+    pub package: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VersionTriple {
+    pub major: u64,
+    pub minor: u64,
+    pub micro: u64,
+}
+
+impl std::fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.qemu.major, self.qemu.minor, self.qemu.micro
+        )?;
+
+        if !self.package.trim().is_empty() {
+            write!(f, " ({})", self.package.trim())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryPci;
+
+impl Command for QueryPci {
+    const NAME: &'static str = "query-pci";
+    type Response = Vec<PciInfo>;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PciInfo {
+    pub bus: u64,
+    pub devices: Vec<PciDeviceInfo>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PciDeviceInfo {
+    pub bus: u64,
+    pub slot: u64,
+    pub function: u64,
+    pub class_info: PciDeviceClass,
+    pub id: PciDeviceId,
+    pub irq: Option<u64>,
+    pub qdev_id: String,
+    pub pci_bridge: Option<PciBridgeInfo>,
+}
+
+impl PciDeviceInfo {
+    // this device and any behind it, if it is a bridge
+    pub fn flatten(&self) -> Vec<PciDeviceInfo> {
+        let mut devices = vec![self.clone()];
+
+        if let Some(bridge) = &self.pci_bridge {
+            for device in bridge.devices.iter().flatten() {
+                devices.append(&mut device.flatten());
+            }
+        }
+
+        devices
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PciDeviceClass {
+    pub desc: Option<String>,
+    pub class: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PciDeviceId {
+    pub device: u64,
+    pub vendor: u64,
+    pub subsystem: Option<u64>,
+    pub subsystem_vendor: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PciBridgeInfo {
+    pub devices: Option<Vec<PciDeviceInfo>>,
+}
@@ -3,3 +3,7 @@
 
 pub mod client;
 pub mod messages;
+pub mod session;
+
+#[cfg(test)]
+pub mod testing;
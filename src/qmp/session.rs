@@ -0,0 +1,213 @@
+use super::{
+    client::Client,
+    messages::{Event, EventMessage},
+};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, warn};
+
+// how long to wait before trying a monitor again, when it is gone while someone is watching
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+const EVENT_BUFFER: usize = 64;
+
+//
+// the QMP sessions charond keeps open, one per VM, so that what a VM reports is seen as it
+// happens instead of only while a command waits for its reply. every event is written to the
+// log and passed on to whoever is watching the VM. a session is kept for as long as its VM is
+// installed, waiting for the VM whenever it isn't running; one opened only for a watcher lasts
+// as long as the VM runs, or while someone still watches.
+//
+
+#[derive(Debug, Clone)]
+struct Session {
+    events: broadcast::Sender<EventMessage>,
+    kept: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<PathBuf, Session>>>,
+}
+
+impl Sessions {
+    // the events of the VM with its monitor at this path, from now on. the session is opened if
+    // it isn't already; title is how the VM is named in the log.
+    pub fn watch(&self, title: &str, monitor: PathBuf) -> broadcast::Receiver<EventMessage> {
+        self.session(title, monitor, false)
+    }
+
+    // keeps the session of an installed VM until it is closed, whether or not anyone watches
+    pub fn open(&self, title: &str, monitor: PathBuf) {
+        self.session(title, monitor, true);
+    }
+
+    // the session ends when the VM stops and nobody is watching any more
+    pub fn close(&self, monitor: &PathBuf) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(monitor) {
+            session.kept = false;
+        }
+    }
+
+    // subscribes under the lock, so the session can't go away before the watcher is counted
+    fn session(
+        &self,
+        title: &str,
+        monitor: PathBuf,
+        kept: bool,
+    ) -> broadcast::Receiver<EventMessage> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        if let Some(session) = sessions.get_mut(&monitor) {
+            session.kept |= kept;
+            return session.events.subscribe();
+        }
+
+        let (events, r) = broadcast::channel(EVENT_BUFFER);
+        sessions.insert(
+            monitor.clone(),
+            Session {
+                events: events.clone(),
+                kept,
+            },
+        );
+        tokio::spawn(run(self.clone(), title.to_string(), monitor, events));
+        r
+    }
+
+    pub fn is_open(&self, monitor: &PathBuf) -> bool {
+        self.sessions.lock().unwrap().contains_key(monitor)
+    }
+}
+
+fn log(title: &str, event: &Event) {
+    match event {
+        Event::BlockIoError(_) | Event::GuestPanicked(_) => error!("VM {} {}", title, event),
+        Event::Shutdown(_) | Event::Reset(_) | Event::Stop | Event::Resume => {
+            info!("VM {} {}", title, event)
+        }
+        Event::JobStatusChange(_) | Event::Other(_) => debug!("VM {}: {}", title, event),
+    }
+}
+
+async fn run(
+    sessions: Sessions,
+    title: String,
+    monitor: PathBuf,
+    events: broadcast::Sender<EventMessage>,
+) {
+    loop {
+        if let Ok(client) = Client::connect(monitor.clone()).await {
+            info!("Watching events of VM {}", title);
+
+            let mut incoming = client.events();
+            loop {
+                match incoming.recv().await {
+                    Ok(event) => {
+                        log(&title, &event.parsed());
+                        // nobody watching is fine, the log has it
+                        let _ = events.send(event);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Missed {} events of VM {}", missed, title)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+
+            info!("Stopped watching events of VM {}", title);
+        }
+
+        {
+            // under the lock, so nobody starts watching a session that is going away
+            let mut all = sessions.sessions.lock().unwrap();
+            let kept = all.get(&monitor).is_some_and(|session| session.kept);
+            if !kept && events.receiver_count() == 0 {
+                all.remove(&monitor);
+                return;
+            }
+        }
+
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmp::testing::{event, reply, FakeQmp};
+    use serde_json::json;
+
+    #[tokio::test(start_paused = true)]
+    async fn kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let monitor = dir.path().join("qmp.sock");
+        let sessions = Sessions::default();
+
+        // nobody is watching and the VM isn't running yet, and the session waits for it
+        sessions.open("vm", monitor.clone());
+        tokio::time::sleep(RECONNECT_INTERVAL * 3).await;
+        assert!(sessions.is_open(&monitor));
+
+        // watching doesn't take that away
+        drop(sessions.watch("vm", monitor.clone()));
+        tokio::time::sleep(RECONNECT_INTERVAL * 2).await;
+        assert!(sessions.is_open(&monitor));
+
+        sessions.close(&monitor);
+        tokio::time::sleep(RECONNECT_INTERVAL * 2).await;
+        assert!(!sessions.is_open(&monitor));
+    }
+
+    #[tokio::test]
+    async fn sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let monitor = dir.path().join("qmp.sock");
+        let sessions = Sessions::default();
+
+        // nothing is running and nobody is watching, so the session doesn't stay
+        drop(sessions.watch("nothing", dir.path().join("missing.sock")));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!sessions.is_open(&dir.path().join("missing.sock")));
+
+        let qmp = FakeQmp::start(&monitor, |_| vec![reply(json!({}))]);
+        let mut first = sessions.watch("vm", monitor.clone());
+        let mut second = sessions.watch("vm", monitor.clone());
+
+        // both watchers share the one session
+        while qmp.connected() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(qmp.connected(), 1);
+
+        qmp.emit(event(
+            "SHUTDOWN",
+            json!({ "guest": true, "reason": "guest-shutdown" }),
+        ));
+        qmp.emit(event("STOP", json!(null)));
+        qmp.emit(event("RTC_CHANGE", json!({ "offset": 1 })));
+
+        for watcher in [&mut first, &mut second] {
+            let shutdown = watcher.recv().await.unwrap().parsed();
+            assert_eq!(
+                shutdown,
+                Event::Shutdown(crate::qmp::messages::ShutdownData {
+                    guest: true,
+                    reason: "guest-shutdown".into(),
+                })
+            );
+            assert_eq!(shutdown.to_string(), "shut down by guest: guest-shutdown");
+            assert_eq!(watcher.recv().await.unwrap().parsed(), Event::Stop);
+            assert_eq!(
+                watcher.recv().await.unwrap().parsed(),
+                Event::Other("RTC_CHANGE".into())
+            );
+        }
+    }
+}
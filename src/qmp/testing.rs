@@ -0,0 +1,191 @@
+use serde_json::{json, Value};
+use std::{
+    io::Write,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+//
+// a stand-in for the QMP monitor of a VM. it greets each connection the way qemu does, and answers
+// each command with what the handler returns for it, in order; replies carry the id of the command,
+// as qemu's do. the commands it was sent are kept, in order, for tests to look at. events can also
+// be sent at any time with emit().
+//
+
+pub struct FakeQmp {
+    pub commands: Arc<Mutex<Vec<Value>>>,
+    // every connection, for emit(); also held while answering so nothing is written over a reply
+    connections: Arc<Mutex<Vec<UnixStream>>>,
+}
+
+fn write(stream: &mut UnixStream, value: &Value) -> std::io::Result<()> {
+    // qemu with pretty=on, which is how charon starts it
+    let out = serde_json::to_string_pretty(value)
+        .unwrap()
+        .replace('\n', "\r\n");
+    stream.write_all(format!("{}\r\n", out).as_bytes())
+}
+
+pub fn reply(value: Value) -> Value {
+    json!({ "return": value })
+}
+
+pub fn error(desc: &str) -> Value {
+    json!({ "error": { "class": "GenericError", "desc": desc } })
+}
+
+pub fn event(name: &str, data: Value) -> Value {
+    json!({
+        "timestamp": { "seconds": 1760745600, "microseconds": 0 },
+        "event": name,
+        "data": data,
+    })
+}
+
+// what a small VM answers to the commands VmInfo is made of
+pub fn introspection(command: &Value) -> Option<Value> {
+    let ret = match command["execute"].as_str()? {
+        "query-status" => json!({ "running": true, "singlestep": false, "status": "running" }),
+        "query-name" => json!({ "name": "plex" }),
+        "query-version" => json!({
+            "qemu": { "major": 9, "minor": 2, "micro": 0 },
+            "package": "Debian 1:9.2.0",
+        }),
+        "query-cpus-fast" => json!([
+            { "cpu-index": 0, "qom-path": "/machine/unattached/device[0]", "thread-id": 1234, "target": "x86_64",
+              "props": { "core-id": 0, "thread-id": 0, "socket-id": 0 } },
+            { "cpu-index": 1, "qom-path": "/machine/unattached/device[1]", "thread-id": 1235, "target": "x86_64",
+              "props": { "core-id": 0, "thread-id": 0, "socket-id": 1 } },
+        ]),
+        "query-memory-size-summary" => json!({ "base-memory": 4294967296u64 }),
+        "query-blockstats" => json!([{
+            "device": "",
+            "node-name": "#block123",
+            "qdev": "/machine/peripheral-anon/device[0]/virtio-backend",
+            "stats": {
+                "rd_bytes": 1024, "wr_bytes": 2048, "rd_operations": 2, "wr_operations": 4,
+                "flush_operations": 1, "failed_rd_operations": 0, "failed_wr_operations": 1,
+                "rd_merged": 0, "wr_merged": 0, "idle_time_ns": 5,
+            },
+        }]),
+        "query-pci" => json!([{
+            "bus": 0,
+            "devices": [
+                { "bus": 0, "slot": 0, "function": 0, "qdev_id": "",
+                  "class_info": { "desc": "Host bridge", "class": 1536 },
+                  "id": { "device": 4663, "vendor": 32902 }, "regions": [] },
+                { "bus": 0, "slot": 3, "function": 0, "qdev_id": "bridge",
+                  "class_info": { "class": 1540 },
+                  "id": { "device": 1, "vendor": 6966 }, "regions": [],
+                  "pci_bridge": {
+                    "bus": { "number": 1, "secondary": 1, "subordinate": 1 },
+                    "devices": [
+                        { "bus": 1, "slot": 1, "function": 0, "qdev_id": "disk0",
+                          "class_info": { "desc": "SCSI controller", "class": 256 },
+                          "id": { "device": 4097, "vendor": 6900 }, "regions": [] },
+                    ],
+                  } },
+            ],
+        }]),
+        _ => return None,
+    };
+
+    Some(reply(ret))
+}
+
+impl FakeQmp {
+    pub fn start<F>(path: &Path, handler: F) -> Self
+    where
+        F: Fn(&Value) -> Vec<Value> + Send + Sync + 'static,
+    {
+        let listener = UnixListener::bind(path).unwrap();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let handler = Arc::new(handler);
+
+        let inner = commands.clone();
+        let all = connections.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    return;
+                };
+                let commands = inner.clone();
+                let connections = all.clone();
+                let handler = handler.clone();
+
+                std::thread::spawn(move || {
+                    let greeting = json!({
+                        "QMP": {
+                            "version": {
+                                "qemu": { "micro": 0, "minor": 2, "major": 9 },
+                                "package": "",
+                            },
+                            "capabilities": ["oob"],
+                        }
+                    });
+                    if write(&mut stream, &greeting).is_err() {
+                        return;
+                    }
+                    connections
+                        .lock()
+                        .unwrap()
+                        .push(stream.try_clone().unwrap());
+
+                    let reader = stream.try_clone().unwrap();
+                    for command in
+                        serde_json::Deserializer::from_reader(reader).into_iter::<Value>()
+                    {
+                        let Ok(command) = command else {
+                            return;
+                        };
+
+                        let replies = if command["execute"] == "qmp_capabilities" {
+                            vec![reply(json!({}))]
+                        } else {
+                            commands.lock().unwrap().push(command.clone());
+                            handler(&command)
+                        };
+
+                        let _writing = connections.lock().unwrap();
+                        for mut reply in replies {
+                            if reply.get("event").is_none() && command.get("id").is_some() {
+                                reply["id"] = command["id"].clone();
+                            }
+                            if write(&mut stream, &reply).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        Self {
+            commands,
+            connections,
+        }
+    }
+
+    // sends an event to everyone connected
+    pub fn emit(&self, event: Value) {
+        for stream in self.connections.lock().unwrap().iter_mut() {
+            let _ = write(stream, &event);
+        }
+    }
+
+    pub fn connected(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    // the names of the commands received so far
+    pub fn executed(&self) -> Vec<String> {
+        self.commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|command| command["execute"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{PackageMetadata, PackageTitle, Registry, SearchQuery, SearchResults, SourcePackage};
+use anyhow::{anyhow, Result};
+
+//
+// several registries combined, e.g. the upstream packages and a team's private ones. they are kept
+// in priority order: a package found in more than one registry is taken from the first. a
+// qualified name such as `ours/plex` picks the registry explicitly.
+//
+
+pub const REGISTRY_SEPARATOR: char = '/';
+
+#[derive(Debug, Clone, Default)]
+pub struct Registries(pub Vec<Registry>);
+
+impl Registries {
+    pub fn get(&self, name: &str) -> Option<&Registry> {
+        self.0.iter().find(|r| r.name() == Some(name))
+    }
+
+    // splits a qualified name into the registry and package names
+    pub fn split(name: &str) -> (Option<&str>, &str) {
+        match name.split_once(REGISTRY_SEPARATOR) {
+            Some((registry, name)) => (Some(registry), name),
+            None => (None, name),
+        }
+    }
+
+    // finds the registry a package, or a version of it, comes from. returns the registry and the
+    // unqualified name of the package.
+    pub fn find(&self, name: &str, version: Option<&str>) -> Result<(Registry, String)> {
+        let (registry, unqualified) = Self::split(name);
+
+        let found = match registry {
+            Some(registry) => {
+                let found = self
+                    .get(registry)
+                    .ok_or_else(|| anyhow!("there is no registry '{}'", registry))?;
+
+                if !found.exists(unqualified, version)? {
+                    return Err(Self::not_found(name, version));
+                }
+
+                found
+            }
+            None => {
+                let mut found = None;
+
+                for registry in &self.0 {
+                    if registry.exists(unqualified, version)? {
+                        found = Some(registry);
+                        break;
+                    }
+                }
+
+                found.ok_or_else(|| Self::not_found(name, version))?
+            }
+        };
+
+        Ok((found.clone(), unqualified.to_string()))
+    }
+
+    fn not_found(name: &str, version: Option<&str>) -> anyhow::Error {
+        match version {
+            Some(version) => anyhow!("package {}-{} does not exist", name, version),
+            None => anyhow!("package {} does not exist", name),
+        }
+    }
+
+    pub fn load(&self, name: &str, version: &str) -> Result<SourcePackage> {
+        let (registry, name) = self.find(name, Some(version))?;
+        registry.load(&name, version)
+    }
+
+    // the metadata of a version of a package, or of its latest version without one
+    pub fn metadata(&self, name: &str, version: Option<&str>) -> Result<PackageMetadata> {
+        let (registry, unqualified) = self.find(name, version)?;
+
+        let versions = registry
+            .index()?
+            .packages
+            .into_iter()
+            .find(|entry| entry.title.name == unqualified)
+            .map(|entry| entry.versions)
+            .unwrap_or_default();
+
+        let version = match version {
+            Some(version) => version.to_string(),
+            None => versions
+                .first()
+                .cloned()
+                .ok_or_else(|| Self::not_found(name, None))?,
+        };
+
+        let mut metadata = registry.load(&unqualified, &version)?.metadata()?;
+        metadata.title.registry = registry.name().map(ToString::to_string);
+        metadata.versions = versions;
+        Ok(metadata)
+    }
+
+    // every package of every registry, in priority order
+    pub fn list(&self) -> Result<Vec<PackageTitle>> {
+        let mut v = Vec::new();
+
+        for registry in &self.0 {
+            v.append(&mut registry.list()?);
+        }
+
+        Ok(v)
+    }
+
+    // packages of every registry matching the query, in priority order and then by name
+    pub fn search(&self, query: &SearchQuery) -> Result<SearchResults> {
+        let mut matched = Vec::new();
+
+        for registry in &self.0 {
+            matched.extend(
+                registry
+                    .index()?
+                    .packages
+                    .into_iter()
+                    .filter(|entry| query.matches(entry)),
+            );
+        }
+
+        Ok(SearchResults {
+            total: matched.len(),
+            packages: matched
+                .into_iter()
+                .skip(query.offset)
+                .take(query.limit())
+                .collect(),
+        })
+    }
+
+    pub fn installed(&self) -> Result<Vec<PackageTitle>> {
+        let mut v = Vec::new();
+
+        for registry in &self.0 {
+            v.append(&mut registry.installed()?);
+        }
+
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Registries;
+    use crate::{PackageTitle, Registry, SourcePackage, Trust};
+
+    #[test]
+    fn resolve() {
+        let upstream = tempfile::tempdir().unwrap();
+        let ours = tempfile::tempdir().unwrap();
+
+        let registries = Registries(vec![
+            Registry::named("ours", ours.path().to_path_buf()).with_trust(Trust::unsigned()),
+            Registry::named("upstream", upstream.path().to_path_buf())
+                .with_trust(Trust::unsigned()),
+        ]);
+
+        let write = |registry: &str, name: &str, version: &str, description: &str| {
+            registries
+                .get(registry)
+                .unwrap()
+                .write(&SourcePackage {
+                    title: PackageTitle {
+                        name: name.into(),
+                        version: version.into(),
+                        ..Default::default()
+                    },
+                    description: description.into(),
+                    ..Default::default()
+                })
+                .unwrap();
+        };
+
+        write("upstream", "plex", "1.0.0", "upstream plex");
+        write("upstream", "plex", "2.0.0", "upstream plex");
+        write("upstream", "nginx", "1.0.0", "upstream nginx");
+        write("ours", "plex", "1.0.0", "our plex");
+
+        // the first registry with the version wins
+        assert_eq!(
+            registries.load("plex", "1.0.0").unwrap().description,
+            "our plex"
+        );
+        assert_eq!(
+            registries.load("plex", "2.0.0").unwrap().description,
+            "upstream plex"
+        );
+        assert_eq!(
+            registries.load("nginx", "1.0.0").unwrap().description,
+            "upstream nginx"
+        );
+
+        // qualified names pick the registry
+        assert_eq!(
+            registries
+                .load("upstream/plex", "1.0.0")
+                .unwrap()
+                .description,
+            "upstream plex"
+        );
+        assert!(registries.load("ours/plex", "2.0.0").is_err());
+        assert!(registries.load("ours/nginx", "1.0.0").is_err());
+        assert!(registries.load("theirs/plex", "1.0.0").is_err());
+        assert!(registries.load("plex", "3.0.0").is_err());
+
+        let (registry, name) = registries.find("upstream/nginx", None).unwrap();
+        assert_eq!(registry.name(), Some("upstream"));
+        assert_eq!(name, "nginx");
+
+        let list = registries.list().unwrap();
+        assert_eq!(
+            list.iter()
+                .map(|t| (
+                    t.registry.clone().unwrap(),
+                    t.name.clone(),
+                    t.version.clone()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("ours".into(), "plex".into(), "1.0.0".into()),
+                ("upstream".into(), "nginx".into(), "1.0.0".into()),
+                ("upstream".into(), "plex".into(), "2.0.0".into()),
+                ("upstream".into(), "plex".into(), "1.0.0".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn installed() {
+        let upstream = tempfile::tempdir().unwrap();
+        let ours = tempfile::tempdir().unwrap();
+
+        let registries = Registries(vec![
+            Registry::named("ours", ours.path().to_path_buf()).with_trust(Trust::unsigned()),
+            Registry::named("upstream", upstream.path().to_path_buf())
+                .with_trust(Trust::unsigned()),
+        ]);
+
+        // neither registry has installed anything yet
+        assert!(registries.installed().unwrap().is_empty());
+
+        let upstream = registries.get("upstream").unwrap();
+        upstream
+            .write(&SourcePackage {
+                title: PackageTitle {
+                    name: "plex".into(),
+                    version: "1.0.0".into(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap();
+        upstream
+            .load("plex", "1.0.0")
+            .unwrap()
+            .compile()
+            .unwrap()
+            .install()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            registries.installed().unwrap(),
+            vec![PackageTitle {
+                name: "plex".into(),
+                version: "1.0.0".into(),
+                registry: Some("upstream".into()),
+            }]
+        );
+    }
+}
@@ -1,27 +1,104 @@
+use crate::qmp::session::Sessions;
 use crate::{
+    check_snapshot_name, check_vm, check_vm_snapshots,
     control_server::{Control, ControlServer},
+    generate_command,
     query_server::{Query, QueryServer},
     status_server::{Status, StatusServer},
-    Config, InputType, PromptResponses, ProtoPackageInstalled, ProtoPackageTitle,
-    ProtoPackageTitleList, ProtoPackageTitleWithRoot, ProtoPrompt, ProtoPromptResponses,
-    ProtoPrompts, ProtoType, ResponseRegistry, SystemdUnit,
+    vm_events_monitor_path, vm_info, vm_ping, vm_resize, vm_snapshot_delete, vm_snapshot_load,
+    vm_snapshot_save, vm_snapshots, CompiledPackage, Config, GlobalRegistry, HostBackend,
+    PromptResponse, PromptResponses, ProtoCompiledPackage, ProtoFacts, ProtoGlobalName,
+    ProtoGlobals, ProtoPackage, ProtoPackageInstalled, ProtoPackageTitle, ProtoPackageTitleList,
+    ProtoPackageTitleWithRoot, ProtoPromptQuery, ProtoPromptResponses, ProtoPrompts, ProtoResized,
+    ProtoSearchQuery, ProtoSearchResults, ProtoSnapshotList, ProtoSnapshotName, ProtoUpgradeTitle,
+    ProtoVmEvent, ProtoVmInfo, ResponseErrors, SourcePackage, SystemdUnit, Upgrade, VmEvent,
 };
-use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+use std::{fs::Permissions, os::unix::fs::PermissionsExt, pin::Pin, sync::Arc};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
 use tonic::{body::Body, transport::Server as TransportServer, Result};
 use tonic_middleware::{Middleware, MiddlewareLayer, ServiceBound};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 #[cfg(test)]
 mod tests;
 
+// events held for a watcher that is slow to take them
+const WATCH_BUFFER: usize = 16;
+
 #[derive(Debug, Clone)]
 pub struct Server {
     config: Config,
+    sessions: Sessions,
+}
+
+// every problem goes back at once, by section, in the status details
+fn compile_failure(pkg: &SourcePackage, e: anyhow::Error) -> tonic::Status {
+    let mut errors = pkg.compile_errors();
+    if errors.is_empty() {
+        errors.push("", "", e.to_string());
+    }
+    errors.into()
 }
 
 impl Server {
     pub fn new(config: Config) -> Self {
-        Self { config }
+        Self {
+            config,
+            sessions: Sessions::default(),
+        }
+    }
+
+    // the globals of a package are kept in the registry it comes from, under its unqualified name
+    fn global_registry(
+        &self,
+        name: &str,
+        version: Option<&str>,
+    ) -> anyhow::Result<(GlobalRegistry, String)> {
+        let (registry, name) = self.config.registries().find(name, version)?;
+        Ok((
+            GlobalRegistry::new(registry.path()).with_state(Some(registry.state())),
+            name,
+        ))
+    }
+
+    fn unit(&self, pkg: CompiledPackage) -> SystemdUnit {
+        SystemdUnit::new(
+            pkg,
+            self.config.systemd_root.clone().unwrap(),
+            self.config.charon_path.clone().unwrap(),
+        )
+    }
+
+    // the events of a VM are watched for as long as it is installed, not only while asked for
+    fn open_session(&self, pkg: &CompiledPackage, volume_root: &std::path::Path) {
+        if check_vm(pkg).is_ok() {
+            self.sessions
+                .open(&pkg.title.to_string(), vm_events_monitor_path(volume_root));
+        }
+    }
+
+    fn open_installed_sessions(&self) -> anyhow::Result<()> {
+        for registry in &self.config.registries().0 {
+            for title in registry.installed()? {
+                let pkg = match registry
+                    .load(&title.name, &title.version)
+                    .and_then(|pkg| pkg.compile())
+                {
+                    Ok(pkg) => pkg,
+                    Err(e) => {
+                        warn!("Not watching events of {}: {}", title, e);
+                        continue;
+                    }
+                };
+
+                if let Some(volume_root) = self.unit(pkg.clone()).volume_root()? {
+                    self.open_session(&pkg, &volume_root);
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub fn start(
@@ -30,6 +107,14 @@ impl Server {
     {
         info!("Starting service.");
 
+        self.config.store_trust()?;
+        self.config.store_facts()?;
+        self.config.store_site_globals()?;
+
+        if let Err(e) = self.open_installed_sessions() {
+            error!("Could not watch the events of installed VMs: {}", e);
+        }
+
         if let Some(parent) = self.config.socket.to_path_buf().parent() {
             std::fs::create_dir_all(&parent)?;
         }
@@ -57,6 +142,12 @@ impl Status for Server {
     async fn ping(&self, _: tonic::Request<()>) -> Result<tonic::Response<()>> {
         Ok(tonic::Response::new(()))
     }
+
+    async fn facts(&self, _: tonic::Request<()>) -> Result<tonic::Response<ProtoFacts>> {
+        Ok(tonic::Response::new(ProtoFacts {
+            facts: self.config.facts().0.into_iter().collect(),
+        }))
+    }
 }
 
 #[tonic::async_trait]
@@ -65,7 +156,7 @@ impl Control for Server {
         &self,
         title: tonic::Request<ProtoPackageTitle>,
     ) -> Result<tonic::Response<ProtoPackageInstalled>> {
-        let r = self.config.registry();
+        let r = self.config.registries();
         let title = title.into_inner();
 
         let pkg = r
@@ -88,7 +179,7 @@ impl Control for Server {
         &self,
         title: tonic::Request<ProtoPackageTitle>,
     ) -> Result<tonic::Response<()>> {
-        let r = self.config.registry();
+        let r = self.config.registries();
         let title = title.into_inner();
 
         let pkg = r
@@ -116,7 +207,7 @@ impl Control for Server {
         &self,
         title: tonic::Request<ProtoPackageTitle>,
     ) -> Result<tonic::Response<()>> {
-        let r = self.config.registry();
+        let r = self.config.registries();
         let title = title.into_inner();
 
         let pkg = r
@@ -140,7 +231,7 @@ impl Control for Server {
         &self,
         title: tonic::Request<ProtoPackageTitleWithRoot>,
     ) -> Result<tonic::Response<()>> {
-        let r = self.config.registry();
+        let r = self.config.registries();
         let title = title.into_inner();
 
         let pkg = r
@@ -149,16 +240,14 @@ impl Control for Server {
             .compile()
             .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
 
-        let unit = SystemdUnit::new(
-            pkg,
-            self.config.systemd_root.clone().unwrap(),
-            self.config.charon_path.clone().unwrap(),
-        );
-        unit.create_unit(r.path(), title.volume_root.into())
+        let volume_root = std::path::PathBuf::from(title.volume_root);
+        let unit = self.unit(pkg.clone());
+        unit.create_unit(pkg.root(), volume_root.clone())
             .await
             .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
 
         info!("Wrote unit to {}", unit.filename().display());
+        self.open_session(&pkg, &volume_root);
 
         Ok(tonic::Response::new(()))
     }
@@ -167,7 +256,7 @@ impl Control for Server {
         &self,
         title: tonic::Request<ProtoPackageTitle>,
     ) -> Result<tonic::Response<()>> {
-        let r = self.config.registry();
+        let r = self.config.registries();
         let title = title.into_inner();
 
         let pkg = r
@@ -176,16 +265,239 @@ impl Control for Server {
             .compile()
             .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
 
-        let unit = SystemdUnit::new(
-            pkg,
-            self.config.systemd_root.clone().unwrap(),
-            self.config.charon_path.clone().unwrap(),
-        );
+        let unit = self.unit(pkg);
+        let volume_root = unit
+            .volume_root()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
         unit.remove_unit()
             .await
             .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
 
         info!("Removed unit {}", unit.filename().display());
+        if let Some(volume_root) = volume_root {
+            self.sessions.close(&vm_events_monitor_path(&volume_root));
+        }
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn upgrade(
+        &self,
+        upgrade: tonic::Request<ProtoUpgradeTitle>,
+    ) -> Result<tonic::Response<()>> {
+        let r = self.config.registries();
+        let upgrade = upgrade.into_inner();
+
+        let from = r
+            .load(&upgrade.name, &upgrade.from_version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?
+            .compile()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        let pkg = r
+            .load(&upgrade.name, &upgrade.to_version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?;
+        let to = pkg.compile().map_err(|e| compile_failure(&pkg, e))?;
+
+        let upgrade = Upgrade {
+            from,
+            to,
+            volume_root: upgrade.volume_root.into(),
+            backend: Arc::new(HostBackend {
+                pool: self.config.facts().get("pool").unwrap_or_default(),
+                systemd_root: self.config.systemd_root.clone().unwrap(),
+                charon_path: self.config.charon_path.clone().unwrap(),
+            }),
+        };
+
+        upgrade
+            .check()
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+        upgrade
+            .run()
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Aborted, e.to_string()))?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn create_snapshot(
+        &self,
+        request: tonic::Request<ProtoSnapshotName>,
+    ) -> Result<tonic::Response<()>> {
+        let request = request.into_inner();
+        let pkg = self
+            .config
+            .registries()
+            .load(&request.name, &request.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?
+            .compile()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        check_vm(&pkg)
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+        let root = std::path::PathBuf::from(request.volume_root);
+
+        check_snapshot_name(&request.snapshot)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
+
+        vm_ping(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Unavailable, e.to_string()))?;
+        check_vm_snapshots(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+
+        vm_snapshot_save(&pkg, &root, &request.snapshot)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn restore_snapshot(
+        &self,
+        request: tonic::Request<ProtoSnapshotName>,
+    ) -> Result<tonic::Response<()>> {
+        let request = request.into_inner();
+        let pkg = self
+            .config
+            .registries()
+            .load(&request.name, &request.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?
+            .compile()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        check_vm(&pkg)
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+        check_snapshot_name(&request.snapshot)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
+        let root = std::path::PathBuf::from(request.volume_root);
+
+        let snapshots = vm_snapshots(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Unavailable, e.to_string()))?;
+        if !snapshots.iter().any(|s| s.name == request.snapshot) {
+            return Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("{} has no snapshot '{}'", pkg.title, request.snapshot),
+            ));
+        }
+        check_vm_snapshots(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+
+        vm_snapshot_load(&pkg, &root, &request.snapshot)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn resize(
+        &self,
+        request: tonic::Request<ProtoPackageTitleWithRoot>,
+    ) -> Result<tonic::Response<ProtoResized>> {
+        let request = request.into_inner();
+        let pkg = self
+            .config
+            .registries()
+            .load(&request.name, &request.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?
+            .compile()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        check_vm(&pkg)
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+        let root = std::path::PathBuf::from(request.volume_root);
+
+        vm_ping(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Unavailable, e.to_string()))?;
+
+        let resized = vm_resize(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Aborted, e.to_string()))?;
+
+        info!(
+            "Resized {}: {} to {} vCPUs, {}M to {}M memory",
+            pkg.title, resized.cpus_before, resized.cpus, resized.memory_before, resized.memory
+        );
+
+        Ok(tonic::Response::new(resized.into()))
+    }
+
+    async fn delete_snapshot(
+        &self,
+        request: tonic::Request<ProtoSnapshotName>,
+    ) -> Result<tonic::Response<()>> {
+        let request = request.into_inner();
+        let pkg = self
+            .config
+            .registries()
+            .load(&request.name, &request.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?
+            .compile()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        check_vm(&pkg)
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+        check_snapshot_name(&request.snapshot)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
+        let root = std::path::PathBuf::from(request.volume_root);
+
+        let snapshots = vm_snapshots(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Unavailable, e.to_string()))?;
+        if !snapshots.iter().any(|s| s.name == request.snapshot) {
+            return Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("{} has no snapshot '{}'", pkg.title, request.snapshot),
+            ));
+        }
+
+        vm_snapshot_delete(&pkg, &root, &request.snapshot)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn set_globals(
+        &self,
+        globals: tonic::Request<ProtoGlobals>,
+    ) -> Result<tonic::Response<()>> {
+        let globals = globals.into_inner();
+        let version = Some(globals.version.as_str()).filter(|v| !v.is_empty());
+        let (gr, name) = self
+            .global_registry(&globals.name, version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?;
+
+        gr.set_variables(&name, version, &globals.variables.into_iter().collect())
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn delete_global(
+        &self,
+        name: tonic::Request<ProtoGlobalName>,
+    ) -> Result<tonic::Response<()>> {
+        let name = name.into_inner();
+        let version = Some(name.version.as_str()).filter(|v| !v.is_empty());
+        let (gr, package) = self
+            .global_registry(&name.name, version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?;
+
+        let mut variables = gr
+            .variables(&package, version)
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        if variables.remove(&name.variable).is_none() {
+            return Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                format!("variable '{}' is not set", name.variable),
+            ));
+        }
+
+        gr.set_variables(&package, version, &variables)
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
 
         Ok(tonic::Response::new(()))
     }
@@ -197,7 +509,7 @@ impl Query for Server {
         &self,
         _empty: tonic::Request<()>,
     ) -> Result<tonic::Response<ProtoPackageTitleList>> {
-        let r = self.config.registry();
+        let r = self.config.registries();
 
         let list = r
             .installed()
@@ -209,6 +521,7 @@ impl Query for Server {
             v.push(ProtoPackageTitle {
                 name: item.name,
                 version: item.version,
+                registry: item.registry.unwrap_or_default(),
             })
         }
 
@@ -219,7 +532,7 @@ impl Query for Server {
         &self,
         _empty: tonic::Request<()>,
     ) -> Result<tonic::Response<ProtoPackageTitleList>> {
-        let r = self.config.registry();
+        let r = self.config.registries();
 
         let list = r
             .list()
@@ -231,28 +544,204 @@ impl Query for Server {
             v.push(ProtoPackageTitle {
                 name: item.name,
                 version: item.version,
+                registry: item.registry.unwrap_or_default(),
             })
         }
 
         Ok(tonic::Response::new(ProtoPackageTitleList { list: v }))
     }
 
+    async fn get_globals(
+        &self,
+        title: tonic::Request<ProtoPackageTitle>,
+    ) -> Result<tonic::Response<ProtoGlobals>> {
+        let title = title.into_inner();
+        let version = Some(title.version.as_str()).filter(|v| !v.is_empty());
+        let (gr, name) = self
+            .global_registry(&title.name, version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?;
+
+        let variables = gr
+            .variables(&name, version)
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        let effective = gr
+            .layers(&name, &title.version, &self.config.facts())
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?
+            .explain();
+
+        Ok(tonic::Response::new(ProtoGlobals {
+            name: title.name,
+            version: title.version,
+            variables: variables.into_iter().collect(),
+            effective: effective.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn search(
+        &self,
+        query: tonic::Request<ProtoSearchQuery>,
+    ) -> Result<tonic::Response<ProtoSearchResults>> {
+        let results = self
+            .config
+            .registries()
+            .search(&query.into_inner().into())
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        Ok(tonic::Response::new(results.into()))
+    }
+
+    async fn get_package(
+        &self,
+        title: tonic::Request<ProtoPackageTitle>,
+    ) -> Result<tonic::Response<ProtoPackage>> {
+        let title = title.into_inner();
+        let metadata = self
+            .config
+            .registries()
+            .metadata(
+                &title.name,
+                Some(title.version.as_str()).filter(|v| !v.is_empty()),
+            )
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?;
+
+        Ok(tonic::Response::new(metadata.into()))
+    }
+
+    async fn compile(
+        &self,
+        title: tonic::Request<ProtoPackageTitleWithRoot>,
+    ) -> Result<tonic::Response<ProtoCompiledPackage>> {
+        let title = title.into_inner();
+        let pkg = self
+            .config
+            .registries()
+            .load(&title.name, &title.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?;
+
+        let compiled = pkg.compile().map_err(|e| compile_failure(&pkg, e))?;
+
+        let command = generate_command(compiled.clone(), title.volume_root.into())
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        Ok(tonic::Response::new(ProtoCompiledPackage {
+            title: Some(compiled.title.clone().into()),
+            package: serde_json::to_string(&compiled)
+                .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?,
+            command,
+        }))
+    }
+
+    async fn vm_info(
+        &self,
+        request: tonic::Request<ProtoPackageTitleWithRoot>,
+    ) -> Result<tonic::Response<ProtoVmInfo>> {
+        let request = request.into_inner();
+        let pkg = self
+            .config
+            .registries()
+            .load(&request.name, &request.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?
+            .compile()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        check_vm(&pkg)
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+        let root = std::path::PathBuf::from(request.volume_root);
+
+        let info = vm_info(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Unavailable, e.to_string()))?;
+
+        Ok(tonic::Response::new(info.into()))
+    }
+
+    type WatchVmStream = Pin<Box<dyn Stream<Item = Result<ProtoVmEvent>> + Send>>;
+
+    async fn watch_vm(
+        &self,
+        request: tonic::Request<ProtoPackageTitleWithRoot>,
+    ) -> Result<tonic::Response<Self::WatchVmStream>> {
+        let request = request.into_inner();
+        let pkg = self
+            .config
+            .registries()
+            .load(&request.name, &request.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?
+            .compile()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        check_vm(&pkg)
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+
+        let monitor = vm_events_monitor_path(std::path::Path::new(&request.volume_root));
+        let mut events = self.sessions.watch(&pkg.title.to_string(), monitor);
+
+        // the session outlives this watcher, which stops as soon as the caller hangs up
+        let (s, r) = mpsc::channel(WATCH_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    _ = s.closed() => return,
+                    event = events.recv() => event,
+                };
+
+                match event {
+                    Ok(event) => {
+                        if s.send(Ok(VmEvent::from(event).into())).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+
+        Ok(tonic::Response::new(Box::pin(ReceiverStream::new(r))))
+    }
+
+    async fn list_snapshots(
+        &self,
+        request: tonic::Request<ProtoPackageTitleWithRoot>,
+    ) -> Result<tonic::Response<ProtoSnapshotList>> {
+        let request = request.into_inner();
+        let pkg = self
+            .config
+            .registries()
+            .load(&request.name, &request.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::NotFound, e.to_string()))?
+            .compile()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        check_vm(&pkg)
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+        let root = std::path::PathBuf::from(request.volume_root);
+
+        let snapshots = vm_snapshots(&pkg, &root)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Unavailable, e.to_string()))?;
+
+        Ok(tonic::Response::new(ProtoSnapshotList {
+            snapshots: snapshots.into_iter().map(Into::into).collect(),
+        }))
+    }
+
     async fn get_responses(
         &self,
         title: tonic::Request<ProtoPackageTitle>,
     ) -> Result<tonic::Response<ProtoPromptResponses>> {
-        let r = ResponseRegistry::new(self.config.registry.path.clone());
+        let r = self.config.registries();
         let title = title.into_inner();
         let responses = r
-            .get(&title.name)
+            .load(&title.name, &title.version)
+            .and_then(|pkg| pkg.responses())
             .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
 
         let mut out = ProtoPromptResponses {
             name: title.name,
+            version: title.version,
             responses: Vec::with_capacity(responses.0.len()),
         };
 
-        for response in responses.0 {
+        // secrets never leave the server
+        for response in responses.redacted().0 {
             out.responses.push(response.into());
         }
 
@@ -261,30 +750,32 @@ impl Query for Server {
 
     async fn get_prompts(
         &self,
-        title: tonic::Request<ProtoPackageTitle>,
+        query: tonic::Request<ProtoPromptQuery>,
     ) -> Result<tonic::Response<ProtoPrompts>> {
-        let r = self.config.registry();
-        let title = title.into_inner();
+        let r = self.config.registries();
+        let query = query.into_inner();
         let pkg = r
-            .load(&title.name, &title.version)
+            .load(&query.name, &query.version)
             .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
-        let prompts = pkg.prompts.unwrap_or_default();
+        let mut prompts = pkg.prompts.unwrap_or_default();
+
+        if query.applicable {
+            let mut pr = Vec::new();
+            for response in query.responses {
+                pr.push(response.try_into().map_err(|e: anyhow::Error| {
+                    tonic::Status::new(tonic::Code::InvalidArgument, e.to_string())
+                })?);
+            }
+
+            prompts = prompts
+                .active(&PromptResponses(pr))
+                .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
+        }
 
         let mut out = ProtoPrompts::default();
 
-        for prompt in &prompts.to_vec() {
-            // FIXME: do a From trait
-            out.prompts.push(ProtoPrompt {
-                template: prompt.template.clone(),
-                question: prompt.question.clone(),
-                input_type: match prompt.input_type {
-                    InputType::String => ProtoType::String,
-                    InputType::Integer => ProtoType::Integer,
-                    InputType::SignedInteger => ProtoType::SignedInteger,
-                    InputType::Boolean => ProtoType::Boolean,
-                }
-                .into(),
-            })
+        for prompt in prompts.to_vec() {
+            out.prompts.push(prompt.into())
         }
 
         Ok(tonic::Response::new(out))
@@ -294,21 +785,96 @@ impl Query for Server {
         &self,
         responses: tonic::Request<ProtoPromptResponses>,
     ) -> Result<tonic::Response<()>> {
-        let r = self.config.registry();
+        let r = self.config.registries();
         let responses = responses.into_inner();
 
+        if responses.version.is_empty() {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "a version of {} is required to check responses against",
+                    responses.name
+                ),
+            ));
+        }
+
+        let pkg = r
+            .load(&responses.name, &responses.version)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
+        let prompts = pkg.prompts.clone().unwrap_or_default();
+
+        let mut errors = ResponseErrors::default();
+
         let mut pr = Vec::new();
         for response in responses.responses {
-            pr.push(response.into());
+            let template = response.template.clone();
+            match PromptResponse::try_from(response) {
+                Ok(response) => pr.push(response),
+                Err(e) => errors.push(&template, e.to_string()),
+            }
+        }
+
+        let mut pr = PromptResponses(pr);
+        if let Err(e) = pr.restore_secrets(&pkg.responses().unwrap_or_default()) {
+            errors.extend(e);
+        }
+
+        if let Err(e) = prompts.validate(&pr) {
+            errors.extend(e);
+        }
+
+        // every problem goes back at once, by prompt, in the status details
+        if !errors.is_empty() {
+            return Err(errors.into());
         }
 
-        r.response_registry()
-            .set(&responses.name, &PromptResponses(pr))
+        pkg.set_responses(&pr)
             .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
-        info!("Wrote responses for package {}", responses.name);
+        info!(
+            "Wrote responses for package {} version {}",
+            responses.name, responses.version
+        );
 
         Ok(tonic::Response::new(()))
     }
+
+    async fn pending_prompts(
+        &self,
+        upgrade: tonic::Request<ProtoUpgradeTitle>,
+    ) -> Result<tonic::Response<ProtoPrompts>> {
+        let r = self.config.registries();
+        let upgrade = upgrade.into_inner();
+
+        let pkg = r
+            .load(&upgrade.name, &upgrade.to_version)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
+
+        // responses are kept in the registry of the package, under its unqualified name
+        let name = &pkg.title.name;
+        let responses = pkg
+            .response_registry()
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?;
+        let old = if responses.exists(name, &upgrade.from_version) {
+            responses
+                .get(name, &upgrade.from_version)
+                .map_err(|e| tonic::Status::new(tonic::Code::Internal, e.to_string()))?
+        } else {
+            PromptResponses::default()
+        };
+
+        let pending = pkg
+            .migrate(&upgrade.from_version, &old)
+            .and_then(|migrated| pkg.prompts.clone().unwrap_or_default().pending(&migrated))
+            .map_err(|e| tonic::Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+
+        let mut out = ProtoPrompts::default();
+
+        for prompt in pending.to_vec() {
+            out.prompts.push(prompt.into())
+        }
+
+        Ok(tonic::Response::new(out))
+    }
 }
 
 #[derive(Default, Clone)]
@@ -1,6 +1,7 @@
 use crate::{
-    Client, Config, Input, InputType, PackageTitle, Prompt, PromptCollection, PromptResponse,
-    PromptResponses, RegistryConfig, Server,
+    generate_command, Client, CompileErrors, CompiledSource, Config, Facts, GlobalSource, Input,
+    InputType, PackageTitle, Prompt, PromptCollection, PromptResponse, PromptResponses,
+    RegistryConfig, ResponseErrors, SearchQuery, Secret, SelectOption, Server, Variables,
 };
 use std::path::PathBuf;
 use tempfile::{tempdir, NamedTempFile};
@@ -24,12 +25,20 @@ async fn start_server(debug: bool) -> (PathBuf, Option<PathBuf>) {
             socket: pb2,
             log_level: None,
             debug: Some(debug),
-            registry: RegistryConfig {
+            registries: vec![RegistryConfig {
+                name: "testdata".into(),
                 path: "testdata/registry".into(),
                 url: None,
-            },
+                priority: 0,
+                pin: None,
+                state: None,
+                keys: Vec::new(),
+                unsigned: true,
+            }],
             systemd_root: inner,
             charon_path: Some(crate::DEFAULT_CHARON_BIN_PATH.into()),
+            facts: Default::default(),
+            globals: None,
         })
         .start()
         .unwrap()
@@ -48,6 +57,101 @@ async fn test_ping() {
     client.status().await.unwrap().ping().await.unwrap();
 }
 
+#[tokio::test]
+async fn test_facts() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let facts = client.status().await.unwrap().facts().await.unwrap();
+    assert_eq!(facts, Facts::detect());
+    assert_eq!(
+        facts.get("cpus").unwrap(),
+        std::thread::available_parallelism().unwrap().to_string()
+    );
+}
+
+#[tokio::test]
+async fn test_globals() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut query = client.query().await.unwrap();
+    let mut control = client.control().await.unwrap();
+
+    assert!(query
+        .get_globals("with-dependencies", None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(query.get_globals("nonexistent", None).await.is_err());
+    assert!(query
+        .get_globals("with-dependencies", Some("9.9.9"))
+        .await
+        .is_err());
+
+    let mut variables = Variables::default();
+    variables.insert("domain".into(), "example.org".into());
+    variables.insert("port".into(), "8080".into());
+
+    control
+        .set_globals("with-dependencies", Some("0.0.1"), variables.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        query
+            .get_globals("with-dependencies", Some("0.0.1"))
+            .await
+            .unwrap(),
+        variables
+    );
+
+    let explained = query
+        .explain_globals("with-dependencies", "0.0.1")
+        .await
+        .unwrap();
+    let domain = explained.iter().find(|e| e.name == "domain").unwrap();
+    assert_eq!(domain.value, "example.org");
+    assert_eq!(domain.source, GlobalSource::Install);
+    assert!(explained
+        .iter()
+        .any(|e| e.name == "host.cpus" && e.source == GlobalSource::Fact));
+
+    // the facts namespace is reserved
+    let mut reserved = Variables::default();
+    reserved.insert("host.ip".into(), "10.0.0.2".into());
+    assert!(control
+        .set_globals("with-dependencies", Some("0.0.1"), reserved)
+        .await
+        .is_err());
+    assert!(control
+        .set_globals("nonexistent", None, variables.clone())
+        .await
+        .is_err());
+
+    control
+        .delete_global("with-dependencies", Some("0.0.1"), "port")
+        .await
+        .unwrap();
+    assert!(control
+        .delete_global("with-dependencies", Some("0.0.1"), "port")
+        .await
+        .is_err());
+    variables.remove("port");
+    assert_eq!(
+        query
+            .get_globals("with-dependencies", Some("0.0.1"))
+            .await
+            .unwrap(),
+        variables
+    );
+
+    control
+        .delete_global("with-dependencies", Some("0.0.1"), "domain")
+        .await
+        .unwrap();
+    assert!(query
+        .get_globals("with-dependencies", Some("0.0.1"))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
 #[cfg(feature = "livetests")]
 #[tokio::test]
 async fn test_write_unit_real() {
@@ -83,8 +187,8 @@ async fn test_write_unit_real() {
 Description=Charon launcher for podman-test, version 0.0.2
 
 [Service]
-ExecStart=/usr/bin/charon -r testdata/registry launch podman-test 0.0.2 /tmp/volroot
-ExecStop=/usr/bin/charon -r testdata/registry stop podman-test 0.0.2 /tmp/volroot
+ExecStart=/usr/bin/charon -r testdata/registry --state testdata/registry launch podman-test 0.0.2 /tmp/volroot
+ExecStop=/usr/bin/charon -r testdata/registry --state testdata/registry stop podman-test 0.0.2 /tmp/volroot
 Restart=always
 
 [Install]
@@ -146,16 +250,19 @@ async fn test_get_prompts() {
             template: "private_path".into(),
             question: "Where do you want this mounted?".into(),
             input_type: InputType::String,
+            ..Default::default()
         },
         Prompt {
             template: "private_size".into(),
             question: "How big should it be?".into(),
             input_type: InputType::Integer,
+            ..Default::default()
         },
         Prompt {
             template: "private_recreate".into(),
             question: "Should we recreate this volume if it already exists?".into(),
             input_type: InputType::Boolean,
+            ..Default::default()
         },
     ]);
 
@@ -184,7 +291,7 @@ async fn set_get_responses() {
         .query()
         .await
         .unwrap()
-        .set_responses("with-prompts", responses.clone())
+        .set_responses("with-prompts", "0.0.1", responses.clone())
         .await
         .unwrap();
 
@@ -192,13 +299,420 @@ async fn set_get_responses() {
         .query()
         .await
         .unwrap()
-        .get_responses("with-prompts")
+        .get_responses("with-prompts", "0.0.1")
         .await
         .unwrap();
 
     assert_eq!(responses, responses2);
 }
 
+#[tokio::test]
+async fn select_prompts() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let prompts = client
+        .query()
+        .await
+        .unwrap()
+        .get_prompts("with-select", "0.0.1")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        prompts.0[0].input_type,
+        InputType::Select(vec![
+            SelectOption {
+                name: "Small".into(),
+                value: Input::Integer(1),
+            },
+            SelectOption {
+                name: "Medium".into(),
+                value: Input::Integer(2),
+            },
+            SelectOption {
+                name: "Large".into(),
+                value: Input::Integer(4),
+            },
+        ])
+    );
+
+    // not one of the options
+    assert!(client
+        .query()
+        .await
+        .unwrap()
+        .set_responses(
+            "with-select",
+            "0.0.1",
+            PromptResponses(vec![PromptResponse {
+                template: "cpus".into(),
+                input: Input::Integer(3),
+            }]),
+        )
+        .await
+        .is_err());
+
+    // nothing to check against without a version
+    let e = client
+        .query()
+        .await
+        .unwrap()
+        .set_responses(
+            "with-select",
+            "",
+            PromptResponses(vec![PromptResponse {
+                template: "cpus".into(),
+                input: Input::Integer(2),
+            }]),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.downcast::<tonic::Status>().unwrap().code(),
+        tonic::Code::InvalidArgument
+    );
+
+    // right value, wrong type
+    assert!(client
+        .query()
+        .await
+        .unwrap()
+        .set_responses(
+            "with-select",
+            "0.0.1",
+            PromptResponses(vec![PromptResponse {
+                template: "cpus".into(),
+                input: Input::String("1".into()),
+            }]),
+        )
+        .await
+        .is_err());
+
+    let responses = PromptResponses(vec![
+        PromptResponse {
+            template: "cpus".into(),
+            input: Input::Integer(2),
+        },
+        PromptResponse {
+            template: "flavor".into(),
+            input: Input::String("chocolate".into()),
+        },
+    ]);
+
+    client
+        .query()
+        .await
+        .unwrap()
+        .set_responses("with-select", "0.0.1", responses.clone())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        client
+            .query()
+            .await
+            .unwrap()
+            .get_responses("with-select", "0.0.1")
+            .await
+            .unwrap(),
+        responses
+    );
+}
+
+#[tokio::test]
+async fn prompt_constraints() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let prompts = client
+        .query()
+        .await
+        .unwrap()
+        .get_prompts("with-constraints", "0.0.1")
+        .await
+        .unwrap();
+
+    let port = prompts.get("port").unwrap();
+    assert_eq!(port.default, Some(Input::Integer(8080)));
+    assert_eq!(port.min, Some(1024));
+    assert_eq!(port.max, Some(65535));
+    assert!(port.help.is_some());
+    assert!(prompts.get("hostname").unwrap().required);
+
+    let table = vec![
+        // out of range
+        (
+            false,
+            vec![
+                ("port", Input::Integer(80)),
+                ("hostname", Input::String("plex".into())),
+            ],
+        ),
+        // doesn't match the pattern
+        (false, vec![("hostname", Input::String("Plex!".into()))]),
+        // hostname is required
+        (false, vec![("port", Input::Integer(8000))]),
+        (true, vec![("hostname", Input::String("plex".into()))]),
+        (
+            true,
+            vec![
+                ("port", Input::Integer(32400)),
+                ("hostname", Input::String("plex-1".into())),
+            ],
+        ),
+    ];
+
+    // every problem is reported by prompt
+    let errors = client
+        .query()
+        .await
+        .unwrap()
+        .set_responses(
+            "with-constraints",
+            "0.0.1",
+            PromptResponses(vec![
+                PromptResponse {
+                    template: "port".into(),
+                    input: Input::Integer(80),
+                },
+                PromptResponse {
+                    template: "size".into(),
+                    input: Input::String("big".into()),
+                },
+                PromptResponse {
+                    template: "color".into(),
+                    input: Input::String("red".into()),
+                },
+            ]),
+        )
+        .await
+        .unwrap_err()
+        .downcast::<ResponseErrors>()
+        .unwrap();
+
+    let mut templates = errors
+        .0
+        .iter()
+        .map(|e| e.template.as_str())
+        .collect::<Vec<&str>>();
+    templates.sort();
+    assert_eq!(templates, vec!["color", "hostname", "port", "size"]);
+
+    for (ok, responses) in table {
+        let responses = PromptResponses(
+            responses
+                .into_iter()
+                .map(|(template, input)| PromptResponse {
+                    template: template.into(),
+                    input,
+                })
+                .collect(),
+        );
+
+        assert_eq!(
+            client
+                .query()
+                .await
+                .unwrap()
+                .set_responses("with-constraints", "0.0.1", responses)
+                .await
+                .is_ok(),
+            ok
+        );
+    }
+}
+
+#[tokio::test]
+async fn conditional_prompts() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+
+    let templates = |prompts: PromptCollection| {
+        prompts
+            .to_vec()
+            .into_iter()
+            .map(|prompt| prompt.template)
+            .collect::<Vec<String>>()
+    };
+
+    let all = client
+        .query()
+        .await
+        .unwrap()
+        .get_prompts("with-conditions", "0.0.1")
+        .await
+        .unwrap();
+    assert_eq!(
+        templates(all.clone()),
+        vec!["http_port", "https", "https_port"]
+    );
+    assert_eq!(all.get("https_port").unwrap().when, Some("https".into()));
+
+    let https = |enabled: bool| {
+        PromptResponses(vec![PromptResponse {
+            template: "https".into(),
+            input: Input::Boolean(enabled),
+        }])
+    };
+
+    let table = vec![
+        (PromptResponses::default(), vec!["http_port", "https"]),
+        (https(false), vec!["http_port", "https"]),
+        (https(true), vec!["http_port", "https", "https_port"]),
+    ];
+
+    for (responses, applicable) in table {
+        let prompts = client
+            .query()
+            .await
+            .unwrap()
+            .get_applicable_prompts("with-conditions", "0.0.1", responses)
+            .await
+            .unwrap();
+        assert_eq!(templates(prompts), applicable);
+    }
+
+    // https_port is only required once https is enabled
+    assert!(client
+        .query()
+        .await
+        .unwrap()
+        .set_responses("with-conditions", "0.0.1", https(false))
+        .await
+        .is_ok());
+    assert!(client
+        .query()
+        .await
+        .unwrap()
+        .set_responses("with-conditions", "0.0.1", https(true))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn secret_responses() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+
+    let responses = PromptResponses(vec![
+        PromptResponse {
+            template: "hostname".into(),
+            input: Input::String("secrets".into()),
+        },
+        PromptResponse {
+            template: "api_token".into(),
+            input: Input::Secret(Secret::new("hunter22")),
+        },
+    ]);
+
+    client
+        .query()
+        .await
+        .unwrap()
+        .set_responses("with-secrets", "0.0.1", responses.clone())
+        .await
+        .unwrap();
+
+    let redacted = client
+        .query()
+        .await
+        .unwrap()
+        .get_responses("with-secrets", "0.0.1")
+        .await
+        .unwrap();
+    assert_eq!(redacted, responses.redacted());
+
+    // sending the redacted responses back keeps the secret
+    client
+        .query()
+        .await
+        .unwrap()
+        .set_responses("with-secrets", "0.0.1", redacted)
+        .await
+        .unwrap();
+
+    let pkg = crate::Registry::new("testdata/registry".into())
+        .with_trust(crate::Trust::unsigned())
+        .load("with-secrets", "0.0.1")
+        .unwrap()
+        .compile()
+        .unwrap();
+    assert_eq!(
+        pkg.secrets,
+        vec![("api_token".to_string(), Secret::new("hunter22"))]
+    );
+}
+
+#[tokio::test]
+async fn migrated_responses() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+
+    client
+        .query()
+        .await
+        .unwrap()
+        .set_responses(
+            "with-migrations",
+            "0.0.1",
+            PromptResponses(vec![
+                PromptResponse {
+                    template: "port".into(),
+                    input: Input::String("8080".into()),
+                },
+                PromptResponse {
+                    template: "size".into(),
+                    input: Input::String("small".into()),
+                },
+            ]),
+        )
+        .await
+        .unwrap();
+
+    // port is renamed and converted, size is mapped, hostname is new
+    let pending = client
+        .query()
+        .await
+        .unwrap()
+        .pending_prompts("with-migrations", "0.0.1", "0.0.2")
+        .await
+        .unwrap();
+    assert_eq!(
+        pending
+            .to_vec()
+            .into_iter()
+            .map(|prompt| prompt.template)
+            .collect::<Vec<String>>(),
+        vec!["hostname"]
+    );
+
+    // 0.0.2 has no responses of its own yet, so it starts with the migrated ones
+    let migrated = client
+        .query()
+        .await
+        .unwrap()
+        .get_responses("with-migrations", "0.0.2")
+        .await
+        .unwrap();
+    assert_eq!(
+        migrated,
+        PromptResponses(vec![
+            PromptResponse {
+                template: "http_port".into(),
+                input: Input::Integer(8080),
+            },
+            PromptResponse {
+                template: "size".into(),
+                input: Input::Integer(1073741824),
+            },
+        ])
+    );
+
+    // the old version keeps its own
+    let old = client
+        .query()
+        .await
+        .unwrap()
+        .get_responses("with-migrations", "0.0.1")
+        .await
+        .unwrap();
+    assert_eq!(old.0[0].input, Input::String("8080".into()));
+}
+
 #[tokio::test]
 async fn list() {
     // NOTE: this table must be updated anytime testdata's registry is.
@@ -210,8 +724,14 @@ async fn list() {
         ("plex", vec!["0.0.2", "0.0.1"]),
         ("plex-qemu", vec!["0.0.2", "0.0.1"]),
         ("podman-test", vec!["0.0.3", "0.0.2", "0.0.1"]),
+        ("unanswered", vec!["0.0.1"]),
+        ("with-conditions", vec!["0.0.1"]),
+        ("with-constraints", vec!["0.0.1"]),
         ("with-dependencies", vec!["0.0.1"]),
+        ("with-migrations", vec!["0.0.2", "0.0.1"]),
         ("with-prompts", vec!["0.0.1"]),
+        ("with-secrets", vec!["0.0.1"]),
+        ("with-select", vec!["0.0.1"]),
     ];
 
     let mut v = Vec::new();
@@ -221,6 +741,7 @@ async fn list() {
             v.push(PackageTitle {
                 name: name.into(),
                 version: version.into(),
+                registry: Some("testdata".into()),
             })
         }
     }
@@ -228,7 +749,449 @@ async fn list() {
     let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
 
     let list = client.query().await.unwrap().list().await.unwrap();
-    assert_eq!(list, v)
+    assert_eq!(list, v);
+
+    // a name qualified with the registry picks it explicitly
+    let mut query = client.query().await.unwrap();
+    assert_eq!(
+        query
+            .get_prompts("testdata/with-prompts", "0.0.1")
+            .await
+            .unwrap(),
+        query.get_prompts("with-prompts", "0.0.1").await.unwrap()
+    );
+    assert!(query
+        .get_prompts("nonexistent/with-prompts", "0.0.1")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_search() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut query = client.query().await.unwrap();
+
+    let results = query
+        .search(SearchQuery {
+            tags: vec!["media".into()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(results.total, 2);
+    assert_eq!(
+        results.packages[0].title,
+        PackageTitle {
+            name: "plex".into(),
+            version: "0.0.2".into(),
+            registry: Some("testdata".into()),
+        }
+    );
+    assert_eq!(results.packages[0].versions, vec!["0.0.2", "0.0.1"]);
+    assert_eq!(results.packages[1].title.name, "plex-qemu");
+    assert_eq!(results.packages[1].tags, vec!["media", "vm"]);
+
+    let results = query
+        .search(SearchQuery {
+            text: "PLEX".into(),
+            tags: vec!["vm".into()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(results.total, 1);
+    assert_eq!(results.packages[0].title.name, "plex-qemu");
+
+    let results = query
+        .search(SearchQuery {
+            text: "with-".into(),
+            offset: 2,
+            limit: 3,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(results.total, 7);
+    assert_eq!(
+        results
+            .packages
+            .iter()
+            .map(|entry| entry.title.name.clone())
+            .collect::<Vec<_>>(),
+        vec!["with-dependencies", "with-migrations", "with-prompts"]
+    );
+
+    let results = query
+        .search(SearchQuery {
+            text: "nonexistent".into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(results.total, 0);
+    assert!(results.packages.is_empty());
+}
+
+#[tokio::test]
+async fn test_get_package() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut query = client.query().await.unwrap();
+
+    let latest = query.get_package("plex", None).await.unwrap();
+    assert_eq!(
+        latest.title,
+        PackageTitle {
+            name: "plex".into(),
+            version: "0.0.2".into(),
+            registry: Some("testdata".into()),
+        }
+    );
+    assert_eq!(latest.versions, vec!["0.0.2", "0.0.1"]);
+    assert_eq!(latest.homepage, Some("https://www.plex.tv".into()));
+    assert_eq!(latest.license, Some("proprietary".into()));
+    assert_eq!(latest.tags, vec!["media"]);
+    assert!(latest.readme.unwrap().starts_with("# Plex"));
+    assert_eq!(latest.icon, None);
+    assert_eq!(
+        latest.definition,
+        std::fs::read_to_string("testdata/registry/packages/plex/0.0.2.json").unwrap()
+    );
+
+    let older = query
+        .get_package("testdata/plex", Some("0.0.1"))
+        .await
+        .unwrap();
+    assert_eq!(older.title.version, "0.0.1");
+    assert!(older.maintainers.is_empty());
+    assert_eq!(older.readme, None);
+    assert_eq!(older.versions, latest.versions);
+
+    assert!(query.get_package("plex", Some("9.9.9")).await.is_err());
+    assert!(query.get_package("nonexistent", None).await.is_err());
+}
+
+#[tokio::test]
+async fn test_compile() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut query = client.query().await.unwrap();
+
+    let (package, command) = query
+        .compile("plex", "0.0.2", "/tmp/volumes".into())
+        .await
+        .unwrap();
+    assert_eq!(package.title.name, "plex");
+    assert_eq!(package.source, CompiledSource::Container("scratch".into()));
+    assert_eq!(
+        command,
+        generate_command(package, "/tmp/volumes".into()).unwrap()
+    );
+
+    let errors = query
+        .compile("unanswered", "0.0.1", "/tmp/volumes".into())
+        .await
+        .unwrap_err()
+        .downcast::<CompileErrors>()
+        .unwrap();
+    let mut found = errors
+        .0
+        .iter()
+        .map(|e| (e.section.as_str(), e.template.as_str()))
+        .collect::<Vec<_>>();
+    found.sort();
+    assert_eq!(
+        found,
+        vec![("networking", ""), ("prompts", "port"), ("storage", "")]
+    );
+    assert!(errors.0.iter().all(|e| !e.message.is_empty()));
+
+    assert!(query
+        .compile("nonexistent", "0.0.1", "/tmp/volumes".into())
+        .await
+        .unwrap_err()
+        .downcast::<CompileErrors>()
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_upgrade() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut control = client.control().await.unwrap();
+
+    let code = |e: anyhow::Error| e.downcast::<tonic::Status>().unwrap().code();
+
+    // nothing is changed for a version that is not installed
+    assert_eq!(
+        code(
+            control
+                .upgrade("podman-test", "0.0.1", "0.0.2", "/tmp/volumes".into())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::FailedPrecondition
+    );
+    assert_eq!(
+        code(
+            control
+                .upgrade("podman-test", "0.0.1", "9.9.9", "/tmp/volumes".into())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::NotFound
+    );
+    assert_eq!(
+        code(
+            control
+                .upgrade("nonexistent", "0.0.1", "0.0.2", "/tmp/volumes".into())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::NotFound
+    );
+}
+
+#[tokio::test]
+async fn test_snapshots() {
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut query = client.query().await.unwrap();
+    let mut control = client.control().await.unwrap();
+    let dir = tempdir().unwrap();
+
+    let code = |e: anyhow::Error| e.downcast::<tonic::Status>().unwrap().code();
+
+    // the VM isn't running
+    assert_eq!(
+        code(
+            query
+                .list_snapshots("plex-qemu", "0.0.2", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::Unavailable
+    );
+    assert_eq!(
+        code(
+            control
+                .restore_snapshot("plex-qemu", "0.0.2", dir.path().to_path_buf(), "first")
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::Unavailable
+    );
+    assert_eq!(
+        code(
+            control
+                .create_snapshot("plex-qemu", "0.0.2", dir.path().to_path_buf(), "")
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::InvalidArgument
+    );
+
+    assert_eq!(
+        code(
+            control
+                .create_snapshot("podman-test", "0.0.2", dir.path().to_path_buf(), "first")
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::FailedPrecondition
+    );
+    assert_eq!(
+        code(
+            query
+                .list_snapshots("nonexistent", "0.0.1", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::NotFound
+    );
+}
+
+#[tokio::test]
+async fn test_vm_info() {
+    use crate::{
+        qmp::testing::{introspection, FakeQmp},
+        vm_monitor_path,
+    };
+
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut query = client.query().await.unwrap();
+    let dir = tempdir().unwrap();
+
+    let code = |e: anyhow::Error| e.downcast::<tonic::Status>().unwrap().code();
+
+    // the VM isn't running
+    assert_eq!(
+        code(
+            query
+                .vm_info("plex-qemu", "0.0.2", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::Unavailable
+    );
+    assert_eq!(
+        code(
+            query
+                .vm_info("podman-test", "0.0.2", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::FailedPrecondition
+    );
+    assert_eq!(
+        code(
+            query
+                .vm_info("nonexistent", "0.0.1", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::NotFound
+    );
+
+    let _qmp = FakeQmp::start(&vm_monitor_path(dir.path()), |command| {
+        vec![introspection(command).unwrap()]
+    });
+
+    let info = query
+        .vm_info("plex-qemu", "0.0.2", dir.path().to_path_buf())
+        .await
+        .unwrap();
+    assert_eq!(info.status, "running");
+    assert_eq!(info.cpus.len(), 2);
+    assert_eq!(info.disks[0].written_bytes, 2048);
+    assert_eq!(info.pci.len(), 3);
+}
+
+#[tokio::test]
+async fn test_resize() {
+    use crate::{
+        qmp::testing::{error, reply, FakeQmp},
+        vm_monitor_path,
+    };
+    use serde_json::json;
+
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut control = client.control().await.unwrap();
+    let dir = tempdir().unwrap();
+
+    let code = |e: anyhow::Error| e.downcast::<tonic::Status>().unwrap().code();
+
+    // the VM isn't running
+    assert_eq!(
+        code(
+            control
+                .resize("plex-qemu", "0.0.2", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::Unavailable
+    );
+    assert_eq!(
+        code(
+            control
+                .resize("podman-test", "0.0.2", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::FailedPrecondition
+    );
+    assert_eq!(
+        code(
+            control
+                .resize("nonexistent", "0.0.1", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::NotFound
+    );
+
+    // started before it had room to grow
+    let _qmp = FakeQmp::start(&vm_monitor_path(dir.path()), |command| {
+        match command["execute"].as_str().unwrap() {
+            "query-balloon" => vec![error("No balloon device has been activated")],
+            _ => vec![reply(json!({}))],
+        }
+    });
+    assert_eq!(
+        code(
+            control
+                .resize("plex-qemu", "0.0.2", dir.path().to_path_buf())
+                .await
+                .unwrap_err()
+        ),
+        tonic::Code::Aborted
+    );
+}
+
+#[tokio::test]
+async fn test_watch_vm() {
+    use crate::{
+        qmp::testing::{event, reply, FakeQmp},
+        vm_events_monitor_path,
+    };
+    use serde_json::json;
+
+    let client = Client::new(start_server(true).await.0.to_path_buf()).unwrap();
+    let mut query = client.query().await.unwrap();
+    let dir = tempdir().unwrap();
+
+    let code = |e: anyhow::Error| e.downcast::<tonic::Status>().unwrap().code();
+
+    assert_eq!(
+        code(
+            query
+                .watch_vm("podman-test", "0.0.2", dir.path().to_path_buf())
+                .await
+                .err()
+                .unwrap()
+        ),
+        tonic::Code::FailedPrecondition
+    );
+    assert_eq!(
+        code(
+            query
+                .watch_vm("nonexistent", "0.0.1", dir.path().to_path_buf())
+                .await
+                .err()
+                .unwrap()
+        ),
+        tonic::Code::NotFound
+    );
+
+    let qmp = FakeQmp::start(&vm_events_monitor_path(dir.path()), |_| {
+        vec![reply(json!({}))]
+    });
+    let mut events = query
+        .watch_vm("plex-qemu", "0.0.2", dir.path().to_path_buf())
+        .await
+        .unwrap();
+
+    while qmp.connected() == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    qmp.emit(event(
+        "BLOCK_IO_ERROR",
+        json!({
+            "device": "",
+            "node-name": "#block123",
+            "operation": "write",
+            "action": "stop",
+            "nospace": true,
+            "reason": "No space left on device",
+        }),
+    ));
+
+    let event = events.next().await.unwrap().unwrap();
+    assert_eq!(event.event, "BLOCK_IO_ERROR");
+    assert_eq!(
+        event.description,
+        "write error on #block123: No space left on device (stop)"
+    );
+    assert_eq!(event.data["node-name"], "#block123");
+    assert_eq!(event.seconds, 1760745600);
 }
 
 #[tokio::test]
@@ -269,7 +1232,8 @@ async fn installer() {
             .unwrap(),
         vec![PackageTitle {
             name: "plex".into(),
-            version: "0.0.2".into()
+            version: "0.0.2".into(),
+            registry: Some("testdata".into()),
         }]
     );
 
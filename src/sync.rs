@@ -0,0 +1,449 @@
+use crate::{
+    FACTS_OVERRIDES_FILENAME, GLOBAL_SUBPATH, INSTALLED_SUBPATH, RESPONSES_SUBPATH,
+    SECRETS_SUBPATH, SITE_GLOBALS_FILENAME,
+};
+use anyhow::{anyhow, Result};
+use git2::{build::CheckoutBuilder, AutotagOption, FetchOptions, Oid, Repository, StatusOptions};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+//
+// keeps a registry in step with its git repository. updates only ever fast-forward, and a
+// registry with local modifications is left alone and reported instead, so nothing is lost.
+// local state is not kept in the repository, see RegistryConfig::state.
+//
+
+const REMOTE_NAME: &str = "origin";
+
+// where local state was kept in the worktree, before it had a directory of its own
+const LEGACY_STATE: &[&str] = &[
+    INSTALLED_SUBPATH,
+    RESPONSES_SUBPATH,
+    SECRETS_SUBPATH,
+    GLOBAL_SUBPATH,
+    SITE_GLOBALS_FILENAME,
+    FACTS_OVERRIDES_FILENAME,
+];
+
+// what a registry follows. without a pin, the default branch of the repository.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub enum RegistryPin {
+    #[serde(rename = "branch")]
+    Branch(String),
+    #[serde(rename = "tag")]
+    Tag(String),
+    #[serde(rename = "commit")]
+    Commit(String),
+}
+
+impl std::fmt::Display for RegistryPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Branch(branch) => write!(f, "branch {}", branch),
+            Self::Tag(tag) => write!(f, "tag {}", tag),
+            Self::Commit(commit) => write!(f, "commit {}", commit),
+        }
+    }
+}
+
+// clones the registry if it isn't there yet, otherwise fetches and updates it to the pin
+pub fn sync_registry(path: &Path, url: &str, pin: Option<&RegistryPin>) -> Result<()> {
+    let repo = if std::fs::exists(path.join(".git"))? {
+        Repository::open(path)?
+    } else {
+        info!("Cloning registry {} into {}", url, path.display());
+        std::fs::create_dir_all(path)?;
+        Repository::clone(url, path)?
+    };
+
+    check_modifications(&repo, path)?;
+
+    let mut remote = repo.find_remote(REMOTE_NAME)?;
+    let mut options = FetchOptions::new();
+    options.download_tags(AutotagOption::All);
+    remote.fetch(&[] as &[&str], Some(&mut options), None)?;
+
+    match pin {
+        Some(RegistryPin::Tag(tag)) => {
+            let target = repo
+                .revparse_single(&format!("refs/tags/{}", tag))
+                .map_err(|_| anyhow!("registry {} has no tag '{}'", path.display(), tag))?
+                .peel_to_commit()?
+                .id();
+            detach(&repo, target)
+        }
+        Some(RegistryPin::Commit(commit)) => {
+            let target = repo
+                .revparse_single(commit)
+                .map_err(|_| anyhow!("registry {} has no commit '{}'", path.display(), commit))?
+                .peel_to_commit()?
+                .id();
+            detach(&repo, target)
+        }
+        Some(RegistryPin::Branch(branch)) => fast_forward(&repo, path, branch),
+        None => fast_forward(&repo, path, &default_branch(&repo)?),
+    }
+}
+
+// registries that kept their local state in the worktree have it moved to the state directory,
+// so it doesn't count as a local modification. that is what git doesn't track, and the variables
+// of packages that were edited in place, which become local edits and are checked out again.
+// anything already in the state directory is left where it is, and reported when the registry
+// is synced.
+pub fn move_legacy_state(path: &Path, state: &Path) -> Result<()> {
+    if !std::fs::exists(path.join(".git"))? {
+        return Ok(());
+    }
+
+    let repo = Repository::open(path)?;
+
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_ignored(false);
+
+    let statuses = repo.statuses(Some(&mut options))?;
+
+    let legacy = statuses
+        .iter()
+        .filter(|entry| entry.status().is_wt_new())
+        .filter_map(|entry| entry.path().ok().map(PathBuf::from))
+        .filter(|relative| {
+            relative
+                .components()
+                .next()
+                .and_then(|first| first.as_os_str().to_str())
+                .is_some_and(|first| LEGACY_STATE.contains(&first))
+        })
+        .collect::<Vec<PathBuf>>();
+
+    // variables/<name>.json, as SetGlobals wrote it for a package's own variables
+    let edited = statuses
+        .iter()
+        .filter(|entry| entry.status().is_wt_modified())
+        .filter_map(|entry| entry.path().ok().map(PathBuf::from))
+        .filter(|relative| {
+            relative.parent() == Some(Path::new(GLOBAL_SUBPATH))
+                && relative.extension().is_some_and(|ext| ext == "json")
+        })
+        .collect::<Vec<PathBuf>>();
+
+    for relative in edited {
+        let to = state.join(&relative);
+
+        if std::fs::exists(&to)? {
+            warn!(
+                "Not moving edits of {} in registry {}: {} already exists",
+                relative.display(),
+                path.display(),
+                to.display()
+            );
+            continue;
+        }
+
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::copy(path.join(&relative), &to)?;
+        repo.checkout_head(Some(CheckoutBuilder::new().force().path(&relative)))?;
+        info!(
+            "Moved edits of {} in registry {} to {}",
+            relative.display(),
+            path.display(),
+            state.display()
+        );
+    }
+
+    for relative in legacy {
+        let to = state.join(&relative);
+
+        if std::fs::exists(&to)? {
+            warn!(
+                "Not moving {} of registry {}: {} already exists",
+                relative.display(),
+                path.display(),
+                to.display()
+            );
+            continue;
+        }
+
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::rename(path.join(&relative), &to)?;
+        info!(
+            "Moved {} of registry {} to {}",
+            relative.display(),
+            path.display(),
+            state.display()
+        );
+    }
+
+    Ok(())
+}
+
+// anything changed in the worktree would be lost, or get in the way, when it is updated
+fn check_modifications(repo: &Repository, path: &Path) -> Result<()> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).include_ignored(false);
+
+    let modified = repo
+        .statuses(Some(&mut options))?
+        .iter()
+        .filter_map(|entry| entry.path().ok().map(ToString::to_string))
+        .collect::<Vec<String>>();
+
+    if !modified.is_empty() {
+        return Err(anyhow!(
+            "registry {} has local modifications, commit or remove them to sync: {}",
+            path.display(),
+            modified.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+fn default_branch(repo: &Repository) -> Result<String> {
+    let head = format!("refs/remotes/{}/HEAD", REMOTE_NAME);
+    let prefix = format!("refs/remotes/{}/", REMOTE_NAME);
+
+    let target = repo
+        .find_reference(&head)
+        .ok()
+        .and_then(|reference| reference.symbolic_target().ok().flatten().map(String::from));
+
+    if let Some(target) = target {
+        return Ok(target.trim_start_matches(&prefix).to_string());
+    }
+
+    // no record of the default branch, stay on the branch that is checked out
+    let head = repo.head()?;
+    match head.shorthand() {
+        Ok(branch) if head.is_branch() => Ok(branch.to_string()),
+        _ => Err(anyhow!(
+            "cannot tell which branch to follow, please pin the registry to one"
+        )),
+    }
+}
+
+fn fast_forward(repo: &Repository, path: &Path, branch: &str) -> Result<()> {
+    let target = repo
+        .find_reference(&format!("refs/remotes/{}/{}", REMOTE_NAME, branch))
+        .map_err(|_| anyhow!("registry {} has no branch '{}'", path.display(), branch))?
+        .peel_to_commit()?
+        .id();
+
+    let local = format!("refs/heads/{}", branch);
+
+    if let Ok(reference) = repo.find_reference(&local) {
+        let current = reference.peel_to_commit()?.id();
+        if current != target && !repo.graph_descendant_of(target, current)? {
+            return Err(anyhow!(
+                "registry {} cannot be fast-forwarded: branch '{}' has diverged from {}",
+                path.display(),
+                branch,
+                REMOTE_NAME
+            ));
+        }
+    }
+
+    repo.reference(&local, target, true, "charon: fast-forward")?;
+    repo.set_head(&local)?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+
+    info!(
+        "Registry {} is at {} of branch {}",
+        path.display(),
+        target,
+        branch
+    );
+    Ok(())
+}
+
+fn detach(repo: &Repository, target: Oid) -> Result<()> {
+    repo.set_head_detached(target)?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{move_legacy_state, sync_registry, RegistryPin};
+    use git2::{Repository, Signature};
+    use std::path::Path;
+
+    fn commit(repo: &Repository, file: &str, content: &str) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join(file), content).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(file)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let signature = Signature::now("test", "test@example.org").unwrap();
+        let parents = match repo.head() {
+            Ok(head) => vec![head.peel_to_commit().unwrap()],
+            Err(_) => Vec::new(),
+        };
+
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            content,
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path.join("file")).unwrap()
+    }
+
+    #[test]
+    fn sync() {
+        let upstream_dir = tempfile::tempdir().unwrap();
+        let upstream = Repository::init(upstream_dir.path()).unwrap();
+        let first = commit(&upstream, "file", "first");
+        upstream
+            .tag_lightweight("v1", &upstream.find_object(first, None).unwrap(), false)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry");
+        let url = upstream_dir.path().to_str().unwrap();
+
+        sync_registry(&path, url, None).unwrap();
+        assert_eq!(read(&path), "first");
+
+        // follows the default branch
+        let second = commit(&upstream, "file", "second");
+        sync_registry(&path, url, None).unwrap();
+        assert_eq!(read(&path), "second");
+
+        // pins
+        sync_registry(&path, url, Some(&RegistryPin::Tag("v1".into()))).unwrap();
+        assert_eq!(read(&path), "first");
+        sync_registry(&path, url, Some(&RegistryPin::Commit(second.to_string()))).unwrap();
+        assert_eq!(read(&path), "second");
+        assert!(sync_registry(&path, url, Some(&RegistryPin::Tag("v2".into()))).is_err());
+
+        let branch = upstream.head().unwrap().shorthand().unwrap().to_string();
+        commit(&upstream, "file", "third");
+        sync_registry(&path, url, Some(&RegistryPin::Branch(branch.clone()))).unwrap();
+        assert_eq!(read(&path), "third");
+        assert!(sync_registry(&path, url, Some(&RegistryPin::Branch("nope".into()))).is_err());
+
+        // local modifications are reported, not stashed
+        std::fs::write(path.join("file"), "local").unwrap();
+        let err = sync_registry(&path, url, None).unwrap_err().to_string();
+        assert!(err.contains("local modifications"), "{}", err);
+        assert!(err.contains("file"), "{}", err);
+        assert_eq!(read(&path), "local");
+        std::fs::write(path.join("file"), "third").unwrap();
+
+        std::fs::write(path.join("untracked"), "").unwrap();
+        assert!(sync_registry(&path, url, None).is_err());
+        std::fs::remove_file(path.join("untracked")).unwrap();
+
+        // a branch that has diverged is not fast-forwarded
+        let local = Repository::open(&path).unwrap();
+        commit(&local, "file", "diverged");
+        commit(&upstream, "file", "fourth");
+        let err = sync_registry(&path, url, Some(&RegistryPin::Branch(branch)))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("fast-forward"), "{}", err);
+        assert_eq!(read(&path), "diverged");
+    }
+
+    #[test]
+    fn legacy_state() {
+        let upstream_dir = tempfile::tempdir().unwrap();
+        let upstream = Repository::init(upstream_dir.path()).unwrap();
+        commit(&upstream, "file", "first");
+        std::fs::create_dir_all(upstream_dir.path().join("variables")).unwrap();
+        commit(&upstream, "variables/plex.json", "{}");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry");
+        let state = dir.path().join("registry-state");
+        let url = upstream_dir.path().to_str().unwrap();
+
+        sync_registry(&path, url, None).unwrap();
+
+        // what charon wrote into the worktree before it had a state directory
+        let legacy = [
+            "installed/plex/1.0.0",
+            "responses/plex/1.0.0.json",
+            "secrets/plex/1.0.0.json",
+            "variables/plex/1.0.0.json",
+            "site.json",
+            "facts.json",
+        ];
+        for file in legacy {
+            let file = path.join(file);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, "legacy").unwrap();
+        }
+        std::fs::write(path.join("untracked"), "").unwrap();
+
+        let err = sync_registry(&path, url, None).unwrap_err().to_string();
+        assert!(err.contains("local modifications"), "{}", err);
+
+        move_legacy_state(&path, &state).unwrap();
+        // the package's own variables weren't edited, so they are left alone
+        assert!(!state.join("variables/plex.json").exists());
+
+        for file in legacy {
+            assert!(!path.join(file).exists(), "{}", file);
+            assert_eq!(std::fs::read_to_string(state.join(file)).unwrap(), "legacy");
+        }
+
+        // what git tracks stays, and anything else is still reported
+        assert_eq!(
+            std::fs::read_to_string(path.join("variables/plex.json")).unwrap(),
+            "{}"
+        );
+        assert!(!state.join("variables/plex.json").exists());
+        let err = sync_registry(&path, url, None).unwrap_err().to_string();
+        assert!(err.contains("untracked"), "{}", err);
+        assert!(!err.contains("installed"), "{}", err);
+
+        std::fs::remove_file(path.join("untracked")).unwrap();
+        commit(&upstream, "file", "second");
+        sync_registry(&path, url, None).unwrap();
+        assert_eq!(read(&path), "second");
+
+        // edits of a package's own variables become local edits kept with the state
+        std::fs::write(path.join("variables/plex.json"), "edited").unwrap();
+        assert!(sync_registry(&path, url, None).is_err());
+        move_legacy_state(&path, &state).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(state.join("variables/plex.json")).unwrap(),
+            "edited"
+        );
+        assert_eq!(
+            std::fs::read_to_string(path.join("variables/plex.json")).unwrap(),
+            "{}"
+        );
+        sync_registry(&path, url, None).unwrap();
+
+        // state that was already moved is not overwritten
+        std::fs::create_dir_all(path.join("installed/plex")).unwrap();
+        std::fs::write(path.join("installed/plex/1.0.0"), "again").unwrap();
+        move_legacy_state(&path, &state).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(state.join("installed/plex/1.0.0")).unwrap(),
+            "legacy"
+        );
+        assert!(sync_registry(&path, url, None).is_err());
+    }
+}
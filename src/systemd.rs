@@ -10,8 +10,8 @@ const UNIT_TEMPLATE: &str = r#"
 Description=Charon launcher for @PACKAGE_NAME@, version @PACKAGE_VERSION@
 
 [Service]
-ExecStart=@CHARON_PATH@ -r @REGISTRY_PATH@ launch @PACKAGE_NAME@ @PACKAGE_VERSION@ @VOLUME_ROOT@
-ExecStop=@CHARON_PATH@ -r @REGISTRY_PATH@ stop @PACKAGE_NAME@ @PACKAGE_VERSION@ @VOLUME_ROOT@
+ExecStart=@CHARON_PATH@ -r @REGISTRY_PATH@ --state @STATE_PATH@ launch @PACKAGE_NAME@ @PACKAGE_VERSION@ @VOLUME_ROOT@
+ExecStop=@CHARON_PATH@ -r @REGISTRY_PATH@ --state @STATE_PATH@ stop @PACKAGE_NAME@ @PACKAGE_VERSION@ @VOLUME_ROOT@
 Restart=always
 
 [Install]
@@ -61,6 +61,9 @@ impl SystemdUnit {
                         "PACKAGE_FILENAME" => out.push_str(&self.package.title.to_string()),
                         "VOLUME_ROOT" => out.push_str(volume_root.to_str().unwrap_or_default()),
                         "REGISTRY_PATH" => out.push_str(registry_path.to_str().unwrap_or_default()),
+                        "STATE_PATH" => {
+                            out.push_str(self.package.state().to_str().unwrap_or_default())
+                        }
                         "CHARON_PATH" => out.push_str(self.charon_path.clone().to_str().unwrap()),
                         _ => return Err(anyhow!("invalid template variable '{}'", variable)),
                     };
@@ -121,6 +124,19 @@ impl SystemdUnit {
         Ok(())
     }
 
+    // the volume root the unit was written with, if there is one
+    pub fn volume_root(&self) -> Result<Option<PathBuf>> {
+        if !std::fs::exists(self.filename())? {
+            return Ok(None);
+        }
+
+        Ok(std::fs::read_to_string(self.filename())?
+            .lines()
+            .find_map(|line| line.strip_prefix("ExecStart="))
+            .and_then(|command| command.split_whitespace().last())
+            .map(PathBuf::from))
+    }
+
     pub async fn remove_unit(&self) -> Result<()> {
         let client = buckle::systemd::Systemd::new_system().await?;
         let _ = client
@@ -143,7 +159,7 @@ impl SystemdUnit {
 #[cfg(test)]
 mod tests {
     use super::SystemdUnit;
-    use crate::{CompiledPackage, Registry, SYSTEMD_SERVICE_ROOT};
+    use crate::{CompiledPackage, Registry, Trust, SYSTEMD_SERVICE_ROOT};
     use anyhow::Result;
     use tempfile::TempDir;
 
@@ -153,7 +169,7 @@ mod tests {
 
     #[test]
     fn unit_names() {
-        let registry = Registry::new("testdata/registry".into());
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
         let unit = SystemdUnit::new(
             load(&registry, "podman-test", "0.0.2").unwrap(),
             SYSTEMD_SERVICE_ROOT.into(),
@@ -169,7 +185,7 @@ mod tests {
 
     #[test]
     fn unit_contents() {
-        let registry = Registry::new("testdata/registry".into());
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
         let td = TempDir::new().unwrap();
         let path = td.path();
         let pkg = load(&registry, "podman-test", "0.0.2").unwrap();
@@ -189,8 +205,8 @@ mod tests {
 Description=Charon launcher for podman-test, version 0.0.2
 
 [Service]
-ExecStart=/usr/bin/charon -r testdata/registry launch podman-test 0.0.2 {}
-ExecStop=/usr/bin/charon -r testdata/registry stop podman-test 0.0.2 {}
+ExecStart=/usr/bin/charon -r testdata/registry --state testdata/registry launch podman-test 0.0.2 {}
+ExecStop=/usr/bin/charon -r testdata/registry --state testdata/registry stop podman-test 0.0.2 {}
 Restart=always
 
 [Install]
@@ -201,4 +217,27 @@ Alias=podman-test-0.0.2.service
             )
         );
     }
+
+    #[test]
+    fn volume_root() {
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
+        let systemd_root = TempDir::new().unwrap();
+        let unit = SystemdUnit::new(
+            load(&registry, "podman-test", "0.0.2").unwrap(),
+            systemd_root.path().to_path_buf(),
+            crate::DEFAULT_CHARON_BIN_PATH.into(),
+        );
+        assert_eq!(unit.volume_root().unwrap(), None);
+
+        std::fs::write(
+            unit.filename(),
+            unit.unit("testdata/registry".into(), "/trunk/podman-test".into())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            unit.volume_root().unwrap(),
+            Some("/trunk/podman-test".into())
+        );
+    }
 }
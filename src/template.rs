@@ -0,0 +1,896 @@
+use anyhow::Result;
+
+//
+// This is the template language shared by globals (@name@) and prompts (?name?). The delimiter is
+// the only thing that differs between the two; everything between a pair of delimiters is an
+// expression, optionally followed by filters:
+//
+//   @host|lower@                   variable with a filter
+//   ?port|default:8080?            fallback when there is no value
+//   ?https|if:443:80?              pick a value based on the truthiness of the expression
+//   ?size / 1048576?               arithmetic: + - * / % and parens, on integers
+//   ?mode == "fast" && cpus > 2?   comparisons: == != < <= > >=, and ! && ||
+//
+// Two delimiters in a row (@@ or ??) produce a single delimiter. An unterminated delimiter, or a
+// pair of delimiters whose contents start or end with whitespace ("why? no reason?"), is left
+// alone as plain text. Variable names may contain letters, numbers, '_', '.' and '-', so
+// subtraction needs spaces around the operator.
+//
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TemplateError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "{} (at character {})",
+            self.message, self.position
+        ))
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+fn error<T>(position: usize, message: String) -> Result<T, TemplateError> {
+    Err(TemplateError { position, message })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Expression(Pipeline),
+}
+
+impl Template {
+    pub fn parse(s: &str, delimiter: char) -> Result<Self, TemplateError> {
+        let chars = s.chars().collect::<Vec<char>>();
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            if chars[i] != delimiter {
+                text.push(chars[i]);
+                i += 1;
+                continue;
+            }
+
+            let start = i + 1;
+            let len = match chars[start..].iter().position(|c| *c == delimiter) {
+                Some(len) => len,
+                None => {
+                    // unterminated, don't swallow anything
+                    text.extend(&chars[i..]);
+                    break;
+                }
+            };
+
+            if len == 0 {
+                // @@ or ??, not a template
+                text.push(delimiter);
+                i = start + 1;
+                continue;
+            }
+
+            let inner = &chars[start..start + len];
+            if inner[0].is_whitespace() || inner[len - 1].is_whitespace() {
+                // prose, not a template. the closing delimiter may start a template of its own.
+                text.push(delimiter);
+                text.extend(inner);
+                i = start + len;
+                continue;
+            }
+
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+
+            segments.push(Segment::Expression(Pipeline::parse(inner, start)?));
+            i = start + len + 1;
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(Self { segments })
+    }
+
+    // parses a bare expression without delimiters, such as the `when` condition of a prompt
+    pub fn parse_expression(s: &str) -> Result<Self, TemplateError> {
+        let chars = s.chars().collect::<Vec<char>>();
+        if chars.iter().all(|c| c.is_whitespace()) {
+            return error(0, "expected an expression".into());
+        }
+
+        Ok(Self {
+            segments: vec![Segment::Expression(Pipeline::parse(&chars, 0)?)],
+        })
+    }
+
+    // the names of all variables referenced, in order of appearance
+    pub fn variables(&self) -> Vec<String> {
+        let mut v = Vec::new();
+        for segment in &self.segments {
+            if let Segment::Expression(pipeline) = segment {
+                pipeline.expr.variables(&mut v);
+            }
+        }
+
+        v
+    }
+
+    pub fn render<F>(&self, lookup: F) -> Result<String>
+    where
+        F: Fn(&str) -> Result<Option<String>>,
+    {
+        let mut out = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => out.push_str(text),
+                Segment::Expression(pipeline) => match pipeline.evaluate(&lookup)? {
+                    Value::Missing(name, position) => {
+                        return Err(TemplateError {
+                            position,
+                            message: format!("No value for '{}'", name),
+                        }
+                        .into())
+                    }
+                    value => out.push_str(&value.to_string()),
+                },
+            }
+        }
+
+        Ok(out)
+    }
+
+    // true if every expression in the template is truthy. unlike render, missing values are not an
+    // error here, they are just false.
+    pub fn truthy<F>(&self, lookup: F) -> Result<bool>
+    where
+        F: Fn(&str) -> Result<Option<String>>,
+    {
+        for segment in &self.segments {
+            match segment {
+                Segment::Expression(pipeline) if !pipeline.evaluate(&lookup)?.truthy() => {
+                    return Ok(false)
+                }
+                _ => {}
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Pipeline {
+    expr: Expr,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Filter {
+    name: String,
+    args: Vec<String>,
+    position: usize,
+}
+
+// splits on `split` where it isn't quoted, parenthesized or doubled (so || survives a split on |).
+// returns each part with the position it starts at.
+fn split_top_level(chars: &[char], offset: usize, split: char) -> Vec<(Vec<char>, usize)> {
+    let mut parts = Vec::new();
+    let mut current = Vec::new();
+    let mut start = offset;
+    let mut quoted = false;
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == '"' {
+            quoted = !quoted;
+        } else if !quoted && ch == '(' {
+            depth += 1;
+        } else if !quoted && ch == ')' && depth > 0 {
+            depth -= 1;
+        } else if !quoted && depth == 0 && ch == split {
+            if chars.get(i + 1) == Some(&split) {
+                current.push(ch);
+                current.push(ch);
+                i += 2;
+                continue;
+            }
+
+            parts.push((std::mem::take(&mut current), start));
+            start = offset + i + 1;
+            i += 1;
+            continue;
+        }
+
+        current.push(ch);
+        i += 1;
+    }
+
+    parts.push((current, start));
+    parts
+}
+
+impl Pipeline {
+    fn parse(chars: &[char], offset: usize) -> Result<Self, TemplateError> {
+        let mut parts = split_top_level(chars, offset, '|').into_iter();
+        let (expr, expr_offset) = parts.next().unwrap();
+
+        let tokens = tokenize(&expr, expr_offset)?;
+        let mut parser = Parser {
+            tokens,
+            index: 0,
+            end: expr_offset + expr.len(),
+        };
+        let expr = parser.expression()?;
+        if let Some(token) = parser.peek() {
+            return error(token.position, "unexpected input after expression".into());
+        }
+
+        let mut filters = Vec::new();
+
+        for (filter, position) in parts {
+            let mut args = split_top_level(&filter, position, ':').into_iter();
+            let (name, _) = args.next().unwrap();
+            let name = name.iter().collect::<String>().trim().to_string();
+            if name.is_empty() {
+                return error(position, "expected a filter name".into());
+            }
+
+            filters.push(Filter {
+                name,
+                args: args.map(|(arg, _)| unquote(&arg)).collect(),
+                position,
+            });
+        }
+
+        Ok(Self { expr, filters })
+    }
+
+    fn evaluate<F>(&self, lookup: &F) -> Result<Value>
+    where
+        F: Fn(&str) -> Result<Option<String>>,
+    {
+        let mut value = self.expr.evaluate(lookup)?;
+
+        for filter in &self.filters {
+            value = filter.apply(value)?;
+        }
+
+        Ok(value)
+    }
+}
+
+fn unquote(arg: &[char]) -> String {
+    let s = arg.iter().collect::<String>();
+    let trimmed = s.trim();
+
+    if trimmed.len() >= 2 && trimmed.starts_with('"') && trimmed.ends_with('"') {
+        trimmed[1..trimmed.len() - 1].to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+impl Filter {
+    fn arity(&self, min: usize, max: usize) -> Result<(), TemplateError> {
+        if self.args.len() < min || self.args.len() > max {
+            let expected = if min == max {
+                min.to_string()
+            } else {
+                format!("{} to {}", min, max)
+            };
+
+            return error(
+                self.position,
+                format!(
+                    "filter '{}' takes {} arguments, got {}",
+                    self.name,
+                    expected,
+                    self.args.len()
+                ),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn apply(&self, value: Value) -> Result<Value, TemplateError> {
+        match self.name.as_str() {
+            "default" => {
+                self.arity(1, 1)?;
+                Ok(match value {
+                    Value::Missing(..) => Value::String(self.args[0].clone()),
+                    value => value,
+                })
+            }
+            "if" => {
+                self.arity(1, 2)?;
+                Ok(Value::String(if value.truthy() {
+                    self.args[0].clone()
+                } else {
+                    self.args.get(1).cloned().unwrap_or_default()
+                }))
+            }
+            "lower" | "upper" | "trim" => {
+                self.arity(0, 0)?;
+                Ok(match value {
+                    Value::Missing(..) => value,
+                    value => {
+                        let s = value.to_string();
+                        Value::String(match self.name.as_str() {
+                            "lower" => s.to_lowercase(),
+                            "upper" => s.to_uppercase(),
+                            _ => s.trim().to_string(),
+                        })
+                    }
+                })
+            }
+            _ => error(self.position, format!("unknown filter '{}'", self.name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Variable(String, usize),
+    Integer(i64),
+    String(String),
+    Boolean(bool),
+    Not(Box<Expr>),
+    Negate(Box<Expr>, usize),
+    Binary(Box<Expr>, Operator, Box<Expr>, usize),
+}
+
+impl Expr {
+    fn variables(&self, v: &mut Vec<String>) {
+        match self {
+            Self::Variable(name, _) => v.push(name.clone()),
+            Self::Not(expr) | Self::Negate(expr, _) => expr.variables(v),
+            Self::Binary(left, _, right, _) => {
+                left.variables(v);
+                right.variables(v);
+            }
+            _ => {}
+        }
+    }
+
+    fn evaluate<F>(&self, lookup: &F) -> Result<Value>
+    where
+        F: Fn(&str) -> Result<Option<String>>,
+    {
+        Ok(match self {
+            Self::Variable(name, position) => match lookup(name)? {
+                Some(value) => Value::String(value),
+                None => Value::Missing(name.clone(), *position),
+            },
+            Self::Integer(x) => Value::Integer(*x),
+            Self::String(x) => Value::String(x.clone()),
+            Self::Boolean(x) => Value::Boolean(*x),
+            Self::Not(expr) => Value::Boolean(!expr.evaluate(lookup)?.truthy()),
+            Self::Negate(expr, position) => {
+                let x = expr.evaluate(lookup)?.integer(*position)?;
+                Value::Integer(x.checked_neg().ok_or_else(|| TemplateError {
+                    position: *position,
+                    message: "integer overflow".into(),
+                })?)
+            }
+            Self::Binary(left, op, right, position) => {
+                let left = left.evaluate(lookup)?;
+
+                // short circuit
+                match op {
+                    Operator::And if !left.truthy() => return Ok(Value::Boolean(false)),
+                    Operator::Or if left.truthy() => return Ok(Value::Boolean(true)),
+                    _ => {}
+                }
+
+                let right = right.evaluate(lookup)?;
+                binary(left, *op, right, *position)?
+            }
+        })
+    }
+}
+
+fn binary(
+    left: Value,
+    op: Operator,
+    right: Value,
+    position: usize,
+) -> Result<Value, TemplateError> {
+    let overflow = || TemplateError {
+        position,
+        message: "integer overflow".into(),
+    };
+
+    Ok(match op {
+        Operator::And | Operator::Or => Value::Boolean(right.truthy()),
+        Operator::Eq | Operator::Ne => {
+            let equal = match (&left, &right) {
+                (Value::Missing(..), _) | (_, Value::Missing(..)) => false,
+                _ => match (left.integer(position), right.integer(position)) {
+                    (Ok(l), Ok(r)) => l == r,
+                    _ => left.to_string() == right.to_string(),
+                },
+            };
+
+            Value::Boolean(if op == Operator::Eq { equal } else { !equal })
+        }
+        Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge => {
+            let ordering = match (left.integer(position), right.integer(position)) {
+                (Ok(l), Ok(r)) => l.cmp(&r),
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            };
+
+            Value::Boolean(match op {
+                Operator::Lt => ordering.is_lt(),
+                Operator::Le => ordering.is_le(),
+                Operator::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })
+        }
+        _ => {
+            let l = left.integer(position)?;
+            let r = right.integer(position)?;
+
+            Value::Integer(match op {
+                Operator::Add => l.checked_add(r).ok_or_else(overflow)?,
+                Operator::Sub => l.checked_sub(r).ok_or_else(overflow)?,
+                Operator::Mul => l.checked_mul(r).ok_or_else(overflow)?,
+                Operator::Div | Operator::Rem if r == 0 => {
+                    return error(position, "division by zero".into())
+                }
+                Operator::Div => l / r,
+                _ => l % r,
+            })
+        }
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Missing(String, usize),
+    Integer(i64),
+    String(String),
+    Boolean(bool),
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Self::Missing(..) => false,
+            Self::Integer(x) => *x != 0,
+            Self::Boolean(x) => *x,
+            Self::String(x) => !matches!(x.trim(), "" | "0" | "false"),
+        }
+    }
+
+    fn integer(&self, position: usize) -> Result<i64, TemplateError> {
+        match self {
+            Self::Integer(x) => Ok(*x),
+            Self::String(x) => x
+                .trim()
+                .parse()
+                .or_else(|_| error(position, format!("'{}' is not an integer", x))),
+            Self::Boolean(x) => error(position, format!("'{}' is not an integer", x)),
+            Self::Missing(name, position) => error(*position, format!("No value for '{}'", name)),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing(..) => Ok(()),
+            Self::Integer(x) => f.write_str(&x.to_string()),
+            Self::String(x) => f.write_str(x),
+            Self::Boolean(x) => f.write_str(&x.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Integer(i64),
+    String(String),
+    Ident(String),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+const OPERATORS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "!",
+];
+
+// whether a name is one templates can refer to, e.g. `http_port` or `host.cpus`
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_identifier_start) && chars.all(is_identifier_char)
+}
+
+fn is_identifier_start(ch: char) -> bool {
+    ch.is_alphabetic() || ch == '_'
+}
+
+fn is_identifier_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ['_', '.', '-'].contains(&ch)
+}
+
+fn tokenize(chars: &[char], offset: usize) -> Result<Vec<Token>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    'outer: while i < chars.len() {
+        let ch = chars[i];
+        let position = offset + i;
+
+        if ch.is_whitespace() {
+            i += 1;
+        } else if ch == '(' || ch == ')' {
+            tokens.push(Token {
+                kind: if ch == '(' {
+                    TokenKind::Open
+                } else {
+                    TokenKind::Close
+                },
+                position,
+            });
+            i += 1;
+        } else if ch == '"' {
+            let len = match chars[i + 1..].iter().position(|c| *c == '"') {
+                Some(len) => len,
+                None => return error(position, "unterminated string".into()),
+            };
+
+            tokens.push(Token {
+                kind: TokenKind::String(chars[i + 1..i + 1 + len].iter().collect()),
+                position,
+            });
+            i += len + 2;
+        } else if ch.is_ascii_digit() {
+            let mut s = String::new();
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                s.push(chars[i]);
+                i += 1;
+            }
+
+            tokens.push(Token {
+                kind: TokenKind::Integer(
+                    s.parse()
+                        .or_else(|_| error(position, format!("integer '{}' is too large", s)))?,
+                ),
+                position,
+            });
+        } else if is_identifier_start(ch) {
+            let mut s = String::new();
+            while i < chars.len() && is_identifier_char(chars[i]) {
+                s.push(chars[i]);
+                i += 1;
+            }
+
+            tokens.push(Token {
+                kind: TokenKind::Ident(s),
+                position,
+            });
+        } else {
+            for op in OPERATORS {
+                let len = op.chars().count();
+                if chars[i..].starts_with(&op.chars().collect::<Vec<char>>()) {
+                    tokens.push(Token {
+                        kind: TokenKind::Operator(op),
+                        position,
+                    });
+                    i += len;
+                    continue 'outer;
+                }
+            }
+
+            return error(position, format!("unexpected character '{}'", ch));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Result<Token, TemplateError> {
+        match self.tokens.get(self.index) {
+            Some(token) => {
+                self.index += 1;
+                Ok(token.clone())
+            }
+            None => error(self.end, "unexpected end of expression".into()),
+        }
+    }
+
+    fn operator(&mut self, ops: &[(&str, Operator)]) -> Option<(Operator, usize)> {
+        if let Some(Token {
+            kind: TokenKind::Operator(s),
+            position,
+        }) = self.peek()
+        {
+            for (name, op) in ops {
+                if name == s {
+                    let res = (*op, *position);
+                    self.index += 1;
+                    return Some(res);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn binary<F>(&mut self, ops: &[(&str, Operator)], next: F) -> Result<Expr, TemplateError>
+    where
+        F: Fn(&mut Self) -> Result<Expr, TemplateError>,
+    {
+        let mut left = next(self)?;
+        while let Some((op, position)) = self.operator(ops) {
+            let right = next(self)?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right), position);
+        }
+
+        Ok(left)
+    }
+
+    fn expression(&mut self) -> Result<Expr, TemplateError> {
+        self.binary(&[("||", Operator::Or)], |p| {
+            p.binary(&[("&&", Operator::And)], Self::comparison)
+        })
+    }
+
+    fn comparison(&mut self) -> Result<Expr, TemplateError> {
+        let left = self.sum()?;
+
+        if let Some((op, position)) = self.operator(&[
+            ("==", Operator::Eq),
+            ("!=", Operator::Ne),
+            ("<=", Operator::Le),
+            (">=", Operator::Ge),
+            ("<", Operator::Lt),
+            (">", Operator::Gt),
+        ]) {
+            let right = self.sum()?;
+            return Ok(Expr::Binary(Box::new(left), op, Box::new(right), position));
+        }
+
+        Ok(left)
+    }
+
+    fn sum(&mut self) -> Result<Expr, TemplateError> {
+        self.binary(&[("+", Operator::Add), ("-", Operator::Sub)], |p| {
+            p.binary(
+                &[
+                    ("*", Operator::Mul),
+                    ("/", Operator::Div),
+                    ("%", Operator::Rem),
+                ],
+                Self::unary,
+            )
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, TemplateError> {
+        if let Some((op, position)) = self.operator(&[("!", Operator::Ne), ("-", Operator::Sub)]) {
+            let expr = Box::new(self.unary()?);
+            return Ok(if op == Operator::Ne {
+                Expr::Not(expr)
+            } else {
+                Expr::Negate(expr, position)
+            });
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, TemplateError> {
+        let token = self.next()?;
+
+        Ok(match token.kind {
+            TokenKind::Integer(x) => Expr::Integer(x),
+            TokenKind::String(x) => Expr::String(x),
+            TokenKind::Ident(x) if x == "true" => Expr::Boolean(true),
+            TokenKind::Ident(x) if x == "false" => Expr::Boolean(false),
+            TokenKind::Ident(x) => Expr::Variable(x, token.position),
+            TokenKind::Open => {
+                let expr = self.expression()?;
+                match self.next()? {
+                    Token {
+                        kind: TokenKind::Close,
+                        ..
+                    } => expr,
+                    token => return error(token.position, "expected ')'".into()),
+                }
+            }
+            _ => return error(token.position, "expected a value".into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Template, TemplateError};
+    use anyhow::Result;
+    use std::collections::HashMap;
+
+    fn render(s: &str, vars: &[(&str, &str)]) -> Result<String> {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>();
+        Template::parse(s, '?')?.render(|name| Ok(vars.get(name).cloned()))
+    }
+
+    fn position(s: &str, vars: &[(&str, &str)]) -> usize {
+        render(s, vars)
+            .unwrap_err()
+            .downcast::<TemplateError>()
+            .unwrap()
+            .position
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(render("?a?", &[("a", "b")]).unwrap(), "b");
+        assert_eq!(
+            render("x ?a? ?b? y", &[("a", "1"), ("b", "2")]).unwrap(),
+            "x 1 2 y"
+        );
+        assert_eq!(render("??", &[]).unwrap(), "?");
+        assert_eq!(render("?a", &[]).unwrap(), "?a");
+        assert_eq!(render("why? no reason?", &[]).unwrap(), "why? no reason?");
+        assert_eq!(render("?a ?b?", &[("b", "c")]).unwrap(), "?a c");
+        assert!(render("?a?", &[]).is_err());
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(render("?port|default:8080?", &[]).unwrap(), "8080");
+        assert_eq!(
+            render("?port|default:8080?", &[("port", "1")]).unwrap(),
+            "1"
+        );
+        assert_eq!(render("?host|lower?", &[("host", "PLEX")]).unwrap(), "plex");
+        assert_eq!(render("?host|default:Plex|upper?", &[]).unwrap(), "PLEX");
+        assert_eq!(render("?x|default:\"a|b:c\"?", &[]).unwrap(), "a|b:c");
+        assert_eq!(
+            render("?https|if:443:80?", &[("https", "true")]).unwrap(),
+            "443"
+        );
+        assert_eq!(
+            render("?https|if:443:80?", &[("https", "false")]).unwrap(),
+            "80"
+        );
+        assert_eq!(render("?https|if:443:80?", &[]).unwrap(), "80");
+        assert!(render("?a|nope?", &[("a", "b")]).is_err());
+        assert!(render("?a|default?", &[]).is_err());
+    }
+
+    #[test]
+    fn expressions() {
+        let size = ("size", "10737418240");
+        assert_eq!(render("?size / 1048576?", &[size]).unwrap(), "10240");
+        assert_eq!(render("?(1 + 2) * 3 - -1?", &[]).unwrap(), "10");
+        assert_eq!(render("?7 % 4?", &[]).unwrap(), "3");
+        assert_eq!(
+            render(
+                "?cpus > 2 && mode == \"fast\"?",
+                &[("cpus", "4"), ("mode", "fast")]
+            )
+            .unwrap(),
+            "true"
+        );
+        assert_eq!(
+            render("?!enabled?", &[("enabled", "false")]).unwrap(),
+            "true"
+        );
+        assert_eq!(render("?missing || 1 == 1?", &[]).unwrap(), "true");
+        assert_eq!(render("?a-b?", &[("a-b", "x")]).unwrap(), "x");
+        assert_eq!(
+            render("?cpus * 2 > 4|if:big:small?", &[("cpus", "4")]).unwrap(),
+            "big"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(position("?1 / 0?", &[]), 3);
+        assert_eq!(position("abc ?missing?", &[]), 5);
+        assert_eq!(position("?a + 1?", &[("a", "x")]), 3);
+        assert_eq!(position("?(1 + 2?", &[]), 7);
+        assert_eq!(position("?1 $ 2?", &[]), 3);
+        assert_eq!(position("?a|bogus?", &[("a", "b")]), 3);
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            Template::parse("@a@ and @b + c|default:1@", '@')
+                .unwrap()
+                .variables(),
+            vec!["a", "b", "c"]
+        );
+        assert!(Template::parse("is it? yes it is?", '?')
+            .unwrap()
+            .variables()
+            .is_empty());
+    }
+
+    #[test]
+    fn conditions() {
+        let truthy = |s: &str, vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<String, String>>();
+            Template::parse_expression(s)
+                .unwrap()
+                .truthy(|name| Ok(vars.get(name).cloned()))
+                .unwrap()
+        };
+
+        assert!(truthy("https", &[("https", "true")]));
+        assert!(!truthy("https", &[("https", "false")]));
+        assert!(!truthy("https", &[]));
+        assert!(truthy("!https", &[]));
+        assert!(truthy(
+            "mode == \"fast\" && cpus > 2",
+            &[("mode", "fast"), ("cpus", "4")]
+        ));
+        assert!(!truthy(
+            "mode == \"fast\" && cpus > 2",
+            &[("mode", "slow"), ("cpus", "4")]
+        ));
+        assert!(Template::parse_expression("a b").is_err());
+        assert!(Template::parse_expression(" ").is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+//
+// packages run with whatever privileges their definitions ask for, so anyone who can change a
+// registry can run anything on the hosts that use it. package definitions are therefore signed:
+// each has a detached signature beside it, `<version>.json.sig`, made with minisign or with
+// `ssh-keygen -Y sign -n charon`. the variables of a package are templated into it just the same,
+// so `variables/<name>.json` is signed the same way when there is one. a registry only loads
+// packages signed by one of the keys it trusts, unless it is explicitly trusted without
+// signatures.
+//
+// the daemon gives each registry what it is configured to trust, and also records it where
+// `charon launch` finds it: in the registry's state directory, or beside the registry when the
+// state is kept in it, so whoever can change the registry cannot change what it trusts. a
+// registry that is trusted with nothing, e.g. one only used with the charon tool, loads nothing.
+//
+
+pub const SIGNATURE_EXTENSION: &str = "sig";
+pub const SSH_SIGNATURE_NAMESPACE: &str = "charon";
+const TRUST_FILENAME: &str = "trust.json";
+const SSH_KEY_PREFIX: &str = "ssh-";
+const SSH_SIGNATURE_HEADER: &str = "-----BEGIN SSH SIGNATURE-----";
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Trust {
+    // minisign public keys, e.g. `RWQ...`, or SSH public keys, e.g. `ssh-ed25519 AAAA...`
+    #[serde(default)]
+    pub keys: Vec<String>,
+    // load packages without checking their signatures at all
+    #[serde(default)]
+    pub unsigned: bool,
+}
+
+enum TrustedKey {
+    Minisign(minisign_verify::PublicKey),
+    Ssh(ssh_key::PublicKey),
+}
+
+impl TrustedKey {
+    fn parse(key: &str) -> Result<Self> {
+        let key = key.trim();
+
+        if key.starts_with(SSH_KEY_PREFIX) {
+            return Ok(Self::Ssh(
+                ssh_key::PublicKey::from_openssh(key)
+                    .map_err(|e| anyhow!("invalid SSH key '{}': {}", key, e))?,
+            ));
+        }
+
+        // the key alone, or the whole of a minisign .pub file
+        let parsed = if key.contains('\n') {
+            minisign_verify::PublicKey::decode(key)
+        } else {
+            minisign_verify::PublicKey::from_base64(key)
+        };
+
+        Ok(Self::Minisign(parsed.map_err(|e| {
+            anyhow!("invalid minisign key '{}': {}", key, e)
+        })?))
+    }
+}
+
+impl Trust {
+    // trusts packages without checking their signatures
+    pub fn unsigned() -> Self {
+        Self {
+            keys: Vec::new(),
+            unsigned: true,
+        }
+    }
+
+    // validates the keys, so a typo is found when the configuration is loaded rather than when a
+    // package is
+    pub fn check(&self) -> Result<()> {
+        self.trusted_keys().map(|_| ())
+    }
+
+    fn trusted_keys(&self) -> Result<Vec<TrustedKey>> {
+        self.keys.iter().map(|key| TrustedKey::parse(key)).collect()
+    }
+
+    pub fn verify(&self, definition: &[u8], signature: Option<&str>) -> Result<()> {
+        if self.unsigned {
+            return Ok(());
+        }
+
+        let signature = signature.ok_or_else(|| anyhow!("it is not signed"))?;
+        let keys = self.trusted_keys()?;
+
+        if keys.is_empty() {
+            return Err(anyhow!("no keys are trusted to sign it"));
+        }
+
+        let verified = if signature.trim_start().starts_with(SSH_SIGNATURE_HEADER) {
+            let signature = ssh_key::SshSig::from_pem(signature)
+                .map_err(|e| anyhow!("its signature is invalid: {}", e))?;
+
+            keys.iter().any(|key| match key {
+                TrustedKey::Ssh(key) => key
+                    .verify(SSH_SIGNATURE_NAMESPACE, definition, &signature)
+                    .is_ok(),
+                TrustedKey::Minisign(_) => false,
+            })
+        } else {
+            let signature = minisign_verify::Signature::decode(signature)
+                .map_err(|e| anyhow!("its signature is invalid: {}", e))?;
+
+            keys.iter().any(|key| match key {
+                TrustedKey::Minisign(key) => key.verify(definition, &signature, false).is_ok(),
+                TrustedKey::Ssh(_) => false,
+            })
+        };
+
+        if !verified {
+            return Err(anyhow!("it is not signed by a trusted key"));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct TrustRegistry {
+    pub root: PathBuf,
+}
+
+impl TrustRegistry {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    // None if nothing was recorded, in which case nothing is trusted
+    pub fn get(&self) -> Result<Option<Trust>> {
+        let path = self.root.join(TRUST_FILENAME);
+
+        if !std::fs::exists(&path)? {
+            return Ok(None);
+        }
+
+        Ok(Some(serde_json::from_reader(
+            std::fs::OpenOptions::new().read(true).open(path)?,
+        )?))
+    }
+
+    pub fn set(&self, trust: &Trust) -> Result<()> {
+        trust.check()?;
+
+        std::fs::create_dir_all(&self.root)?;
+        let path = self.root.join(TRUST_FILENAME);
+        let tmp = self.root.join(format!("{}.tmp", TRUST_FILENAME));
+        serde_json::to_writer_pretty(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&tmp)?,
+            trust,
+        )?;
+
+        Ok(std::fs::rename(tmp, path)?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{Trust, TrustRegistry, SIGNATURE_EXTENSION, SSH_SIGNATURE_NAMESPACE};
+    use crate::{PackageTitle, Registry, SourcePackage};
+    use ssh_key::{private::Ed25519Keypair, HashAlg, LineEnding, PrivateKey};
+
+    // made with minisign, signing MINISIGN_MESSAGE. also used by the tests of index.rs
+    pub(crate) const MINISIGN_KEY: &str =
+        "RWQBAgMEBQYHCAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4";
+    pub(crate) const MINISIGN_MESSAGE: &[u8] = br#"{"title":{"name":"signed","version":"1.0.0"}}"#;
+    pub(crate) const MINISIGN_SIGNATURE: &str = "untrusted comment: signature from minisign secret key\nRUQBAgMEBQYHCLuIETdnEo1nL638aqjNqOqx0JLYoBjS8y2UkmY2klBzIJSfb03JHCYArEBuhp8pdiu3sjajWJYmO+4HkRkUtA0=\ntrusted comment: timestamp:1760745600\tfile:1.0.0.json\npn9tlKwru0XcT9Vl1mu5iXGJ7CFD+UW3bjCfRYzIaTnFGEopvyymRTIlEp+StSmka70tFcBxadVnCHkFbRAaBg==\n";
+
+    pub(crate) fn ssh_key(seed: u8) -> PrivateKey {
+        PrivateKey::from(Ed25519Keypair::from_seed(&[seed; 32]))
+    }
+
+    pub(crate) fn ssh_sign(key: &PrivateKey, message: &[u8]) -> String {
+        key.sign(SSH_SIGNATURE_NAMESPACE, HashAlg::Sha512, message)
+            .unwrap()
+            .to_pem(LineEnding::LF)
+            .unwrap()
+    }
+
+    #[test]
+    fn minisign() {
+        let trust = Trust {
+            keys: vec![MINISIGN_KEY.into()],
+            unsigned: false,
+        };
+        assert!(trust.check().is_ok());
+        assert!(trust
+            .verify(MINISIGN_MESSAGE, Some(MINISIGN_SIGNATURE))
+            .is_ok());
+        assert!(trust
+            .verify(b"something else", Some(MINISIGN_SIGNATURE))
+            .is_err());
+        assert!(trust.verify(MINISIGN_MESSAGE, None).is_err());
+        assert!(trust.verify(MINISIGN_MESSAGE, Some("garbage")).is_err());
+
+        // not a key that is trusted
+        let key = ssh_key(1);
+        assert!(Trust {
+            keys: vec![key.public_key().to_openssh().unwrap()],
+            unsigned: false,
+        }
+        .verify(MINISIGN_MESSAGE, Some(MINISIGN_SIGNATURE))
+        .is_err());
+
+        assert!(Trust {
+            keys: vec!["RWQnope".into()],
+            unsigned: false,
+        }
+        .check()
+        .is_err());
+    }
+
+    #[test]
+    fn ssh() {
+        let key = ssh_key(1);
+        let other = ssh_key(2);
+        let trust = Trust {
+            keys: vec![MINISIGN_KEY.into(), key.public_key().to_openssh().unwrap()],
+            unsigned: false,
+        };
+        assert!(trust.check().is_ok());
+
+        let signature = ssh_sign(&key, b"message");
+        assert!(trust.verify(b"message", Some(&signature)).is_ok());
+        assert!(trust.verify(b"massage", Some(&signature)).is_err());
+        assert!(trust
+            .verify(b"message", Some(&ssh_sign(&other, b"message")))
+            .is_err());
+
+        // signed for something other than charon
+        let signature = key
+            .sign("file", HashAlg::Sha512, b"message")
+            .unwrap()
+            .to_pem(LineEnding::LF)
+            .unwrap();
+        assert!(trust.verify(b"message", Some(&signature)).is_err());
+
+        // nothing is checked when unsigned packages are trusted
+        let trust = Trust {
+            keys: Vec::new(),
+            unsigned: true,
+        };
+        assert!(trust.verify(b"message", None).is_ok());
+        assert!(trust.verify(b"message", Some("garbage")).is_ok());
+    }
+
+    #[test]
+    fn registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let registry =
+            Registry::new(dir.path().to_path_buf()).with_state(Some(state.path().to_path_buf()));
+
+        for version in ["1.0.0", "2.0.0"] {
+            registry
+                .write(&SourcePackage {
+                    title: PackageTitle {
+                        name: "signed".into(),
+                        version: version.into(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let key = ssh_key(1);
+        let definition = dir.path().join("packages/signed/1.0.0.json");
+        let signature = definition.with_extension(format!("json.{}", SIGNATURE_EXTENSION));
+        std::fs::write(
+            &signature,
+            ssh_sign(&key, &std::fs::read(&definition).unwrap()),
+        )
+        .unwrap();
+
+        // nothing recorded, nothing loads
+        let err = registry.load("signed", "2.0.0").unwrap_err().to_string();
+        assert!(err.contains("no keys are trusted"), "{}", err);
+
+        let tr = TrustRegistry::new(state.path().to_path_buf());
+        assert!(tr.get().unwrap().is_none());
+        let trust = Trust {
+            keys: vec![key.public_key().to_openssh().unwrap()],
+            unsigned: false,
+        };
+        tr.set(&trust).unwrap();
+        assert_eq!(tr.get().unwrap(), Some(trust));
+
+        assert!(registry.load("signed", "1.0.0").is_ok());
+        let err = registry.load("signed", "2.0.0").unwrap_err().to_string();
+        assert!(err.contains("not signed"), "{}", err);
+
+        // the variables of the package are signed too
+        let variables = dir.path().join("variables/signed.json");
+        std::fs::create_dir_all(variables.parent().unwrap()).unwrap();
+        std::fs::write(&variables, r#"{"name":"signed","variables":{}}"#).unwrap();
+        let err = registry.load("signed", "1.0.0").unwrap_err().to_string();
+        assert!(err.contains("its variables"), "{}", err);
+        std::fs::write(
+            variables.with_extension(format!("json.{}", SIGNATURE_EXTENSION)),
+            ssh_sign(&key, &std::fs::read(&variables).unwrap()),
+        )
+        .unwrap();
+        assert!(registry.load("signed", "1.0.0").is_ok());
+        std::fs::write(&variables, r#"{"name":"signed","variables":{"a":"b"}}"#).unwrap();
+        assert!(registry.load("signed", "1.0.0").is_err());
+        std::fs::remove_file(&variables).unwrap();
+
+        // signatures are not listed as versions, nor are versions that are not signed
+        let list = registry.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].version, "1.0.0");
+
+        std::fs::write(
+            &definition,
+            r#"{"title":{"name":"signed","version":"1.0.0"},"description":"tampered","source":{"container":"scratch"}}"#,
+        )
+        .unwrap();
+        assert!(registry.load("signed", "1.0.0").is_err());
+
+        tr.set(&Trust {
+            keys: Vec::new(),
+            unsigned: true,
+        })
+        .unwrap();
+        assert_eq!(
+            registry.load("signed", "1.0.0").unwrap().description,
+            "tampered"
+        );
+        assert!(registry.load("signed", "2.0.0").is_ok());
+
+        assert!(tr
+            .set(&Trust {
+                keys: vec!["nope".into()],
+                unsigned: false,
+            })
+            .is_err());
+
+        // what the registry is given wins over the record
+        let given = registry.clone().with_trust(Trust {
+            keys: vec![key.public_key().to_openssh().unwrap()],
+            unsigned: false,
+        });
+        assert!(given.load("signed", "1.0.0").is_err());
+        assert!(given.load("signed", "2.0.0").is_err());
+
+        // the record is never kept in the registry, even when the state is
+        let parent = tempfile::tempdir().unwrap();
+        let root = parent.path().join("registry");
+        let registry = Registry::new(root.clone());
+        assert_eq!(
+            registry.trust_registry().root,
+            parent.path().join("registry-state")
+        );
+        let registry = Registry::new(root.clone()).with_state(Some(root.join("state")));
+        assert_eq!(
+            registry.trust_registry().root,
+            parent.path().join("registry-state")
+        );
+    }
+}
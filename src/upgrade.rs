@@ -0,0 +1,497 @@
+use crate::{CompiledPackage, CompiledSource, InstallStatus, SystemdUnit, VolumeSnapshot};
+use anyhow::{anyhow, Result};
+use buckle::systemd::LastRunState;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+//
+// moves an installed package to another version in place. its volumes are snapshotted first, then
+// the unit of the old version is replaced by one for the new version, which has to come up and
+// stay up for HEALTH_CHECK_PERIOD. if it doesn't, the volumes are rolled back to the snapshot and
+// the old version is put back, so a failed upgrade leaves things as they were.
+//
+
+const HEALTH_CHECK_PERIOD: Duration = Duration::from_secs(30);
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const SNAPSHOT_PREFIX: &str = "charon-upgrade";
+
+// what an upgrade changes on the host: the snapshots of the volumes, the packages and their units
+#[tonic::async_trait]
+pub trait UpgradeBackend: std::fmt::Debug + Send + Sync {
+    fn snapshot(&self, volume_root: &Path, name: &str) -> Result<VolumeSnapshot>;
+    fn rollback_snapshot(&self, snapshot: &VolumeSnapshot) -> Result<()>;
+    fn destroy_snapshot(&self, snapshot: &VolumeSnapshot) -> Result<()>;
+    fn is_installed(&self, package: &CompiledPackage) -> Result<bool>;
+    fn has_unit(&self, package: &CompiledPackage) -> Result<bool>;
+    async fn status(&self, package: &CompiledPackage) -> Result<InstallStatus>;
+    async fn install(&self, package: &CompiledPackage) -> Result<()>;
+    async fn uninstall(&self, package: &CompiledPackage) -> Result<()>;
+    async fn create_unit(&self, package: &CompiledPackage, volume_root: &Path) -> Result<()>;
+    async fn remove_unit(&self, package: &CompiledPackage) -> Result<()>;
+}
+
+// ZFS and systemd
+#[derive(Debug, Clone)]
+pub struct HostBackend {
+    // the ZFS pool the volume root is in
+    pub pool: String,
+    pub systemd_root: PathBuf,
+    pub charon_path: PathBuf,
+}
+
+impl HostBackend {
+    fn unit(&self, package: &CompiledPackage) -> SystemdUnit {
+        SystemdUnit::new(
+            package.clone(),
+            self.systemd_root.clone(),
+            self.charon_path.clone(),
+        )
+    }
+}
+
+#[tonic::async_trait]
+impl UpgradeBackend for HostBackend {
+    fn snapshot(&self, volume_root: &Path, name: &str) -> Result<VolumeSnapshot> {
+        VolumeSnapshot::create(&self.pool, volume_root, name)
+    }
+
+    fn rollback_snapshot(&self, snapshot: &VolumeSnapshot) -> Result<()> {
+        snapshot.rollback()
+    }
+
+    fn destroy_snapshot(&self, snapshot: &VolumeSnapshot) -> Result<()> {
+        snapshot.destroy()
+    }
+
+    fn is_installed(&self, package: &CompiledPackage) -> Result<bool> {
+        package.is_installed()
+    }
+
+    fn has_unit(&self, package: &CompiledPackage) -> Result<bool> {
+        Ok(std::fs::exists(self.unit(package).filename())?)
+    }
+
+    async fn status(&self, package: &CompiledPackage) -> Result<InstallStatus> {
+        package.installed().await
+    }
+
+    async fn install(&self, package: &CompiledPackage) -> Result<()> {
+        package.install().await
+    }
+
+    async fn uninstall(&self, package: &CompiledPackage) -> Result<()> {
+        package.uninstall().await
+    }
+
+    async fn create_unit(&self, package: &CompiledPackage, volume_root: &Path) -> Result<()> {
+        self.unit(package)
+            .create_unit(package.root(), volume_root.to_path_buf())
+            .await
+    }
+
+    async fn remove_unit(&self, package: &CompiledPackage) -> Result<()> {
+        self.unit(package).remove_unit().await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Upgrade {
+    pub from: CompiledPackage,
+    pub to: CompiledPackage,
+    pub volume_root: PathBuf,
+    pub backend: Arc<dyn UpgradeBackend>,
+}
+
+impl Upgrade {
+    fn snapshot_name(&self) -> String {
+        format!(
+            "{}-{}-{}",
+            SNAPSHOT_PREFIX, self.from.title.version, self.to.title.version
+        )
+    }
+
+    // a VM keeps its disk in the volume root, a container only what it has volumes for
+    fn has_data(&self) -> bool {
+        matches!(self.from.source, CompiledSource::URL(_)) || !self.from.storage.volumes.is_empty()
+    }
+
+    // what has to hold before anything is changed
+    pub fn check(&self) -> Result<()> {
+        if self.from.title.name != self.to.title.name {
+            return Err(anyhow!(
+                "cannot upgrade {} to a different package, {}",
+                self.from.title,
+                self.to.title
+            ));
+        }
+
+        if !self.backend.is_installed(&self.from)? {
+            return Err(anyhow!("{} is not installed", self.from.title));
+        }
+
+        if self.backend.is_installed(&self.to)? {
+            return Err(anyhow!("{} is already installed", self.to.title));
+        }
+
+        Ok(())
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        self.check()?;
+
+        let snapshot = if self.has_data() {
+            let snapshot = self
+                .backend
+                .snapshot(&self.volume_root, &self.snapshot_name())?;
+            info!("Took snapshot {} of {}", snapshot, self.from.title);
+            Some(snapshot)
+        } else {
+            None
+        };
+
+        match self.switch().await {
+            Ok(()) => {
+                if let Some(Err(e)) = snapshot
+                    .as_ref()
+                    .map(|snapshot| self.backend.destroy_snapshot(snapshot))
+                {
+                    warn!(
+                        "Could not remove the snapshot of {}: {}",
+                        self.from.title, e
+                    );
+                }
+
+                info!("Upgraded {} to {}", self.from.title, self.to.title);
+                Ok(())
+            }
+            Err(e) => {
+                error!(
+                    "Upgrade of {} to {} failed, rolling back: {}",
+                    self.from.title, self.to.title, e
+                );
+
+                self.rollback(snapshot.as_ref()).await.map_err(|re| {
+                    anyhow!(
+                        "upgrade to {} failed: {}; rolling back to {} also failed: {}",
+                        self.to.title,
+                        e,
+                        self.from.title,
+                        re
+                    )
+                })?;
+
+                Err(anyhow!(
+                    "upgrade to {} failed and {} was restored: {}",
+                    self.to.title,
+                    self.from.title,
+                    e
+                ))
+            }
+        }
+    }
+
+    async fn switch(&self) -> Result<()> {
+        self.backend.remove_unit(&self.from).await?;
+        self.backend.uninstall(&self.from).await?;
+        self.backend.install(&self.to).await?;
+        self.backend
+            .create_unit(&self.to, &self.volume_root)
+            .await?;
+
+        self.wait_healthy().await
+    }
+
+    // undoes as much of switch() as was done
+    async fn rollback(&self, snapshot: Option<&VolumeSnapshot>) -> Result<()> {
+        if self.backend.has_unit(&self.to)? {
+            self.backend.remove_unit(&self.to).await?;
+        }
+
+        if self.backend.is_installed(&self.to)? {
+            self.backend.uninstall(&self.to).await?;
+        }
+
+        if let Some(snapshot) = snapshot {
+            self.backend.rollback_snapshot(snapshot)?;
+            self.backend.destroy_snapshot(snapshot)?;
+        }
+
+        if !self.backend.is_installed(&self.from)? {
+            self.backend.install(&self.from).await?;
+        }
+
+        self.backend
+            .create_unit(&self.from, &self.volume_root)
+            .await
+    }
+
+    // the new version has to be running at the end of the period, without failing in between
+    async fn wait_healthy(&self) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + HEALTH_CHECK_PERIOD;
+        let mut running = false;
+
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+
+            running = match self.backend.status(&self.to).await? {
+                InstallStatus::Installed(status) => match status.last_run_state {
+                    LastRunState::Failed | LastRunState::Dead => {
+                        return Err(anyhow!("{} stopped after it was started", self.to.title))
+                    }
+                    LastRunState::Running => true,
+                    _ => false,
+                },
+                InstallStatus::NotInstalled => {
+                    return Err(anyhow!("{} is no longer installed", self.to.title))
+                }
+            };
+        }
+
+        if !running {
+            return Err(anyhow!(
+                "{} was not running {} seconds after it was started",
+                self.to.title,
+                HEALTH_CHECK_PERIOD.as_secs()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Upgrade, UpgradeBackend, HEALTH_CHECK_INTERVAL, HEALTH_CHECK_PERIOD};
+    use crate::{CompiledPackage, InstallStatus, Registry, Trust, VolumeSnapshot};
+    use anyhow::{anyhow, Result};
+    use buckle::systemd::{LastRunState, LoadState, RuntimeState, Status};
+    use std::{
+        collections::BTreeSet,
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    // keeps what is installed, the units and the snapshots in memory
+    #[derive(Debug, Default)]
+    struct FakeBackend {
+        installed: Mutex<BTreeSet<String>>,
+        units: Mutex<BTreeSet<String>>,
+        snapshots: Mutex<BTreeSet<String>>,
+        rolled_back: Mutex<Vec<String>>,
+        // what status() reports for each check, the last one from then on
+        states: Mutex<Vec<LastRunState>>,
+        checks: Mutex<usize>,
+    }
+
+    #[tonic::async_trait]
+    impl UpgradeBackend for FakeBackend {
+        fn snapshot(&self, _volume_root: &Path, name: &str) -> Result<VolumeSnapshot> {
+            let snapshot = VolumeSnapshot {
+                dataset: "trunk/app".into(),
+                name: name.into(),
+            };
+            self.snapshots.lock().unwrap().insert(snapshot.to_string());
+            Ok(snapshot)
+        }
+
+        fn rollback_snapshot(&self, snapshot: &VolumeSnapshot) -> Result<()> {
+            if !self
+                .snapshots
+                .lock()
+                .unwrap()
+                .contains(&snapshot.to_string())
+            {
+                return Err(anyhow!("no snapshot {}", snapshot));
+            }
+
+            self.rolled_back.lock().unwrap().push(snapshot.to_string());
+            Ok(())
+        }
+
+        fn destroy_snapshot(&self, snapshot: &VolumeSnapshot) -> Result<()> {
+            self.snapshots.lock().unwrap().remove(&snapshot.to_string());
+            Ok(())
+        }
+
+        fn is_installed(&self, package: &CompiledPackage) -> Result<bool> {
+            Ok(self
+                .installed
+                .lock()
+                .unwrap()
+                .contains(&package.title.to_string()))
+        }
+
+        fn has_unit(&self, package: &CompiledPackage) -> Result<bool> {
+            Ok(self
+                .units
+                .lock()
+                .unwrap()
+                .contains(&package.title.to_string()))
+        }
+
+        async fn status(&self, package: &CompiledPackage) -> Result<InstallStatus> {
+            if !self.is_installed(package)? {
+                return Ok(InstallStatus::NotInstalled);
+            }
+
+            let mut checks = self.checks.lock().unwrap();
+            let states = self.states.lock().unwrap();
+            let state = states[(*checks).min(states.len() - 1)].clone();
+            *checks += 1;
+
+            Ok(InstallStatus::Installed(Status {
+                load_state: LoadState::Loaded,
+                runtime_state: RuntimeState::Started,
+                last_run_state: state,
+            }))
+        }
+
+        async fn install(&self, package: &CompiledPackage) -> Result<()> {
+            self.installed
+                .lock()
+                .unwrap()
+                .insert(package.title.to_string());
+            Ok(())
+        }
+
+        async fn uninstall(&self, package: &CompiledPackage) -> Result<()> {
+            self.installed
+                .lock()
+                .unwrap()
+                .remove(&package.title.to_string());
+            Ok(())
+        }
+
+        async fn create_unit(&self, package: &CompiledPackage, _volume_root: &Path) -> Result<()> {
+            self.units.lock().unwrap().insert(package.title.to_string());
+            Ok(())
+        }
+
+        async fn remove_unit(&self, package: &CompiledPackage) -> Result<()> {
+            self.units
+                .lock()
+                .unwrap()
+                .remove(&package.title.to_string());
+            Ok(())
+        }
+    }
+
+    fn upgrade(backend: Arc<FakeBackend>) -> Upgrade {
+        let registry = Registry::new("testdata/registry".into()).with_trust(Trust::unsigned());
+        let load = |version| {
+            registry
+                .load("podman-test", version)
+                .unwrap()
+                .compile()
+                .unwrap()
+        };
+
+        Upgrade {
+            from: load("0.0.1"),
+            to: load("0.0.2"),
+            volume_root: "/trunk/app".into(),
+            backend,
+        }
+    }
+
+    fn set(items: &[&str]) -> BTreeSet<String> {
+        items.iter().map(ToString::to_string).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_healthy() {
+        let backend = Arc::new(FakeBackend::default());
+        let upgrade = upgrade(backend.clone());
+        backend
+            .installed
+            .lock()
+            .unwrap()
+            .insert(upgrade.to.title.to_string());
+
+        // up for the whole period
+        *backend.states.lock().unwrap() = vec![LastRunState::Waiting, LastRunState::Running];
+        upgrade.wait_healthy().await.unwrap();
+        assert_eq!(
+            *backend.checks.lock().unwrap() as u64,
+            HEALTH_CHECK_PERIOD.as_secs() / HEALTH_CHECK_INTERVAL.as_secs()
+        );
+
+        // up, then failed
+        *backend.checks.lock().unwrap() = 0;
+        *backend.states.lock().unwrap() = vec![
+            LastRunState::Running,
+            LastRunState::Running,
+            LastRunState::Failed,
+        ];
+        let err = upgrade.wait_healthy().await.unwrap_err().to_string();
+        assert!(err.contains("stopped after it was started"), "{}", err);
+        assert_eq!(*backend.checks.lock().unwrap(), 3);
+
+        // never came up
+        *backend.checks.lock().unwrap() = 0;
+        *backend.states.lock().unwrap() = vec![LastRunState::Waiting];
+        let err = upgrade.wait_healthy().await.unwrap_err().to_string();
+        assert!(err.contains("was not running 30 seconds"), "{}", err);
+
+        // went away
+        backend.installed.lock().unwrap().clear();
+        let err = upgrade.wait_healthy().await.unwrap_err().to_string();
+        assert!(err.contains("no longer installed"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rollback() {
+        let backend = Arc::new(FakeBackend::default());
+        let upgrade = upgrade(backend.clone());
+        let from = upgrade.from.title.to_string();
+        let to = upgrade.to.title.to_string();
+
+        // the new version is in place, the old one is gone
+        *backend.installed.lock().unwrap() = set(&[&to]);
+        *backend.units.lock().unwrap() = set(&[&to]);
+        let snapshot = backend.snapshot(&upgrade.volume_root, "before").unwrap();
+
+        upgrade.rollback(Some(&snapshot)).await.unwrap();
+        assert_eq!(*backend.installed.lock().unwrap(), set(&[&from]));
+        assert_eq!(*backend.units.lock().unwrap(), set(&[&from]));
+        assert_eq!(
+            *backend.rolled_back.lock().unwrap(),
+            vec![snapshot.to_string()]
+        );
+        assert!(backend.snapshots.lock().unwrap().is_empty());
+
+        // it failed before the old version was uninstalled
+        backend.rolled_back.lock().unwrap().clear();
+        upgrade.rollback(None).await.unwrap();
+        assert_eq!(*backend.installed.lock().unwrap(), set(&[&from]));
+        assert_eq!(*backend.units.lock().unwrap(), set(&[&from]));
+        assert!(backend.rolled_back.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run() {
+        let backend = Arc::new(FakeBackend::default());
+        let upgrade = upgrade(backend.clone());
+        let from = upgrade.from.title.to_string();
+        let to = upgrade.to.title.to_string();
+
+        *backend.installed.lock().unwrap() = set(&[&from]);
+        *backend.units.lock().unwrap() = set(&[&from]);
+        *backend.states.lock().unwrap() = vec![LastRunState::Failed];
+
+        let err = upgrade.run().await.unwrap_err().to_string();
+        assert!(err.contains("was restored"), "{}", err);
+        assert_eq!(*backend.installed.lock().unwrap(), set(&[&from]));
+        assert_eq!(*backend.units.lock().unwrap(), set(&[&from]));
+
+        *backend.states.lock().unwrap() = vec![LastRunState::Running];
+        upgrade.run().await.unwrap();
+        assert_eq!(*backend.installed.lock().unwrap(), set(&[&to]));
+        assert_eq!(*backend.units.lock().unwrap(), set(&[&to]));
+        assert!(backend.snapshots.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Result};
+use buckle::zfs::Pool;
+use std::path::Path;
+use std::process::Stdio;
+
+//
+// snapshots of the volumes of a package, so that changing it can be undone. the volume root of a
+// package is a ZFS filesystem of its own, and its volumes may be filesystems of their own under
+// it, so snapshots are taken of all of them at once. filesystems are found through buckle, which
+// has no snapshot operations, so those run zfs.
+//
+
+const ZFS_COMMAND: &str = "zfs";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VolumeSnapshot {
+    // the filesystem of the volume root
+    pub dataset: String,
+    pub name: String,
+}
+
+impl std::fmt::Display for VolumeSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.dataset, self.name)
+    }
+}
+
+// snapshot operations only
+fn zfs(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new(ZFS_COMMAND)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "zfs {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+impl VolumeSnapshot {
+    // the filesystem mounted at a path. snapshots are recursive and rolling one back discards
+    // everything written since, so a path that is only a directory in a filesystem is refused:
+    // the snapshot would take in, and a rollback undo, whatever else that filesystem holds.
+    pub fn dataset(pool: &str, path: &Path) -> Result<String> {
+        Pool::new(pool)
+            .list(None)?
+            .into_iter()
+            .find(|stat| stat.mountpoint.as_deref().map(Path::new) == Some(path))
+            .map(|stat| stat.full_name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} is not the mountpoint of a filesystem in ZFS pool {}",
+                    path.display(),
+                    pool
+                )
+            })
+    }
+
+    pub fn create(pool: &str, path: &Path, name: &str) -> Result<Self> {
+        let snapshot = Self {
+            dataset: Self::dataset(pool, path)?,
+            name: name.to_string(),
+        };
+
+        zfs(&["snapshot", "-r", &snapshot.to_string()])?;
+        Ok(snapshot)
+    }
+
+    // discards everything written since the snapshot was taken, in every filesystem it was taken
+    // of. filesystems created since are left alone.
+    pub fn rollback(&self) -> Result<()> {
+        let suffix = format!("@{}", self.name);
+
+        for snapshot in zfs(&[
+            "list",
+            "-H",
+            "-o",
+            "name",
+            "-t",
+            "snapshot",
+            "-r",
+            &self.dataset,
+        ])?
+        .lines()
+        .filter(|snapshot| snapshot.ends_with(&suffix))
+        {
+            zfs(&["rollback", "-r", snapshot])?;
+        }
+
+        Ok(())
+    }
+
+    pub fn destroy(&self) -> Result<()> {
+        zfs(&["destroy", "-r", &self.to_string()]).map(|_| ())
+    }
+}